@@ -0,0 +1,250 @@
+/// This module contains analyses over expressions which are shared between
+/// several compiler passes (such as the optimization passes), and which work
+/// for any expression type implementing `ExprMeta`.
+use crate::common::{ExprKind, ExprMeta, PrimOp};
+
+use std::collections::BTreeSet;
+
+/// Determines whether evaluating an expression is free of side effects,
+/// meaning that it can be safely discarded if its value is never used.
+///
+/// An expression is considered impure if it may assign to a variable (set!)
+/// or to a field of a record (record-set!), call a function (which may do
+/// anything), print output (display, newline), raise an exception, capture or
+/// throw to a continuation, yield from or resume a generator, or trap at
+/// runtime (division, or taking the car/cdr of a null list). Allocating
+/// tuples, records, lists, closures or generators is considered pure, since
+/// nothing can observe an allocation whose result is discarded.
+pub fn is_pure<E: ExprMeta>(exp: &E) -> bool {
+    match exp.kind() {
+        ExprKind::Num(_) | ExprKind::Int64(_) | ExprKind::Float(_) => true,
+        ExprKind::Bool(_) | ExprKind::Str(_) | ExprKind::Id(_) => true,
+        ExprKind::Char(_) | ExprKind::Symbol(_) | ExprKind::Quote(_) => true,
+        ExprKind::Null(_) => true,
+        ExprKind::Lambda(_params, _ret_type, _body) => true,
+        ExprKind::Prim(PrimOp::Divide | PrimOp::Remainder | PrimOp::Modulo, args) => {
+            // Integer division traps when dividing by zero, or when dividing
+            // the smallest integer by -1 (since the result overflows), but
+            // floating point division never traps.
+            let safe_divisor = match args[1].kind() {
+                ExprKind::Num(x) => *x != 0 && *x != -1,
+                ExprKind::Int64(x) => *x != 0 && *x != -1,
+                ExprKind::Float(_) => true,
+                _ => false,
+            };
+            is_pure(&args[0]) && safe_divisor
+        }
+        ExprKind::Prim(PrimOp::SetCar | PrimOp::SetCdr, _args) => false,
+        // Converting a float to an integer traps if it is out of range, and
+        // so does converting an int which is too large for the result (or
+        // for the 32 bits that the bitwise operations act on)
+        ExprKind::Prim(PrimOp::FloatToInt | PrimOp::FloatToInt64 | PrimOp::IntToInt64, _args) => {
+            false
+        }
+        // Converting an int to a char traps if it isn't a valid code point
+        ExprKind::Prim(PrimOp::IntToChar, _args) => false,
+        ExprKind::Prim(
+            PrimOp::BitAnd
+            | PrimOp::BitOr
+            | PrimOp::BitXor
+            | PrimOp::BitNot
+            | PrimOp::ShiftLeft
+            | PrimOp::ShiftRight,
+            _args,
+        ) => false,
+        ExprKind::Prim(_op, args) => args.iter().all(is_pure),
+        ExprKind::If(pred, cons, alt) => is_pure(pred) && is_pure(cons) && is_pure(alt),
+        ExprKind::Let(bindings, body) => {
+            bindings.iter().all(|(_name, exp)| is_pure(exp)) && is_pure(body)
+        }
+        // the bindings of a letrec are lambdas
+        ExprKind::Letrec(_bindings, body) => is_pure(body),
+        ExprKind::Begin(exps) => exps.iter().all(is_pure),
+        ExprKind::Set(_var, _exp) => false,
+        ExprKind::Cons(first, rest) => is_pure(first) && is_pure(rest),
+        ExprKind::Car(_exp) | ExprKind::Cdr(_exp) => false,
+        ExprKind::IsNull(exp) => is_pure(exp),
+        ExprKind::Display(_) | ExprKind::Newline => false,
+        ExprKind::FnApp(_func, _args) => false,
+        ExprKind::Tuple(exps) => exps.iter().all(is_pure),
+        ExprKind::TupleGet(tuple, _key) => is_pure(tuple),
+        ExprKind::TupleSet(_tuple, _key, _val) => false,
+        ExprKind::Pack(val, _sub, _exist) => is_pure(val),
+        ExprKind::Unpack(_var, package, _type_var, body) => is_pure(package) && is_pure(body),
+        ExprKind::Record(bindings) => bindings.iter().all(|(_name, exp, _mutable)| is_pure(exp)),
+        ExprKind::RecordGet(record, _key) => is_pure(record),
+        ExprKind::RecordSet(_record, _key, _val) => false,
+        ExprKind::Raise(_exn, _typ) => false,
+        // a handler only runs if its body raises an exception
+        ExprKind::Handle(_var, _typ, handler, body) => is_pure(handler) && is_pure(body),
+        ExprKind::CallCC(_func) => false,
+        ExprKind::Throw(_cont, _val, _typ) => false,
+        // the body of a generator only runs when it is resumed
+        ExprKind::MakeGenerator(_typ, _body) => true,
+        ExprKind::Yield(_val) | ExprKind::Next(_val) => false,
+        ExprKind::Derived(_form, exp) => is_pure(exp),
+    }
+}
+
+/// Computes the set of variables which occur free within an expression, i.e.
+/// those which are not bound by an enclosing let, letrec, lambda, unpack or
+/// handler within the expression itself.
+///
+/// The target of a set! expression counts as a free occurrence of a
+/// variable.
+pub fn free_vars<E: ExprMeta>(exp: &E) -> BTreeSet<String> {
+    let mut vars = BTreeSet::new();
+    collect_free_vars(exp, &mut vars);
+    vars
+}
+
+fn collect_free_vars<E: ExprMeta>(exp: &E, vars: &mut BTreeSet<String>) {
+    match exp.kind() {
+        ExprKind::Id(x) => {
+            vars.insert(x.clone());
+        }
+        ExprKind::Set(x, val) => {
+            vars.insert(x.clone());
+            collect_free_vars(val, vars);
+        }
+        ExprKind::Let(bindings, body) => {
+            let mut body_vars = free_vars(body);
+            for (name, _exp) in bindings {
+                body_vars.remove(name);
+            }
+            vars.extend(body_vars);
+            for (_name, exp) in bindings {
+                collect_free_vars(exp, vars);
+            }
+        }
+        ExprKind::Letrec(bindings, body) => {
+            let mut letrec_vars = free_vars(body);
+            for (_name, exp) in bindings {
+                collect_free_vars(exp, &mut letrec_vars);
+            }
+            for (name, _exp) in bindings {
+                letrec_vars.remove(name);
+            }
+            vars.extend(letrec_vars);
+        }
+        ExprKind::Lambda(params, _ret_type, body) => {
+            let mut body_vars = free_vars(body);
+            for (name, _typ) in params {
+                body_vars.remove(name);
+            }
+            vars.extend(body_vars);
+        }
+        ExprKind::Unpack(var, package, _type_var, body) => {
+            let mut body_vars = free_vars(body);
+            body_vars.remove(var);
+            vars.extend(body_vars);
+            collect_free_vars(package, vars);
+        }
+        ExprKind::Handle(var, _typ, handler, body) => {
+            let mut handler_vars = free_vars(handler);
+            handler_vars.remove(var);
+            vars.extend(handler_vars);
+            collect_free_vars(body, vars);
+        }
+        _ => {
+            for child in children(exp) {
+                collect_free_vars(child, vars);
+            }
+        }
+    }
+}
+
+/// Computes the set of variable names which are the target of a set!
+/// expression anywhere within an expression (including within nested
+/// lambdas).
+pub fn assigned_vars<E: ExprMeta>(exp: &E) -> BTreeSet<String> {
+    let mut vars = BTreeSet::new();
+    collect_assigned_vars(exp, &mut vars);
+    vars
+}
+
+fn collect_assigned_vars<E: ExprMeta>(exp: &E, vars: &mut BTreeSet<String>) {
+    if let ExprKind::Set(x, _val) = exp.kind() {
+        vars.insert(x.clone());
+    }
+    for child in children(exp) {
+        collect_assigned_vars(child, vars);
+    }
+}
+
+/// Computes the set of variable names which are bound by a let, letrec,
+/// lambda, unpack or handler anywhere within an expression.
+pub fn bound_vars<E: ExprMeta>(exp: &E) -> BTreeSet<String> {
+    let mut vars = BTreeSet::new();
+    collect_bound_vars(exp, &mut vars);
+    vars
+}
+
+fn collect_bound_vars<E: ExprMeta>(exp: &E, vars: &mut BTreeSet<String>) {
+    match exp.kind() {
+        ExprKind::Let(bindings, _body) | ExprKind::Letrec(bindings, _body) => {
+            vars.extend(bindings.iter().map(|(name, _exp)| name.clone()));
+        }
+        ExprKind::Lambda(params, _ret_type, _body) => {
+            vars.extend(params.iter().map(|(name, _typ)| name.clone()));
+        }
+        ExprKind::Unpack(var, _, _, _) | ExprKind::Handle(var, _, _, _) => {
+            vars.insert(var.clone());
+        }
+        _ => {}
+    }
+    for child in children(exp) {
+        collect_bound_vars(child, vars);
+    }
+}
+
+/// Computes the size of an expression, measured as the number of nodes in
+/// its abstract syntax tree.
+pub fn expr_size<E: ExprMeta>(exp: &E) -> usize {
+    1 + children(exp).into_iter().map(expr_size).sum::<usize>()
+}
+
+/// Returns the immediate subexpressions of an expression, in evaluation
+/// order.
+pub fn children<E: ExprMeta>(exp: &E) -> Vec<&E> {
+    match exp.kind() {
+        ExprKind::Num(_) | ExprKind::Int64(_) | ExprKind::Float(_) => vec![],
+        ExprKind::Bool(_) | ExprKind::Str(_) | ExprKind::Id(_) => vec![],
+        ExprKind::Char(_) | ExprKind::Symbol(_) | ExprKind::Quote(_) => vec![],
+        ExprKind::Null(_) | ExprKind::Newline => vec![],
+        ExprKind::Prim(_op, args) => args.iter().collect(),
+        ExprKind::If(pred, cons, alt) => vec![pred, cons, alt],
+        ExprKind::Let(bindings, body) => bindings
+            .iter()
+            .map(|(_name, exp)| exp)
+            .chain(std::iter::once(body))
+            .collect(),
+        ExprKind::Letrec(bindings, body) => bindings
+            .iter()
+            .map(|(_name, exp)| exp)
+            .chain(std::iter::once(body))
+            .collect(),
+        ExprKind::Lambda(_params, _ret_type, body) => vec![body],
+        ExprKind::Begin(exps) => exps.iter().collect(),
+        ExprKind::Set(_var, val) => vec![val],
+        ExprKind::Cons(first, rest) => vec![first, rest],
+        ExprKind::Car(exp) | ExprKind::Cdr(exp) | ExprKind::IsNull(exp) => vec![exp],
+        ExprKind::Display(exp) => vec![exp],
+        ExprKind::FnApp(func, args) => std::iter::once(func).chain(args.iter()).collect(),
+        ExprKind::Tuple(exps) => exps.iter().collect(),
+        ExprKind::TupleGet(tuple, _key) => vec![tuple],
+        ExprKind::TupleSet(tuple, _key, val) => vec![tuple, val],
+        ExprKind::Pack(val, _sub, _exist) => vec![val],
+        ExprKind::Unpack(_var, package, _type_var, body) => vec![package, body],
+        ExprKind::Record(bindings) => bindings.iter().map(|(_name, exp, _mutable)| exp).collect(),
+        ExprKind::RecordGet(record, _key) => vec![record],
+        ExprKind::RecordSet(record, _key, val) => vec![record, val],
+        ExprKind::Raise(exn, _typ) => vec![exn],
+        ExprKind::Handle(_var, _typ, handler, body) => vec![body, handler],
+        ExprKind::CallCC(func) => vec![func],
+        ExprKind::Throw(cont, val, _typ) => vec![cont, val],
+        ExprKind::MakeGenerator(_typ, body) => vec![body],
+        ExprKind::Yield(val) | ExprKind::Next(val) => vec![val],
+        ExprKind::Derived(_form, exp) => vec![exp],
+    }
+}
@@ -1,9 +1,9 @@
 /// This module contains an assortment of functions for transforming Type,
 /// Expr, and TypedExpr structs that aim to eliminate the need for
 /// re-implementing recursion on these data structures.
-use crate::common::{ExprKind, Prog, TypedExpr};
+use crate::common::{Expr, ExprKind, Prog, TypedExpr};
 use crate::type_check::validate_lambda_type;
-use crate::types::Type;
+use crate::types::{Type, sort_fields};
 
 use im_rc::Vector;
 
@@ -26,8 +26,12 @@ where
     }
     match typ {
         Type::Int => Ok(Type::Int),
+        Type::Int64 => Ok(Type::Int64),
+        Type::Float => Ok(Type::Float),
         Type::Bool => Ok(Type::Bool),
         Type::Str => Ok(Type::Str),
+        Type::Char => Ok(Type::Char),
+        Type::Symbol => Ok(Type::Symbol),
         Type::List(base_type) => {
             let tbase_type = transform_type_recursive(base_type, transform_type)?;
             Ok(Type::List(Box::new(tbase_type)))
@@ -37,6 +41,14 @@ where
             let tret_type = transform_type_recursive(ret_type, transform_type)?;
             Ok(Type::Func(tin_types, Box::new(tret_type)))
         }
+        Type::Cont(base_type) => {
+            let tbase_type = transform_type_recursive(base_type, transform_type)?;
+            Ok(Type::Cont(Box::new(tbase_type)))
+        }
+        Type::Generator(base_type) => {
+            let tbase_type = transform_type_recursive(base_type, transform_type)?;
+            Ok(Type::Generator(Box::new(tbase_type)))
+        }
         Type::Tuple(types) => {
             let ttypes = transform_type_array(types, transform_type)?;
             Ok(Type::Tuple(ttypes))
@@ -44,13 +56,14 @@ where
         Type::Record(bindings) => {
             let tbindings = bindings
                 .iter()
-                .map(|(name, inner_type)| {
+                .map(|(name, inner_type, mutable)| {
                     Ok((
                         name.clone(),
                         transform_type_recursive(inner_type, transform_type)?,
+                        *mutable,
                     ))
                 })
-                .collect::<Result<Vector<(String, Type)>, E>>()?;
+                .collect::<Result<Vector<(String, Type, bool)>, E>>()?;
             Ok(Type::Record(tbindings))
         }
         Type::Exists(type_var, base_type) => {
@@ -80,8 +93,8 @@ where
 /// the function should choose some ExprKind cases to transform, and
 /// some ExprKind cases to leave as normal (but still recurse on). For example,
 /// the Record Elimination compiler pass will have special logic to return
-/// Some(Result) for the cases ExprKind::Record and ExprKind::RecordGet, but
-/// in all other cases it will return None.
+/// Some(Result) for the cases ExprKind::Record, ExprKind::RecordGet and
+/// ExprKind::RecordSet, but in all other cases it will return None.
 ///
 /// By doing so, we will still recursively apply the desired transformation
 /// everywhere in the tree, but the individualized logic for recursing into
@@ -114,21 +127,30 @@ where
     // Otherwise, recurse normally according to the individual structures.
     match &*exp.kind {
         ExprKind::Num(x) => Ok(TypedExpr::new(Type::Int, ExprKind::Num(*x))),
+        ExprKind::Int64(x) => Ok(TypedExpr::new(Type::Int64, ExprKind::Int64(*x))),
+        ExprKind::Float(x) => Ok(TypedExpr::new(Type::Float, ExprKind::Float(*x))),
         ExprKind::Bool(x) => Ok(TypedExpr::new(Type::Bool, ExprKind::Bool(*x))),
         ExprKind::Str(x) => Ok(TypedExpr::new(Type::Str, ExprKind::Str(x.clone()))),
+        ExprKind::Char(x) => Ok(TypedExpr::new(Type::Char, ExprKind::Char(*x))),
+        ExprKind::Symbol(x) => Ok(TypedExpr::new(Type::Symbol, ExprKind::Symbol(x.clone()))),
+        ExprKind::Quote(data) => Ok(TypedExpr::new(
+            exp.typ.clone(),
+            ExprKind::Quote(data.clone()),
+        )),
         ExprKind::Id(x) => Ok(TypedExpr::new(
             transform_type_recursive(&exp.typ, transform_type)?,
             ExprKind::Id(x.clone()),
         )),
-        ExprKind::Binop(op, arg1, arg2) => {
-            let targ1 = transform_typed_exp_recursive(arg1, transform_exp, transform_type)?;
-            let targ2 = transform_typed_exp_recursive(arg2, transform_exp, transform_type)?;
+        ExprKind::Prim(op, args) => {
+            let targs = args
+                .iter()
+                .map(|arg| transform_typed_exp_recursive(arg, transform_exp, transform_type))
+                .collect::<Result<Vector<TypedExpr>, E>>()?;
             Ok(TypedExpr::new(
-                // I suppose we can recurse here (opposed to just cloning
-                // exp.typ) in case there are binops in the future that
-                // return non-primitive types.
+                // primitive operations on lists return non-primitive types,
+                // so the type is transformed too
                 transform_type_recursive(&exp.typ, transform_type)?,
-                ExprKind::Binop(*op, targ1, targ2),
+                ExprKind::Prim(*op, targs),
             ))
         }
         ExprKind::If(pred, cons, alt) => {
@@ -155,6 +177,21 @@ where
                 ExprKind::Let(tbindings, tbody),
             ))
         }
+        ExprKind::Letrec(bindings, body) => {
+            let tbindings = bindings
+                .iter()
+                .map(|(name, subexp)| {
+                    let tsubexp =
+                        transform_typed_exp_recursive(subexp, transform_exp, transform_type)?;
+                    Ok((name.clone(), tsubexp))
+                })
+                .collect::<Result<Vector<(String, TypedExpr)>, E>>()?;
+            let tbody = transform_typed_exp_recursive(body, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                tbody.typ.clone(),
+                ExprKind::Letrec(tbindings, tbody),
+            ))
+        }
         ExprKind::Lambda(params, ret_type, body) => {
             let tparams = params
                 .iter()
@@ -217,6 +254,11 @@ where
             let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
             Ok(TypedExpr::new(Type::Bool, ExprKind::IsNull(tval)))
         }
+        ExprKind::Display(val) => {
+            let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(Type::Int, ExprKind::Display(tval)))
+        }
+        ExprKind::Newline => Ok(TypedExpr::new(Type::Int, ExprKind::Newline)),
         ExprKind::Null(typ) => {
             let ttyp = transform_type_recursive(typ, transform_type)?;
             Ok(TypedExpr::new(
@@ -252,19 +294,29 @@ where
                 _ => Err(E::from("First expression in tuple-ref is not a tuple.")),
             }
         }
+        ExprKind::TupleSet(tuple, key, val) => {
+            let ttuple = transform_typed_exp_recursive(tuple, transform_exp, transform_type)?;
+            let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                tval.typ.clone(),
+                ExprKind::TupleSet(ttuple, *key, tval),
+            ))
+        }
         ExprKind::Record(bindings) => {
             let tbindings = bindings
                 .iter()
-                .map(|(name, subexp)| {
+                .map(|(name, subexp, mutable)| {
                     let tsubexp =
                         transform_typed_exp_recursive(subexp, transform_exp, transform_type)?;
-                    Ok((name.clone(), tsubexp))
+                    Ok((name.clone(), tsubexp, *mutable))
                 })
-                .collect::<Result<Vector<(String, TypedExpr)>, E>>()?;
-            let types_vec: Vector<(String, Type)> = tbindings
+                .collect::<Result<Vector<(String, TypedExpr, bool)>, E>>()?;
+            let types_vec: Vector<(String, Type, bool)> = tbindings
                 .iter()
-                .map(|(field, exp)| (field.clone(), exp.typ.clone()))
+                .map(|(field, exp, mutable)| (field.clone(), exp.typ.clone(), *mutable))
                 .collect();
+            let types_vec = sort_fields(&types_vec, |field| &field.0)
+                .map_err(|_| E::from("Record has more than one field with the same name."))?;
             Ok(TypedExpr::new(
                 Type::Record(types_vec),
                 ExprKind::Record(tbindings),
@@ -274,8 +326,8 @@ where
             let trecord = transform_typed_exp_recursive(record, transform_exp, transform_type)?;
             let tkey_type = match trecord.typ.clone() {
                 Type::Record(fields) => {
-                    let matches: Vector<(String, Type)> = fields
-                        .iter().filter(|&pair| pair.0 == *key).cloned()
+                    let matches: Vector<(String, Type, bool)> = fields
+                        .iter().filter(|&field| field.0 == *key).cloned()
                         .collect();
                     if matches.is_empty() {
                         return Err(E::from("Key in record-get not found in record."));
@@ -289,6 +341,14 @@ where
                 ExprKind::RecordGet(trecord, key.clone()),
             ))
         }
+        ExprKind::RecordSet(record, key, val) => {
+            let trecord = transform_typed_exp_recursive(record, transform_exp, transform_type)?;
+            let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                tval.typ.clone(),
+                ExprKind::RecordSet(trecord, key.clone(), tval),
+            ))
+        }
         ExprKind::Pack(val, sub, exist) => {
             let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
             let tsub = transform_type_recursive(sub, transform_type)?;
@@ -306,6 +366,59 @@ where
                 ExprKind::Unpack(var.clone(), tpackage, *type_sub, tbody),
             ))
         }
+        ExprKind::Raise(exn, typ) => {
+            let texn = transform_typed_exp_recursive(exn, transform_exp, transform_type)?;
+            let ttyp = transform_type_recursive(typ, transform_type)?;
+            Ok(TypedExpr::new(ttyp.clone(), ExprKind::Raise(texn, ttyp)))
+        }
+        ExprKind::Handle(var, typ, handler, body) => {
+            let ttyp = transform_type_recursive(typ, transform_type)?;
+            let thandler = transform_typed_exp_recursive(handler, transform_exp, transform_type)?;
+            let tbody = transform_typed_exp_recursive(body, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                tbody.typ.clone(),
+                ExprKind::Handle(var.clone(), ttyp, thandler, tbody),
+            ))
+        }
+        ExprKind::CallCC(func) => {
+            let tfunc = transform_typed_exp_recursive(func, transform_exp, transform_type)?;
+            let ttyp = transform_type_recursive(&exp.typ, transform_type)?;
+            Ok(TypedExpr::new(ttyp, ExprKind::CallCC(tfunc)))
+        }
+        ExprKind::Throw(cont, val, typ) => {
+            let tcont = transform_typed_exp_recursive(cont, transform_exp, transform_type)?;
+            let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
+            let ttyp = transform_type_recursive(typ, transform_type)?;
+            Ok(TypedExpr::new(
+                ttyp.clone(),
+                ExprKind::Throw(tcont, tval, ttyp),
+            ))
+        }
+        ExprKind::MakeGenerator(typ, body) => {
+            let ttyp = transform_type_recursive(typ, transform_type)?;
+            let tbody = transform_typed_exp_recursive(body, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                Type::Generator(Box::new(ttyp.clone())),
+                ExprKind::MakeGenerator(ttyp, tbody),
+            ))
+        }
+        ExprKind::Yield(val) => {
+            let tval = transform_typed_exp_recursive(val, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(tval.typ.clone(), ExprKind::Yield(tval)))
+        }
+        ExprKind::Next(generator) => {
+            let tgenerator =
+                transform_typed_exp_recursive(generator, transform_exp, transform_type)?;
+            let ttyp = transform_type_recursive(&exp.typ, transform_type)?;
+            Ok(TypedExpr::new(ttyp, ExprKind::Next(tgenerator)))
+        }
+        ExprKind::Derived(form, subexp) => {
+            let tsubexp = transform_typed_exp_recursive(subexp, transform_exp, transform_type)?;
+            Ok(TypedExpr::new(
+                tsubexp.typ.clone(),
+                ExprKind::Derived(form.clone(), tsubexp),
+            ))
+        }
         ExprKind::FnApp(func, args) => {
             let tfunc = transform_typed_exp_recursive(func, transform_exp, transform_type)?;
             let targs = args
@@ -328,6 +441,96 @@ where
     }
 }
 
+/// Removes the type annotations from a type checked expression.
+///
+/// The type checker inserts conversions between subtypes and supertypes into
+/// the expressions it produces (see `type_check::coerce`), so this is used to
+/// continue compiling the converted expression.
+pub fn erase_types(exp: &TypedExpr) -> Expr {
+    let erase_array =
+        |exps: &Vector<TypedExpr>| exps.iter().map(erase_types).collect::<Vector<Expr>>();
+    let erase_bindings = |bindings: &Vector<(String, TypedExpr)>| {
+        bindings
+            .iter()
+            .map(|(name, exp)| (name.clone(), erase_types(exp)))
+            .collect::<Vector<(String, Expr)>>()
+    };
+    let kind = match &*exp.kind {
+        ExprKind::Prim(op, args) => ExprKind::Prim(*op, erase_array(args)),
+        ExprKind::If(pred, cons, alt) => {
+            ExprKind::If(erase_types(pred), erase_types(cons), erase_types(alt))
+        }
+        ExprKind::Let(bindings, body) => ExprKind::Let(erase_bindings(bindings), erase_types(body)),
+        ExprKind::Letrec(bindings, body) => {
+            ExprKind::Letrec(erase_bindings(bindings), erase_types(body))
+        }
+        ExprKind::Lambda(params, ret_type, body) => {
+            ExprKind::Lambda(params.clone(), ret_type.clone(), erase_types(body))
+        }
+        ExprKind::Begin(exps) => ExprKind::Begin(erase_array(exps)),
+        ExprKind::Set(var, val) => ExprKind::Set(var.clone(), erase_types(val)),
+        ExprKind::Cons(car, cdr) => ExprKind::Cons(erase_types(car), erase_types(cdr)),
+        ExprKind::Car(pair) => ExprKind::Car(erase_types(pair)),
+        ExprKind::Cdr(pair) => ExprKind::Cdr(erase_types(pair)),
+        ExprKind::IsNull(list) => ExprKind::IsNull(erase_types(list)),
+        ExprKind::Null(typ) => ExprKind::Null(typ.clone()),
+        ExprKind::Display(val) => ExprKind::Display(erase_types(val)),
+        ExprKind::Newline => ExprKind::Newline,
+        ExprKind::FnApp(func, args) => ExprKind::FnApp(erase_types(func), erase_array(args)),
+        ExprKind::Tuple(exps) => ExprKind::Tuple(erase_array(exps)),
+        ExprKind::TupleGet(tuple, key) => ExprKind::TupleGet(erase_types(tuple), *key),
+        ExprKind::TupleSet(tuple, key, val) => {
+            ExprKind::TupleSet(erase_types(tuple), *key, erase_types(val))
+        }
+        ExprKind::Pack(val, sub, exist) => {
+            ExprKind::Pack(erase_types(val), sub.clone(), exist.clone())
+        }
+        ExprKind::Unpack(var, package, type_var, body) => ExprKind::Unpack(
+            var.clone(),
+            erase_types(package),
+            *type_var,
+            erase_types(body),
+        ),
+        ExprKind::Record(bindings) => ExprKind::Record(
+            bindings
+                .iter()
+                .map(|(name, exp, mutable)| (name.clone(), erase_types(exp), *mutable))
+                .collect(),
+        ),
+        ExprKind::RecordGet(record, key) => ExprKind::RecordGet(erase_types(record), key.clone()),
+        ExprKind::RecordSet(record, key, val) => {
+            ExprKind::RecordSet(erase_types(record), key.clone(), erase_types(val))
+        }
+        ExprKind::Raise(exn, typ) => ExprKind::Raise(erase_types(exn), typ.clone()),
+        ExprKind::Handle(var, typ, handler, body) => ExprKind::Handle(
+            var.clone(),
+            typ.clone(),
+            erase_types(handler),
+            erase_types(body),
+        ),
+        ExprKind::CallCC(func) => ExprKind::CallCC(erase_types(func)),
+        ExprKind::Throw(cont, val, typ) => {
+            ExprKind::Throw(erase_types(cont), erase_types(val), typ.clone())
+        }
+        ExprKind::MakeGenerator(typ, body) => {
+            ExprKind::MakeGenerator(typ.clone(), erase_types(body))
+        }
+        ExprKind::Yield(val) => ExprKind::Yield(erase_types(val)),
+        ExprKind::Next(generator) => ExprKind::Next(erase_types(generator)),
+        ExprKind::Id(x) => ExprKind::Id(x.clone()),
+        ExprKind::Num(x) => ExprKind::Num(*x),
+        ExprKind::Int64(x) => ExprKind::Int64(*x),
+        ExprKind::Float(x) => ExprKind::Float(*x),
+        ExprKind::Bool(x) => ExprKind::Bool(*x),
+        ExprKind::Str(x) => ExprKind::Str(x.clone()),
+        ExprKind::Char(x) => ExprKind::Char(*x),
+        ExprKind::Symbol(x) => ExprKind::Symbol(x.clone()),
+        ExprKind::Quote(data) => ExprKind::Quote(data.clone()),
+        ExprKind::Derived(form, exp) => ExprKind::Derived(form.clone(), erase_types(exp)),
+    };
+    Expr::new(kind)
+}
+
 /// Converts a program into one without record or record-ref expressions.
 ///
 /// See `record_elim_exp` for more specific details.
@@ -352,7 +555,15 @@ where
             ))
         })
         .collect::<Result<Vector<(String, TypedExpr)>, E>>()?;
+    let tglobals = prog
+        .globals
+        .iter()
+        .map(|(name, typ)| Ok((name.clone(), transform_type_recursive(typ, transform_type)?)))
+        .collect::<Result<Vector<(String, Type)>, E>>()?;
     Ok(Prog {
+        externs: prog.externs.clone(),
+        globals: tglobals,
+        exports: prog.exports.clone(),
         exp: texp,
         fns: tfns,
     })
@@ -1,4 +1,6 @@
-use crate::common::{generate_env_name, generate_id, generate_var_name, Expr, ExprKind, TypeEnv};
+use crate::common::{
+    generate_env_name, generate_func_name, generate_id, generate_var_name, Expr, ExprKind, TypeEnv,
+};
 use crate::type_check::tc_with_env;
 use crate::types::Type;
 use im_rc::{vector, Vector};
@@ -21,11 +23,17 @@ impl std::fmt::Display for ClosureConvertError {
     }
 }
 
-fn cc_type(typ: &Type) -> Result<Type, ClosureConvertError> {
+/// Convert a type into the type of its closure converted values, i.e. where
+/// every function type becomes the type of a closure.
+pub fn cc_type(typ: &Type) -> Result<Type, ClosureConvertError> {
     match typ {
         Type::Int => Ok(Type::Int),
+        Type::Int64 => Ok(Type::Int64),
+        Type::Float => Ok(Type::Float),
         Type::Bool => Ok(Type::Bool),
         Type::Str => Ok(Type::Str),
+        Type::Char => Ok(Type::Char),
+        Type::Symbol => Ok(Type::Symbol),
         Type::List(base_typ) => {
             let cc_base_typ = cc_type(base_typ)?;
             Ok(Type::List(Box::new(cc_base_typ)))
@@ -49,14 +57,20 @@ fn cc_type(typ: &Type) -> Result<Type, ClosureConvertError> {
         Type::Record(bindings) => {
             let cc_bindings = bindings
                 .iter()
-                .map(|pair| Ok((pair.0.clone(), cc_type(&pair.1)?)))
-                .collect::<Result<Vector<(String, Type)>, ClosureConvertError>>()?;
+                .map(|field| Ok((field.0.clone(), cc_type(&field.1)?, field.2)))
+                .collect::<Result<Vector<(String, Type, bool)>, ClosureConvertError>>()?;
             Ok(Type::Record(cc_bindings))
         }
         Type::Exists(typ_var, base_typ) => {
             let cc_base_typ = cc_type(base_typ)?;
             Ok(Type::Exists(*typ_var, Box::new(cc_base_typ)))
         }
+        Type::Cont(_base_typ) => Err(ClosureConvertError::from(
+            "Continuation types should have been removed by CPS conversion.",
+        )),
+        Type::Generator(_base_typ) => Err(ClosureConvertError::from(
+            "Generator types should have been removed by CPS conversion.",
+        )),
         Type::TypeVar(x) => Ok(Type::TypeVar(*x)),
         Type::Unknown => Ok(Type::Unknown),
     }
@@ -67,12 +81,12 @@ fn cc_type_array(typs: &Vector<Type>) -> Result<Vector<Type>, ClosureConvertErro
 }
 
 fn cc_bindings(
-    bindings: &Vector<(String, Expr)>,
+    bindings: &Vector<(String, Expr, bool)>,
     env: &TypeEnv,
-) -> Result<Vector<(String, Expr)>, ClosureConvertError> {
+) -> Result<Vector<(String, Expr, bool)>, ClosureConvertError> {
     bindings
         .iter()
-        .map(|pair| cc(&pair.1, env).map(|cexp| (pair.0.clone(), cexp)))
+        .map(|field| cc(&field.1, env).map(|cexp| (field.0.clone(), cexp, field.2)))
         .collect()
 }
 
@@ -83,20 +97,23 @@ fn cc_lambda(
     env: &TypeEnv,
 ) -> Result<Expr, ClosureConvertError> {
     // Closure convert the body, with knowledge of the types of the lambda's parameters
+    // (the environment only contains the original, unconverted types)
     let mut new_body = cc(body, &env.add_bindings(params.clone()))?;
 
     // Calculate the set of free variables in the lambda
     // which is the free variables in the body, minus the variables bound by the parameters
-    let free_vars = get_free_vars_lambda(params, &new_body)?;
+    // (global definitions do not need to be stored in the environment)
+    let mut free_vars = get_free_vars_lambda(params, &new_body)?;
+    free_vars.retain(|var| !env.is_global(var));
 
     // Construct the environment name
     let env_name: String = generate_env_name();
 
     // Construct the environment
     // (x, Id(x)) (y, Id(y)) ...
-    let env_contents: Vector<(String, Expr)> = free_vars
+    let env_contents: Vector<(String, Expr, bool)> = free_vars
         .iter()
-        .map(|var| (var.clone(), Expr::new(ExprKind::Id(var.clone()))))
+        .map(|var| (var.clone(), Expr::new(ExprKind::Id(var.clone())), false))
         .collect();
     let new_env = Expr::new(ExprKind::Record(env_contents));
 
@@ -113,18 +130,7 @@ fn cc_lambda(
         )?;
     }
 
-    let free_var_types: Vector<(String, Type)> = free_vars
-        .iter()
-        .cloned()
-        .map(|var| {
-            Ok((
-                var.clone(),
-                cc_type(env.find(&var).ok_or({
-                    "No type found for free variable during closure conversion."
-                })?)?,
-            ))
-        })
-        .collect::<Result<Vector<(String, Type)>, ClosureConvertError>>()?;
+    let record_typ = Type::Record(free_var_types(&free_vars, env)?);
 
     // Construct new parameter list
     // Same as original parameter list, except an environment is appended to the beginning
@@ -134,17 +140,15 @@ fn cc_lambda(
     // (ex. function types are replaced with existential types)
     let mut new_params = params
         .iter()
-        .cloned()
         .map(|pair| Ok((pair.0.clone(), cc_type(&pair.1)?)))
         .collect::<Result<Vector<(String, Type)>, ClosureConvertError>>()?;
-    let record_typ = Type::Record(free_var_types);
     new_params.push_front((env_name, record_typ.clone()));
 
     let new_ret_typ = cc_type(&ret_type.clone())?;
 
     let new_lambda = Expr::new(ExprKind::Lambda(new_params, new_ret_typ, new_body));
 
-    let orig_param_typs = params.clone().iter().map(|pair| pair.1.clone()).collect();
+    let orig_param_typs = params.iter().map(|pair| pair.1.clone()).collect();
     let new_lambda_typ = cc_type(&Type::Func(orig_param_typs, Box::new(ret_type.clone())))?;
 
     let new_closure = Expr::new(ExprKind::Tuple(vector![new_lambda, new_env]));
@@ -155,6 +159,150 @@ fn cc_lambda(
     )))
 }
 
+/// The (closure converted) types of the variables captured in a closure's
+/// environment, as a list of record fields.
+fn free_var_types(
+    free_vars: &Vector<String>,
+    env: &TypeEnv,
+) -> Result<Vector<(String, Type, bool)>, ClosureConvertError> {
+    free_vars
+        .iter()
+        .map(|var| {
+            Ok((
+                var.clone(),
+                cc_type(
+                    env.find(var)
+                        .ok_or("No type found for free variable during closure conversion.")?,
+                )?,
+                false,
+            ))
+        })
+        .collect()
+}
+
+/// Closure convert a letrec, whose bindings are lambdas which may refer to
+/// themselves and to each other.
+///
+/// A closure can't capture itself (or a closure defined alongside it) in its
+/// environment, since neither exists until the environment has been built.
+/// Instead, the lambdas share a single environment holding everything any of
+/// them captures, and are bound by the letrec to new function names. Within
+/// their bodies, a reference to one of the letrec's names is replaced with a
+/// closure built from that function and the environment passed in, and the
+/// body of the letrec gets the same closures built from a new environment.
+/// ex. (letrec ((f (lambda ((x : int)) : int (f y)))) (f 1))
+///  -> (letrec ((funcX (lambda ((envX : (record (y : int))) (x : int)) : int
+///                       ((pack (make-tuple funcX envX) ...) (record-ref envX y)))))
+///       (let ((f (pack (make-tuple funcX (record (y y))) ...))) (f 1)))
+fn cc_letrec(
+    bindings: &Vector<(String, Expr)>,
+    body: &Expr,
+    env: &TypeEnv,
+) -> Result<Expr, ClosureConvertError> {
+    let mut lambdas = Vector::new();
+    let mut func_types = Vector::new();
+    for (name, binding) in bindings {
+        match &*binding.kind {
+            ExprKind::Lambda(params, ret_typ, lambda_body) => {
+                let param_typs = params.iter().map(|pair| pair.1.clone()).collect();
+                func_types.push_back((
+                    name.clone(),
+                    Type::Func(param_typs, Box::new(ret_typ.clone())),
+                ));
+                lambdas.push_back((params, ret_typ, lambda_body));
+            }
+            _ => {
+                return Err(ClosureConvertError(format!(
+                    "Letrec binding {name} is not a lambda."
+                )));
+            }
+        }
+    }
+    let names: Vector<String> = bindings.iter().map(|pair| pair.0.clone()).collect();
+    let rec_env = env.add_bindings(func_types.clone());
+
+    // Closure convert the bodies of the lambdas, and find everything which
+    // must be stored in their shared environment
+    let mut new_bodies = Vector::new();
+    let mut free_vars: Vector<String> = vector![];
+    for (params, _ret_typ, lambda_body) in &lambdas {
+        let new_body = cc(lambda_body, &rec_env.add_bindings((*params).clone()))?;
+        for var in get_free_vars_lambda(params, &new_body)? {
+            if !env.is_global(&var) && !names.contains(&var) && !free_vars.contains(&var) {
+                free_vars.push_back(var);
+            }
+        }
+        new_bodies.push_back(new_body);
+    }
+
+    let env_name: String = generate_env_name();
+    let record_typ = Type::Record(free_var_types(&free_vars, env)?);
+    let func_names: Vector<String> = names.iter().map(|_name| generate_func_name()).collect();
+
+    // ex. if f is bound to funcX, replace f with (pack (make-tuple funcX <env>) ...)
+    let closure = |index: usize, closure_env: Expr| -> Result<Expr, ClosureConvertError> {
+        Ok(Expr::new(ExprKind::Pack(
+            Expr::new(ExprKind::Tuple(vector![
+                Expr::new(ExprKind::Id(func_names[index].clone())),
+                closure_env,
+            ])),
+            record_typ.clone(),
+            cc_type(&func_types[index].1)?,
+        )))
+    };
+
+    let mut new_lambdas = Vector::new();
+    for (index, ((params, ret_typ, _lambda_body), mut new_body)) in
+        lambdas.iter().zip(new_bodies).enumerate()
+    {
+        // Substitute free variables in the body with references to the environment
+        for var in &free_vars {
+            new_body = substitute(
+                &new_body,
+                var,
+                &Expr::new(ExprKind::RecordGet(
+                    Expr::new(ExprKind::Id(env_name.clone())),
+                    var.clone(),
+                )),
+            )?;
+        }
+        // (unless a parameter shadows the name)
+        for (other, name) in names.iter().enumerate() {
+            if !params.iter().any(|pair| pair.0 == *name) {
+                let self_closure = closure(other, Expr::new(ExprKind::Id(env_name.clone())))?;
+                new_body = substitute(&new_body, name, &self_closure)?;
+            }
+        }
+
+        let mut new_params = params
+            .iter()
+            .map(|pair| Ok((pair.0.clone(), cc_type(&pair.1)?)))
+            .collect::<Result<Vector<(String, Type)>, ClosureConvertError>>()?;
+        new_params.push_front((env_name.clone(), record_typ.clone()));
+        let new_lambda = Expr::new(ExprKind::Lambda(new_params, cc_type(ret_typ)?, new_body));
+        new_lambdas.push_back((func_names[index].clone(), new_lambda));
+    }
+
+    let env_contents: Vector<(String, Expr, bool)> = free_vars
+        .iter()
+        .map(|var| (var.clone(), Expr::new(ExprKind::Id(var.clone())), false))
+        .collect();
+    let closures = names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| {
+            let new_env = Expr::new(ExprKind::Record(env_contents.clone()));
+            Ok((name.clone(), closure(index, new_env)?))
+        })
+        .collect::<Result<Vector<(String, Expr)>, ClosureConvertError>>()?;
+
+    let new_body = cc(body, &rec_env)?;
+    Ok(Expr::new(ExprKind::Letrec(
+        new_lambdas,
+        Expr::new(ExprKind::Let(closures, new_body)),
+    )))
+}
+
 fn cc_fn_app(func: &Expr, args: &Vector<Expr>, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
     let tuple_name = generate_var_name();
     let tuple_name_id = Expr::new(ExprKind::Id(tuple_name.clone()));
@@ -183,17 +331,19 @@ fn substitute_array(
         .collect()
 }
 
-fn substitute(
+/// Substitute `replace_with` for every free occurrence of the variable
+/// `match_exp` within `exp`.
+///
+/// Returns an error if doing so would cause a free variable of
+/// `replace_with` to be captured by a binding within `exp`, or if `exp`
+/// assigns to `match_exp` using set!.
+pub(crate) fn substitute(
     exp: &Expr,
     match_exp: &str,
     replace_with: &Expr,
 ) -> Result<Expr, ClosureConvertError> {
     match &*exp.kind {
-        ExprKind::Binop(op, arg1, arg2) => {
-            substitute(arg1, match_exp, replace_with).and_then(|sarg1| {
-                substitute(arg2, match_exp, replace_with).map(|sarg2| Expr::new(ExprKind::Binop(*op, sarg1, sarg2)))
-            })
-        }
+        ExprKind::Prim(op, args) => substitute_array(args, match_exp, replace_with).map(|sargs| Expr::new(ExprKind::Prim(*op, sargs))),
         ExprKind::If(pred, cons, alt) => {
             substitute(pred, match_exp, replace_with).and_then(|spred| {
                 substitute(cons, match_exp, replace_with).and_then(|scons| {
@@ -209,8 +359,31 @@ fn substitute(
                 })
                 .collect();
             let bindings_sub: Vector<(String, Expr)> = bindings_sub?;
+            if bindings.iter().any(|pair| pair.0 == match_exp) {
+                return Ok(Expr::new(ExprKind::Let(bindings_sub, body.clone())));
+            }
+            let sub_free_vars = get_free_vars(replace_with)?;
+            if bindings.iter().any(|pair| sub_free_vars.contains(&pair.0)) {
+                return Err(ClosureConvertError::from("Tried to substitute an expression with free variables into a let which will result in said free variables getting captured!"));
+            }
             substitute(body, match_exp, replace_with).map(|sbody| Expr::new(ExprKind::Let(bindings_sub, sbody)))
         }
+        ExprKind::Letrec(bindings, body) => {
+            if bindings.iter().any(|pair| pair.0 == match_exp) {
+                return Ok(exp.clone());
+            }
+            let sub_free_vars = get_free_vars(replace_with)?;
+            if bindings.iter().any(|pair| sub_free_vars.contains(&pair.0)) {
+                return Err(ClosureConvertError::from("Tried to substitute an expression with free variables into a letrec which will result in said free variables getting captured!"));
+            }
+            let bindings_sub = bindings
+                .iter()
+                .map(|pair| {
+                    substitute(&pair.1, match_exp, replace_with).map(|sexp| (pair.0.clone(), sexp))
+                })
+                .collect::<Result<Vector<(String, Expr)>, ClosureConvertError>>()?;
+            substitute(body, match_exp, replace_with).map(|sbody| Expr::new(ExprKind::Letrec(bindings_sub, sbody)))
+        }
         ExprKind::Lambda(params, ret_type, body) => {
             let param_names: Vector<String> = params.iter().map(|pair| pair.0.clone()).collect();
             if !param_names.contains(&String::from(match_exp)) {
@@ -242,15 +415,26 @@ fn substitute(
         ExprKind::Record(bindings) => {
             let cbindings = bindings
                 .iter()
-                .map(|pair| {
-                    substitute(&pair.1, match_exp, replace_with).map(|sexp| (pair.0.clone(), sexp))
+                .map(|field| {
+                    substitute(&field.1, match_exp, replace_with)
+                        .map(|sexp| (field.0.clone(), sexp, field.2))
                 })
-                .collect::<Result<Vector<(String, Expr)>, ClosureConvertError>>()?;
+                .collect::<Result<Vector<(String, Expr, bool)>, ClosureConvertError>>()?;
             Ok(Expr::new(ExprKind::Record(cbindings)))
         }
         ExprKind::RecordGet(record, key) => substitute(record, match_exp, replace_with).map(|srecord| Expr::new(ExprKind::RecordGet(srecord, key.clone()))),
+        ExprKind::RecordSet(record, key, val) => substitute(record, match_exp, replace_with)
+            .and_then(|srecord| {
+                substitute(val, match_exp, replace_with)
+                    .map(|sval| Expr::new(ExprKind::RecordSet(srecord, key.clone(), sval)))
+            }),
         ExprKind::Begin(exps) => substitute_array(exps, match_exp, replace_with).map(|sexps| Expr::new(ExprKind::Begin(sexps))),
-        ExprKind::Set(var, val) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Set(var.clone(), sval))),
+        ExprKind::Set(var, val) => {
+            if var == match_exp {
+                return Err(ClosureConvertError::from("Tried to substitute an expression for a variable which is assigned to with set!"));
+            }
+            substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Set(var.clone(), sval)))
+        }
         ExprKind::Cons(first, second) => {
             substitute(first, match_exp, replace_with).and_then(|sfirst| {
                 substitute(second, match_exp, replace_with).map(|ssecond| Expr::new(ExprKind::Cons(sfirst, ssecond)))
@@ -260,8 +444,21 @@ fn substitute(
         ExprKind::Cdr(val) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Cdr(sval))),
         ExprKind::Tuple(vals) => substitute_array(vals, match_exp, replace_with).map(|svals| Expr::new(ExprKind::Tuple(svals))),
         ExprKind::TupleGet(tuple, key) => substitute(tuple, match_exp, replace_with).map(|stuple| Expr::new(ExprKind::TupleGet(stuple, *key))),
+        ExprKind::TupleSet(tuple, key, val) => {
+            substitute(tuple, match_exp, replace_with).and_then(|stuple| {
+                substitute(val, match_exp, replace_with)
+                    .map(|sval| Expr::new(ExprKind::TupleSet(stuple, *key, sval)))
+            })
+        }
         ExprKind::Pack(val, sub, exist) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Pack(sval, sub.clone(), exist.clone()))),
         ExprKind::Unpack(var, package, type_sub, body) => {
+            if var == match_exp {
+                let spackage = substitute(package, match_exp, replace_with)?;
+                return Ok(Expr::new(ExprKind::Unpack(var.clone(), spackage, *type_sub, body.clone())));
+            }
+            if get_free_vars(replace_with)?.contains(var) {
+                return Err(ClosureConvertError::from("Tried to substitute an expression with free variables into an unpack which will result in said free variables getting captured!"));
+            }
             substitute(package, match_exp, replace_with).and_then(|spackage| {
                 substitute(body, match_exp, replace_with).map(|sbody| Expr::new(ExprKind::Unpack(
                         var.clone(),
@@ -271,8 +468,35 @@ fn substitute(
                     )))
             })
         }
+        ExprKind::Raise(exn, typ) => substitute(exn, match_exp, replace_with).map(|sexn| Expr::new(ExprKind::Raise(sexn, typ.clone()))),
+        ExprKind::Handle(var, typ, handler, body) => {
+            let sbody = substitute(body, match_exp, replace_with)?;
+            if var == match_exp {
+                return Ok(Expr::new(ExprKind::Handle(var.clone(), typ.clone(), handler.clone(), sbody)));
+            }
+            if get_free_vars(replace_with)?.contains(var) {
+                return Err(ClosureConvertError::from("Tried to substitute an expression with free variables into a handler which will result in said free variables getting captured!"));
+            }
+            substitute(handler, match_exp, replace_with).map(|shandler| Expr::new(ExprKind::Handle(
+                    var.clone(),
+                    typ.clone(),
+                    shandler,
+                    sbody,
+                )))
+        }
+        ExprKind::CallCC(func) => substitute(func, match_exp, replace_with).map(|sfunc| Expr::new(ExprKind::CallCC(sfunc))),
+        ExprKind::Throw(cont, val, typ) => {
+            substitute(cont, match_exp, replace_with).and_then(|scont| {
+                substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Throw(scont, sval, typ.clone())))
+            })
+        }
+        ExprKind::MakeGenerator(typ, body) => substitute(body, match_exp, replace_with).map(|sbody| Expr::new(ExprKind::MakeGenerator(typ.clone(), sbody))),
+        ExprKind::Yield(val) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Yield(sval))),
+        ExprKind::Next(generator) => substitute(generator, match_exp, replace_with).map(|sgenerator| Expr::new(ExprKind::Next(sgenerator))),
         ExprKind::IsNull(val) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::IsNull(sval))),
         ExprKind::Null(_) => Ok(exp.clone()),
+        ExprKind::Display(val) => substitute(val, match_exp, replace_with).map(|sval| Expr::new(ExprKind::Display(sval))),
+        ExprKind::Newline => Ok(exp.clone()),
         ExprKind::Id(x) => {
             if x == match_exp {
                 Ok(replace_with.clone())
@@ -281,8 +505,14 @@ fn substitute(
             }
         }
         ExprKind::Num(_) => Ok(exp.clone()),
+        ExprKind::Int64(_) => Ok(exp.clone()),
+        ExprKind::Float(_) => Ok(exp.clone()),
         ExprKind::Bool(_) => Ok(exp.clone()),
         ExprKind::Str(_) => Ok(exp.clone()),
+        ExprKind::Char(_) => Ok(exp.clone()),
+        ExprKind::Symbol(_) => Ok(exp.clone()),
+        ExprKind::Quote(_) => Ok(exp.clone()),
+        ExprKind::Derived(form, subexp) => substitute(subexp, match_exp, replace_with).map(|sexp| Expr::new(ExprKind::Derived(form.clone(), sexp))),
     }
 }
 
@@ -296,8 +526,7 @@ fn get_free_vars_array(exps: &Vector<Expr>) -> Result<Vector<String>, ClosureCon
 
 fn get_free_vars(exp: &Expr) -> Result<Vector<String>, ClosureConvertError> {
     match &*exp.kind {
-        ExprKind::Binop(_op, arg1, arg2) => get_free_vars(arg1)
-            .and_then(|vars1| get_free_vars(arg2).map(|vars2| vars1 + vars2)),
+        ExprKind::Prim(_op, args) => get_free_vars_array(args),
         ExprKind::If(pred, cons, alt) => get_free_vars(pred).and_then(|vars1| {
             get_free_vars(cons)
                 .and_then(|vars2| get_free_vars(alt).map(|vars3| vars1 + vars2 + vars3))
@@ -309,12 +538,22 @@ fn get_free_vars(exp: &Expr) -> Result<Vector<String>, ClosureConvertError> {
             body_vars.retain(|var| !binding_vars.contains(var));
             Ok(body_vars + get_free_vars_array(&binding_exps)?)
         }
+        ExprKind::Letrec(bindings, body) => {
+            let binding_exps: Vector<Expr> = bindings.iter().map(|pair| pair.1.clone()).collect();
+            let binding_vars: Vector<String> = bindings.iter().map(|pair| pair.0.clone()).collect();
+            let mut free_vars = get_free_vars_array(&binding_exps)? + get_free_vars(body)?;
+            free_vars.retain(|var| !binding_vars.contains(var));
+            Ok(free_vars)
+        }
         ExprKind::Lambda(params, _ret_type, body) => get_free_vars_lambda(params, body),
         ExprKind::FnApp(func, args) => get_free_vars_array(&(vector![func.clone()] + args.clone())),
         ExprKind::Record(bindings) => {
-            get_free_vars_array(&bindings.iter().map(|pair| pair.1.clone()).collect())
+            get_free_vars_array(&bindings.iter().map(|field| field.1.clone()).collect())
         }
         ExprKind::RecordGet(record, _key) => get_free_vars(record),
+        ExprKind::RecordSet(record, _key, val) => {
+            get_free_vars(record).and_then(|vars1| get_free_vars(val).map(|vars2| vars1 + vars2))
+        }
         ExprKind::Begin(exps) => get_free_vars_array(exps),
         ExprKind::Set(_var, val) => get_free_vars(val),
         ExprKind::Cons(first, second) => get_free_vars(first)
@@ -323,18 +562,41 @@ fn get_free_vars(exp: &Expr) -> Result<Vector<String>, ClosureConvertError> {
         ExprKind::Cdr(val) => get_free_vars(val),
         ExprKind::Tuple(vals) => get_free_vars_array(vals),
         ExprKind::TupleGet(tuple, _key) => get_free_vars(tuple),
+        ExprKind::TupleSet(tuple, _key, val) => {
+            get_free_vars(tuple).and_then(|vars1| get_free_vars(val).map(|vars2| vars1 + vars2))
+        }
         ExprKind::Pack(val, _sub, _exist) => get_free_vars(val),
         ExprKind::Unpack(var, package, _type_sub, body) => {
             let mut free_vars = get_free_vars(package)? + get_free_vars(body)?;
             free_vars.retain(|free_var| free_var != var);
             Ok(free_vars)
         }
+        ExprKind::Raise(exn, _typ) => get_free_vars(exn),
+        ExprKind::Handle(var, _typ, handler, body) => {
+            let mut handler_vars = get_free_vars(handler)?;
+            handler_vars.retain(|free_var| free_var != var);
+            Ok(get_free_vars(body)? + handler_vars)
+        }
+        ExprKind::CallCC(func) => get_free_vars(func),
+        ExprKind::Throw(cont, val, _typ) => get_free_vars(cont)
+            .and_then(|vars1| get_free_vars(val).map(|vars2| vars1 + vars2)),
+        ExprKind::MakeGenerator(_typ, body) => get_free_vars(body),
+        ExprKind::Yield(val) => get_free_vars(val),
+        ExprKind::Next(generator) => get_free_vars(generator),
         ExprKind::IsNull(val) => get_free_vars(val),
         ExprKind::Null(_) => Ok(vector![]),
+        ExprKind::Display(val) => get_free_vars(val),
+        ExprKind::Newline => Ok(vector![]),
         ExprKind::Id(x) => Ok(vector![x.clone()]),
         ExprKind::Num(_) => Ok(vector![]),
+        ExprKind::Int64(_) => Ok(vector![]),
+        ExprKind::Float(_) => Ok(vector![]),
         ExprKind::Bool(_) => Ok(vector![]),
         ExprKind::Str(_) => Ok(vector![]),
+        ExprKind::Char(_) => Ok(vector![]),
+        ExprKind::Symbol(_) => Ok(vector![]),
+        ExprKind::Quote(_) => Ok(vector![]),
+        ExprKind::Derived(_form, exp) => get_free_vars(exp),
     }
 }
 
@@ -345,13 +607,57 @@ fn get_free_vars_lambda(
     let param_vars: Vector<String> = params.iter().map(|pair| pair.0.clone()).collect();
     let mut free_vars: Vector<String> = get_free_vars(body)?;
     free_vars.retain(|var| !param_vars.contains(var));
-    Ok(free_vars)
+    // A variable may occur several times in the body, but it is only stored
+    // in the environment once
+    let mut unique_vars: Vector<String> = vector![];
+    for var in free_vars {
+        if !unique_vars.contains(&var) {
+            unique_vars.push_back(var);
+        }
+    }
+    Ok(unique_vars)
 }
 
 pub fn closure_convert(exp: &Expr) -> Result<Expr, ClosureConvertError> {
     cc(exp, &TypeEnv::new())
 }
 
+/// Closure convert an expression which may refer to extern functions and
+/// global definitions.
+///
+/// The converted expression binds each extern to a closure of the wrapper
+/// function with the same name (see `type_check::type_check_prog`), so that
+/// externs can be called and passed around like any other function. Global
+/// definitions can be referred to from anywhere, so they are never captured
+/// in the environments of closures.
+pub fn closure_convert_with_globals(
+    exp: &Expr,
+    externs: &Vector<(String, Type)>,
+    globals: &Vector<(String, Type)>,
+) -> Result<Expr, ClosureConvertError> {
+    let env = TypeEnv::from(externs.clone()).add_globals(globals.clone());
+    let cexp = cc(exp, &env)?;
+    if externs.is_empty() {
+        return Ok(cexp);
+    }
+    let bindings = externs
+        .iter()
+        .map(|(name, typ)| {
+            let closure = Expr::new(ExprKind::Tuple(vector![
+                Expr::new(ExprKind::Id(name.clone())),
+                Expr::new(ExprKind::Record(vector![])),
+            ]));
+            let package = Expr::new(ExprKind::Pack(
+                closure,
+                Type::Record(vector![]),
+                cc_type(typ)?,
+            ));
+            Ok((name.clone(), package))
+        })
+        .collect::<Result<Vector<(String, Expr)>, ClosureConvertError>>()?;
+    Ok(Expr::new(ExprKind::Let(bindings, cexp)))
+}
+
 /// Q: Why is a type environment needed for closure conversion?
 ///
 /// A: When closure converting lambdas, it is necessary to keep track of types
@@ -362,12 +668,19 @@ pub fn closure_convert(exp: &Expr) -> Result<Expr, ClosureConvertError> {
 fn cc(exp: &Expr, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
     match &*exp.kind {
         ExprKind::Num(x) => Ok(Expr::new(ExprKind::Num(*x))),
+        ExprKind::Int64(x) => Ok(Expr::new(ExprKind::Int64(*x))),
+        ExprKind::Float(x) => Ok(Expr::new(ExprKind::Float(*x))),
         ExprKind::Bool(x) => Ok(Expr::new(ExprKind::Bool(*x))),
         ExprKind::Str(x) => Ok(Expr::new(ExprKind::Str(x.clone()))),
+        ExprKind::Char(x) => Ok(Expr::new(ExprKind::Char(*x))),
+        ExprKind::Symbol(x) => Ok(Expr::new(ExprKind::Symbol(x.clone()))),
+        ExprKind::Quote(data) => Ok(Expr::new(ExprKind::Quote(data.clone()))),
         ExprKind::Id(x) => Ok(Expr::new(ExprKind::Id(x.clone()))),
-        ExprKind::Binop(op, arg1, arg2) => cc(arg1, env).and_then(|carg1| {
-            cc(arg2, env).map(|carg2| Expr::new(ExprKind::Binop(*op, carg1, carg2)))
-        }),
+        ExprKind::Prim(op, args) => {
+            let cargs_wrapped: Result<Vector<Expr>, ClosureConvertError> =
+                args.iter().map(|arg| cc(arg, env)).collect();
+            cargs_wrapped.map(|cargs| Expr::new(ExprKind::Prim(*op, cargs)))
+        }
         ExprKind::If(pred, cons, alt) => cc(pred, env).and_then(|cpred| {
             cc(cons, env).and_then(|ccons| {
                 cc(alt, env).map(|calt| Expr::new(ExprKind::If(cpred, ccons, calt)))
@@ -376,18 +689,24 @@ fn cc(exp: &Expr, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
         ExprKind::Let(bindings, body) => {
             // We need a map of the types for the bindings to ensure that we can properly
             // closure convert the body of the let expression
-            let cbindings = cc_bindings(bindings, env)?;
-            let binding_type_map = cbindings
-                .iter()
-                .map(|pair| match tc_with_env(&pair.1, env) {
-                    Ok(typed_exp) => Ok((pair.0.clone(), typed_exp.typ)),
-                    Err(e) => Err(ClosureConvertError(format!(
-                        "Type checking error during closure conversion: {e}"
-                    ))),
-                })
-                .collect::<Result<Vector<(String, Type)>, ClosureConvertError>>()?;
+            let mut cbindings = Vector::new();
+            let mut binding_type_map = Vector::new();
+            for (name, binding) in bindings {
+                let cbinding = cc(binding, env)?;
+                let typ = match tc_with_env(binding, env) {
+                    Ok(typed_exp) => typed_exp.typ,
+                    Err(e) => {
+                        return Err(ClosureConvertError(format!(
+                            "Type checking error during closure conversion: {e}"
+                        )));
+                    }
+                };
+                cbindings.push_back((name.clone(), cbinding));
+                binding_type_map.push_back((name.clone(), typ));
+            }
             cc(body, &env.add_bindings(binding_type_map)).map(|cbody| Expr::new(ExprKind::Let(cbindings, cbody)))
         }
+        ExprKind::Letrec(bindings, body) => cc_letrec(bindings, body, env),
         ExprKind::Lambda(params, ret_typ, body) => cc_lambda(params, ret_typ, body, env),
         ExprKind::Begin(exps) => {
             let cexps_wrapped: Result<Vector<Expr>, ClosureConvertError> =
@@ -406,6 +725,10 @@ fn cc(exp: &Expr, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
             cc(val, env).map(|cval| Expr::new(ExprKind::IsNull(cval)))
         }
         ExprKind::Null(typ) => Ok(Expr::new(ExprKind::Null(cc_type(typ)?))),
+        ExprKind::Display(val) => {
+            cc(val, env).map(|cval| Expr::new(ExprKind::Display(cval)))
+        }
+        ExprKind::Newline => Ok(Expr::new(ExprKind::Newline)),
         ExprKind::Tuple(exps) => {
             let cexps_wrapped: Result<Vector<Expr>, ClosureConvertError> =
                 exps.iter().map(|subexp| cc(subexp, env)).collect();
@@ -414,8 +737,18 @@ fn cc(exp: &Expr, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
         ExprKind::TupleGet(tuple, key) => {
             cc(tuple, env).map(|ctuple| Expr::new(ExprKind::TupleGet(ctuple, *key)))
         }
+        ExprKind::TupleSet(tuple, key, val) => Ok(Expr::new(ExprKind::TupleSet(
+            cc(tuple, env)?,
+            *key,
+            cc(val, env)?,
+        ))),
         ExprKind::Record(bindings) => cc_bindings(bindings, env).map(|cbindings| Expr::new(ExprKind::Record(cbindings))),
         ExprKind::RecordGet(record, key) => cc(record, env).map(|crecord| Expr::new(ExprKind::RecordGet(crecord, key.clone()))),
+        ExprKind::RecordSet(record, key, val) => Ok(Expr::new(ExprKind::RecordSet(
+            cc(record, env)?,
+            key.clone(),
+            cc(val, env)?,
+        ))),
         ExprKind::Pack(val, sub, exist) => Ok(Expr::new(ExprKind::Pack(
             cc(val, env)?,
             cc_type(sub)?,
@@ -427,6 +760,25 @@ fn cc(exp: &Expr, env: &TypeEnv) -> Result<Expr, ClosureConvertError> {
             *type_sub,
             cc(body, env)?,
         ))),
+        ExprKind::Raise(exn, typ) => Ok(Expr::new(ExprKind::Raise(cc(exn, env)?, cc_type(typ)?))),
+        ExprKind::Handle(var, typ, handler, body) => {
+            let handler_env = env.add_bindings(vector![(var.clone(), typ.clone())]);
+            Ok(Expr::new(ExprKind::Handle(
+                var.clone(),
+                cc_type(typ)?,
+                cc(handler, &handler_env)?,
+                cc(body, env)?,
+            )))
+        }
+        ExprKind::CallCC(_) | ExprKind::Throw(_, _, _) => Err(ClosureConvertError::from(
+            "Continuations should have been removed by CPS conversion.",
+        )),
+        ExprKind::MakeGenerator(_, _) | ExprKind::Yield(_) | ExprKind::Next(_) => Err(
+            ClosureConvertError::from("Generators should have been removed by CPS conversion."),
+        ),
+        ExprKind::Derived(_, _) => Err(ClosureConvertError::from(
+            "Derived forms should have been removed by type checking.",
+        )),
         ExprKind::FnApp(func, args) => cc_fn_app(func, args, env),
     }
 }
@@ -518,4 +870,29 @@ mod tests {
         //     expected
         // );
     }
+
+    #[test]
+    fn test_substitute_binders() {
+        // [x -> s](let x = e in t) = (let x = [x -> s]e in t)
+        let exp = parse(&lexpr::from_str("(let ((x x)) x)").unwrap()).unwrap();
+        let replace_with = parse(&lexpr::from_str("s").unwrap()).unwrap();
+        let expected = parse(&lexpr::from_str("(let ((x s)) x)").unwrap()).unwrap();
+        assert_eq!(substitute(&exp, "x", &replace_with).unwrap(), expected);
+
+        // [x -> s](let y = e in t) = (let y = [x -> s]e in [x -> s]t)  if y != x
+        let exp = parse(&lexpr::from_str("(let ((y x)) (+ x y))").unwrap()).unwrap();
+        let replace_with = parse(&lexpr::from_str("s").unwrap()).unwrap();
+        let expected = parse(&lexpr::from_str("(let ((y s)) (+ s y))").unwrap()).unwrap();
+        assert_eq!(substitute(&exp, "x", &replace_with).unwrap(), expected);
+
+        // [x -> z](let z = e in x) would capture z
+        let exp = parse(&lexpr::from_str("(let ((z 1)) x)").unwrap()).unwrap();
+        let replace_with = parse(&lexpr::from_str("z").unwrap()).unwrap();
+        assert!(substitute(&exp, "x", &replace_with).is_err());
+
+        // [x -> s](set! x e) cannot be expressed
+        let exp = parse(&lexpr::from_str("(set! x 3)").unwrap()).unwrap();
+        let replace_with = parse(&lexpr::from_str("s").unwrap()).unwrap();
+        assert!(substitute(&exp, "x", &replace_with).is_err());
+    }
 }
@@ -33,6 +33,16 @@ pub fn generate_func_name() -> String {
     name
 }
 
+/// Generate a fresh name for a variable introduced by a macro expansion
+/// (see `macro_expand`) or by desugaring a derived form. The name can't clash
+/// with names generated by later passes, and the % keeps it apart from
+/// ordinary variable names.
+pub fn generate_macro_name(name: &str) -> String {
+    let name = format!("{name}%{}", GENSYM_COUNT.load(Ordering::SeqCst));
+    GENSYM_COUNT.fetch_add(1, Ordering::SeqCst);
+    name
+}
+
 pub fn generate_id() -> u64 {
     let val = GENSYM_COUNT.load(Ordering::SeqCst);
     GENSYM_COUNT.fetch_add(1, Ordering::SeqCst);
@@ -122,22 +132,18 @@ impl TypedExpr {
 /// method of recursive definition, which is through an `ExprKind` type field.
 /// Through this definition, we will still get the type guarantee that children
 /// of a E (Expr, TypedExpr) must also be a corresponding E, without having
-/// to define the ExprKind cases (Binop, If, etc.) in multiple places.
+/// to define the ExprKind cases (Prim, If, etc.) in multiple places.
 ///
 /// TODO: See if it's possible to add a "kind: ExprKind" field as a requirement
 /// ExprMeta, in a way so that it's then required for both Expr and TypedExpr
 /// and any other expression types that are developed later.
-/// TODO: add (not x) operation
-/// TODO: add (string-equal? x y) operation
-/// TODO: add (str-len x) operation
-/// TODO: add (incr x) and (decr x) operations
-/// TODO: add (set-car! x) and (set-cdr! x) operations
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum ExprKind<E: ExprMeta> {
-    Binop(BinOp, E, E),                      // operator, arg1, arg2
+    Prim(PrimOp, Vector<E>),                 // operation, arguments
     If(E, E, E),                             // pred, consequent, alternate
     Let(Vector<(String, E)>, E),             // variable bindings, body
+    Letrec(Vector<(String, E)>, E), // lambda bindings (which may refer to each other), body
     Lambda(Vector<(String, Type)>, Type, E), // arg names/types, return type, body
     Begin(Vector<E>),
     Set(String, E),
@@ -146,17 +152,77 @@ pub enum ExprKind<E: ExprMeta> {
     Cdr(E),
     IsNull(E),
     Null(Type),
-    FnApp(E, Vector<E>),         // func, arguments
-    Tuple(Vector<E>),            // list of expressions, type annotation
-    TupleGet(E, u32),            // env, index - index must explicitly be a number
-    Pack(E, Type, Type),         // exp, type substitution, existential type
-    Unpack(String, E, u64, E),   // new var, package, type var, body
-    Record(Vector<(String, E)>), // map from values to labels
-    RecordGet(E, String),        // record, label
+    Display(E),
+    Newline,
+    FnApp(E, Vector<E>),               // func, arguments
+    Tuple(Vector<E>),                  // list of expressions, type annotation
+    TupleGet(E, u32),                  // env, index - index must explicitly be a number
+    TupleSet(E, u32, E),               // tuple, index, new value
+    Pack(E, Type, Type),               // exp, type substitution, existential type
+    Unpack(String, E, u64, E),         // new var, package, type var, body
+    Record(Vector<(String, E, bool)>), // map from values to labels, and whether each is mutable
+    RecordGet(E, String),              // record, label
+    RecordSet(E, String, E),           // record, label, new value
+    Raise(E, Type),                    // exception, type of the raise expression
+    Handle(String, Type, E, E),        // exception var, exception type, handler, body
+    CallCC(E),                         // function receiving the current continuation
+    Throw(E, E, Type),                 // continuation, value, type of the throw expression
+    MakeGenerator(Type, E),            // type of yielded values, body
+    Yield(E),
+    Next(E),
     Id(String),
     Num(i32),
+    Int64(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Symbol(String),
+    Quote(Vector<Datum>), // the elements of a quoted list
+    Derived(String, E),   // source of a derived form (e.g. cond), the expression it desugars to
+}
+
+/// An element of a quoted list, e.g. `'(1 (2 3))`, which is either a literal
+/// or another list.
+///
+/// Quoted lists are constants, so unlike the other expressions they don't
+/// have any children which need to be compiled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Datum {
+    Num(i32),
+    Int64(i64),
+    Float(f64),
     Bool(bool),
     Str(String),
+    Char(char),
+    Symbol(String),
+    List(Vector<Datum>),
+}
+
+// printed the way lexpr reads them
+fn write_char(f: &mut std::fmt::Formatter<'_>, val: char) -> std::fmt::Result {
+    match val {
+        ' ' => write!(f, "#\\space"),
+        val if val.is_ascii_graphic() => write!(f, "#\\{val}"),
+        val => write!(f, "#\\x{:x}", val as u32),
+    }
+}
+
+/// Data are printed the way they are written within a quoted list, so
+/// symbols are not quoted.
+impl Display for Datum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Datum::Num(val) => write!(f, "{val}"),
+            Datum::Int64(val) => write!(f, "{val}"),
+            Datum::Float(val) => write!(f, "{val:?}"),
+            Datum::Bool(val) => write!(f, "{}", if *val { "#t" } else { "#f" }),
+            Datum::Str(val) => write!(f, "\"{val}\""),
+            Datum::Char(val) => write_char(f, *val),
+            Datum::Symbol(val) => write!(f, "{val}"),
+            Datum::List(data) => write!(f, "({})", format_vector(data.clone())),
+        }
+    }
 }
 
 impl Display for Expr {
@@ -176,7 +242,7 @@ impl Display for TypedExpr {
 impl<E: ExprMeta> Display for ExprKind<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ExprKind::Binop(op, exp1, exp2) => write!(f, "({op} {exp1} {exp2})"),
+            ExprKind::Prim(op, args) => write!(f, "({op} {})", format_vector(args.clone())),
             ExprKind::If(pred, cons, alt) => write!(f, "(if {pred} {cons} {alt})"),
             ExprKind::Let(bindings, body) => {
                 let bindings_str_vec = bindings
@@ -185,6 +251,13 @@ impl<E: ExprMeta> Display for ExprKind<E> {
                     .collect();
                 write!(f, "(let ({}) {})", format_vector(bindings_str_vec), body)
             }
+            ExprKind::Letrec(bindings, body) => {
+                let bindings_str_vec = bindings
+                    .iter()
+                    .map(|pair| format!("({} {})", pair.0, pair.1))
+                    .collect();
+                write!(f, "(letrec ({}) {})", format_vector(bindings_str_vec), body)
+            }
             ExprKind::Lambda(params, ret_type, body) => {
                 let params_str_vec = params
                     .iter()
@@ -204,12 +277,18 @@ impl<E: ExprMeta> Display for ExprKind<E> {
                 _ => {
                     let bindings_str_vec = bindings
                         .iter()
-                        .map(|pair| format!("({} {})", pair.0, pair.1))
+                        .map(|(name, exp, mutable)| match mutable {
+                            true => format!("(mutable {name} {exp})"),
+                            false => format!("({name} {exp})"),
+                        })
                         .collect();
                     write!(f, "(make-record {})", format_vector(bindings_str_vec))
                 }
             },
             ExprKind::RecordGet(record, key) => write!(f, "(record-ref {record} {key})"),
+            ExprKind::RecordSet(record, key, val) => {
+                write!(f, "(record-set! {record} {key} {val})")
+            }
             ExprKind::Begin(exps) => write!(f, "(begin {})", format_vector(exps.clone())),
             ExprKind::Set(var_name, exp) => write!(f, "(set! {var_name} {exp})"),
             ExprKind::Cons(first, second) => write!(f, "(cons {first} {second})"),
@@ -217,57 +296,244 @@ impl<E: ExprMeta> Display for ExprKind<E> {
             ExprKind::Cdr(exp) => write!(f, "(cdr {exp})"),
             ExprKind::IsNull(exp) => write!(f, "(null? {exp})"),
             ExprKind::Null(typ) => write!(f, "(null {typ})"),
+            ExprKind::Display(exp) => write!(f, "(display {exp})"),
+            ExprKind::Newline => write!(f, "(newline)"),
             ExprKind::Tuple(exps) => match exps.len() {
                 0 => write!(f, "(make-tuple)"),
                 _ => write!(f, "(make-tuple {})", format_vector(exps.clone())),
             },
             ExprKind::TupleGet(tup, key) => write!(f, "(tuple-ref {tup} {key})"),
+            ExprKind::TupleSet(tup, key, val) => write!(f, "(tuple-set! {tup} {key} {val})"),
             // TODO: change to (pack type_sub val : exist)?
             ExprKind::Pack(val, sub, exist) => write!(f, "(pack {val} {sub} {exist})"),
             ExprKind::Unpack(var, package, type_sub, body) => {
                 write!(f, "(unpack ({var} {package} T{type_sub}) {body})")
             }
+            ExprKind::Raise(exn, typ) => write!(f, "(raise {exn} : {typ})"),
+            ExprKind::Handle(var, typ, handler, body) => {
+                write!(f, "(with-handler (({var} : {typ}) {handler}) {body})")
+            }
+            ExprKind::CallCC(func) => write!(f, "(call/cc {func})"),
+            ExprKind::Throw(cont, val, typ) => write!(f, "(throw {cont} {val} : {typ})"),
+            ExprKind::MakeGenerator(typ, body) => {
+                write!(f, "(make-generator {typ} {body})")
+            }
+            ExprKind::Yield(val) => write!(f, "(yield {val})"),
+            ExprKind::Next(generator) => write!(f, "(next {generator})"),
             ExprKind::Id(val) => write!(f, "{val}"),
             ExprKind::Num(val) => write!(f, "{val}"),
+            ExprKind::Int64(val) => write!(f, "{val}"),
+            ExprKind::Float(val) => write!(f, "{val:?}"),
             ExprKind::Bool(val) => write!(f, "{}", if *val { "true" } else { "false" }),
             ExprKind::Str(val) => write!(f, "\"{val}\""),
+            ExprKind::Char(val) => write_char(f, *val),
+            ExprKind::Symbol(val) => write!(f, "'{val}"),
+            ExprKind::Quote(data) => write!(f, "'({})", format_vector(data.clone())),
+            ExprKind::Derived(_form, exp) => write!(f, "{exp}"),
         }
     }
 }
 
+/// A primitive operation of the language, such as `+` or `set-car!`.
+///
+/// Every primitive is applied to a fixed number of arguments, whose types
+/// are given by its signature (see `PrimOp::signature`). The names by which
+/// primitives are called in source programs are listed in `PRIM_OPS`.
 #[derive(Copy, Clone, Debug, PartialEq)]
-pub enum BinOp {
+pub enum PrimOp {
     Add,
     Subtract,
     Multiply,
     Divide,
+    Remainder,
+    Modulo,
+    Incr,
+    Decr,
     LessThan,
     GreaterThan,
     LessOrEqual,
     GreaterOrEqual,
     EqualTo,
+    NotEqualTo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
     And,
     Or,
+    Not,
     Concat,
+    StringEqual,
+    StrLen,
+    SetCar,
+    SetCdr,
+    IntToInt64,
+    Int64ToInt,
+    IntToFloat,
+    FloatToInt,
+    Int64ToFloat,
+    FloatToInt64,
+    CharEqual,
+    SymbolEqual,
+    CharToInt,
+    IntToChar,
+    SymbolToString,
 }
 
-impl std::fmt::Display for BinOp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// The table of primitive operations, by the name they are called with.
+pub const PRIM_OPS: [(&str, PrimOp); 39] = [
+    ("+", PrimOp::Add),
+    ("-", PrimOp::Subtract),
+    ("*", PrimOp::Multiply),
+    ("/", PrimOp::Divide),
+    ("remainder", PrimOp::Remainder),
+    ("modulo", PrimOp::Modulo),
+    ("incr", PrimOp::Incr),
+    ("decr", PrimOp::Decr),
+    ("<", PrimOp::LessThan),
+    (">", PrimOp::GreaterThan),
+    ("<=", PrimOp::LessOrEqual),
+    (">=", PrimOp::GreaterOrEqual),
+    ("=", PrimOp::EqualTo),
+    ("!=", PrimOp::NotEqualTo),
+    ("bitwise-and", PrimOp::BitAnd),
+    ("bitwise-or", PrimOp::BitOr),
+    ("bitwise-xor", PrimOp::BitXor),
+    ("bitwise-not", PrimOp::BitNot),
+    ("shift-left", PrimOp::ShiftLeft),
+    ("shift-right", PrimOp::ShiftRight),
+    ("and", PrimOp::And),
+    ("or", PrimOp::Or),
+    ("not", PrimOp::Not),
+    ("concat", PrimOp::Concat),
+    ("string-equal?", PrimOp::StringEqual),
+    ("str-len", PrimOp::StrLen),
+    ("set-car!", PrimOp::SetCar),
+    ("set-cdr!", PrimOp::SetCdr),
+    ("int->int64", PrimOp::IntToInt64),
+    ("int64->int", PrimOp::Int64ToInt),
+    ("int->float", PrimOp::IntToFloat),
+    ("float->int", PrimOp::FloatToInt),
+    ("int64->float", PrimOp::Int64ToFloat),
+    ("float->int64", PrimOp::FloatToInt64),
+    ("char=?", PrimOp::CharEqual),
+    ("symbol=?", PrimOp::SymbolEqual),
+    ("char->int", PrimOp::CharToInt),
+    ("int->char", PrimOp::IntToChar),
+    ("symbol->string", PrimOp::SymbolToString),
+];
+
+/// The type variable which stands for some type within the signature of a
+/// primitive operation (see `PrimOp::signature` and `PrimOp::instances`).
+pub const PRIM_TYPE_VAR: u64 = 0;
+
+impl PrimOp {
+    /// Returns the primitive operation with the given name, if there is one.
+    pub fn from_name(name: &str) -> Option<PrimOp> {
+        PRIM_OPS
+            .iter()
+            .find(|(op_name, _op)| *op_name == name)
+            .map(|(_name, op)| *op)
+    }
+
+    pub fn name(&self) -> &'static str {
+        PRIM_OPS
+            .iter()
+            .find(|(_name, op)| op == self)
+            .map(|(name, _op)| *name)
+            .unwrap()
+    }
+
+    /// The number of arguments the operation is applied to.
+    pub fn arity(&self) -> usize {
+        self.signature().0.len()
+    }
+
+    /// The types of the operation's arguments, and of its result.
+    ///
+    /// Arithmetic works on any kind of number, and operations on lists work
+    /// for lists of any type. In their signatures, `PRIM_TYPE_VAR` stands for
+    /// the type of the numbers (or of the list's elements), and must be the
+    /// same type everywhere it appears.
+    pub fn signature(&self) -> (Vec<Type>, Type) {
+        let elem = Type::TypeVar(PRIM_TYPE_VAR);
+        let list = Type::List(Box::new(elem.clone()));
         match self {
-            BinOp::Add => write!(f, "+"),
-            BinOp::Subtract => write!(f, "-"),
-            BinOp::Multiply => write!(f, "*"),
-            BinOp::Divide => write!(f, "/"),
-            BinOp::LessThan => write!(f, "<"),
-            BinOp::GreaterThan => write!(f, ">"),
-            BinOp::LessOrEqual => write!(f, "<="),
-            BinOp::GreaterOrEqual => write!(f, ">="),
-            BinOp::EqualTo => write!(f, "="),
-            BinOp::And => write!(f, "and"),
-            BinOp::Or => write!(f, "or"),
-            BinOp::Concat => write!(f, "concat"),
+            PrimOp::Add
+            | PrimOp::Subtract
+            | PrimOp::Multiply
+            | PrimOp::Divide
+            | PrimOp::Remainder
+            | PrimOp::Modulo
+            | PrimOp::BitAnd
+            | PrimOp::BitOr
+            | PrimOp::BitXor
+            | PrimOp::ShiftLeft
+            | PrimOp::ShiftRight => (vec![elem.clone(), elem.clone()], elem),
+            PrimOp::Incr | PrimOp::Decr | PrimOp::BitNot => (vec![elem.clone()], elem),
+            PrimOp::LessThan
+            | PrimOp::GreaterThan
+            | PrimOp::LessOrEqual
+            | PrimOp::GreaterOrEqual
+            | PrimOp::EqualTo
+            | PrimOp::NotEqualTo => (vec![elem.clone(), elem], Type::Bool),
+            PrimOp::And | PrimOp::Or => (vec![Type::Bool, Type::Bool], Type::Bool),
+            PrimOp::Not => (vec![Type::Bool], Type::Bool),
+            PrimOp::Concat => (vec![Type::Str, Type::Str], Type::Str),
+            PrimOp::StringEqual => (vec![Type::Str, Type::Str], Type::Bool),
+            PrimOp::StrLen => (vec![Type::Str], Type::Int),
+            // like set!, these return the value which is assigned
+            PrimOp::SetCar => (vec![list, elem.clone()], elem),
+            PrimOp::SetCdr => (vec![list.clone(), list.clone()], list),
+            PrimOp::IntToInt64 => (vec![Type::Int], Type::Int64),
+            PrimOp::Int64ToInt => (vec![Type::Int64], Type::Int),
+            PrimOp::IntToFloat => (vec![Type::Int], Type::Float),
+            PrimOp::FloatToInt => (vec![Type::Float], Type::Int),
+            PrimOp::Int64ToFloat => (vec![Type::Int64], Type::Float),
+            PrimOp::FloatToInt64 => (vec![Type::Float], Type::Int64),
+            PrimOp::CharEqual => (vec![Type::Char, Type::Char], Type::Bool),
+            PrimOp::SymbolEqual => (vec![Type::Symbol, Type::Symbol], Type::Bool),
+            PrimOp::CharToInt => (vec![Type::Char], Type::Int),
+            PrimOp::IntToChar => (vec![Type::Int], Type::Char),
+            PrimOp::SymbolToString => (vec![Type::Symbol], Type::Str),
         }
     }
+
+    /// The types which `PRIM_TYPE_VAR` may stand for in the operation's
+    /// signature, or None if it may stand for any type.
+    pub fn instances(&self) -> Option<Vec<Type>> {
+        match self {
+            PrimOp::Add
+            | PrimOp::Subtract
+            | PrimOp::Multiply
+            | PrimOp::Divide
+            | PrimOp::Incr
+            | PrimOp::Decr
+            | PrimOp::LessThan
+            | PrimOp::GreaterThan
+            | PrimOp::LessOrEqual
+            | PrimOp::GreaterOrEqual
+            | PrimOp::EqualTo
+            | PrimOp::NotEqualTo => Some(vec![Type::Int, Type::Int64, Type::Float]),
+            PrimOp::Remainder
+            | PrimOp::Modulo
+            | PrimOp::BitAnd
+            | PrimOp::BitOr
+            | PrimOp::BitXor
+            | PrimOp::BitNot
+            | PrimOp::ShiftLeft
+            | PrimOp::ShiftRight => Some(vec![Type::Int, Type::Int64]),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PrimOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 /// TypeEnv is a data structure used to keep track of mappings from variable
@@ -284,9 +550,18 @@ impl std::fmt::Display for BinOp {
 /// Thus, to type-check the body of the let-expression, the type checker needs
 /// to remember this binding and be able to find it as needed. This is achieved
 /// by passing a TypeEnv between different type-checker calls.
-#[derive(Default, Debug)]
+///
+/// The environment also tracks which identifiers refer to global definitions
+/// (made with a top-level define) as opposed to local variables, since
+/// closure conversion does not need to capture globals.
+///
+/// Within the body of a generator, it also tracks the type of the values the
+/// generator yields.
+#[derive(Clone, Default, Debug)]
 pub struct TypeEnv {
     bindings: Vector<(String, Type)>,
+    globals: Vector<String>,
+    yield_typ: Option<Type>,
 }
 
 // New values are appended to the front of the frame
@@ -294,23 +569,66 @@ impl TypeEnv {
     pub fn new() -> Self {
         TypeEnv {
             bindings: Vector::new(),
+            globals: Vector::new(),
+            yield_typ: None,
         }
     }
 
     /// Returns a new environment extended with the provided binding.
     pub fn add_binding(&self, new_binding: (String, Type)) -> TypeEnv {
         let mut bindings = self.bindings.clone();
+        let mut globals = self.globals.clone();
+        globals.retain(|name| *name != new_binding.0);
         bindings.push_front(new_binding);
-        TypeEnv { bindings }
+        TypeEnv {
+            bindings,
+            globals,
+            yield_typ: self.yield_typ.clone(),
+        }
     }
 
     /// Returns a new environment extended with the provided bindings.
     pub fn add_bindings(&self, new_bindings: Vector<(String, Type)>) -> TypeEnv {
         let mut bindings = self.bindings.clone();
+        let mut globals = self.globals.clone();
         for binding in new_bindings {
+            globals.retain(|name| *name != binding.0);
             bindings.push_front(binding);
         }
-        TypeEnv { bindings }
+        TypeEnv {
+            bindings,
+            globals,
+            yield_typ: self.yield_typ.clone(),
+        }
+    }
+
+    /// Returns a new environment extended with the provided global
+    /// definitions.
+    pub fn add_globals(&self, new_globals: Vector<(String, Type)>) -> TypeEnv {
+        let names: Vector<String> = new_globals.iter().map(|pair| pair.0.clone()).collect();
+        let mut env = self.add_bindings(new_globals);
+        env.globals.append(names);
+        env
+    }
+
+    /// Returns whether an identifier refers to a global definition (and is
+    /// not shadowed by a local variable).
+    pub fn is_global(&self, key: &str) -> bool {
+        self.globals.iter().any(|name| name == key)
+    }
+
+    /// Returns a new environment for the body of a generator yielding values
+    /// of the provided type.
+    pub fn with_yield_type(&self, yield_typ: Type) -> TypeEnv {
+        TypeEnv {
+            yield_typ: Some(yield_typ),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the type of values yielded by the enclosing generator, if any.
+    pub fn yield_type(&self) -> Option<&Type> {
+        self.yield_typ.as_ref()
     }
 
     pub fn find(&self, key: &str) -> Option<&Type> {
@@ -325,7 +643,11 @@ impl TypeEnv {
 
 impl From<Vector<(String, Type)>> for TypeEnv {
     fn from(bindings: Vector<(String, Type)>) -> Self {
-        TypeEnv { bindings }
+        TypeEnv {
+            bindings,
+            globals: Vector::new(),
+            yield_typ: None,
+        }
     }
 }
 
@@ -333,8 +655,94 @@ impl From<Vector<(String, Type)>> for TypeEnv {
 // implementing a TypedExpr -> TypedExpr function; then automatically allow any
 // pass to be applied to Prog<TypedExpr> through a generic implementation
 
+/// The names the compiled WebAssembly module exports on its own (see
+/// `generate_code::construct_module_from_prog`), which exported definitions
+/// can't use.
+pub const RESERVED_EXPORTS: [&str; 5] =
+    ["memory", "$$MAIN$$", "_start", "$$HEAP_BASE$$", "$$FUEL$$"];
+
+/// A top-level declaration within a source program or module.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decl {
+    /// A function provided by the host environment, e.g. `(extern print-int
+    /// (-> int int))`. It is imported into the WebAssembly module from the
+    /// "env" module under the same name.
+    Extern(String, Type),
+    /// A global definition, e.g. `(define add (lambda ...))`. Definitions are
+    /// evaluated in order before the main expression, and may refer to
+    /// earlier definitions (or to themselves, if they are lambdas).
+    Define(String, Expr),
+    /// Exports a global definition of a function from the WebAssembly module,
+    /// under the given name, e.g. `(export add "add")`.
+    Export(String, String),
+    /// Makes global definitions available to other modules which import
+    /// this one, e.g. `(provide add sub)`.
+    Provide(Vector<String>),
+    /// Imports the definitions provided by the module in another file, e.g.
+    /// `(import "math.scm")`. The path is relative to the importing file.
+    Import(String),
+}
+
+/// A program as written in the source language: a list of top-level
+/// declarations, followed by the main expression to evaluate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceProg {
+    pub decls: Vector<Decl>,
+    pub exp: Expr,
+}
+
+impl SourceProg {
+    /// Returns the names and types of the extern functions declared within
+    /// the program.
+    pub fn externs(&self) -> Vector<(String, Type)> {
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Extern(name, typ) => Some((name.clone(), typ.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the names and expressions of the global definitions within
+    /// the program, in order.
+    pub fn defines(&self) -> Vector<(String, Expr)> {
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Define(name, exp) => Some((name.clone(), exp.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the names of the exported definitions within the program,
+    /// along with the names they are exported as.
+    pub fn exports(&self) -> Vector<(String, String)> {
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Export(name, export_name) => Some((name.clone(), export_name.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A program after lambda lifting: a list of top-level functions, followed by
+/// the main expression to evaluate.
+///
+/// `externs` lists the functions the program imports from the host
+/// environment, along with their (source language) types. `globals` lists
+/// the global definitions and their types, which are initialized by the main
+/// expression.
+/// `exports` lists the global definitions of functions which are exported,
+/// with the name they are exported as and their (source language) type.
 #[derive(Clone, Debug)]
 pub struct Prog<E: ExprMeta> {
+    pub externs: Vector<(String, Type)>,
+    pub globals: Vector<(String, Type)>,
+    pub exports: Vector<(String, String, Type)>,
     pub fns: Vector<(String, E)>,
     pub exp: E,
 }
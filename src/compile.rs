@@ -1,8 +1,40 @@
-use crate::closure_convert::closure_convert;
-use crate::common::{Expr, Prog, TypedExpr};
+use crate::ast_transform::erase_types;
+use crate::closure_convert::{cc_type, closure_convert_with_globals};
+use crate::common::{Expr, ExprKind, Prog, SourceProg, TypeEnv, TypedExpr};
+use crate::const_fold::const_fold_prog;
+use crate::cps::{cps_prog, uses_continuations};
+use crate::dead_code::dce_prog;
+use crate::inline::inline_exp;
 use crate::lambda_lift::lambda_lift;
 use crate::record_elim::record_elim_prog;
-use crate::type_check::{type_check, type_check_prog};
+use crate::type_check::{tc_defines, tc_with_env, type_check_prog};
+use crate::types::Type;
+use im_rc::Vector;
+
+/// Options controlling the optimizations performed during compilation.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// Whether to inline functions before closure conversion (see
+    /// `inline::inline_exp`).
+    pub inline: bool,
+    /// The largest size (in number of AST nodes) of a let-bound function's
+    /// body for it to be inlined at its call sites.
+    pub inline_threshold: usize,
+    /// Whether to convert the program into continuation-passing style after
+    /// inlining (see `cps::cps_prog`), which is required to use `call/cc` and
+    /// generators.
+    pub cps: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            inline: true,
+            inline_threshold: 16,
+            cps: false,
+        }
+    }
+}
 
 /// Perform a complete compilation from an Expr to a Prog - in other words, all
 /// all compiler passes before code generation.
@@ -14,13 +46,104 @@ use crate::type_check::{type_check, type_check_prog};
 /// `Box<dyn Error>`, but I'm not sure if this is necessary or what is
 /// best form.
 pub fn compile_exp(exp: &Expr) -> Result<Prog<TypedExpr>, Box<dyn std::error::Error>> {
-    // the type information is not currently used for closure conversion, but
-    // we want to type check just to catch errors early on
-    type_check(exp)?;
+    compile_exp_with_options(exp, &CompileOptions::default())
+}
 
-    let cc_exp = closure_convert(exp)?;
-    let prog = lambda_lift(&cc_exp)?;
-    let typed_prog = type_check_prog(&prog)?;
+/// Same as `compile_exp`, but with custom options.
+pub fn compile_exp_with_options(
+    exp: &Expr,
+    options: &CompileOptions,
+) -> Result<Prog<TypedExpr>, Box<dyn std::error::Error>> {
+    let prog = SourceProg {
+        decls: Vector::new(),
+        exp: exp.clone(),
+    };
+    compile_prog_with_options(&prog, options)
+}
+
+/// Perform a complete compilation of a source program (with top-level
+/// declarations, see `parse::parse_prog()`) to a Prog.
+pub fn compile_prog(prog: &SourceProg) -> Result<Prog<TypedExpr>, Box<dyn std::error::Error>> {
+    compile_prog_with_options(prog, &CompileOptions::default())
+}
+
+/// Same as `compile_prog`, but with custom options.
+pub fn compile_prog_with_options(
+    prog: &SourceProg,
+    options: &CompileOptions,
+) -> Result<Prog<TypedExpr>, Box<dyn std::error::Error>> {
+    let externs = prog.externs();
+    let defines = tc_defines(prog)?;
+    let globals: Vector<(String, Type)> = defines
+        .iter()
+        .map(|(name, typed_exp)| (name.clone(), typed_exp.typ.clone()))
+        .collect();
+    let exports: Vector<(String, String, Type)> = prog
+        .exports()
+        .into_iter()
+        .map(|(name, export_name)| {
+            let typ = globals
+                .iter()
+                .find(|pair| pair.0 == name)
+                .map(|pair| pair.1.clone())
+                .ok_or("Exported name is not a top-level definition.")?;
+            Ok((name, export_name, typ))
+        })
+        .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+    // The type checker converts values to the supertypes they are used as
+    // (see `type_check::coerce`), so the rest of the compiler works on the
+    // converted program, in which each value has exactly the type expected
+    // wherever it is used.
+    let env = TypeEnv::from(externs.clone()).add_globals(globals.clone());
+    let main_exp = init_globals(&defines, &erase_types(&tc_with_env(&prog.exp, &env)?));
+    let inlined_exp = if options.inline {
+        inline_exp(&main_exp, options.inline_threshold)?
+    } else {
+        main_exp
+    };
+    let (inlined_exp, globals, exports) = if options.cps {
+        let typed_prog = Prog {
+            externs: externs.clone(),
+            globals,
+            exports,
+            fns: Vector::new(),
+            exp: tc_with_env(&inlined_exp, &env)?,
+        };
+        let cps_prog = cps_prog(&typed_prog)?;
+        (cps_prog.exp, cps_prog.globals, cps_prog.exports)
+    } else if uses_continuations(&inlined_exp) {
+        return Err("call/cc, throw and generators require CPS conversion to be enabled.".into());
+    } else {
+        (inlined_exp, globals, exports)
+    };
+    let cc_exp = closure_convert_with_globals(&inlined_exp, &externs, &globals)?;
+    let cc_globals = globals
+        .iter()
+        .map(|(name, typ)| Ok((name.clone(), cc_type(typ)?)))
+        .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+    let lifted_prog = Prog {
+        externs,
+        globals: cc_globals,
+        exports,
+        ..lambda_lift(&cc_exp)?
+    };
+    let typed_prog = type_check_prog(&lifted_prog)?;
     let re_typed_prog = record_elim_prog(&typed_prog)?;
-    Ok(re_typed_prog)
+    let folded_prog = const_fold_prog(&re_typed_prog)?;
+    let live_prog = dce_prog(&folded_prog)?;
+    Ok(live_prog)
+}
+
+/// Returns the main expression of a program, preceded by assignments which
+/// initialize each (type checked) global definition in order.
+fn init_globals(defines: &Vector<(String, TypedExpr)>, exp: &Expr) -> Expr {
+    if defines.is_empty() {
+        return exp.clone();
+    }
+    let mut exps: Vector<Expr> = defines
+        .iter()
+        .map(|(name, typed_exp)| Expr::new(ExprKind::Set(name.clone(), erase_types(typed_exp))))
+        .collect();
+    exps.push_back(exp.clone());
+    Expr::new(ExprKind::Begin(exps))
 }
@@ -0,0 +1,347 @@
+use crate::analysis::is_pure;
+use crate::ast_transform::{transform_typed_exp_recursive, transform_typed_prog_recursive};
+use crate::common::{ExprKind, PrimOp, Prog, TypedExpr};
+use crate::types::Type;
+use im_rc::Vector;
+
+#[derive(Clone, Debug)]
+pub struct ConstFoldError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for ConstFoldError {}
+
+impl From<&str> for ConstFoldError {
+    fn from(message: &str) -> Self {
+        ConstFoldError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for ConstFoldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ConstFoldError: {}", self.0)
+    }
+}
+
+/// Simplify an expression by evaluating the parts of it which can be computed
+/// at compile time.
+///
+/// This folds primitive operations on constants (e.g. `(+ 1 2)` becomes `3`),
+/// applies algebraic identities (e.g. `(* x 1)` becomes `x`), prunes if
+/// expressions with constant predicates, and simplifies projections out of
+/// freshly constructed values (e.g. `(car (cons a b))` becomes `a`) as long
+/// as the parts being discarded are pure.
+///
+/// Folding never changes the type of an expression, so the output is still
+/// correctly annotated with types.
+pub fn const_fold_exp(exp: &TypedExpr) -> Result<TypedExpr, ConstFoldError> {
+    transform_typed_exp_recursive(exp, cf_helper, cf_type_helper)
+}
+
+/// Simplify all expressions within a program.
+///
+/// See `const_fold_exp` for more specific details.
+pub fn const_fold_prog(prog: &Prog<TypedExpr>) -> Result<Prog<TypedExpr>, ConstFoldError> {
+    transform_typed_prog_recursive(prog, cf_helper, cf_type_helper)
+}
+
+fn cf_type_helper(_typ: &Type) -> Option<Result<Type, ConstFoldError>> {
+    None
+}
+
+fn cf_helper(exp: &TypedExpr) -> Option<Result<TypedExpr, ConstFoldError>> {
+    match &*exp.kind {
+        ExprKind::Prim(op, args) => Some(cf_prim(*op, args, &exp.typ)),
+        ExprKind::If(pred, cons, alt) => Some(cf_if(pred, cons, alt, &exp.typ)),
+        ExprKind::TupleGet(tuple, key) => Some(cf_tuple_get(tuple, *key, &exp.typ)),
+        ExprKind::Car(pair) => Some(cf_car(pair, &exp.typ)),
+        ExprKind::Cdr(pair) => Some(cf_cdr(pair, &exp.typ)),
+        _ => None,
+    }
+}
+
+fn num(val: i32) -> TypedExpr {
+    TypedExpr::new(Type::Int, ExprKind::Num(val))
+}
+
+fn num64(val: i64) -> TypedExpr {
+    TypedExpr::new(Type::Int64, ExprKind::Int64(val))
+}
+
+fn float(val: f64) -> TypedExpr {
+    TypedExpr::new(Type::Float, ExprKind::Float(val))
+}
+
+fn boolean(val: bool) -> TypedExpr {
+    TypedExpr::new(Type::Bool, ExprKind::Bool(val))
+}
+
+/// Returns the value of an integer constant (of type int or int64).
+fn int_value(exp: &TypedExpr) -> Option<i64> {
+    match &*exp.kind {
+        ExprKind::Num(x) => Some(*x as i64),
+        ExprKind::Int64(x) => Some(*x),
+        _ => None,
+    }
+}
+
+/// Evaluate a unary operation whose argument is a constant, if possible.
+///
+/// Converting a float to an integer is only folded if it would not trap at
+/// runtime (i.e. if the float is in range of the integer type). Since ints
+/// never overflow, results which don't fit in a 32-bit literal are left to
+/// be computed at runtime. Likewise, an int is only converted to a char if
+/// it is a valid code point.
+fn eval_unop(op: PrimOp, arg: &TypedExpr) -> Option<TypedExpr> {
+    match (op, &*arg.kind) {
+        (PrimOp::Incr, ExprKind::Num(x)) => x.checked_add(1).map(num),
+        (PrimOp::Decr, ExprKind::Num(x)) => x.checked_sub(1).map(num),
+        (PrimOp::BitNot, ExprKind::Num(x)) => Some(num(!x)),
+        (PrimOp::Incr, ExprKind::Int64(x)) => Some(num64(x.wrapping_add(1))),
+        (PrimOp::Decr, ExprKind::Int64(x)) => Some(num64(x.wrapping_sub(1))),
+        (PrimOp::BitNot, ExprKind::Int64(x)) => Some(num64(!x)),
+        (PrimOp::Incr, ExprKind::Float(x)) => Some(float(x + 1.0)),
+        (PrimOp::Decr, ExprKind::Float(x)) => Some(float(x - 1.0)),
+        (PrimOp::Not, ExprKind::Bool(x)) => Some(boolean(!x)),
+        (PrimOp::StrLen, ExprKind::Str(x)) => Some(num(x.len() as i32)),
+        (PrimOp::IntToInt64, ExprKind::Num(x)) => Some(num64(*x as i64)),
+        (PrimOp::Int64ToInt, ExprKind::Int64(x)) => i32::try_from(*x).ok().map(num),
+        (PrimOp::IntToFloat, ExprKind::Num(x)) => Some(float(*x as f64)),
+        (PrimOp::Int64ToFloat, ExprKind::Int64(x)) => Some(float(*x as f64)),
+        (PrimOp::FloatToInt, ExprKind::Float(x))
+            if x.trunc() >= i32::MIN as f64 && x.trunc() <= i32::MAX as f64 =>
+        {
+            Some(num(*x as i32))
+        }
+        (PrimOp::FloatToInt64, ExprKind::Float(x))
+            if x.trunc() >= i64::MIN as f64 && x.trunc() < i64::MAX as f64 =>
+        {
+            Some(num64(*x as i64))
+        }
+        (PrimOp::CharToInt, ExprKind::Char(x)) => Some(num(*x as i32)),
+        (PrimOp::IntToChar, ExprKind::Num(x)) => u32::try_from(*x)
+            .ok()
+            .and_then(char::from_u32)
+            .map(|c| TypedExpr::new(Type::Char, ExprKind::Char(c))),
+        (PrimOp::SymbolToString, ExprKind::Symbol(x)) => {
+            Some(TypedExpr::new(Type::Str, ExprKind::Str(x.clone())))
+        }
+        _ => None,
+    }
+}
+
+/// Evaluate a binary operation whose arguments are both constants, if
+/// possible.
+///
+/// Arithmetic on int64s wraps around on overflow in the same way as
+/// WebAssembly's integer instructions, while arithmetic on ints is exact, so
+/// it is only folded if the result fits in a literal. Division is never
+/// folded if it would trap at runtime, so that the trap is preserved.
+fn eval_binop(op: PrimOp, arg1: &TypedExpr, arg2: &TypedExpr) -> Option<TypedExpr> {
+    match (op, &*arg1.kind, &*arg2.kind) {
+        (_, ExprKind::Num(x), ExprKind::Num(y)) => eval_int_binop(op, *x, *y),
+        (_, ExprKind::Int64(x), ExprKind::Int64(y)) => eval_int64_binop(op, *x, *y),
+        (_, ExprKind::Float(x), ExprKind::Float(y)) => eval_float_binop(op, *x, *y),
+        (PrimOp::And, ExprKind::Bool(x), ExprKind::Bool(y)) => Some(boolean(*x && *y)),
+        (PrimOp::Or, ExprKind::Bool(x), ExprKind::Bool(y)) => Some(boolean(*x || *y)),
+        (PrimOp::Concat, ExprKind::Str(x), ExprKind::Str(y)) => {
+            Some(TypedExpr::new(Type::Str, ExprKind::Str(format!("{x}{y}"))))
+        }
+        (PrimOp::StringEqual, ExprKind::Str(x), ExprKind::Str(y)) => Some(boolean(x == y)),
+        (PrimOp::CharEqual, ExprKind::Char(x), ExprKind::Char(y)) => Some(boolean(x == y)),
+        (PrimOp::SymbolEqual, ExprKind::Symbol(x), ExprKind::Symbol(y)) => Some(boolean(x == y)),
+        _ => None,
+    }
+}
+
+fn eval_int_binop(op: PrimOp, x: i32, y: i32) -> Option<TypedExpr> {
+    match op {
+        PrimOp::Add => x.checked_add(y).map(num),
+        PrimOp::Subtract => x.checked_sub(y).map(num),
+        PrimOp::Multiply => x.checked_mul(y).map(num),
+        PrimOp::Divide => x.checked_div(y).map(num),
+        PrimOp::Remainder => x.checked_rem(y).map(num),
+        PrimOp::Modulo => {
+            // the result has the same sign as the divisor
+            x.checked_rem(y)
+                .map(|rem| {
+                    if rem != 0 && (rem < 0) != (y < 0) {
+                        rem + y
+                    } else {
+                        rem
+                    }
+                })
+                .map(num)
+        }
+        PrimOp::LessThan => Some(boolean(x < y)),
+        PrimOp::GreaterThan => Some(boolean(x > y)),
+        PrimOp::LessOrEqual => Some(boolean(x <= y)),
+        PrimOp::GreaterOrEqual => Some(boolean(x >= y)),
+        PrimOp::EqualTo => Some(boolean(x == y)),
+        PrimOp::NotEqualTo => Some(boolean(x != y)),
+        PrimOp::BitAnd => Some(num(x & y)),
+        PrimOp::BitOr => Some(num(x | y)),
+        PrimOp::BitXor => Some(num(x ^ y)),
+        // the bitwise operations act on 32-bit integers, and like
+        // WebAssembly, shift by the amount modulo 32
+        PrimOp::ShiftLeft => Some(num(x.wrapping_shl(y as u32))),
+        PrimOp::ShiftRight => Some(num(x.wrapping_shr(y as u32))),
+        _ => None,
+    }
+}
+
+fn eval_int64_binop(op: PrimOp, x: i64, y: i64) -> Option<TypedExpr> {
+    match op {
+        PrimOp::Add => Some(num64(x.wrapping_add(y))),
+        PrimOp::Subtract => Some(num64(x.wrapping_sub(y))),
+        PrimOp::Multiply => Some(num64(x.wrapping_mul(y))),
+        PrimOp::Divide => x.checked_div(y).map(num64),
+        PrimOp::Remainder => x.checked_rem(y).map(num64),
+        PrimOp::Modulo => {
+            // the result has the same sign as the divisor
+            x.checked_rem(y)
+                .map(|rem| {
+                    if rem != 0 && (rem < 0) != (y < 0) {
+                        rem + y
+                    } else {
+                        rem
+                    }
+                })
+                .map(num64)
+        }
+        PrimOp::LessThan => Some(boolean(x < y)),
+        PrimOp::GreaterThan => Some(boolean(x > y)),
+        PrimOp::LessOrEqual => Some(boolean(x <= y)),
+        PrimOp::GreaterOrEqual => Some(boolean(x >= y)),
+        PrimOp::EqualTo => Some(boolean(x == y)),
+        PrimOp::NotEqualTo => Some(boolean(x != y)),
+        PrimOp::BitAnd => Some(num64(x & y)),
+        PrimOp::BitOr => Some(num64(x | y)),
+        PrimOp::BitXor => Some(num64(x ^ y)),
+        // like WebAssembly, shift by the amount modulo 64
+        PrimOp::ShiftLeft => Some(num64(x.wrapping_shl(y as u32))),
+        PrimOp::ShiftRight => Some(num64(x.wrapping_shr(y as u32))),
+        _ => None,
+    }
+}
+
+fn eval_float_binop(op: PrimOp, x: f64, y: f64) -> Option<TypedExpr> {
+    match op {
+        PrimOp::Add => Some(float(x + y)),
+        PrimOp::Subtract => Some(float(x - y)),
+        PrimOp::Multiply => Some(float(x * y)),
+        PrimOp::Divide => Some(float(x / y)),
+        PrimOp::LessThan => Some(boolean(x < y)),
+        PrimOp::GreaterThan => Some(boolean(x > y)),
+        PrimOp::LessOrEqual => Some(boolean(x <= y)),
+        PrimOp::GreaterOrEqual => Some(boolean(x >= y)),
+        PrimOp::EqualTo => Some(boolean(x == y)),
+        PrimOp::NotEqualTo => Some(boolean(x != y)),
+        _ => None,
+    }
+}
+
+/// Simplify a binary operation where only one of the arguments is a
+/// constant, using algebraic identities such as `x + 0 = x`.
+///
+/// Identities which discard an argument entirely (such as `x * 0 = 0`) are
+/// only applied if the discarded argument is pure. The identities on
+/// integers are not applied to floats, since they do not all hold for
+/// floating point arithmetic (e.g. `-0.0 + 0 = 0.0`).
+fn simplify_binop(op: PrimOp, arg1: &TypedExpr, arg2: &TypedExpr) -> Option<TypedExpr> {
+    match (op, int_value(arg1), int_value(arg2)) {
+        (PrimOp::Add, Some(0), _) => return Some(arg2.clone()),
+        (PrimOp::Add, _, Some(0)) => return Some(arg1.clone()),
+        (PrimOp::Subtract, _, Some(0)) => return Some(arg1.clone()),
+        (PrimOp::Multiply, Some(1), _) => return Some(arg2.clone()),
+        (PrimOp::Multiply, _, Some(1)) => return Some(arg1.clone()),
+        (PrimOp::Multiply, Some(0), _) if is_pure(arg2) => return Some(arg1.clone()),
+        (PrimOp::Multiply, _, Some(0)) if is_pure(arg1) => return Some(arg2.clone()),
+        (PrimOp::Divide, _, Some(1)) => return Some(arg1.clone()),
+        _ => {}
+    }
+    match (op, &*arg1.kind, &*arg2.kind) {
+        (PrimOp::And, ExprKind::Bool(true), _) => Some(arg2.clone()),
+        (PrimOp::And, _, ExprKind::Bool(true)) => Some(arg1.clone()),
+        (PrimOp::And, ExprKind::Bool(false), _) if is_pure(arg2) => Some(boolean(false)),
+        (PrimOp::And, _, ExprKind::Bool(false)) if is_pure(arg1) => Some(boolean(false)),
+        (PrimOp::Or, ExprKind::Bool(false), _) => Some(arg2.clone()),
+        (PrimOp::Or, _, ExprKind::Bool(false)) => Some(arg1.clone()),
+        (PrimOp::Or, ExprKind::Bool(true), _) if is_pure(arg2) => Some(boolean(true)),
+        (PrimOp::Or, _, ExprKind::Bool(true)) if is_pure(arg1) => Some(boolean(true)),
+        (PrimOp::Concat, ExprKind::Str(x), _) if x.is_empty() => Some(arg2.clone()),
+        (PrimOp::Concat, _, ExprKind::Str(y)) if y.is_empty() => Some(arg1.clone()),
+        _ => None,
+    }
+}
+
+fn cf_prim(op: PrimOp, args: &Vector<TypedExpr>, typ: &Type) -> Result<TypedExpr, ConstFoldError> {
+    let fargs = args
+        .iter()
+        .map(const_fold_exp)
+        .collect::<Result<Vector<TypedExpr>, ConstFoldError>>()?;
+    let folded = match fargs.len() {
+        1 => eval_unop(op, &fargs[0]),
+        2 => eval_binop(op, &fargs[0], &fargs[1])
+            .or_else(|| simplify_binop(op, &fargs[0], &fargs[1])),
+        _ => None,
+    };
+    match folded {
+        Some(folded) => Ok(folded),
+        None => Ok(TypedExpr::new(typ.clone(), ExprKind::Prim(op, fargs))),
+    }
+}
+
+fn cf_if(
+    pred: &TypedExpr,
+    cons: &TypedExpr,
+    alt: &TypedExpr,
+    typ: &Type,
+) -> Result<TypedExpr, ConstFoldError> {
+    let fpred = const_fold_exp(pred)?;
+    match &*fpred.kind {
+        ExprKind::Bool(true) => const_fold_exp(cons),
+        ExprKind::Bool(false) => const_fold_exp(alt),
+        _ => {
+            let fcons = const_fold_exp(cons)?;
+            let falt = const_fold_exp(alt)?;
+            Ok(TypedExpr::new(
+                typ.clone(),
+                ExprKind::If(fpred, fcons, falt),
+            ))
+        }
+    }
+}
+
+fn cf_tuple_get(tuple: &TypedExpr, key: u32, typ: &Type) -> Result<TypedExpr, ConstFoldError> {
+    let ftuple = const_fold_exp(tuple)?;
+    if let ExprKind::Tuple(exps) = &*ftuple.kind {
+        let others_pure = exps
+            .iter()
+            .enumerate()
+            .all(|(index, exp)| index == key as usize || is_pure(exp));
+        if others_pure && (key as usize) < exps.len() {
+            return Ok(exps[key as usize].clone());
+        }
+    }
+    Ok(TypedExpr::new(typ.clone(), ExprKind::TupleGet(ftuple, key)))
+}
+
+fn cf_car(pair: &TypedExpr, typ: &Type) -> Result<TypedExpr, ConstFoldError> {
+    let fpair = const_fold_exp(pair)?;
+    if let ExprKind::Cons(first, rest) = &*fpair.kind
+        && is_pure(rest)
+    {
+        return Ok(first.clone());
+    }
+    Ok(TypedExpr::new(typ.clone(), ExprKind::Car(fpair)))
+}
+
+fn cf_cdr(pair: &TypedExpr, typ: &Type) -> Result<TypedExpr, ConstFoldError> {
+    let fpair = const_fold_exp(pair)?;
+    if let ExprKind::Cons(first, rest) = &*fpair.kind
+        && is_pure(first)
+    {
+        return Ok(rest.clone());
+    }
+    Ok(TypedExpr::new(typ.clone(), ExprKind::Cdr(fpair)))
+}
@@ -0,0 +1,1158 @@
+use crate::analysis::{assigned_vars, children};
+use crate::common::{Expr, ExprKind, PrimOp, Prog, TypedExpr, generate_var_name};
+use crate::type_check::contains_func_type;
+use crate::types::{Type, type_var_substitute};
+
+use im_rc::{HashMap, Vector, vector};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug)]
+pub struct CpsError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for CpsError {}
+
+impl From<&str> for CpsError {
+    fn from(message: &str) -> Self {
+        CpsError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for CpsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CpsError: {}", self.0)
+    }
+}
+
+/// What a variable in scope of the expression being converted refers to.
+#[derive(Clone, Debug)]
+enum Binding {
+    /// A local variable, renamed to a fresh name. Variables which are
+    /// assigned to with set! are stored in a box (a single element list).
+    Local(String, bool),
+    /// A global definition, which may be assigned to by any function.
+    Global,
+    /// An extern function, which is called in direct style.
+    Extern(Type),
+    /// The innermost generator (bound to `GENERATOR`), given by the names of
+    /// its state (see `cps_make_generator`) and the type of values it yields.
+    Generator(String, String, Type),
+}
+
+/// The name the innermost generator is bound to within its body.
+const GENERATOR: &str = "$$GENERATOR$$";
+
+/// A key-value map for finding what each variable in scope refers to.
+type Scope = HashMap<String, Binding>;
+
+/// Information about the whole program being converted.
+struct Context {
+    /// The type which every function and continuation ends up returning
+    /// (see `cps_prog`).
+    answer: Type,
+    /// The names of local variables which are assigned to anywhere.
+    assigned: BTreeSet<String>,
+    /// The type of each exception raised or handled in the program, and the
+    /// name of the box holding its current handler (see `cps_handle`).
+    handlers: Vec<(Type, String)>,
+}
+
+/// The rest of the computation, given the value of the expression being
+/// converted.
+///
+/// While converting, the continuation is usually a Rust closure which builds
+/// the code that follows the expression, so that no continuation lambdas are
+/// created for expressions which can't capture their continuation. It is only
+/// turned into a lambda (reified) when it is passed to a function.
+enum Cont<'a> {
+    Meta(Box<dyn FnOnce(Expr) -> Result<Expr, CpsError> + 'a>),
+    /// An atomic expression (e.g. a variable) holding a continuation lambda.
+    Var(Expr),
+}
+
+impl<'a> Cont<'a> {
+    fn meta(cont: impl FnOnce(Expr) -> Result<Expr, CpsError> + 'a) -> Self {
+        Cont::Meta(Box::new(cont))
+    }
+
+    fn apply(self, val: Expr) -> Result<Expr, CpsError> {
+        match self {
+            Cont::Meta(cont) => cont(val),
+            Cont::Var(cont) => Ok(Expr::new(ExprKind::FnApp(cont, vector![val]))),
+        }
+    }
+
+    /// Returns an expression for the continuation, which takes values of
+    /// type `typ`.
+    fn reify(self, typ: &Type, ctx: &Context) -> Result<Expr, CpsError> {
+        match self {
+            Cont::Meta(cont) => {
+                let var = generate_var_name();
+                let body = cont(Expr::new(ExprKind::Id(var.clone())))?;
+                Ok(Expr::new(ExprKind::Lambda(
+                    vector![(var, cps_type(typ, &ctx.answer))],
+                    ctx.answer.clone(),
+                    body,
+                )))
+            }
+            Cont::Var(cont) => Ok(cont),
+        }
+    }
+}
+
+/// Convert a type into the type of its CPS converted values, where the
+/// result of the whole program has type `answer`.
+///
+/// Every function takes its continuation as an extra (last) parameter, and
+/// returns the answer, and a continuation `(cont T)` becomes a function
+/// `(-> T answer)`.
+pub fn cps_type(typ: &Type, answer: &Type) -> Type {
+    match typ {
+        Type::Func(in_typs, ret_typ) => {
+            let mut cps_in_typs: Vector<Type> =
+                in_typs.iter().map(|typ| cps_type(typ, answer)).collect();
+            cps_in_typs.push_back(cps_type(&Type::Cont(ret_typ.clone()), answer));
+            Type::Func(cps_in_typs, Box::new(answer.clone()))
+        }
+        Type::Cont(val_typ) => {
+            Type::Func(vector![cps_type(val_typ, answer)], Box::new(answer.clone()))
+        }
+        // a box holding the function which resumes the generator
+        Type::Generator(val_typ) => Type::List(Box::new(resume_type(val_typ, answer))),
+        Type::List(base_typ) => Type::List(Box::new(cps_type(base_typ, answer))),
+        Type::Tuple(typs) => Type::Tuple(typs.iter().map(|typ| cps_type(typ, answer)).collect()),
+        Type::Record(bindings) => Type::Record(
+            bindings
+                .iter()
+                .map(|(name, typ, mutable)| (name.clone(), cps_type(typ, answer), *mutable))
+                .collect(),
+        ),
+        Type::Exists(typ_var, base_typ) => {
+            Type::Exists(*typ_var, Box::new(cps_type(base_typ, answer)))
+        }
+        Type::Int
+        | Type::Int64
+        | Type::Float
+        | Type::Bool
+        | Type::Str
+        | Type::Char
+        | Type::Symbol
+        | Type::TypeVar(_)
+        | Type::Unknown => typ.clone(),
+    }
+}
+
+/// The type of a function which resumes a generator yielding values of type
+/// `val_typ`, given the continuation of the call to `next`.
+fn resume_type(val_typ: &Type, answer: &Type) -> Type {
+    let next_typ = Type::List(Box::new(val_typ.clone()));
+    Type::Func(
+        vector![cps_type(&Type::Cont(Box::new(next_typ)), answer)],
+        Box::new(answer.clone()),
+    )
+}
+
+/// Check whether an expression uses continuations or generators, which
+/// require CPS conversion.
+pub fn uses_continuations(exp: &Expr) -> bool {
+    matches!(
+        &*exp.kind,
+        ExprKind::CallCC(_)
+            | ExprKind::Throw(_, _, _)
+            | ExprKind::MakeGenerator(_, _)
+            | ExprKind::Yield(_)
+            | ExprKind::Next(_)
+    ) || children(exp).into_iter().any(uses_continuations)
+}
+
+/// Collect the types of the exceptions raised or handled in an expression.
+fn exception_types(exp: &TypedExpr, typs: &mut Vec<Type>) {
+    let typ = match &*exp.kind {
+        ExprKind::Raise(exn, _typ) => Some(&exn.typ),
+        ExprKind::Handle(_var, typ, _handler, _body) => Some(typ),
+        _ => None,
+    };
+    if let Some(typ) = typ
+        && !typs.contains(typ)
+    {
+        typs.push(typ.clone());
+    }
+    for child in children(exp) {
+        exception_types(child, typs);
+    }
+}
+
+/// Convert a (type checked) program into continuation-passing style, so
+/// that `call/cc` and `throw` can be expressed with ordinary functions.
+///
+/// Every function is given its continuation as an extra parameter (see
+/// `cps_type`), and calls it with its result instead of returning, so the
+/// continuation captured by `call/cc` is just a function which can be called
+/// (with `throw`) any number of times. Every call is then in tail position,
+/// so the stack stays bounded if the calls are compiled as tail calls (see
+/// `CodeGenerateOptions::tail_calls`).
+///
+/// Functions and continuations all return an int (the answer) which is
+/// never used: the main expression is given a continuation which stores the
+/// result of the program in a new global, and returns it once the converted
+/// expression has finished. Similarly, each export is replaced with a
+/// function in direct style, which calls the converted function with a
+/// continuation storing its result in a global of its own.
+///
+/// Extern functions are still called in direct style, while global
+/// definitions are CPS converted like everything else. Since continuations
+/// (like any other closure) copy the variables they refer to, local
+/// variables which are assigned to with set! are stored in boxes (single
+/// element lists, updated with set-car!) so that every continuation sees the
+/// same variable. Exception handlers are also kept in boxes (see
+/// `cps_handle`).
+pub fn cps_prog(prog: &Prog<TypedExpr>) -> Result<Prog<Expr>, CpsError> {
+    let exp = &prog.exp;
+    if contains_func_type(&exp.typ) {
+        return Err(CpsError(format!(
+            "The result of a CPS converted program cannot contain functions or continuations, but has type {}.",
+            exp.typ
+        )));
+    }
+    let answer = Type::Int;
+    let mut scope = Scope::new();
+    let mut globals = Vector::new();
+    for (name, typ) in &prog.globals {
+        scope.insert(name.clone(), Binding::Global);
+        globals.push_back((name.clone(), cps_type(typ, &answer)));
+    }
+    for (name, typ) in &prog.externs {
+        scope.insert(name.clone(), Binding::Extern(typ.clone()));
+    }
+    let mut exn_typs = vec![];
+    exception_types(exp, &mut exn_typs);
+    let ctx = Context {
+        answer,
+        assigned: assigned_vars(exp),
+        handlers: exn_typs
+            .into_iter()
+            .map(|typ| (typ, generate_var_name()))
+            .collect(),
+    };
+
+    let mut exps = Vector::new();
+    let mut exports = Vector::new();
+    for (name, export_name, typ) in &prog.exports {
+        let Type::Func(in_typs, ret_typ) = typ else {
+            return Err(CpsError(format!("Export {name} is not a function.")));
+        };
+        let func = generate_var_name();
+        let result = generate_var_name();
+        exps.push_back(Expr::new(ExprKind::Set(
+            func.clone(),
+            direct_lambda(name, in_typs, ret_typ, &result),
+        )));
+        globals.push_back((func.clone(), typ.clone()));
+        globals.push_back((result, (**ret_typ).clone()));
+        exports.push_back((func, export_name.clone(), typ.clone()));
+    }
+
+    let result = generate_var_name();
+    let finish = |val| {
+        Ok(Expr::new(ExprKind::Begin(vector![
+            Expr::new(ExprKind::Set(result.clone(), val)),
+            Expr::new(ExprKind::Num(0)),
+        ])))
+    };
+    let mut cexp = cps(exp, &scope, &ctx, Cont::meta(finish))?;
+    // each handler box starts out holding a handler for uncaught exceptions
+    for (typ, name) in ctx.handlers.iter().rev() {
+        let var = generate_var_name();
+        let uncaught = Expr::new(ExprKind::Lambda(
+            vector![(var.clone(), typ.clone())],
+            ctx.answer.clone(),
+            Expr::new(ExprKind::Raise(
+                Expr::new(ExprKind::Id(var)),
+                ctx.answer.clone(),
+            )),
+        ));
+        cexp = Expr::new(ExprKind::Let(
+            vector![(
+                name.clone(),
+                Expr::new(ExprKind::Cons(
+                    uncaught,
+                    Expr::new(ExprKind::Null(handler_type(typ, &ctx.answer))),
+                )),
+            )],
+            cexp,
+        ));
+    }
+    exps.push_back(cexp);
+    exps.push_back(Expr::new(ExprKind::Id(result.clone())));
+    globals.push_back((result, exp.typ.clone()));
+    Ok(Prog {
+        externs: prog.externs.clone(),
+        globals,
+        exports,
+        fns: Vector::new(),
+        exp: Expr::new(ExprKind::Begin(exps)),
+    })
+}
+
+/// Returns a function in direct style which calls the converted global
+/// function `name`, whose result is stored in the global `result`.
+fn direct_lambda(name: &str, in_typs: &Vector<Type>, ret_typ: &Type, result: &str) -> Expr {
+    let params: Vector<(String, Type)> = in_typs
+        .iter()
+        .map(|typ| (generate_var_name(), typ.clone()))
+        .collect();
+    let mut args: Vector<Expr> = params
+        .iter()
+        .map(|(name, _typ)| Expr::new(ExprKind::Id(name.clone())))
+        .collect();
+    let var = generate_var_name();
+    args.push_back(Expr::new(ExprKind::Lambda(
+        vector![(var.clone(), ret_typ.clone())],
+        Type::Int,
+        Expr::new(ExprKind::Begin(vector![
+            Expr::new(ExprKind::Set(
+                result.to_string(),
+                Expr::new(ExprKind::Id(var))
+            )),
+            Expr::new(ExprKind::Num(0)),
+        ])),
+    )));
+    Expr::new(ExprKind::Lambda(
+        params,
+        ret_typ.clone(),
+        Expr::new(ExprKind::Begin(vector![
+            Expr::new(ExprKind::FnApp(
+                Expr::new(ExprKind::Id(name.to_string())),
+                args
+            )),
+            Expr::new(ExprKind::Id(result.to_string())),
+        ])),
+    ))
+}
+
+/// The type of a handler for exceptions of type `typ`, which is a
+/// continuation taking the exception.
+fn handler_type(typ: &Type, answer: &Type) -> Type {
+    Type::Func(vector![typ.clone()], Box::new(answer.clone()))
+}
+
+/// Convert an expression, passing its value to the continuation `k`.
+fn cps<'a>(
+    exp: &'a TypedExpr,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    match &*exp.kind {
+        ExprKind::Num(x) => k.apply(Expr::new(ExprKind::Num(*x))),
+        ExprKind::Int64(x) => k.apply(Expr::new(ExprKind::Int64(*x))),
+        ExprKind::Float(x) => k.apply(Expr::new(ExprKind::Float(*x))),
+        ExprKind::Bool(x) => k.apply(Expr::new(ExprKind::Bool(*x))),
+        ExprKind::Str(x) => k.apply(Expr::new(ExprKind::Str(x.clone()))),
+        ExprKind::Char(x) => k.apply(Expr::new(ExprKind::Char(*x))),
+        ExprKind::Symbol(x) => k.apply(Expr::new(ExprKind::Symbol(x.clone()))),
+        ExprKind::Quote(data) => k.apply(Expr::new(ExprKind::Quote(data.clone()))),
+        ExprKind::Null(typ) => k.apply(Expr::new(ExprKind::Null(cps_type(typ, &ctx.answer)))),
+        ExprKind::Newline => bind(Expr::new(ExprKind::Newline), k),
+        ExprKind::Id(x) => cps_id(x, scope, ctx, k),
+        ExprKind::Lambda(params, ret_typ, body) => {
+            k.apply(cps_lambda(params, ret_typ, body, scope, ctx)?)
+        }
+        ExprKind::Prim(op, args) => {
+            let op = *op;
+            cps_array(
+                args.iter().collect(),
+                scope,
+                ctx,
+                Box::new(move |vals| bind(Expr::new(ExprKind::Prim(op, vals.into())), k)),
+            )
+        }
+        ExprKind::If(pred, cons, alt) => {
+            let scope = scope.clone();
+            cps(
+                pred,
+                &scope.clone(),
+                ctx,
+                Cont::meta(move |pred_val| match k {
+                    Cont::Var(cont) => Ok(Expr::new(ExprKind::If(
+                        pred_val,
+                        cps(cons, &scope, ctx, Cont::Var(cont.clone()))?,
+                        cps(alt, &scope, ctx, Cont::Var(cont))?,
+                    ))),
+                    // both branches continue with the same code, so it is
+                    // shared in a join point
+                    Cont::Meta(_) => {
+                        let join = generate_var_name();
+                        let join_lambda = k.reify(&exp.typ, ctx)?;
+                        let join_var = Expr::new(ExprKind::Id(join.clone()));
+                        Ok(Expr::new(ExprKind::Let(
+                            vector![(join, join_lambda)],
+                            Expr::new(ExprKind::If(
+                                pred_val,
+                                cps(cons, &scope, ctx, Cont::Var(join_var.clone()))?,
+                                cps(alt, &scope, ctx, Cont::Var(join_var))?,
+                            )),
+                        )))
+                    }
+                }),
+            )
+        }
+        ExprKind::Let(bindings, body) => cps_let(bindings, body, scope, ctx, k),
+        ExprKind::Letrec(bindings, body) => cps_letrec(bindings, body, scope, ctx, k),
+        ExprKind::Begin(exps) => cps_array(
+            exps.iter().collect(),
+            scope,
+            ctx,
+            Box::new(move |mut vals| k.apply(vals.pop().ok_or("Empty begin expression.")?)),
+        ),
+        ExprKind::Set(var, val) => {
+            let binding = scope.get(var).cloned();
+            let var = var.clone();
+            cps(
+                val,
+                scope,
+                ctx,
+                Cont::meta(move |val| match binding {
+                    Some(Binding::Local(name, true)) => bind(
+                        Expr::new(ExprKind::Prim(
+                            PrimOp::SetCar,
+                            vector![Expr::new(ExprKind::Id(name)), val],
+                        )),
+                        k,
+                    ),
+                    Some(Binding::Global) => bind(Expr::new(ExprKind::Set(var, val)), k),
+                    _ => Err(CpsError(format!("Cannot assign to {var}."))),
+                }),
+            )
+        }
+        ExprKind::Cons(first, rest) => cps_array(
+            vec![first, rest],
+            scope,
+            ctx,
+            Box::new(move |vals| {
+                let [first, rest] = <[Expr; 2]>::try_from(vals).unwrap();
+                bind(Expr::new(ExprKind::Cons(first, rest)), k)
+            }),
+        ),
+        ExprKind::Car(val) => cps_unary(val, scope, ctx, k, ExprKind::Car),
+        ExprKind::Cdr(val) => cps_unary(val, scope, ctx, k, ExprKind::Cdr),
+        ExprKind::IsNull(val) => cps_unary(val, scope, ctx, k, ExprKind::IsNull),
+        ExprKind::Display(val) => cps_unary(val, scope, ctx, k, ExprKind::Display),
+        ExprKind::Tuple(exps) => cps_array(
+            exps.iter().collect(),
+            scope,
+            ctx,
+            Box::new(move |vals| bind(Expr::new(ExprKind::Tuple(vals.into())), k)),
+        ),
+        ExprKind::TupleGet(tuple, key) => {
+            let key = *key;
+            cps_unary(tuple, scope, ctx, k, move |tuple| {
+                ExprKind::TupleGet(tuple, key)
+            })
+        }
+        ExprKind::TupleSet(tuple, key, val) => {
+            let key = *key;
+            cps_array(
+                vec![tuple, val],
+                scope,
+                ctx,
+                Box::new(move |vals| {
+                    let [tuple, val] = <[Expr; 2]>::try_from(vals).unwrap();
+                    bind(Expr::new(ExprKind::TupleSet(tuple, key, val)), k)
+                }),
+            )
+        }
+        ExprKind::Record(bindings) => {
+            let fields: Vec<(String, bool)> = bindings
+                .iter()
+                .map(|(name, _exp, mutable)| (name.clone(), *mutable))
+                .collect();
+            cps_array(
+                bindings.iter().map(|(_name, exp, _mutable)| exp).collect(),
+                scope,
+                ctx,
+                Box::new(move |vals| {
+                    let record = fields
+                        .into_iter()
+                        .zip(vals)
+                        .map(|((name, mutable), val)| (name, val, mutable))
+                        .collect();
+                    bind(Expr::new(ExprKind::Record(record)), k)
+                }),
+            )
+        }
+        ExprKind::RecordGet(record, key) => {
+            let key = key.clone();
+            cps_unary(record, scope, ctx, k, move |record| {
+                ExprKind::RecordGet(record, key)
+            })
+        }
+        ExprKind::RecordSet(record, key, val) => {
+            let key = key.clone();
+            cps_array(
+                vec![record, val],
+                scope,
+                ctx,
+                Box::new(move |vals| {
+                    let [record, val] = <[Expr; 2]>::try_from(vals).unwrap();
+                    bind(Expr::new(ExprKind::RecordSet(record, key, val)), k)
+                }),
+            )
+        }
+        ExprKind::Pack(val, sub, exist) => {
+            let sub = cps_type(sub, &ctx.answer);
+            let exist = cps_type(exist, &ctx.answer);
+            cps_unary(val, scope, ctx, k, move |val| {
+                ExprKind::Pack(val, sub, exist)
+            })
+        }
+        ExprKind::Unpack(var, package, type_var, body) => {
+            let var_typ = match &package.typ {
+                Type::Exists(package_type_var, base_typ) => {
+                    type_var_substitute(base_typ, *package_type_var, &Type::TypeVar(*type_var))
+                }
+                _ => {
+                    return Err(CpsError::from(
+                        "Package in unpack expression is not existentially typed.",
+                    ));
+                }
+            };
+            let scope = scope.clone();
+            let type_var = *type_var;
+            cps(
+                package,
+                &scope.clone(),
+                ctx,
+                Cont::meta(move |package_val| {
+                    let (name, body_scope, init) = bind_local(var, &var_typ, &scope, ctx);
+                    let cbody = cps(body, &body_scope, ctx, k)?;
+                    Ok(Expr::new(ExprKind::Unpack(
+                        name,
+                        package_val,
+                        type_var,
+                        wrap_box(init, cbody),
+                    )))
+                }),
+            )
+        }
+        ExprKind::FnApp(func, args) => cps_fn_app(func, args, scope, ctx, k),
+        ExprKind::CallCC(func) => {
+            let typ = exp.typ.clone();
+            cps(
+                func,
+                scope,
+                ctx,
+                Cont::meta(move |func_val| {
+                    // the continuation is both the argument and the
+                    // continuation of the function
+                    let cont = generate_var_name();
+                    let cont_var = Expr::new(ExprKind::Id(cont.clone()));
+                    Ok(Expr::new(ExprKind::Let(
+                        vector![(cont, k.reify(&typ, ctx)?)],
+                        Expr::new(ExprKind::FnApp(
+                            func_val,
+                            vector![cont_var.clone(), cont_var],
+                        )),
+                    )))
+                }),
+            )
+        }
+        ExprKind::Throw(cont, val, _typ) => {
+            // the current continuation is discarded
+            cps_array(
+                vec![cont, val],
+                scope,
+                ctx,
+                Box::new(|vals| {
+                    let [cont, val] = <[Expr; 2]>::try_from(vals).unwrap();
+                    Ok(Expr::new(ExprKind::FnApp(cont, vector![val])))
+                }),
+            )
+        }
+        ExprKind::MakeGenerator(typ, body) => cps_make_generator(typ, body, scope, ctx, k),
+        ExprKind::Yield(val) => {
+            let Some(Binding::Generator(state, next_cont, val_typ)) = scope.get(GENERATOR).cloned()
+            else {
+                return Err(CpsError::from(
+                    "Yield expression is not within a generator.",
+                ));
+            };
+            cps(
+                val,
+                scope,
+                ctx,
+                Cont::meta(move |val| {
+                    // the generator continues from here when it is next
+                    // resumed
+                    let resume = resume_lambda(&next_cont, &val_typ, ctx, k.apply(val.clone())?);
+                    let next_val = Expr::new(ExprKind::Cons(
+                        val,
+                        Expr::new(ExprKind::Null(cps_type(&val_typ, &ctx.answer))),
+                    ));
+                    Ok(Expr::new(ExprKind::Begin(vector![
+                        set_box(&state, resume),
+                        return_to_next(&next_cont, next_val),
+                    ])))
+                }),
+            )
+        }
+        ExprKind::Next(generator) => {
+            let typ = exp.typ.clone();
+            cps(
+                generator,
+                scope,
+                ctx,
+                Cont::meta(move |generator_val| {
+                    let resume = Expr::new(ExprKind::Car(generator_val));
+                    let cont = k.reify(&typ, ctx)?;
+                    let var = generate_var_name();
+                    Ok(Expr::new(ExprKind::Let(
+                        vector![(var.clone(), resume)],
+                        Expr::new(ExprKind::FnApp(Expr::new(ExprKind::Id(var)), vector![cont])),
+                    )))
+                }),
+            )
+        }
+        ExprKind::Raise(exn, _typ) => {
+            let handler = handler_box(&exn.typ, ctx)?;
+            // the current continuation is discarded
+            cps(
+                exn,
+                scope,
+                ctx,
+                Cont::meta(move |exn_val| {
+                    let var = generate_var_name();
+                    Ok(Expr::new(ExprKind::Let(
+                        vector![(
+                            var.clone(),
+                            Expr::new(ExprKind::Car(Expr::new(ExprKind::Id(handler))))
+                        )],
+                        Expr::new(ExprKind::FnApp(
+                            Expr::new(ExprKind::Id(var)),
+                            vector![exn_val],
+                        )),
+                    )))
+                }),
+            )
+        }
+        ExprKind::Handle(var, exn_typ, handler, body) => {
+            cps_handle(var, exn_typ, handler, body, &exp.typ, scope, ctx, k)
+        }
+        ExprKind::Derived(_, _) => Err(CpsError::from(
+            "Derived forms should have been removed by type checking.",
+        )),
+    }
+}
+
+/// Returns the name of the box holding the current handler for exceptions
+/// of type `typ`.
+fn handler_box(typ: &Type, ctx: &Context) -> Result<String, CpsError> {
+    ctx.handlers
+        .iter()
+        .find(|(handler_typ, _name)| handler_typ == typ)
+        .map(|(_typ, name)| name.clone())
+        .ok_or_else(|| CpsError(format!("Handler for exceptions of type {typ} not found.")))
+}
+
+/// Convert a with-handler expression.
+///
+/// Since the body is given its continuation rather than returning to the
+/// handler, exceptions can't propagate up the call stack. Instead, the
+/// current handler for each type of exception is kept in a box, and a raise
+/// passes the exception to the handler in the box for its type. The handler
+/// is installed while the body runs, and the boxes are restored to what they
+/// held before the body whenever it finishes (normally, or by passing an
+/// exception to the handler), so that the handler doesn't catch exceptions
+/// raised by the continuation.
+#[allow(clippy::too_many_arguments)]
+fn cps_handle<'a>(
+    var: &str,
+    exn_typ: &Type,
+    handler: &'a TypedExpr,
+    body: &'a TypedExpr,
+    typ: &Type,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    let handler_name = handler_box(exn_typ, ctx)?;
+    let saved: Vec<(String, String)> = ctx
+        .handlers
+        .iter()
+        .map(|(_typ, name)| (generate_var_name(), name.clone()))
+        .collect();
+    let saved_bindings = ctx
+        .handlers
+        .iter()
+        .zip(&saved)
+        .map(|(_handler, (saved, name))| {
+            (
+                saved.clone(),
+                Expr::new(ExprKind::Car(Expr::new(ExprKind::Id(name.clone())))),
+            )
+        })
+        .collect();
+
+    let restore = move |exp: Expr| {
+        let mut exps: Vector<Expr> = saved
+            .iter()
+            .map(|(saved, name)| set_box(name, Expr::new(ExprKind::Id(saved.clone()))))
+            .collect();
+        exps.push_back(exp);
+        Expr::new(ExprKind::Begin(exps))
+    };
+    // both the body and the handler continue with the same code, so it is
+    // shared in a join point
+    let join = generate_var_name();
+    let join_var = Expr::new(ExprKind::Id(join.clone()));
+    let join_lambda = k.reify(typ, ctx)?;
+
+    let (param, handler_scope, init) = bind_local(var, exn_typ, scope, ctx);
+    let chandler = cps(handler, &handler_scope, ctx, Cont::Var(join_var.clone()))?;
+    let handler_lambda = Expr::new(ExprKind::Lambda(
+        vector![(param, exn_typ.clone())],
+        ctx.answer.clone(),
+        restore.clone()(wrap_box(init, chandler)),
+    ));
+    let cbody = cps(
+        body,
+        scope,
+        ctx,
+        Cont::meta(move |val| Ok(restore(Expr::new(ExprKind::FnApp(join_var, vector![val]))))),
+    )?;
+    Ok(Expr::new(ExprKind::Let(
+        saved_bindings,
+        Expr::new(ExprKind::Let(
+            vector![(join, join_lambda)],
+            Expr::new(ExprKind::Begin(vector![
+                set_box(&handler_name, handler_lambda),
+                cbody,
+            ])),
+        )),
+    )))
+}
+
+/// Convert a generator, which is represented by its state: a box holding the
+/// function which resumes it (see `resume_type`).
+///
+/// Resuming the generator runs its body until the next yield, which replaces
+/// the function in the box with one that continues after the yield, and
+/// passes the yielded value to the continuation of `next`. Since the body
+/// can't refer to the continuation of `next` directly (a different one is
+/// given each time the generator is resumed), the latest one is stored in a
+/// second box, holding a list which is empty until the generator is first
+/// resumed. Once the body finishes, the generator is replaced with one which
+/// always gives an empty list.
+fn cps_make_generator<'a>(
+    val_typ: &Type,
+    body: &'a TypedExpr,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    let state = generate_var_name();
+    let next_cont = generate_var_name();
+    let cont_typ = cps_type(
+        &Type::Cont(Box::new(Type::List(Box::new(val_typ.clone())))),
+        &ctx.answer,
+    );
+    let body_scope = scope.update(
+        GENERATOR.to_string(),
+        Binding::Generator(state.clone(), next_cont.clone(), val_typ.clone()),
+    );
+    let finish = |_val| {
+        let empty = Expr::new(ExprKind::Null(cps_type(val_typ, &ctx.answer)));
+        Ok(Expr::new(ExprKind::Begin(vector![
+            set_box(&state, finished_lambda(val_typ, ctx)),
+            return_to_next(&next_cont, empty),
+        ])))
+    };
+    let start = resume_lambda(
+        &next_cont,
+        val_typ,
+        ctx,
+        cps(body, &body_scope, ctx, Cont::meta(finish))?,
+    );
+    let bindings = vector![
+        (
+            next_cont.clone(),
+            Expr::new(ExprKind::Cons(
+                Expr::new(ExprKind::Null(cont_typ.clone())),
+                Expr::new(ExprKind::Null(Type::List(Box::new(cont_typ)))),
+            )),
+        ),
+        (
+            state.clone(),
+            Expr::new(ExprKind::Cons(
+                finished_lambda(val_typ, ctx),
+                Expr::new(ExprKind::Null(resume_type(val_typ, &ctx.answer))),
+            )),
+        ),
+    ];
+    Ok(Expr::new(ExprKind::Let(
+        bindings,
+        Expr::new(ExprKind::Begin(vector![
+            set_box(&state, start),
+            k.apply(Expr::new(ExprKind::Id(state.clone())))?,
+        ])),
+    )))
+}
+
+/// Returns a function which resumes a generator by saving the continuation
+/// of `next` it is given, and then running `body`.
+fn resume_lambda(next_cont: &str, val_typ: &Type, ctx: &Context, body: Expr) -> Expr {
+    let Type::Func(param_typs, _ret_typ) = resume_type(val_typ, &ctx.answer) else {
+        unreachable!()
+    };
+    let cont = generate_var_name();
+    let saved_cont = Expr::new(ExprKind::Cons(
+        Expr::new(ExprKind::Id(cont.clone())),
+        Expr::new(ExprKind::Null(param_typs[0].clone())),
+    ));
+    Expr::new(ExprKind::Lambda(
+        vector![(cont, param_typs[0].clone())],
+        ctx.answer.clone(),
+        Expr::new(ExprKind::Begin(vector![
+            set_box(next_cont, saved_cont),
+            body
+        ])),
+    ))
+}
+
+/// Returns a function which resumes a generator that has finished.
+fn finished_lambda(val_typ: &Type, ctx: &Context) -> Expr {
+    let Type::Func(param_typs, _ret_typ) = resume_type(val_typ, &ctx.answer) else {
+        unreachable!()
+    };
+    let cont = generate_var_name();
+    let empty = Expr::new(ExprKind::Null(cps_type(val_typ, &ctx.answer)));
+    Expr::new(ExprKind::Lambda(
+        vector![(cont.clone(), param_typs[0].clone())],
+        ctx.answer.clone(),
+        Expr::new(ExprKind::FnApp(
+            Expr::new(ExprKind::Id(cont)),
+            vector![empty],
+        )),
+    ))
+}
+
+/// Returns an expression replacing the value in a box.
+fn set_box(name: &str, val: Expr) -> Expr {
+    Expr::new(ExprKind::Prim(
+        PrimOp::SetCar,
+        vector![Expr::new(ExprKind::Id(name.to_string())), val],
+    ))
+}
+
+/// Returns an expression passing a value to the latest continuation of
+/// `next` that a generator was resumed with.
+fn return_to_next(next_cont: &str, val: Expr) -> Expr {
+    let cont = Expr::new(ExprKind::Car(Expr::new(ExprKind::Car(Expr::new(
+        ExprKind::Id(next_cont.to_string()),
+    )))));
+    let var = generate_var_name();
+    Expr::new(ExprKind::Let(
+        vector![(var.clone(), cont)],
+        Expr::new(ExprKind::FnApp(Expr::new(ExprKind::Id(var)), vector![val])),
+    ))
+}
+
+/// Convert a sequence of expressions, evaluated in order, passing all of
+/// their values to `k`.
+#[allow(clippy::type_complexity)]
+fn cps_array<'a>(
+    exps: Vec<&'a TypedExpr>,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Box<dyn FnOnce(Vec<Expr>) -> Result<Expr, CpsError> + 'a>,
+) -> Result<Expr, CpsError> {
+    let mut exps = exps.into_iter();
+    let Some(first) = exps.next() else {
+        return k(vec![]);
+    };
+    let rest: Vec<&'a TypedExpr> = exps.collect();
+    let scope_rest = scope.clone();
+    cps(
+        first,
+        scope,
+        ctx,
+        Cont::meta(move |first_val| {
+            cps_array(
+                rest,
+                &scope_rest,
+                ctx,
+                Box::new(move |mut vals| {
+                    vals.insert(0, first_val);
+                    k(vals)
+                }),
+            )
+        }),
+    )
+}
+
+/// Convert an expression with a single subexpression, which can't capture
+/// its continuation itself.
+fn cps_unary<'a>(
+    val: &'a TypedExpr,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+    make: impl FnOnce(Expr) -> ExprKind<Expr> + 'a,
+) -> Result<Expr, CpsError> {
+    cps(
+        val,
+        scope,
+        ctx,
+        Cont::meta(move |val| bind(Expr::new(make(val)), k)),
+    )
+}
+
+/// Pass the value of an expression which can't capture its continuation to
+/// `k`, binding it to a variable first so that it is evaluated exactly once,
+/// before anything that follows it.
+fn bind(exp: Expr, k: Cont) -> Result<Expr, CpsError> {
+    let var = generate_var_name();
+    let body = k.apply(Expr::new(ExprKind::Id(var.clone())))?;
+    Ok(Expr::new(ExprKind::Let(vector![(var, exp)], body)))
+}
+
+fn cps_id(x: &str, scope: &Scope, ctx: &Context, k: Cont) -> Result<Expr, CpsError> {
+    match scope.get(x) {
+        Some(Binding::Local(name, false)) => k.apply(Expr::new(ExprKind::Id(name.clone()))),
+        Some(Binding::Local(name, true)) => bind(
+            Expr::new(ExprKind::Car(Expr::new(ExprKind::Id(name.clone())))),
+            k,
+        ),
+        // globals may be assigned to by a function called later on, so they
+        // are read immediately
+        Some(Binding::Global) => bind(Expr::new(ExprKind::Id(x.to_string())), k),
+        // an extern used as a value is wrapped in a CPS converted function
+        Some(Binding::Extern(Type::Func(in_typs, ret_typ))) => {
+            let params: Vector<(String, Type)> = in_typs
+                .iter()
+                .map(|typ| (generate_var_name(), typ.clone()))
+                .collect();
+            let cont = generate_var_name();
+            let args = params
+                .iter()
+                .map(|(name, _typ)| Expr::new(ExprKind::Id(name.clone())))
+                .collect();
+            let call = Expr::new(ExprKind::FnApp(
+                Expr::new(ExprKind::Id(x.to_string())),
+                args,
+            ));
+            let mut cps_params = params.clone();
+            cps_params.push_back((
+                cont.clone(),
+                cps_type(&Type::Cont(ret_typ.clone()), &ctx.answer),
+            ));
+            k.apply(Expr::new(ExprKind::Lambda(
+                cps_params,
+                ctx.answer.clone(),
+                Expr::new(ExprKind::FnApp(
+                    Expr::new(ExprKind::Id(cont)),
+                    vector![call],
+                )),
+            )))
+        }
+        Some(Binding::Extern(_typ)) => Err(CpsError(format!("Extern {x} is not a function."))),
+        Some(Binding::Generator(_, _, _)) | None => {
+            Err(CpsError(format!("Variable {x} is not in scope.")))
+        }
+    }
+}
+
+/// Bring a new local variable into scope, returning its fresh name, the new
+/// scope, and (if it is assigned to) the name of the variable holding its
+/// initial value, which needs to be put in a box (see `wrap_box`).
+fn bind_local(
+    var: &str,
+    typ: &Type,
+    scope: &Scope,
+    ctx: &Context,
+) -> (String, Scope, Option<(String, String, Type)>) {
+    let name = generate_var_name();
+    let boxed = ctx.assigned.contains(var);
+    let scope = scope.update(var.to_string(), Binding::Local(name.clone(), boxed));
+    if boxed {
+        let init = generate_var_name();
+        (
+            init.clone(),
+            scope,
+            Some((name, init, cps_type(typ, &ctx.answer))),
+        )
+    } else {
+        (name, scope, None)
+    }
+}
+
+/// Put the initial value of an assigned variable (see `bind_local`) into its
+/// box before running `body`.
+fn wrap_box(init: Option<(String, String, Type)>, body: Expr) -> Expr {
+    match init {
+        Some((name, init, typ)) => Expr::new(ExprKind::Let(
+            vector![(
+                name,
+                Expr::new(ExprKind::Cons(
+                    Expr::new(ExprKind::Id(init)),
+                    Expr::new(ExprKind::Null(typ)),
+                )),
+            )],
+            body,
+        )),
+        None => body,
+    }
+}
+
+fn cps_lambda(
+    params: &Vector<(String, Type)>,
+    ret_typ: &Type,
+    body: &TypedExpr,
+    scope: &Scope,
+    ctx: &Context,
+) -> Result<Expr, CpsError> {
+    let mut body_scope = scope.clone();
+    let mut cps_params = Vector::new();
+    let mut inits = vec![];
+    for (name, typ) in params {
+        let (param_name, param_scope, init) = bind_local(name, typ, &body_scope, ctx);
+        body_scope = param_scope;
+        cps_params.push_back((param_name, cps_type(typ, &ctx.answer)));
+        inits.push(init);
+    }
+    let cont = generate_var_name();
+    cps_params.push_back((
+        cont.clone(),
+        cps_type(&Type::Cont(Box::new(ret_typ.clone())), &ctx.answer),
+    ));
+    let mut cbody = cps(
+        body,
+        &body_scope,
+        ctx,
+        Cont::Var(Expr::new(ExprKind::Id(cont))),
+    )?;
+    for init in inits.into_iter().rev() {
+        cbody = wrap_box(init, cbody);
+    }
+    Ok(Expr::new(ExprKind::Lambda(
+        cps_params,
+        ctx.answer.clone(),
+        cbody,
+    )))
+}
+
+/// Convert a let expression.
+///
+/// The bound expressions are evaluated in order before any of the variables
+/// come into scope.
+fn cps_let<'a>(
+    bindings: &'a Vector<(String, TypedExpr)>,
+    body: &'a TypedExpr,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    let mut body_scope = scope.clone();
+    let mut names = vec![];
+    let mut inits = vec![];
+    for (name, exp) in bindings {
+        let (local_name, local_scope, init) = bind_local(name, &exp.typ, &body_scope, ctx);
+        body_scope = local_scope;
+        names.push(local_name);
+        inits.push(init);
+    }
+    cps_array(
+        bindings.iter().map(|(_name, exp)| exp).collect(),
+        scope,
+        ctx,
+        Box::new(move |vals| {
+            let cbindings: Vector<(String, Expr)> = names.into_iter().zip(vals).collect();
+            let mut cbody = cps(body, &body_scope, ctx, k)?;
+            for init in inits.into_iter().rev() {
+                cbody = wrap_box(init, cbody);
+            }
+            Ok(Expr::new(ExprKind::Let(cbindings, cbody)))
+        }),
+    )
+}
+
+/// Convert a letrec expression.
+///
+/// The names are bound directly to the converted lambdas, which are in scope
+/// of all of them, so (unlike the variables of a let) they can't be put in
+/// boxes to be assigned to.
+fn cps_letrec<'a>(
+    bindings: &'a Vector<(String, TypedExpr)>,
+    body: &'a TypedExpr,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    let mut rec_scope = scope.clone();
+    let mut names = vec![];
+    for (name, _exp) in bindings {
+        if ctx.assigned.contains(name) {
+            return Err(CpsError(format!(
+                "Cannot assign to the recursive function {name}."
+            )));
+        }
+        let local_name = generate_var_name();
+        rec_scope = rec_scope.update(name.clone(), Binding::Local(local_name.clone(), false));
+        names.push(local_name);
+    }
+    let cbindings = names
+        .into_iter()
+        .zip(bindings)
+        .map(|(local_name, (name, exp))| match &*exp.kind {
+            ExprKind::Lambda(params, ret_typ, lambda_body) => Ok((
+                local_name,
+                cps_lambda(params, ret_typ, lambda_body, &rec_scope, ctx)?,
+            )),
+            _ => Err(CpsError(format!("Letrec binding {name} is not a lambda."))),
+        })
+        .collect::<Result<Vector<(String, Expr)>, CpsError>>()?;
+    let cbody = cps(body, &rec_scope, ctx, k)?;
+    Ok(Expr::new(ExprKind::Letrec(cbindings, cbody)))
+}
+
+fn cps_fn_app<'a>(
+    func: &'a TypedExpr,
+    args: &'a Vector<TypedExpr>,
+    scope: &Scope,
+    ctx: &'a Context,
+    k: Cont<'a>,
+) -> Result<Expr, CpsError> {
+    // externs are called in direct style
+    if let ExprKind::Id(name) = &*func.kind
+        && let Some(Binding::Extern(_typ)) = scope.get(name)
+    {
+        let func_val = Expr::new(ExprKind::Id(name.clone()));
+        return cps_array(
+            args.iter().collect(),
+            scope,
+            ctx,
+            Box::new(move |vals| bind(Expr::new(ExprKind::FnApp(func_val, vals.into())), k)),
+        );
+    }
+    let ret_typ = match &func.typ {
+        Type::Func(_in_typs, ret_typ) => (**ret_typ).clone(),
+        _ => {
+            return Err(CpsError::from(
+                "Cannot apply a value which is not a function.",
+            ));
+        }
+    };
+    let mut exps = vec![func];
+    exps.extend(args.iter());
+    cps_array(
+        exps,
+        scope,
+        ctx,
+        Box::new(move |mut vals| {
+            let func_val = vals.remove(0);
+            let mut arg_vals: Vector<Expr> = vals.into();
+            arg_vals.push_back(k.reify(&ret_typ, ctx)?);
+            Ok(Expr::new(ExprKind::FnApp(func_val, arg_vals)))
+        }),
+    )
+}
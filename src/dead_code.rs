@@ -0,0 +1,190 @@
+use crate::analysis::{children, free_vars, is_pure};
+use crate::ast_transform::transform_typed_exp_recursive;
+use crate::common::{ExprKind, Prog, TypedExpr};
+use crate::types::{Type, type_contains_var};
+
+use im_rc::Vector;
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug)]
+pub struct DeadCodeError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for DeadCodeError {}
+
+impl From<&str> for DeadCodeError {
+    fn from(message: &str) -> Self {
+        DeadCodeError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for DeadCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "DeadCodeError: {}", self.0)
+    }
+}
+
+/// Remove computations whose results are never used.
+///
+/// This drops let bindings which are never referenced, unpack expressions
+/// whose variable is never referenced, handlers around bodies which cannot
+/// raise an exception, and all but the last expression of a begin, as long as
+/// the expressions being removed are pure (see `analysis::is_pure`).
+/// Expressions which may have side effects, such as set!, function calls, or
+/// operations which may trap, are always kept. A variable which is the target
+/// of a set! counts as being referenced.
+pub fn dce_exp(exp: &TypedExpr) -> Result<TypedExpr, DeadCodeError> {
+    transform_typed_exp_recursive(exp, dce_helper, dce_type_helper)
+}
+
+/// Remove dead code from all expressions within a program, and remove any
+/// functions which cannot be reached from the main expression.
+///
+/// See `dce_exp` for more specific details.
+pub fn dce_prog(prog: &Prog<TypedExpr>) -> Result<Prog<TypedExpr>, DeadCodeError> {
+    let exp = dce_exp(&prog.exp)?;
+    let fns = prog
+        .fns
+        .iter()
+        .map(|(name, func)| Ok((name.clone(), dce_exp(func)?)))
+        .collect::<Result<Vector<(String, TypedExpr)>, DeadCodeError>>()?;
+
+    // functions can only be referred to by name, so everything reachable is
+    // found by following names from the main expression
+    let mut reachable = BTreeSet::new();
+    let mut worklist: Vec<String> = free_vars(&exp).into_iter().collect();
+    while let Some(name) = worklist.pop() {
+        if reachable.contains(&name) {
+            continue;
+        }
+        if let Some((_name, func)) = fns.iter().find(|(fn_name, _func)| *fn_name == name) {
+            worklist.extend(free_vars(func));
+            reachable.insert(name);
+        }
+    }
+
+    Ok(Prog {
+        externs: prog.externs.clone(),
+        globals: prog.globals.clone(),
+        exports: prog.exports.clone(),
+        fns: fns
+            .into_iter()
+            .filter(|(name, _func)| reachable.contains(name))
+            .collect(),
+        exp,
+    })
+}
+
+fn dce_type_helper(_typ: &Type) -> Option<Result<Type, DeadCodeError>> {
+    None
+}
+
+fn dce_helper(exp: &TypedExpr) -> Option<Result<TypedExpr, DeadCodeError>> {
+    match &*exp.kind {
+        ExprKind::Let(bindings, body) => Some(dce_let(bindings, body, &exp.typ)),
+        ExprKind::Unpack(var, package, type_var, body) => {
+            Some(dce_unpack(var, package, *type_var, body, &exp.typ))
+        }
+        ExprKind::Begin(exps) => Some(dce_begin(exps, &exp.typ)),
+        ExprKind::Handle(var, typ, handler, body) => {
+            Some(dce_handle(var, typ, handler, body, &exp.typ))
+        }
+        _ => None,
+    }
+}
+
+fn dce_let(
+    bindings: &Vector<(String, TypedExpr)>,
+    body: &TypedExpr,
+    typ: &Type,
+) -> Result<TypedExpr, DeadCodeError> {
+    let dbody = dce_exp(body)?;
+    let body_vars = free_vars(&dbody);
+    let mut dbindings = Vector::new();
+    for (name, exp) in bindings {
+        let dexp = dce_exp(exp)?;
+        if body_vars.contains(name) || !is_pure(&dexp) {
+            dbindings.push_back((name.clone(), dexp));
+        }
+    }
+    if dbindings.is_empty() {
+        Ok(dbody)
+    } else {
+        Ok(TypedExpr::new(typ.clone(), ExprKind::Let(dbindings, dbody)))
+    }
+}
+
+fn dce_unpack(
+    var: &str,
+    package: &TypedExpr,
+    type_var: u64,
+    body: &TypedExpr,
+    typ: &Type,
+) -> Result<TypedExpr, DeadCodeError> {
+    let dpackage = dce_exp(package)?;
+    let dbody = dce_exp(body)?;
+    // the body can only be moved out of the unpack if it does not depend on
+    // anything it binds
+    if is_pure(&dpackage)
+        && !free_vars(&dbody).contains(var)
+        && !mentions_type_var(&dbody, type_var)
+    {
+        return Ok(dbody);
+    }
+    Ok(TypedExpr::new(
+        typ.clone(),
+        ExprKind::Unpack(var.to_string(), dpackage, type_var, dbody),
+    ))
+}
+
+fn dce_handle(
+    var: &str,
+    exn_typ: &Type,
+    handler: &TypedExpr,
+    body: &TypedExpr,
+    typ: &Type,
+) -> Result<TypedExpr, DeadCodeError> {
+    let dbody = dce_exp(body)?;
+    // a pure body never raises, so the handler can never run
+    if is_pure(&dbody) {
+        return Ok(dbody);
+    }
+    let dhandler = dce_exp(handler)?;
+    Ok(TypedExpr::new(
+        typ.clone(),
+        ExprKind::Handle(var.to_string(), exn_typ.clone(), dhandler, dbody),
+    ))
+}
+
+fn mentions_type_var(exp: &TypedExpr, type_var: u64) -> bool {
+    if type_contains_var(&exp.typ, type_var) {
+        return true;
+    }
+    if let ExprKind::Pack(_val, sub, _exist) = &*exp.kind
+        && type_contains_var(sub, type_var)
+    {
+        return true;
+    }
+    children(exp)
+        .into_iter()
+        .any(|child| mentions_type_var(child, type_var))
+}
+
+fn dce_begin(exps: &Vector<TypedExpr>, typ: &Type) -> Result<TypedExpr, DeadCodeError> {
+    let dexps = exps
+        .iter()
+        .map(dce_exp)
+        .collect::<Result<Vector<TypedExpr>, DeadCodeError>>()?;
+    let last_index = dexps.len().saturating_sub(1);
+    let kept: Vector<TypedExpr> = dexps
+        .into_iter()
+        .enumerate()
+        .filter(|(index, exp)| *index == last_index || !is_pure(exp))
+        .map(|(_index, exp)| exp)
+        .collect();
+    if kept.len() == 1 {
+        Ok(kept[0].clone())
+    } else {
+        Ok(TypedExpr::new(typ.clone(), ExprKind::Begin(kept)))
+    }
+}
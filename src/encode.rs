@@ -0,0 +1,202 @@
+/// This module serializes the WebAssembly modules built by `generate_code`
+/// into their binary format, including the instructions of WebAssembly
+/// proposals which parity-wasm can't represent.
+///
+/// Code generation marks where these instructions go by putting a `nop` in
+/// front of instructions which parity-wasm can represent (`nop` is never
+/// generated otherwise):
+///
+/// - `nop; call f` becomes `return_call f`
+/// - `nop; call_indirect t` becomes `return_call_indirect t`
+/// - `nop; block t` becomes `try t`
+/// - `nop; i32.const tag; else` becomes `catch tag`
+/// - `nop; i32.const tag; unreachable` becomes `throw tag`
+///
+/// The tags of exceptions are declared in a tag section, which is passed on
+/// by code generation in a custom section (see `tag_section`).
+///
+/// A module serialized by parity-wasm alone is still valid if it doesn't
+/// use native exceptions, and behaves the same, except that its tail calls
+/// each use a stack frame.
+use parity_wasm::elements::{CustomSection, FuncBody, Instruction, Module, Section, VarUint32};
+
+#[derive(Clone, Debug)]
+pub struct EncodeError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for EncodeError {}
+
+impl From<&str> for EncodeError {
+    fn from(message: &str) -> Self {
+        EncodeError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EncodeError: {}", self.0)
+    }
+}
+
+impl From<parity_wasm::SerializationError> for EncodeError {
+    fn from(error: parity_wasm::SerializationError) -> Self {
+        EncodeError(error.to_string())
+    }
+}
+
+const CODE_SECTION_ID: u8 = 10;
+const TAG_SECTION_ID: u8 = 13;
+/// The ids of the sections which come after the tag section.
+const AFTER_TAG_SECTION_IDS: [u8; 7] = [6, 7, 8, 9, 10, 11, 12];
+const TAG_SECTION_NAME: &str = "$$TAGS$$";
+const TRY: u8 = 0x06;
+const CATCH: u8 = 0x07;
+const THROW: u8 = 0x08;
+const RETURN_CALL: u8 = 0x12;
+const RETURN_CALL_INDIRECT: u8 = 0x13;
+
+/// Returns a custom section standing in for the tag section, which declares
+/// a tag for each of the given type signatures (of functions which take the
+/// exception's value and return nothing).
+pub fn tag_section(sig_indices: &[u32]) -> Result<CustomSection, EncodeError> {
+    let mut payload = leb128(sig_indices.len())?;
+    for sig_index in sig_indices {
+        // the attribute of every tag is 0 (an exception)
+        payload.push(0);
+        payload.append(&mut leb128(*sig_index as usize)?);
+    }
+    Ok(CustomSection::new(TAG_SECTION_NAME.to_string(), payload))
+}
+
+/// Serialize a module built by `generate_code` into the WebAssembly binary
+/// format.
+pub fn serialize_module(mut module: Module) -> Result<Vec<u8>, EncodeError> {
+    let tags = module
+        .custom_sections()
+        .find(|section| section.name() == TAG_SECTION_NAME)
+        .map(|section| section.payload().to_vec());
+    module.sections_mut().retain(
+        |section| !matches!(section, Section::Custom(custom) if custom.name() == TAG_SECTION_NAME),
+    );
+    let code = match module.code_section() {
+        Some(code) => Some(encode_code_section(code.bodies())?),
+        None => None,
+    };
+    let binary = parity_wasm::serialize(module)?;
+    let mut sections = split_sections(&binary)?;
+    for (id, section) in sections.iter_mut() {
+        if let (CODE_SECTION_ID, Some(code)) = (*id, &code) {
+            *section = code.clone();
+        }
+    }
+    if let Some(tags) = tags {
+        let index = sections
+            .iter()
+            .position(|(id, _section)| AFTER_TAG_SECTION_IDS.contains(id))
+            .unwrap_or(sections.len());
+        sections.insert(index, (TAG_SECTION_ID, tags));
+    }
+    // the magic number and version come before the sections
+    let mut result = binary[..8].to_vec();
+    for (id, section) in sections {
+        result.push(id);
+        result.append(&mut leb128(section.len())?);
+        result.extend(section);
+    }
+    Ok(result)
+}
+
+/// Split the sections of a serialized module into their ids and contents.
+fn split_sections(binary: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, EncodeError> {
+    let mut sections = vec![];
+    let mut pos = 8;
+    while pos < binary.len() {
+        let id = binary[pos];
+        let (size, size_len) = read_leb128(&binary[pos + 1..])?;
+        let start = pos + 1 + size_len;
+        let contents = binary
+            .get(start..start + size)
+            .ok_or("Section extends past the end of the module.")?;
+        sections.push((id, contents.to_vec()));
+        pos = start + size;
+    }
+    Ok(sections)
+}
+
+/// Encode the contents of the code section, which holds the body of each
+/// function.
+fn encode_code_section(bodies: &[FuncBody]) -> Result<Vec<u8>, EncodeError> {
+    let mut section = leb128(bodies.len())?;
+    for body in bodies {
+        let mut encoded = leb128(body.locals().len())?;
+        for local in body.locals() {
+            encoded.append(&mut parity_wasm::serialize(*local)?);
+        }
+        encoded.append(&mut encode_instructions(body.code().elements())?);
+        section.append(&mut leb128(encoded.len())?);
+        section.append(&mut encoded);
+    }
+    Ok(section)
+}
+
+fn encode_instructions(instrs: &[Instruction]) -> Result<Vec<u8>, EncodeError> {
+    let mut encoded = vec![];
+    let mut index = 0;
+    while index < instrs.len() {
+        match (&instrs[index], instrs.get(index + 1)) {
+            (Instruction::Nop, Some(Instruction::I32Const(tag))) => {
+                let opcode = match instrs.get(index + 2) {
+                    Some(Instruction::Else) => CATCH,
+                    Some(Instruction::Unreachable) => THROW,
+                    _ => return Err(EncodeError::from("Unknown marked instruction.")),
+                };
+                encoded.push(opcode);
+                encoded.append(&mut leb128(*tag as usize)?);
+                index += 3;
+            }
+            (Instruction::Nop, Some(block @ Instruction::Block(_))) => {
+                // a block and a try only differ in their opcode
+                let mut block = parity_wasm::serialize(block.clone())?;
+                block[0] = TRY;
+                encoded.append(&mut block);
+                index += 2;
+            }
+            (Instruction::Nop, Some(Instruction::Call(func))) => {
+                encoded.push(RETURN_CALL);
+                encoded.append(&mut leb128(*func as usize)?);
+                index += 2;
+            }
+            (Instruction::Nop, Some(Instruction::CallIndirect(sig, table))) => {
+                encoded.push(RETURN_CALL_INDIRECT);
+                encoded.append(&mut leb128(*sig as usize)?);
+                encoded.push(*table);
+                index += 2;
+            }
+            (instr, _) => {
+                encoded.append(&mut parity_wasm::serialize(instr.clone())?);
+                index += 1;
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+/// Encode an unsigned integer in the variable-length format (LEB128) used by
+/// the WebAssembly binary format.
+fn leb128(value: usize) -> Result<Vec<u8>, EncodeError> {
+    let value = u32::try_from(value).map_err(|_| "Value is too large to encode.")?;
+    Ok(parity_wasm::serialize(VarUint32::from(value))?)
+}
+
+/// Decode an unsigned LEB128 integer, returning it along with the number of
+/// bytes it takes up.
+fn read_leb128(bytes: &[u8]) -> Result<(usize, usize), EncodeError> {
+    let mut value = 0;
+    for (index, byte) in bytes.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+    Err(EncodeError::from("Invalid LEB128 integer."))
+}
@@ -1,11 +1,17 @@
-use crate::common::{BinOp, ExprKind, Prog, TypedExpr};
+use crate::analysis::{children, free_vars};
+use crate::common::{Datum, ExprKind, PrimOp, Prog, TypedExpr};
+use crate::encode::tag_section;
+use crate::type_check::is_displayable;
 use crate::types::Type;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use im_rc::Vector;
 use parity_wasm::builder;
-use parity_wasm::elements::{BlockType, Instruction, Instructions, Local, Module, ValueType};
+use parity_wasm::elements::{
+    BlockType, DataSegment, ExportEntry, External, GlobalEntry, GlobalType, ImportEntry, InitExpr,
+    Instruction, Instructions, Internal, Local, Module, Section, ValueType,
+};
 
 #[derive(Clone, Debug)]
 pub struct CodeGenerateError(String);
@@ -32,19 +38,151 @@ impl std::fmt::Display for CodeGenerateError {
 /// stored in a local variable within the WebAssembly function, and the index
 /// needs to be tracked so that it can be provided to the WebAssembly
 /// local.get function when `a` is referenced within the body.
+///
+/// Only the variables which are in scope are kept in the map, so a variable
+/// which shadows another replaces its entry until the end of its scope.
 type LocalsMap = BTreeMap<String, u32>;
 
 /// A key-value map for finding the index of a WebAssembly function instance
 /// which is associated with a particular identifier (string).
 type FuncsMap = BTreeMap<String, u32>;
 
+/// The kind of environment that a WebAssembly module is generated for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Target {
+    /// A host environment which embeds the module, e.g. using wasmer. The host
+    /// calls the exported `$$MAIN$$` function itself, and provides the extern
+    /// functions imported by the program.
+    #[default]
+    Host,
+    /// A WASI runtime, e.g. `wasmtime prog.wasm`. The module exports a
+    /// `_start` function which runs the main expression and prints its
+    /// result, and `display` and `newline` print to stdout using the
+    /// `fd_write` function imported from WASI.
+    Wasi,
+}
+
+/// How raised exceptions are unwound to their handlers (see
+/// `gen_instr_raise`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExceptionHandling {
+    /// The `try`/`catch`/`throw` instructions of the WebAssembly
+    /// exception-handling proposal. The module builder can't represent
+    /// these, so the module must be serialized with
+    /// `encode::serialize_module`, and run on an engine which supports the
+    /// proposal (wasmer doesn't yet).
+    Native,
+    /// Plain WebAssembly which runs on any engine. A raised exception is
+    /// stored in globals, and each function returns early until a function
+    /// with a matching handler is reached, checking for a pending exception
+    /// after every call.
+    #[default]
+    Fallback,
+}
+
+/// Options controlling how the WebAssembly module for a program is generated.
+#[derive(Clone, Debug)]
+pub struct CodeGenerateOptions {
+    /// The kind of environment the module will run in.
+    pub target: Target,
+    /// Whether running the module consumes fuel from the exported global
+    /// `$$FUEL$$`, trapping once it runs out. Each time a function is called,
+    /// it is charged the number of instructions in its body outside of any
+    /// loop, and each iteration of a loop is charged the number of
+    /// instructions in the loop (see `meter_instructions`).
+    pub metering: bool,
+    /// The size of the module's linear memory, in 64 KiB pages.
+    pub memory_pages: u32,
+    /// How exceptions are compiled, if the program uses them.
+    pub exceptions: ExceptionHandling,
+    /// Whether calls in tail position use the `return_call` instructions of
+    /// the WebAssembly tail-call proposal, so that they don't use a stack
+    /// frame of their own. A CPS converted program calls a continuation at
+    /// the end of every function, so it needs this to loop any number of
+    /// times. The module must then be serialized with
+    /// `encode::serialize_module`, and run on an engine which supports tail
+    /// calls (wasmer doesn't yet).
+    pub tail_calls: bool,
+}
+
+impl Default for CodeGenerateOptions {
+    fn default() -> Self {
+        CodeGenerateOptions {
+            target: Target::Host,
+            metering: false,
+            memory_pages: 32,
+            exceptions: ExceptionHandling::Fallback,
+            tail_calls: false,
+        }
+    }
+}
+
+/// The layout of the memory reserved (at the start of linear memory) for
+/// printing values when targeting WASI:
+/// - bytes 0..8 hold the (pointer, length) pair passed to `fd_write`
+/// - bytes 8..12 hold the number of bytes written by `fd_write`
+/// - bytes 12..32 are a buffer for formatting numbers, filled from the end
+/// - bytes 32..45 hold the constant text printed for other values
+const WASI_DIGITS_END: i32 = 32;
+const WASI_TEXT: &[u8] = b"truefalse() \n";
+const WASI_TRUE: (i32, i32) = (32, 4);
+const WASI_FALSE: (i32, i32) = (36, 5);
+const WASI_OPEN_PAREN: (i32, i32) = (41, 1);
+const WASI_CLOSE_PAREN: (i32, i32) = (42, 1);
+const WASI_SPACE: (i32, i32) = (43, 1);
+const WASI_NEWLINE: (i32, i32) = (44, 1);
+const WASI_RESERVED_SIZE: u32 = 48;
+
+/// The range of ints which are represented as fixnums (see
+/// `construct_int_helpers`).
+pub const FIXNUM_MIN: i64 = -(1 << 30);
+pub const FIXNUM_MAX: i64 = (1 << 30) - 1;
+
+/// The number of bytes of scratch memory used by the int helpers (at the end
+/// of linear memory), which holds two fixnums while they are converted into
+/// bignums.
+const BIGNUM_SCRATCH_SIZE: u32 = 24;
+
+/// A key-value map for finding the index of the WebAssembly global variable
+/// which holds a particular global definition.
+type GlobalsMap = BTreeMap<String, u32>;
+
+/// The WebAssembly types of a function's parameters and of its result.
+type FuncSig = (Vec<ValueType>, ValueType);
+
 /// A key-value map for finding the index of the signature associated with a
 /// particular type signature.
+type SignaturesMap = HashMap<FuncSig, u32>;
+
+/// A key-value map for finding the lambda-lifted function that a local
+/// variable is statically known to hold a closure of.
+///
+/// E.g. after closure conversion and lambda lifting, `(let ((f (lambda ...)))
+/// (f 3))` binds `f` to `(pack (make-tuple func0 ...) ...)`, so any
+/// application of `f` within the body can call `func0` directly instead of
+/// dispatching through the function table with `CallIndirect`.
+type ClosuresMap = BTreeMap<String, String>;
+
+/// A key-value map for finding the address in linear memory at which the
+/// name of an interned symbol is stored.
 ///
-/// All type signatures currently generated by our compiler are only unique
-/// with respect to the number of i32s they take, so this table is fairly
-/// basic.
-type SignaturesMap = BTreeMap<u32, u32>;
+/// Each symbol's name is only placed in memory once, so two symbols are equal
+/// exactly when their addresses are.
+type SymbolsMap = BTreeMap<String, u32>;
+
+/// The globals used to pass a raised exception to its handler (see
+/// `gen_instr_raise`).
+struct ExceptionGlobals {
+    /// The tag of the exception being raised, or 0 if there is none. Each
+    /// type of exception is given its own tag, so that handlers only catch
+    /// exceptions of their own type.
+    tag: u32,
+    /// The value of the exception being raised, widened to 64 bits.
+    value: u32,
+    /// The number of handlers which are currently running their bodies, so
+    /// that exceptions which cannot be caught trap immediately.
+    handlers: u32,
+}
 
 /// Maintains metadata used by code-generating functions.
 ///
@@ -53,53 +191,628 @@ type SignaturesMap = BTreeMap<u32, u32>;
 ///
 /// This struct store information such as:
 /// a) the local variables that are within scope of the expression being
-///    compiled
-/// b) the first free index within WebAssembly's linear memory safe to allocate
-///    new data (tuples, records, etc.) to
+///    compiled, and which local variable slots of the current function are
+///    free to be reused (since the scope of the variable they held has ended)
+/// b) the first free index within WebAssembly's linear memory safe to place
+///    constant data at
+/// c) the local variables which are known to hold closures of a particular
+///    lambda-lifted function
+/// d) the constant data (such as strings and interned symbols) which needs to
+///    be placed in linear memory when the module is instantiated
+/// e) the globals used to allocate tuples, lists and bignums at runtime
+/// f) how exceptions are compiled, the handlers which enclose the expression
+///    being compiled within the current function, and the types of
+///    exceptions which have been given tags
+/// g) whether calls in tail position are marked as tail calls (see
+///    `mark_tail_calls`)
+/// h) the variables which may still be used after the expression being
+///    compiled (see `free_dead_locals`)
 #[derive(Default)]
 pub struct CodeGenerateState {
     locals: LocalsMap,
+    num_params: u32,
+    local_types: Vec<ValueType>,
+    free_locals: Vec<u32>,
     funcs: FuncsMap,
+    globals: GlobalsMap,
     sigs: SignaturesMap,
+    closures: ClosuresMap,
+    num_imports: u32,
     mem_index: u32,
+    data: Vec<(u32, Vec<u8>)>,
+    symbols: SymbolsMap,
+    fuel_global: Option<u32>,
+    heap_global: u32,
+    heap_base_global: u32,
+    native_exceptions: bool,
+    exn_globals: Option<ExceptionGlobals>,
+    exn_tags: Vec<Type>,
+    handler_depths: Vec<u32>,
+    block_depth: u32,
+    ret_type: Option<ValueType>,
+    tail_calls: bool,
+    live: Option<BTreeSet<String>>,
 }
 
 impl CodeGenerateState {
     pub fn new() -> Self {
         CodeGenerateState {
             locals: LocalsMap::new(),
+            num_params: 0,
+            local_types: vec![],
+            free_locals: vec![],
             funcs: FuncsMap::new(),
+            globals: GlobalsMap::new(),
             sigs: SignaturesMap::new(),
+            closures: ClosuresMap::new(),
+            num_imports: 0,
             mem_index: 0,
+            data: vec![],
+            symbols: SymbolsMap::new(),
+            fuel_global: None,
+            heap_global: 0,
+            heap_base_global: 0,
+            native_exceptions: false,
+            exn_globals: None,
+            exn_tags: vec![],
+            handler_depths: vec![],
+            block_depth: 0,
+            ret_type: None,
+            tail_calls: false,
+            live: None,
+        }
+    }
+}
+
+/// Reset the local variables tracked in `state` before compiling a new
+/// function, making the function's parameters the first local variables.
+fn start_function(param_names: &[String], state: &mut CodeGenerateState) {
+    state.locals.clear();
+    state.handler_depths.clear();
+    state.block_depth = 0;
+    state.ret_type = None;
+    // nothing is used after the body of the function
+    state.live = Some(BTreeSet::new());
+    state.free_locals.clear();
+    state.closures.clear();
+    for (index, name) in param_names.iter().enumerate() {
+        state.locals.insert(name.clone(), index as u32);
+    }
+    state.num_params = param_names.len() as u32;
+    state.local_types.clear();
+}
+
+/// The WebAssembly type of the values of a type.
+///
+/// 64-bit numbers are stored as i64s and f64s, while every other value is
+/// either a 32-bit primitive (such as a boolean) or a pointer into linear
+/// memory, and so is stored as an i32.
+pub fn wasm_type(typ: &Type) -> ValueType {
+    match typ {
+        Type::Int64 => ValueType::I64,
+        Type::Float => ValueType::F64,
+        _ => ValueType::I32,
+    }
+}
+
+/// The number of bytes taken up by a value of a type when it is stored in
+/// linear memory (e.g. as a component of a tuple).
+pub fn wasm_size(typ: &Type) -> u32 {
+    match wasm_type(typ) {
+        ValueType::I32 => 4,
+        _ => 8,
+    }
+}
+
+/// Returns the instruction loading a value of a type from linear memory,
+/// given its address on the stack.
+fn load_instr(typ: &Type, offset: u32) -> Instruction {
+    match wasm_type(typ) {
+        ValueType::I64 => Instruction::I64Load(0, offset),
+        ValueType::F64 => Instruction::F64Load(0, offset),
+        _ => Instruction::I32Load(0, offset),
+    }
+}
+
+/// Returns the instruction storing a value of a type into linear memory,
+/// given its address and the value on the stack.
+fn store_instr(typ: &Type, offset: u32) -> Instruction {
+    match wasm_type(typ) {
+        ValueType::I64 => Instruction::I64Store(0, offset),
+        ValueType::F64 => Instruction::F64Store(0, offset),
+        _ => Instruction::I32Store(0, offset),
+    }
+}
+
+/// Returns the instruction pushing a zero of the given WebAssembly type.
+fn zero_instr(value_type: ValueType) -> Instruction {
+    match value_type {
+        ValueType::I64 => Instruction::I64Const(0),
+        ValueType::F64 => Instruction::F64Const(0),
+        _ => Instruction::I32Const(0),
+    }
+}
+
+/// Find a local variable slot of the given WebAssembly type which is not in
+/// use by any live variable.
+///
+/// Slots of variables which are dead (see `free_dead_locals`) or whose scope
+/// has ended are reused before new slots are added to the function. The slot
+/// is not visible to the expression being compiled until it is bound to a
+/// name with `bind_local`.
+fn alloc_local(value_type: ValueType, state: &mut CodeGenerateState) -> u32 {
+    let num_params = state.num_params;
+    let free_position = state.free_locals.iter().rposition(|local_index| {
+        state.local_types[(local_index - num_params) as usize] == value_type
+    });
+    match free_position {
+        Some(position) => state.free_locals.remove(position),
+        None => {
+            // The parameters of a function are the first local variables
+            let local_index = num_params + state.local_types.len() as u32;
+            state.local_types.push(value_type);
+            local_index
+        }
+    }
+}
+
+/// Bring `name` into scope as the local variable slot `local_index`,
+/// returning the slot of the variable it shadows (if any).
+fn bind_local(name: &str, local_index: u32, state: &mut CodeGenerateState) -> Option<u32> {
+    state.locals.insert(name.to_string(), local_index)
+}
+
+/// End the scope of `name`, freeing its slot for later bindings and bringing
+/// the variable it shadowed (if any) back into scope.
+///
+/// Bindings must be removed in the reverse order they were made.
+fn unbind_local(name: &str, previous: Option<u32>, state: &mut CodeGenerateState) {
+    if let Some(local_index) = state.locals.remove(name) {
+        state.free_locals.push(local_index);
+    }
+    if let Some(previous_index) = previous {
+        state.locals.insert(name.to_string(), previous_index);
+    }
+}
+
+/// Free the slots of the local variables in scope which are dead, i.e. which
+/// are never used after the expression just compiled.
+///
+/// Which variables are still live is only known within expressions that
+/// evaluate their subexpressions in sequence (begin, let and if, see
+/// `gen_instr_live`), starting from the body of the function, after which
+/// nothing is live. Any other expression compiles its subexpressions without
+/// this knowledge, so nothing is freed within them.
+fn free_dead_locals(state: &mut CodeGenerateState) {
+    let Some(live) = &state.live else {
+        return;
+    };
+    let dead: Vec<String> = state
+        .locals
+        .iter()
+        .filter(|(name, local_index)| **local_index >= state.num_params && !live.contains(*name))
+        .map(|(name, _local_index)| name.clone())
+        .collect();
+    for name in dead {
+        // removing the binding keeps `unbind_local` from freeing it again
+        if let Some(local_index) = state.locals.remove(&name) {
+            state.free_locals.push(local_index);
         }
     }
 }
 
-/// Generate instructions for a binop (binary operation) expression.
-fn gen_instr_binop(
-    op: BinOp,
-    arg1: &TypedExpr,
-    arg2: &TypedExpr,
+/// Compile a subexpression, followed by code which may use the variables in
+/// `later` (besides those live after the enclosing expression), and free
+/// the slots of the variables which are dead afterwards.
+fn gen_instr_live(
+    exp: &TypedExpr,
+    later: BTreeSet<String>,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let outer = state.live.clone();
+    state.live = outer.clone().map(|mut live| {
+        live.extend(later);
+        live
+    });
+    let instructions = gen_instr(exp, state);
+    free_dead_locals(state);
+    state.live = outer;
+    instructions
+}
+
+/// Returns the variables which occur free in any of the expressions.
+fn free_vars_of<'a>(exps: impl IntoIterator<Item = &'a TypedExpr>) -> BTreeSet<String> {
+    exps.into_iter().flat_map(free_vars).collect()
+}
+
+/// Check whether an identifier refers to a lambda-lifted function (as opposed
+/// to a local variable which shadows it).
+fn is_func_name(sym: &str, state: &CodeGenerateState) -> bool {
+    !state.locals.contains_key(sym)
+        && !state.globals.contains_key(sym)
+        && state.funcs.contains_key(sym)
+}
+
+/// Determine which lambda-lifted function (if any) an expression is
+/// statically known to evaluate a closure of.
+///
+/// Closure conversion always constructs closures as a pack of a tuple, where
+/// the first component is the function and the second is its environment, so
+/// we only need to recognize that shape, or a local variable that was
+/// previously bound to it.
+fn known_closure(exp: &TypedExpr, state: &CodeGenerateState) -> Option<String> {
+    match &*exp.kind {
+        ExprKind::Pack(val, _sub, _exist) => known_closure(val, state),
+        ExprKind::Tuple(exps) if exps.len() == 2 => match &*exps[0].kind {
+            ExprKind::Id(sym) if is_func_name(sym, state) => Some(sym.clone()),
+            _ => None,
+        },
+        ExprKind::Id(sym) => state.closures.get(sym).cloned(),
+        _ => None,
+    }
+}
+
+/// Determine which lambda-lifted function (if any) a function application can
+/// call directly, without going through the function table.
+///
+/// This is the case either when the function is referred to by name, or when
+/// it is the first component of a closure that is statically known (see
+/// `known_closure`).
+fn direct_call_target(func: &TypedExpr, state: &CodeGenerateState) -> Option<String> {
+    match &*func.kind {
+        ExprKind::Id(sym) if is_func_name(sym, state) => Some(sym.clone()),
+        ExprKind::TupleGet(closure, 0) => match &*closure.kind {
+            ExprKind::Id(sym) => state.closures.get(sym).cloned(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Record whether a newly bound local variable holds a known closure,
+/// returning the previous entry so that it can be restored with
+/// `restore_closure` once the variable goes out of scope.
+fn bind_closure(
+    name: &str,
+    known: Option<String>,
+    state: &mut CodeGenerateState,
+) -> Option<String> {
+    match known {
+        Some(func_name) => state.closures.insert(name.to_string(), func_name),
+        None => state.closures.remove(name),
+    }
+}
+
+fn restore_closure(name: &str, previous: Option<String>, state: &mut CodeGenerateState) {
+    match previous {
+        Some(func_name) => state.closures.insert(name.to_string(), func_name),
+        None => state.closures.remove(name),
+    };
+}
+
+/// Generate instructions for a primitive operation.
+///
+/// All arguments are evaluated in order and left on the stack. Operations
+/// which need to refer to an argument more than once pop them off into
+/// temporary locals afterwards, so that the locals cannot be clobbered while
+/// a later argument is being evaluated.
+///
+/// Arithmetic is applied to whichever kind of number its arguments are (see
+/// `PrimOp::instances`), so the instruction used depends on their type.
+/// Arithmetic on ints is handled by `gen_instr_int_op`.
+fn gen_instr_prim(
+    op: PrimOp,
+    args: &Vector<TypedExpr>,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let mut prim_instr = vec![];
+    for arg in args.iter() {
+        prim_instr.append(&mut gen_instr(arg, state)?);
+    }
+    if args[0].typ == Type::Int && op.instances().is_some() {
+        prim_instr.append(&mut gen_instr_int_op(op, state)?);
+        return Ok(prim_instr);
+    }
+    let num_instr = |i64_instr, f64_instr| match wasm_type(&args[0].typ) {
+        ValueType::I64 => i64_instr,
+        _ => f64_instr,
+    };
+    let one_instr = num_instr(
+        Instruction::I64Const(1),
+        Instruction::F64Const(1f64.to_bits()),
+    );
+    match op {
+        PrimOp::Add => prim_instr.push(num_instr(Instruction::I64Add, Instruction::F64Add)),
+        PrimOp::Subtract => prim_instr.push(num_instr(Instruction::I64Sub, Instruction::F64Sub)),
+        PrimOp::Multiply => prim_instr.push(num_instr(Instruction::I64Mul, Instruction::F64Mul)),
+        PrimOp::Divide => prim_instr.push(num_instr(Instruction::I64DivS, Instruction::F64Div)),
+        PrimOp::Remainder => prim_instr.push(Instruction::I64RemS),
+        PrimOp::Modulo => prim_instr.append(&mut gen_instr_modulo(state)),
+        PrimOp::Incr => prim_instr.extend([
+            one_instr,
+            num_instr(Instruction::I64Add, Instruction::F64Add),
+        ]),
+        PrimOp::Decr => prim_instr.extend([
+            one_instr,
+            num_instr(Instruction::I64Sub, Instruction::F64Sub),
+        ]),
+        PrimOp::LessThan => prim_instr.push(num_instr(Instruction::I64LtS, Instruction::F64Lt)),
+        PrimOp::GreaterThan => prim_instr.push(num_instr(Instruction::I64GtS, Instruction::F64Gt)),
+        PrimOp::LessOrEqual => prim_instr.push(num_instr(Instruction::I64LeS, Instruction::F64Le)),
+        PrimOp::GreaterOrEqual => {
+            prim_instr.push(num_instr(Instruction::I64GeS, Instruction::F64Ge))
+        }
+        PrimOp::EqualTo => prim_instr.push(num_instr(Instruction::I64Eq, Instruction::F64Eq)),
+        PrimOp::NotEqualTo => prim_instr.push(num_instr(Instruction::I64Ne, Instruction::F64Ne)),
+        PrimOp::BitAnd => prim_instr.push(Instruction::I64And),
+        PrimOp::BitOr => prim_instr.push(Instruction::I64Or),
+        PrimOp::BitXor => prim_instr.push(Instruction::I64Xor),
+        PrimOp::BitNot => prim_instr.extend([Instruction::I64Const(-1), Instruction::I64Xor]),
+        PrimOp::ShiftLeft => prim_instr.push(Instruction::I64Shl),
+        PrimOp::ShiftRight => prim_instr.push(Instruction::I64ShrS),
+        PrimOp::And => prim_instr.push(Instruction::I32And),
+        PrimOp::Or => prim_instr.push(Instruction::I32Or),
+        PrimOp::Not => prim_instr.push(Instruction::I32Eqz),
+        PrimOp::Concat => return Err(CodeGenerateError::from("Unhandled binop: concat.")),
+        // strings are stored as their length followed by their bytes
+        PrimOp::StrLen => prim_instr.extend([
+            Instruction::I32Load(0, 0),
+            Instruction::I64ExtendUI32,
+            call_runtime("$$int-from-i64", state)?,
+        ]),
+        PrimOp::StringEqual => prim_instr.append(&mut gen_instr_string_equal(state)),
+        PrimOp::SetCar => prim_instr.append(&mut gen_instr_set_field(&args[1].typ, 0, state)),
+        PrimOp::SetCdr => {
+            // the cdr is stored after the car (see `gen_instr_cons`)
+            let car_size = match &args[0].typ {
+                Type::List(base_typ) => wasm_size(base_typ),
+                _ => return Err(CodeGenerateError::from("set-cdr! applied to a non-list.")),
+            };
+            prim_instr.append(&mut gen_instr_set_field(&args[1].typ, car_size, state))
+        }
+        // Ints are converted to and from 64-bit integers by the int helpers,
+        // trapping if they are out of range. Floats are truncated towards
+        // zero, also trapping if they are out of range.
+        PrimOp::IntToInt64 => prim_instr.push(call_runtime("$$int-to-i64", state)?),
+        PrimOp::Int64ToInt => prim_instr.push(call_runtime("$$int-from-i64", state)?),
+        PrimOp::IntToFloat => prim_instr.push(call_runtime("$$int-to-f64", state)?),
+        PrimOp::FloatToInt => prim_instr.extend([
+            Instruction::I64TruncSF64,
+            call_runtime("$$int-from-i64", state)?,
+        ]),
+        PrimOp::Int64ToFloat => prim_instr.push(Instruction::F64ConvertSI64),
+        PrimOp::FloatToInt64 => prim_instr.push(Instruction::I64TruncSF64),
+        // chars are stored as their code point, and symbols as the address of
+        // their interned name (see `gen_instr_symbol`)
+        PrimOp::CharEqual | PrimOp::SymbolEqual => prim_instr.push(Instruction::I32Eq),
+        PrimOp::CharToInt => prim_instr.extend([
+            Instruction::I32Const(1),
+            Instruction::I32Shl,
+            Instruction::I32Const(1),
+            Instruction::I32Or,
+        ]),
+        PrimOp::IntToChar => {
+            prim_instr.push(call_runtime("$$int-to-i32", state)?);
+            prim_instr.append(&mut gen_instr_check_char(state));
+        }
+        PrimOp::SymbolToString => (),
+    }
+    Ok(prim_instr)
+}
+
+/// Generate instructions which trap unless the i32 on the stack is a Unicode
+/// scalar value, i.e. a code point which isn't a surrogate, leaving it on the
+/// stack.
+fn gen_instr_check_char(state: &mut CodeGenerateState) -> Vec<Instruction> {
+    let char_idx = alloc_local(ValueType::I32, state);
+    let check_instr = vec![
+        Instruction::TeeLocal(char_idx),
+        Instruction::I32Const(0x10FFFF),
+        Instruction::I32GtU,
+        // surrogates are in the range 0xD800..=0xDFFF
+        Instruction::GetLocal(char_idx),
+        Instruction::I32Const(0xD800),
+        Instruction::I32Sub,
+        Instruction::I32Const(0x800),
+        Instruction::I32LtU,
+        Instruction::I32Or,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Unreachable,
+        Instruction::End,
+        Instruction::GetLocal(char_idx),
+    ];
+    state.free_locals.push(char_idx);
+    check_instr
+}
+
+/// Generate instructions for an arithmetic, comparison or bitwise operation
+/// on ints, given its arguments on the stack.
+///
+/// Arithmetic and comparisons are performed by the int helpers (see
+/// `construct_int_helpers`), since either argument may be a bignum. The
+/// bitwise operations act on 32-bit integers, so their arguments are
+/// converted to i32s first (trapping if they don't fit), and the result is
+/// converted back into an int.
+fn gen_instr_int_op(
+    op: PrimOp,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
-    let arg1_instr = gen_instr(arg1, state)?;
-    let arg2_instr = gen_instr(arg2, state)?;
+    let compare_instr = |cmp_instr, state: &CodeGenerateState| {
+        Ok(vec![
+            call_runtime("$$int-compare", state)?,
+            Instruction::I32Const(0),
+            cmp_instr,
+        ])
+    };
+    let bitwise_instr = |bit_instr, state: &mut CodeGenerateState| {
+        let rhs_idx = alloc_local(ValueType::I32, state);
+        let instr = vec![
+            call_runtime("$$int-to-i32", state)?,
+            Instruction::SetLocal(rhs_idx),
+            call_runtime("$$int-to-i32", state)?,
+            Instruction::GetLocal(rhs_idx),
+            bit_instr,
+            Instruction::I64ExtendSI32,
+            call_runtime("$$int-from-i64", state)?,
+        ];
+        state.free_locals.push(rhs_idx);
+        Ok(instr)
+    };
     match op {
-        BinOp::Add => Ok([arg1_instr, arg2_instr, vec![Instruction::I32Add]].concat()),
-        BinOp::Subtract => Ok([arg1_instr, arg2_instr, vec![Instruction::I32Sub]].concat()),
-        BinOp::Multiply => Ok([arg1_instr, arg2_instr, vec![Instruction::I32Mul]].concat()),
-        BinOp::Divide => Ok([arg1_instr, arg2_instr, vec![Instruction::I32DivS]].concat()),
-        BinOp::LessThan => Ok([arg1_instr, arg2_instr, vec![Instruction::I32LtS]].concat()),
-        BinOp::GreaterThan => Ok([arg1_instr, arg2_instr, vec![Instruction::I32GtS]].concat()),
-        BinOp::LessOrEqual => Ok([arg1_instr, arg2_instr, vec![Instruction::I32LeS]].concat()),
-        BinOp::GreaterOrEqual => Ok([arg1_instr, arg2_instr, vec![Instruction::I32GeS]].concat()),
-        BinOp::EqualTo => Ok([arg1_instr, arg2_instr, vec![Instruction::I32Eq]].concat()),
-        BinOp::And => Ok([arg1_instr, arg2_instr, vec![Instruction::I32And]].concat()),
-        BinOp::Or => Ok([arg1_instr, arg2_instr, vec![Instruction::I32Or]].concat()),
-        BinOp::Concat => Err(CodeGenerateError::from("Unhandled binop: concat.")),
+        PrimOp::Add => Ok(vec![call_runtime("$$int-add", state)?]),
+        PrimOp::Subtract => Ok(vec![call_runtime("$$int-sub", state)?]),
+        PrimOp::Multiply => Ok(vec![call_runtime("$$int-mul", state)?]),
+        // $$int-divide computes the quotient or the remainder
+        PrimOp::Divide => Ok(vec![
+            Instruction::I32Const(0),
+            call_runtime("$$int-divide", state)?,
+        ]),
+        PrimOp::Remainder => Ok(vec![
+            Instruction::I32Const(1),
+            call_runtime("$$int-divide", state)?,
+        ]),
+        PrimOp::Modulo => Ok(vec![call_runtime("$$int-modulo", state)?]),
+        // 3 is the fixnum representing 1
+        PrimOp::Incr => Ok(vec![
+            Instruction::I32Const(3),
+            call_runtime("$$int-add", state)?,
+        ]),
+        PrimOp::Decr => Ok(vec![
+            Instruction::I32Const(3),
+            call_runtime("$$int-sub", state)?,
+        ]),
+        PrimOp::LessThan => compare_instr(Instruction::I32LtS, state),
+        PrimOp::GreaterThan => compare_instr(Instruction::I32GtS, state),
+        PrimOp::LessOrEqual => compare_instr(Instruction::I32LeS, state),
+        PrimOp::GreaterOrEqual => compare_instr(Instruction::I32GeS, state),
+        PrimOp::EqualTo => compare_instr(Instruction::I32Eq, state),
+        PrimOp::NotEqualTo => compare_instr(Instruction::I32Ne, state),
+        PrimOp::BitAnd => bitwise_instr(Instruction::I32And, state),
+        PrimOp::BitOr => bitwise_instr(Instruction::I32Or, state),
+        PrimOp::BitXor => bitwise_instr(Instruction::I32Xor, state),
+        PrimOp::ShiftLeft => bitwise_instr(Instruction::I32Shl, state),
+        PrimOp::ShiftRight => bitwise_instr(Instruction::I32ShrS, state),
+        PrimOp::BitNot => Ok(vec![
+            call_runtime("$$int-to-i32", state)?,
+            Instruction::I32Const(-1),
+            Instruction::I32Xor,
+            Instruction::I64ExtendSI32,
+            call_runtime("$$int-from-i64", state)?,
+        ]),
+        _ => Err(CodeGenerateError(format!(
+            "Primitive operation {op} cannot be applied to ints."
+        ))),
     }
 }
 
+/// Generate instructions for modulo on int64s, given the two arguments on
+/// the stack.
+///
+/// Unlike remainder, the result of modulo has the same sign as the divisor,
+/// so the remainder is adjusted by the divisor whenever their signs differ.
+fn gen_instr_modulo(state: &mut CodeGenerateState) -> Vec<Instruction> {
+    let divisor_idx = alloc_local(ValueType::I64, state);
+    let rem_idx = alloc_local(ValueType::I64, state);
+    let modulo_instr = vec![
+        Instruction::SetLocal(divisor_idx),
+        Instruction::GetLocal(divisor_idx),
+        Instruction::I64RemS,
+        Instruction::SetLocal(rem_idx),
+        // rem + divisor
+        Instruction::GetLocal(rem_idx),
+        Instruction::GetLocal(divisor_idx),
+        Instruction::I64Add,
+        // rem
+        Instruction::GetLocal(rem_idx),
+        // rem != 0 && (rem ^ divisor) < 0
+        Instruction::GetLocal(rem_idx),
+        Instruction::I64Const(0),
+        Instruction::I64Ne,
+        Instruction::GetLocal(rem_idx),
+        Instruction::GetLocal(divisor_idx),
+        Instruction::I64Xor,
+        Instruction::I64Const(0),
+        Instruction::I64LtS,
+        Instruction::I32And,
+        Instruction::Select,
+    ];
+    state.free_locals.push(rem_idx);
+    state.free_locals.push(divisor_idx);
+    modulo_instr
+}
+
+/// Generate instructions for string-equal?, given the two string addresses
+/// on the stack.
+///
+/// The lengths are compared first, and then the bytes are compared from the
+/// back of the strings until a mismatch is found.
+fn gen_instr_string_equal(state: &mut CodeGenerateState) -> Vec<Instruction> {
+    let str1_idx = alloc_local(ValueType::I32, state);
+    let str2_idx = alloc_local(ValueType::I32, state);
+    let index_idx = alloc_local(ValueType::I32, state);
+    let equal_idx = alloc_local(ValueType::I32, state);
+    let equal_instr = vec![
+        Instruction::SetLocal(str2_idx),
+        Instruction::SetLocal(str1_idx),
+        Instruction::GetLocal(str1_idx),
+        Instruction::I32Load(0, 0),
+        Instruction::TeeLocal(index_idx),
+        Instruction::GetLocal(str2_idx),
+        Instruction::I32Load(0, 0),
+        Instruction::I32Eq,
+        Instruction::SetLocal(equal_idx),
+        Instruction::Block(BlockType::NoResult),
+        Instruction::Loop(BlockType::NoResult),
+        // stop once a mismatch is found or all bytes have been compared
+        Instruction::GetLocal(equal_idx),
+        Instruction::I32Eqz,
+        Instruction::BrIf(1),
+        Instruction::GetLocal(index_idx),
+        Instruction::I32Eqz,
+        Instruction::BrIf(1),
+        Instruction::GetLocal(index_idx),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::SetLocal(index_idx),
+        // the bytes start after the 4-byte length
+        Instruction::GetLocal(str1_idx),
+        Instruction::GetLocal(index_idx),
+        Instruction::I32Add,
+        Instruction::I32Load8U(0, 4),
+        Instruction::GetLocal(str2_idx),
+        Instruction::GetLocal(index_idx),
+        Instruction::I32Add,
+        Instruction::I32Load8U(0, 4),
+        Instruction::I32Eq,
+        Instruction::SetLocal(equal_idx),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::GetLocal(equal_idx),
+    ];
+    state.free_locals.push(equal_idx);
+    state.free_locals.push(index_idx);
+    state.free_locals.push(str2_idx);
+    state.free_locals.push(str1_idx);
+    equal_instr
+}
+
+/// Generate instructions for set-car!, set-cdr! or tuple-set!, given the cons
+/// cell or tuple and the new value (of type `typ`) on the stack.
+///
+/// The new value is left on the stack, so that the expression evaluates to
+/// it in the same way as set!.
+fn gen_instr_set_field(typ: &Type, offset: u32, state: &mut CodeGenerateState) -> Vec<Instruction> {
+    let value_idx = alloc_local(wasm_type(typ), state);
+    let set_instr = vec![
+        Instruction::TeeLocal(value_idx),
+        store_instr(typ, offset),
+        Instruction::GetLocal(value_idx),
+    ];
+    state.free_locals.push(value_idx);
+    set_instr
+}
+
 /// Generate instructions for an if expression.
 fn gen_instr_if(
     pred: &TypedExpr,
@@ -107,18 +820,18 @@ fn gen_instr_if(
     alt: &TypedExpr,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
-    let pred_instr = gen_instr(pred, state)?;
-    let cons_instr = gen_instr(cons, state)?;
+    let pred_instr = gen_instr_live(pred, free_vars_of([cons, alt]), state)?;
+    state.block_depth += 1;
+    // variables freed within the consequent must not be used by the
+    // alternate, even though only one of them runs
+    let cons_instr = gen_instr_live(cons, free_vars(alt), state)?;
     let alt_instr = gen_instr(alt, state)?;
+    state.block_depth -= 1;
 
     // In WebAssembly, if-expressions must be given a type annotation of the
     // type of the block, so that during validation, the values produced
     // in each branch can be verified to have the same type in both cases.
-    //
-    // Since all values in our language are all 4 bytes in size (either
-    // primtivies or pointers to the linear memory), we declare the block
-    // type as I32.
-    let block_type = BlockType::Value(ValueType::I32);
+    let block_type = BlockType::Value(wasm_type(&cons.typ));
     Ok([
         pred_instr,
         vec![Instruction::If(block_type)],
@@ -136,16 +849,25 @@ fn gen_instr_if(
 /// WebAssembly local variable to keep track of the value, for whichever
 /// function this let expression is being compiled into. This gets tracked
 /// within `state.locals`.
+///
+/// All of the bound expressions are evaluated before any of the names come
+/// into scope. Each local variable is freed once it is dead (which may be
+/// before the body, if the body never uses it) or once the body has been
+/// compiled, so that later let expressions can reuse it.
 fn gen_instr_let(
     bindings: &Vector<(String, TypedExpr)>,
     body: &TypedExpr,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
     let mut let_instr: Vec<Instruction> = vec![];
-    for pair in bindings {
-        let mut exp_instr = gen_instr(&pair.1, state)?;
-        let local_index = state.locals.len() as u32;
-        state.locals.insert(pair.0.clone(), local_index);
+    let mut slots: Vec<(u32, Option<String>)> = vec![];
+    for (index, pair) in bindings.iter().enumerate() {
+        let later_exps = bindings.iter().skip(index + 1).map(|pair| &pair.1);
+        let later = free_vars_of(later_exps.chain([body]));
+        let mut exp_instr = gen_instr_live(&pair.1, later, state)?;
+        let known = known_closure(&pair.1, state);
+        let local_index = alloc_local(wasm_type(&pair.1.typ), state);
+        slots.push((local_index, known));
         let_instr.append(&mut exp_instr);
         // SetLocal will pop the current value from the stack and store it
         // in nth local variable, where n is the index passed in.
@@ -156,8 +878,24 @@ fn gen_instr_let(
         // be from 0 to n+k-1.
         let_instr.push(Instruction::SetLocal(local_index));
     }
+    let mut previous_bindings: Vec<(String, Option<u32>, Option<String>)> = vec![];
+    for (pair, (local_index, known)) in bindings.iter().zip(slots) {
+        let previous_local = bind_local(&pair.0, local_index, state);
+        let previous_closure = bind_closure(&pair.0, known, state);
+        previous_bindings.push((pair.0.clone(), previous_local, previous_closure));
+    }
+    let outer = state.live.clone();
+    if let Some(live) = &mut state.live {
+        live.extend(free_vars(body));
+    }
+    free_dead_locals(state);
+    state.live = outer;
     let mut body_instr = gen_instr(body, state)?;
     let_instr.append(&mut body_instr);
+    for (name, previous_local, previous_closure) in previous_bindings.into_iter().rev() {
+        restore_closure(&name, previous_closure, state);
+        unbind_local(&name, previous_local, state);
+    }
     Ok(let_instr)
 }
 
@@ -175,8 +913,9 @@ fn gen_instr_begin(
     let first_exps = exps.iter().take(exps.len() - 1);
     let last_exp = exps.last().unwrap();
     let mut begin_instr: Vec<Instruction> = vec![];
-    for exp in first_exps {
-        let mut exp_instr = gen_instr(exp, state)?;
+    for (index, exp) in first_exps.enumerate() {
+        let later = free_vars_of(exps.iter().skip(index + 1));
+        let mut exp_instr = gen_instr_live(exp, later, state)?;
         begin_instr.append(&mut exp_instr);
         begin_instr.push(Instruction::Drop);
     }
@@ -192,16 +931,35 @@ fn gen_instr_set(
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
     // set! only semantically makes sense if the symbol provided is the name
-    // of a function parameter or a local variable (created by let). So we
-    // must look the WebAssembly local index corresponding to the name.
-    let local_idx = *(state
-        .locals
-        .get(sym)
-        .ok_or("Symbol not found within local scope.")?);
+    // of a function parameter, a local variable (created by let), or a global
+    // definition. So we must look the WebAssembly local (or global) index
+    // corresponding to the name.
     let mut set_instr = gen_instr(exp, state)?;
-    // WebAssembly's TeeLocal instruction will put a copy of the local
-    // variable's value on top of the stack.
-    set_instr.push(Instruction::TeeLocal(local_idx));
+    match (state.locals.get(sym), state.globals.get(sym)) {
+        (Some(local_idx), _) => {
+            // WebAssembly's TeeLocal instruction will put a copy of the local
+            // variable's value on top of the stack.
+            set_instr.push(Instruction::TeeLocal(*local_idx));
+        }
+        (None, Some(global_idx)) => {
+            set_instr.push(Instruction::SetGlobal(*global_idx));
+            set_instr.push(Instruction::GetGlobal(*global_idx));
+        }
+        (None, None) => {
+            return Err(CodeGenerateError::from(
+                "Symbol not found within local or global scope.",
+            ));
+        }
+    }
+    // The closures map is only valid within the function being generated
+    // (see `start_function`), and the code generated for a function never
+    // jumps backwards: loops (named let and do) are lowered to calls of
+    // recursive functions, and the WebAssembly loops generated for primitive
+    // operations (such as string-equal? or displaying a list) never contain
+    // a set!. So any code generated after this point is either evaluated
+    // after the assignment or on a separate branch, and it is sufficient (if
+    // conservative) to forget what the variable held.
+    state.closures.remove(sym);
     Ok(set_instr)
 }
 
@@ -211,19 +969,25 @@ fn gen_instr_set(
 /// linear memory in order (i.e. contiguously) so that they can be easily
 /// retrieved. The pointer for the head of the tuple is left on the top of the
 /// stack, so that the nth component of the tuple can be retrieved by loading
-/// from the linear memory at mem[head_index + 4 * n].
+/// from the linear memory at mem[head_index + offset], where the offset is
+/// the total size of the components before it (see `tuple_offset`). Numbers
+/// of type int64 and float take up 8 bytes, and all other values 4 bytes.
+///
+/// Tuples are allocated at runtime by `$$alloc` (see `construct_alloc_helper`),
+/// so that each evaluation of a make-tuple expression creates a new tuple.
 ///
 /// In the example below, a tuple with three parts (A, B, C) is constructed,
 /// where A and B are two arbitrary values that require memory allocation, and
-/// C is just a number. The instructions for calculating A, B, and C are
-/// generated, which results in A and B getting allocated into memory at 0 and
-/// 4 respectively. Then, the values left on the stack (a pointer to A (0),
-/// a pointer to B (4), and the value of C (C)) are stored in the linear memory
-/// (at indices 12, 16, and 20), with the first index put on the stack.
+/// C is just a number. The tuple is allocated first (at 12), and then the
+/// instructions for calculating A, B, and C are generated, which results in A
+/// and B getting allocated into memory below it at 8 and 0 respectively. Each
+/// value is stored into the tuple as soon as it is calculated (a pointer to A
+/// (8), a pointer to B (0), and the value of C (C)), and then the pointer to
+/// the tuple is put on the stack.
 ///
 /// Memory:
 /// +---+---+---+---+---+---+
-/// | A | B     | 0 | 4 | C |
+/// | B     | A | 8 | 0 | C |
 /// +---+---+---+---+---+---+
 /// 0   4   8   12  16  20  24
 /// Stack:
@@ -245,50 +1009,29 @@ fn gen_instr_tuple(
     exprs: &Vector<TypedExpr>,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
-    let mut tuple_instr: Vec<Instruction> = vec![];
-
-    // First, calculate and construct all of the components of the tuple (making
-    // any calculations and memory allocations as necessary). This will leave n
-    // values on top of the stack, where n is the tuple size. Each value will
-    // be either a primitive (if it was a boolean or number), or a pointer
-    // (represented as an I32.const).
-    //
-    // While doing so, we keep track of the types of each value so that we
-    // can allocate the parts of the tuple in reverse order (since the last
-    // component of the tuple will be on the top of the stack, etc.).
-    let mut tuple_part_wasm_types: Vec<ValueType> = vec![];
-    let mut tuple_wasm_size: u32 = 0;
+    // The tuple is allocated before any of its components are calculated,
+    // since they may be allocated themselves. Each component is then stored
+    // at its offset from the pointer to the tuple.
+    let tuple_wasm_size: u32 = exprs.iter().map(|exp| wasm_size(&exp.typ)).sum();
+    let ptr = alloc_local(ValueType::I32, state);
+    let mut tuple_instr = vec![
+        Instruction::I32Const(tuple_wasm_size.max(4) as i32),
+        call_runtime("$$alloc", state)?,
+        Instruction::SetLocal(ptr),
+    ];
 
+    let mut offset = 0;
     for exp in exprs {
-        // This extra instruction is needed so that after calculating the
-        // first component of the tuple, the stack will have I32.const 0 and
-        // the component value on the stack. Both arguments are needed
-        // for the value to be stored into linear memory (with I32Store or
-        // I64Store).
-        tuple_instr.push(Instruction::I32Const(0));
-
+        tuple_instr.push(Instruction::GetLocal(ptr));
         let mut exp_instr = gen_instr(exp, state)?;
-        tuple_wasm_size += 4; // all tuple components take up 4 bytes in memory
         tuple_instr.append(&mut exp_instr);
-        tuple_part_wasm_types.push(ValueType::I32);
+        tuple_instr.push(store_instr(&exp.typ, offset));
+        offset += wasm_size(&exp.typ);
     }
 
-    // Allocate the stack values into linear memory by processing through them
-    // in reverse order.
-    //
-    // We also allocate the values backwards in memory, so we start bringing
-    // state.mem_index all the way forward, then iterate through backwards,
-    // and reset state.mem_index to the first free index once we are finished.
-    let tuple_head_idx = state.mem_index;
-    state.mem_index += tuple_wasm_size;
-    for _ in 0..tuple_part_wasm_types.len() {
-        state.mem_index -= 4;
-        tuple_instr.push(Instruction::I32Store(0, state.mem_index));
-    }
-    state.mem_index = tuple_head_idx + tuple_wasm_size;
-
-    // Finally, leave the index for the head of the tuple on top of the stack.
-    tuple_instr.push(Instruction::I32Const(tuple_head_idx as i32));
+    // Finally, leave the pointer to the head of the tuple on top of the stack.
+    tuple_instr.push(Instruction::GetLocal(ptr));
+    state.free_locals.push(ptr);
     Ok(tuple_instr)
 }
 
@@ -304,10 +1047,10 @@ fn gen_instr_tuple_get(
     let tuple_instr = gen_instr(tuple, state)?;
     let mut tuple_get_instr: Vec<Instruction> = vec![];
     match &tuple.typ {
-        // Since all types take up the same size (4 bytes) in our compiler,
-        // we do not need to look at the specific types of the components
-        // of the tuple.
-        Type::Tuple(_inner_types) => tuple_get_instr.push(Instruction::I32Load(0, 4 * key)),
+        Type::Tuple(inner_types) => tuple_get_instr.push(load_instr(
+            &inner_types[key as usize],
+            tuple_offset(inner_types, key),
+        )),
         _ => {
             return Err(CodeGenerateError::from(
                 "get_instr_tuple_get called with non-tuple expression.",
@@ -317,47 +1060,74 @@ fn gen_instr_tuple_get(
     Ok([tuple_instr, tuple_get_instr].concat())
 }
 
+/// Generate instructions for a tuple-set! expression (which record
+/// elimination produces from record-set!), storing the new value over the
+/// component of the tuple in place.
+fn gen_instr_tuple_set(
+    tuple: &TypedExpr,
+    key: u32,
+    val: &TypedExpr,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let offset = match &tuple.typ {
+        Type::Tuple(inner_types) => tuple_offset(inner_types, key),
+        _ => {
+            return Err(CodeGenerateError::from(
+                "gen_instr_tuple_set called with non-tuple expression.",
+            ));
+        }
+    };
+    let tuple_instr = gen_instr(tuple, state)?;
+    let val_instr = gen_instr(val, state)?;
+    let set_instr = gen_instr_set_field(&val.typ, offset, state);
+    Ok([tuple_instr, val_instr, set_instr].concat())
+}
+
+/// Returns the offset of a component of a tuple from the start of the tuple
+/// in linear memory, given the types of its components.
+pub fn tuple_offset(types: &Vector<Type>, key: u32) -> u32 {
+    types.iter().take(key as usize).map(wasm_size).sum()
+}
+
 /// Generate instructions for a cons expression.
 ///
 /// A List expression is stored as simply a pair of values: a car (sometimes
-/// a pointer), and a cdr (always a pointer).
+/// a pointer), and a cdr (always a pointer), which is stored right after the
+/// car (so at an offset of 4 or 8 bytes, depending on the type of the car).
 ///
-/// Our strategy is to first generate the instructions for the car and cdr of
-/// the expression (leaving two values, most likely pointers, on the stack),
-/// and then store the two stack values in memory, leaving an index to the
-/// cons pair on the stack.
+/// Our strategy is to first allocate the cons pair at runtime (see
+/// `construct_alloc_helper`), and then generate the instructions for the car
+/// and cdr of the expression, storing each value into the pair as soon as it
+/// is calculated, and leaving the address of the pair on the stack.
 fn gen_instr_cons(
     car: &TypedExpr,
     cdr: &TypedExpr,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
-    let mut cons_instr: Vec<Instruction> = vec![];
-
-    // This extra instruction is needed so that after calculating the
-    // car of the cons, the stack will have I32.const 0 and the car value
-    // on the stack. Both arguments are needed for the value to be
-    // stored into linear memory (with I32Store or I64Store).
-    cons_instr.push(Instruction::I32Const(0));
+    // In order, we:
+    // - allocate the cons pair, keeping its address in a local
+    // - store car value at mem[cons_idx + 0]
+    // - store cdr value at mem[cons_idx + car_size]
+    // - push cons_idx on the stack
+    let car_size = wasm_size(&car.typ);
+    let ptr = alloc_local(ValueType::I32, state);
+    let mut cons_instr = vec![
+        Instruction::I32Const((car_size + 4) as i32),
+        call_runtime("$$alloc", state)?,
+        Instruction::SetLocal(ptr),
+        Instruction::GetLocal(ptr),
+    ];
     let mut car_instr = gen_instr(car, state)?;
     cons_instr.append(&mut car_instr);
+    cons_instr.push(store_instr(&car.typ, 0));
 
-    // See comment above for why this I32.const instruction is needed.
-    cons_instr.push(Instruction::I32Const(0));
+    cons_instr.push(Instruction::GetLocal(ptr));
     let mut cdr_instr = gen_instr(cdr, state)?;
     cons_instr.append(&mut cdr_instr);
+    cons_instr.push(Instruction::I32Store(0, car_size));
 
-    // state.mem_index, by assumption, contains the first address of free space
-    // in the WebAssembly linear memory. In order, we:
-    // - store cdr value at mem[cons_idx + 4]
-    // - store car value at mem[cons_idx + 0]
-    // - push cons_idx in the store
-    // - update the next free index from state.mem_index to state.mem_index + 8
-    let cons_idx = state.mem_index;
-    cons_instr.push(Instruction::I32Store(0, state.mem_index + 4));
-    cons_instr.push(Instruction::I32Store(0, state.mem_index));
-    cons_instr.push(Instruction::I32Const(cons_idx as i32));
-    state.mem_index += 8;
-
+    cons_instr.push(Instruction::GetLocal(ptr));
+    state.free_locals.push(ptr);
     Ok(cons_instr)
 }
 
@@ -374,7 +1144,10 @@ fn gen_instr_car(
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
     let mut car_instr = gen_instr(cons, state)?;
-    car_instr.push(Instruction::I32Load(0, 0));
+    match &cons.typ {
+        Type::List(base_typ) => car_instr.push(load_instr(base_typ, 0)),
+        _ => return Err(CodeGenerateError::from("car applied to a non-list.")),
+    }
     Ok(car_instr)
 }
 
@@ -382,7 +1155,7 @@ fn gen_instr_car(
 ///
 /// Evaluating the cons expression will leave a 32-bit pointer (the address of
 /// the cons structure in WebAssembly linear memory). To obtain the second
-/// component, we access the pointer contents with a 4-byte offset.
+/// component, we access the pointer contents offset by the size of the car.
 ///
 /// See `gen_instr_cons` for more information about how cons expressions
 /// are stored in linear memory.
@@ -391,7 +1164,10 @@ fn gen_instr_cdr(
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
     let mut cdr_instr = gen_instr(cons, state)?;
-    cdr_instr.push(Instruction::I32Load(0, 4));
+    match &cons.typ {
+        Type::List(base_typ) => cdr_instr.push(Instruction::I32Load(0, wasm_size(base_typ))),
+        _ => return Err(CodeGenerateError::from("cdr applied to a non-list.")),
+    }
     Ok(cdr_instr)
 }
 
@@ -408,6 +1184,224 @@ fn gen_instr_null(
     Ok(vec![Instruction::I32Const(-1)])
 }
 
+/// Generate instructions for a string literal.
+///
+/// Strings are stored in linear memory as their length in bytes (4 bytes),
+/// followed by their UTF-8 contents, and the string's value is the address of
+/// its length. Since strings are immutable, the contents are placed in memory
+/// by a data segment when the module is instantiated.
+fn gen_instr_str(val: &str, state: &mut CodeGenerateState) -> Vec<Instruction> {
+    vec![Instruction::I32Const(static_str(val, state) as i32)]
+}
+
+/// Place a string in memory with a data segment, returning its address.
+fn static_str(val: &str, state: &mut CodeGenerateState) -> u32 {
+    let str_idx = state.mem_index;
+    let mut bytes = (val.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(val.as_bytes());
+    // keep the next allocation aligned to 4 bytes
+    state.mem_index += (bytes.len() as u32).div_ceil(4) * 4;
+    state.data.push((str_idx, bytes));
+    str_idx
+}
+
+/// Generate instructions for a symbol literal.
+///
+/// A symbol is represented by the address of its name, which is stored the
+/// same way as a string. Names are interned, so every occurrence of a symbol
+/// in the program refers to the same address.
+fn gen_instr_symbol(val: &str, state: &mut CodeGenerateState) -> Vec<Instruction> {
+    vec![Instruction::I32Const(static_symbol(val, state) as i32)]
+}
+
+/// Returns the address of a symbol's interned name, placing the name in
+/// memory if the symbol hasn't been used before.
+fn static_symbol(val: &str, state: &mut CodeGenerateState) -> u32 {
+    if let Some(symbol_idx) = state.symbols.get(val) {
+        return *symbol_idx;
+    }
+    let symbol_idx = static_str(val, state);
+    state.symbols.insert(val.to_string(), symbol_idx);
+    symbol_idx
+}
+
+/// Generate instructions for an int literal.
+///
+/// Ints which are too large to be fixnums are bignums, which (like strings)
+/// are placed in memory by a data segment, so no allocation is needed at
+/// runtime. See `construct_int_helpers` for how ints are represented.
+fn gen_instr_num(val: i32, state: &mut CodeGenerateState) -> Vec<Instruction> {
+    vec![Instruction::I32Const(static_int(val, state))]
+}
+
+/// Returns the value representing an int, placing it in memory if it is a
+/// bignum.
+fn static_int(val: i32, state: &mut CodeGenerateState) -> i32 {
+    if (FIXNUM_MIN..=FIXNUM_MAX).contains(&(val as i64)) {
+        return (val << 1) | 1;
+    }
+    let bignum_idx = state.mem_index;
+    let mut bytes = 1u32.to_le_bytes().to_vec();
+    bytes.extend(((val < 0) as u32).to_le_bytes());
+    bytes.extend(val.unsigned_abs().to_le_bytes());
+    state.mem_index += bytes.len() as u32;
+    state.data.push((bignum_idx, bytes));
+    bignum_idx as i32
+}
+
+/// Generate instructions for a quoted list.
+///
+/// Quoted lists are constants, so their cons cells (see `gen_instr_cons`) are
+/// placed in memory by data segments, along with any strings or bignums they
+/// contain, instead of being stored when the list is evaluated.
+fn gen_instr_quote(data: &Vector<Datum>, state: &mut CodeGenerateState) -> Vec<Instruction> {
+    vec![Instruction::I32Const(static_list(data, state))]
+}
+
+/// Place the cons cells of a quoted list in memory, returning the address
+/// of the first cell.
+fn static_list(data: &Vector<Datum>, state: &mut CodeGenerateState) -> i32 {
+    // (null 'typ) is represented as -1
+    let mut list_idx: i32 = -1;
+    for datum in data.iter().rev() {
+        let mut bytes = static_datum(datum, state);
+        bytes.extend(list_idx.to_le_bytes());
+        let cons_idx = state.mem_index;
+        state.mem_index += bytes.len() as u32;
+        state.data.push((cons_idx, bytes));
+        list_idx = cons_idx as i32;
+    }
+    list_idx
+}
+
+/// Returns the bytes of an element of a quoted list, as it is stored in the
+/// car of a cons cell.
+fn static_datum(datum: &Datum, state: &mut CodeGenerateState) -> Vec<u8> {
+    match datum {
+        Datum::Num(x) => static_int(*x, state).to_le_bytes().to_vec(),
+        Datum::Int64(x) => x.to_le_bytes().to_vec(),
+        Datum::Float(x) => x.to_bits().to_le_bytes().to_vec(),
+        Datum::Bool(x) => (*x as i32).to_le_bytes().to_vec(),
+        Datum::Str(x) => static_str(x, state).to_le_bytes().to_vec(),
+        Datum::Char(x) => (*x as u32).to_le_bytes().to_vec(),
+        Datum::Symbol(x) => static_symbol(x, state).to_le_bytes().to_vec(),
+        Datum::List(data) => static_list(data, state).to_le_bytes().to_vec(),
+    }
+}
+
+/// Returns the instruction calling one of the helper functions which are
+/// added to the module when targeting WASI (see `construct_wasi_helpers`).
+fn call_helper(name: &str, state: &CodeGenerateState) -> Result<Instruction, CodeGenerateError> {
+    let table_idx = *state
+        .funcs
+        .get(name)
+        .ok_or("Printing is only supported when targeting WASI.")?;
+    Ok(Instruction::Call(state.num_imports + table_idx))
+}
+
+/// Returns the instruction calling one of the int helpers, which are added
+/// to every module generated from a program (see `construct_int_helpers`).
+fn call_runtime(name: &str, state: &CodeGenerateState) -> Result<Instruction, CodeGenerateError> {
+    let table_idx = *state
+        .funcs
+        .get(name)
+        .ok_or("Ints can only be used in modules which include the int helpers.")?;
+    Ok(Instruction::Call(state.num_imports + table_idx))
+}
+
+/// Generate instructions which print some constant text from the memory
+/// reserved for printing, leaving the result of `fd_write` on the stack.
+fn gen_instr_write_text(
+    (text_idx, len): (i32, i32),
+    state: &CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    Ok(vec![
+        Instruction::I32Const(text_idx),
+        Instruction::I32Const(len),
+        call_helper("$$write", state)?,
+    ])
+}
+
+/// Generate instructions which print the value of type `typ` on top of the
+/// stack, replacing it with the result of `fd_write`.
+///
+/// Numbers, booleans, strings, chars and symbols are printed by helper
+/// functions, while lists are printed with a loop over their elements, e.g.
+/// `(1 2 3)`.
+fn gen_instr_print(
+    typ: &Type,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    match typ {
+        Type::Int => Ok(vec![call_helper("$$display-int", state)?]),
+        Type::Int64 => Ok(vec![call_helper("$$display-int64", state)?]),
+        Type::Bool => Ok(vec![call_helper("$$display-bool", state)?]),
+        Type::Str => Ok(vec![call_helper("$$display-str", state)?]),
+        Type::Char => Ok(vec![call_helper("$$display-char", state)?]),
+        // symbols are printed as their name, without a quote
+        Type::Symbol => Ok(vec![call_helper("$$display-str", state)?]),
+        Type::List(base_typ) => {
+            let list_idx = alloc_local(ValueType::I32, state);
+            let mut print_instr = vec![Instruction::SetLocal(list_idx)];
+            print_instr.append(&mut gen_instr_write_text(WASI_OPEN_PAREN, state)?);
+            print_instr.push(Instruction::Drop);
+            print_instr.push(Instruction::Block(BlockType::NoResult));
+            print_instr.push(Instruction::Loop(BlockType::NoResult));
+            // stop once the end of the list is reached
+            print_instr.push(Instruction::GetLocal(list_idx));
+            print_instr.push(Instruction::I32Const(-1));
+            print_instr.push(Instruction::I32Eq);
+            print_instr.push(Instruction::BrIf(1));
+            print_instr.push(Instruction::GetLocal(list_idx));
+            print_instr.push(load_instr(base_typ, 0));
+            print_instr.append(&mut gen_instr_print(base_typ, state)?);
+            print_instr.push(Instruction::Drop);
+            // separate elements by spaces
+            print_instr.push(Instruction::GetLocal(list_idx));
+            print_instr.push(Instruction::I32Load(0, wasm_size(base_typ)));
+            print_instr.push(Instruction::TeeLocal(list_idx));
+            print_instr.push(Instruction::I32Const(-1));
+            print_instr.push(Instruction::I32Ne);
+            print_instr.push(Instruction::If(BlockType::NoResult));
+            print_instr.append(&mut gen_instr_write_text(WASI_SPACE, state)?);
+            print_instr.push(Instruction::Drop);
+            print_instr.push(Instruction::End);
+            print_instr.push(Instruction::Br(0));
+            print_instr.push(Instruction::End);
+            print_instr.push(Instruction::End);
+            print_instr.append(&mut gen_instr_write_text(WASI_CLOSE_PAREN, state)?);
+            state.free_locals.push(list_idx);
+            Ok(print_instr)
+        }
+        _ => Err(CodeGenerateError(format!(
+            "Values of type {typ} cannot be displayed."
+        ))),
+    }
+}
+
+/// Generate instructions for a display expression.
+///
+/// Since the language has no unit type, display (and newline) evaluate to 0
+/// (which is represented by the fixnum 1).
+fn gen_instr_display(
+    exp: &TypedExpr,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let mut display_instr = gen_instr(exp, state)?;
+    display_instr.append(&mut gen_instr_print(&exp.typ, state)?);
+    display_instr.push(Instruction::Drop);
+    display_instr.push(Instruction::I32Const(1));
+    Ok(display_instr)
+}
+
+/// Generate instructions for a newline expression.
+fn gen_instr_newline(state: &mut CodeGenerateState) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let mut newline_instr = gen_instr_write_text(WASI_NEWLINE, state)?;
+    newline_instr.push(Instruction::Drop);
+    newline_instr.push(Instruction::I32Const(1));
+    Ok(newline_instr)
+}
+
 /// Generate instructions for a null? expression.
 ///
 /// Our current implementation will allow non-List type expressions to appear
@@ -446,12 +1440,21 @@ fn gen_instr_is_null(
 /// almost always be a tuple (since pack expressions are solely generated
 /// through closure conversion). This is safe since the values stored inside
 /// the pack are all calculated (constant in some sense) and type checked.
+///
+/// Since the code using a package does not know what its abstract type
+/// stands for, values of the abstract type must all be represented in the
+/// same way, as 4-byte values.
 fn gen_instr_pack(
     val: &TypedExpr,
-    _sub: &Type,
+    sub: &Type,
     _exist: &Type,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
+    if wasm_type(sub) != ValueType::I32 {
+        return Err(CodeGenerateError(format!(
+            "Values of type {sub} cannot be packed into an existential type."
+        )));
+    }
     gen_instr(val, state)
 }
 
@@ -472,11 +1475,15 @@ fn gen_instr_unpack(
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
     let mut let_instr: Vec<Instruction> = vec![];
     let mut exp_instr = gen_instr(package, state)?;
-    let local_index = state.locals.len() as u32;
-    state.locals.insert(var.to_string(), local_index);
+    let known = known_closure(package, state);
+    let local_index = alloc_local(wasm_type(&package.typ), state);
+    let previous_local = bind_local(var, local_index, state);
+    let previous_closure = bind_closure(var, known, state);
     let_instr.append(&mut exp_instr);
     let_instr.push(Instruction::SetLocal(local_index));
     let body_instr = gen_instr(body, state)?;
+    restore_closure(var, previous_closure, state);
+    unbind_local(var, previous_local, state);
 
     Ok([let_instr, body_instr].concat())
 }
@@ -490,6 +1497,10 @@ fn gen_instr_unpack(
 /// our arguments onto the stack followed by the function index, and then use
 /// WebAssembly's CallIndirect to call the appropriate function in our table,
 /// consuming all of the arguments we provided.
+///
+/// If the function being applied is statically known (see
+/// `direct_call_target`), we instead emit a direct Call, which avoids the
+/// table dispatch and gives the engine an opportunity to inline the call.
 fn gen_instr_fn_app(
     func: &TypedExpr,
     args: &Vector<TypedExpr>,
@@ -500,49 +1511,329 @@ fn gen_instr_fn_app(
         let mut exp_instr = gen_instr(exp, state)?;
         fn_app_instr.append(&mut exp_instr);
     }
+    if let Some(func_name) = direct_call_target(func, state) {
+        // Functions are added to the table in the same order as they are
+        // added to the module, but imported functions come before all other
+        // functions in the module's index space.
+        let table_idx = *state
+            .funcs
+            .get(&func_name)
+            .ok_or("Function not found in function table.")?;
+        fn_app_instr.push(Instruction::Call(state.num_imports + table_idx));
+        fn_app_instr.append(&mut gen_instr_check_exception(state)?);
+        return Ok(fn_app_instr);
+    }
     let mut func_idx_instr: Vec<Instruction> = gen_instr(func, state)?;
     fn_app_instr.append(&mut func_idx_instr);
-    let sig_index = match state.sigs.get(&(args.len() as u32)) {
+    let sig_index = match func_signature(&func.typ).and_then(|sig| state.sigs.get(&sig)) {
         Some(val) => *val,
         None => return Err(CodeGenerateError::from("Signature index not found!")),
     };
     fn_app_instr.push(Instruction::CallIndirect(sig_index, 0));
+    fn_app_instr.append(&mut gen_instr_check_exception(state)?);
     Ok(fn_app_instr)
 }
 
+/// Generate instructions for a raise expression.
+///
+/// With native exceptions, this is a `throw` of the tag for the exception's
+/// type, which the engine unwinds to the innermost `catch` of that tag, or
+/// out of the module if there is none (see `encode` for how the instructions
+/// of the exception-handling proposal are marked).
+///
+/// Engines without support for the exception-handling proposal have no way
+/// to unwind the call stack, so the exception is stored in globals, and the
+/// function returns early (with a zero value, which is never used) unless a
+/// handler within the same function encloses the raise. Each call is
+/// followed by a check for an exception raised by the callee (see
+/// `gen_instr_check_exception`), so that the exception keeps propagating
+/// until it reaches a handler. An exception which would escape every handler
+/// traps where it is raised.
+fn gen_instr_raise(
+    exn: &TypedExpr,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let mut raise_instr = gen_instr(exn, state)?;
+    if state.native_exceptions {
+        let tag = exception_tag(&exn.typ, state) - 1;
+        raise_instr.extend(vec![
+            Instruction::Nop,
+            Instruction::I32Const(tag as i32),
+            Instruction::Unreachable,
+        ]);
+        return Ok(raise_instr);
+    }
+    // without any handlers in the program, the exception can't be caught
+    if state.exn_globals.is_none() {
+        raise_instr.push(Instruction::Unreachable);
+        return Ok(raise_instr);
+    }
+    raise_instr.append(&mut widen_exception(&exn.typ));
+    let tag = exception_tag(&exn.typ, state);
+    let globals = state
+        .exn_globals
+        .as_ref()
+        .ok_or("Exception globals not found.")?;
+    raise_instr.push(Instruction::SetGlobal(globals.value));
+    raise_instr.push(Instruction::I32Const(tag as i32));
+    raise_instr.push(Instruction::SetGlobal(globals.tag));
+    raise_instr.append(&mut gen_instr_propagate(state)?);
+    Ok(raise_instr)
+}
+
+/// Generate instructions for a with-handler expression.
+///
+/// The body is run within a block which is exited early when an exception
+/// is raised (see `gen_instr_propagate`). If the exception has the type the
+/// handler expects, it is bound to the handler's variable and the handler
+/// is run instead, and otherwise it continues propagating.
+fn gen_instr_handle(
+    var: &str,
+    exn_typ: &Type,
+    handler: &TypedExpr,
+    body: &TypedExpr,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    if state.native_exceptions {
+        return gen_instr_handle_native(var, exn_typ, handler, body, state);
+    }
+    let tag = exception_tag(exn_typ, state);
+    let (tag_global, value_global, handlers_global) = match &state.exn_globals {
+        Some(globals) => (globals.tag, globals.value, globals.handlers),
+        None => return Err(CodeGenerateError::from("Exception globals not found.")),
+    };
+    let count_handler = |delta: i32| {
+        vec![
+            Instruction::GetGlobal(handlers_global),
+            Instruction::I32Const(delta),
+            Instruction::I32Add,
+            Instruction::SetGlobal(handlers_global),
+        ]
+    };
+
+    // the outer block produces the result, and exceptions raised by the body
+    // branch to the end of the inner block
+    state.block_depth += 2;
+    state.handler_depths.push(state.block_depth);
+    let body_instr = gen_instr(body, state)?;
+    state.handler_depths.pop();
+    let mut handle_instr = vec![
+        Instruction::Block(BlockType::Value(wasm_type(&body.typ))),
+        Instruction::Block(BlockType::NoResult),
+    ];
+    handle_instr.append(&mut count_handler(1));
+    handle_instr.extend(body_instr);
+    handle_instr.append(&mut count_handler(-1));
+    handle_instr.push(Instruction::Br(1));
+    handle_instr.push(Instruction::End);
+    state.block_depth -= 1;
+
+    handle_instr.append(&mut count_handler(-1));
+    handle_instr.extend(vec![
+        Instruction::GetGlobal(tag_global),
+        Instruction::I32Const(tag as i32),
+        Instruction::I32Ne,
+        Instruction::If(BlockType::NoResult),
+    ]);
+    state.block_depth += 1;
+    handle_instr.append(&mut gen_instr_propagate(state)?);
+    state.block_depth -= 1;
+    handle_instr.push(Instruction::End);
+
+    let local_index = alloc_local(wasm_type(exn_typ), state);
+    handle_instr.extend(vec![
+        Instruction::I32Const(0),
+        Instruction::SetGlobal(tag_global),
+        Instruction::GetGlobal(value_global),
+    ]);
+    handle_instr.append(&mut narrow_exception(exn_typ));
+    handle_instr.push(Instruction::SetLocal(local_index));
+    let previous_local = bind_local(var, local_index, state);
+    let previous_closure = bind_closure(var, None, state);
+    let mut handler_instr = gen_instr(handler, state)?;
+    restore_closure(var, previous_closure, state);
+    unbind_local(var, previous_local, state);
+    state.block_depth -= 1;
+    handle_instr.append(&mut handler_instr);
+    handle_instr.push(Instruction::End);
+    Ok(handle_instr)
+}
+
+/// Generate instructions for a with-handler expression using native
+/// exceptions: the body is run within a `try`, whose `catch` of the tag for
+/// the handler's exception type binds the exception to the handler's
+/// variable and runs the handler.
+fn gen_instr_handle_native(
+    var: &str,
+    exn_typ: &Type,
+    handler: &TypedExpr,
+    body: &TypedExpr,
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let tag = exception_tag(exn_typ, state) - 1;
+    state.block_depth += 1;
+    let mut handle_instr = vec![
+        Instruction::Nop,
+        Instruction::Block(BlockType::Value(wasm_type(&body.typ))),
+    ];
+    handle_instr.append(&mut gen_instr(body, state)?);
+    handle_instr.extend(vec![
+        Instruction::Nop,
+        Instruction::I32Const(tag as i32),
+        Instruction::Else,
+    ]);
+
+    let local_index = alloc_local(wasm_type(exn_typ), state);
+    handle_instr.push(Instruction::SetLocal(local_index));
+    let previous_local = bind_local(var, local_index, state);
+    let previous_closure = bind_closure(var, None, state);
+    let mut handler_instr = gen_instr(handler, state)?;
+    restore_closure(var, previous_closure, state);
+    unbind_local(var, previous_local, state);
+    state.block_depth -= 1;
+    handle_instr.append(&mut handler_instr);
+    handle_instr.push(Instruction::End);
+    Ok(handle_instr)
+}
+
+/// Generate instructions which pass the exception being raised on to the
+/// innermost handler enclosing the current expression. If it is in another
+/// function, the current function returns early, unless there are no
+/// handlers at all, in which case the exception is uncaught and traps.
+fn gen_instr_propagate(state: &CodeGenerateState) -> Result<Vec<Instruction>, CodeGenerateError> {
+    if let Some(handler_depth) = state.handler_depths.last() {
+        return Ok(vec![Instruction::Br(state.block_depth - handler_depth)]);
+    }
+    let globals = state
+        .exn_globals
+        .as_ref()
+        .ok_or("Exception globals not found.")?;
+    let ret_type = state.ret_type.ok_or("Return type of function not found.")?;
+    Ok(vec![
+        Instruction::GetGlobal(globals.handlers),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Unreachable,
+        Instruction::End,
+        zero_instr(ret_type),
+        Instruction::Return,
+    ])
+}
+
+/// Generate instructions which check whether a function which was just
+/// called raised an exception, and if so, propagate it. Nothing is
+/// generated for programs which don't use exceptions.
+fn gen_instr_check_exception(
+    state: &mut CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    let tag_global = match &state.exn_globals {
+        Some(globals) => globals.tag,
+        None => return Ok(vec![]),
+    };
+    state.block_depth += 1;
+    let propagate_instr = gen_instr_propagate(state);
+    state.block_depth -= 1;
+    Ok([
+        vec![
+            Instruction::GetGlobal(tag_global),
+            Instruction::If(BlockType::NoResult),
+        ],
+        propagate_instr?,
+        vec![Instruction::End],
+    ]
+    .concat())
+}
+
+/// Returns the tag of exceptions of a type (see `ExceptionGlobals`).
+fn exception_tag(typ: &Type, state: &mut CodeGenerateState) -> u32 {
+    match state.exn_tags.iter().position(|tag_typ| tag_typ == typ) {
+        Some(index) => index as u32 + 1,
+        None => {
+            state.exn_tags.push(typ.clone());
+            state.exn_tags.len() as u32
+        }
+    }
+}
+
+/// Instructions converting an exception's value into the i64 stored in the
+/// exception's global.
+fn widen_exception(typ: &Type) -> Vec<Instruction> {
+    match wasm_type(typ) {
+        ValueType::I64 => vec![],
+        ValueType::F64 => vec![Instruction::I64ReinterpretF64],
+        _ => vec![Instruction::I64ExtendUI32],
+    }
+}
+
+/// Instructions converting the i64 stored in the exception's global back
+/// into a value of the exception's type.
+fn narrow_exception(typ: &Type) -> Vec<Instruction> {
+    match wasm_type(typ) {
+        ValueType::I64 => vec![],
+        ValueType::F64 => vec![Instruction::F64ReinterpretI64],
+        _ => vec![Instruction::I32WrapI64],
+    }
+}
+
+/// Check whether an expression handles exceptions.
+fn uses_handlers(exp: &TypedExpr) -> bool {
+    matches!(&*exp.kind, ExprKind::Handle(_, _, _, _))
+        || children(exp).into_iter().any(uses_handlers)
+}
+
 /// Generate instructions for an arbitrary expression kind by dispatching
 /// on the kind of the expression.
 pub fn gen_instr(
     exp: &TypedExpr,
     state: &mut CodeGenerateState,
 ) -> Result<Vec<Instruction>, CodeGenerateError> {
+    // only the expressions which keep track of the variables that are still
+    // live pass them on to their subexpressions (see `free_dead_locals`)
+    let live = match &*exp.kind {
+        ExprKind::Begin(_) | ExprKind::Let(_, _) | ExprKind::If(_, _, _) => state.live.clone(),
+        _ => state.live.take(),
+    };
     let instructions: Result<Vec<Instruction>, CodeGenerateError> = match &*exp.kind {
-        ExprKind::Num(x) => Ok(vec![Instruction::I32Const(*x)]),
+        ExprKind::Num(x) => Ok(gen_instr_num(*x, state)),
+        ExprKind::Int64(x) => Ok(vec![Instruction::I64Const(*x)]),
+        ExprKind::Float(x) => Ok(vec![Instruction::F64Const(x.to_bits())]),
         ExprKind::Bool(x) => Ok(vec![Instruction::I32Const(*x as i32)]),
-        ExprKind::Str(_) => Err(CodeGenerateError::from("Unhandled gen_instr case: Str")),
+        ExprKind::Str(x) => Ok(gen_instr_str(x, state)),
+        ExprKind::Char(x) => Ok(vec![Instruction::I32Const(*x as i32)]),
+        ExprKind::Symbol(x) => Ok(gen_instr_symbol(x, state)),
+        ExprKind::Quote(data) => Ok(gen_instr_quote(data, state)),
         ExprKind::Id(sym) => match state.locals.get(sym) {
             Some(local_idx) => Ok(vec![Instruction::GetLocal(*local_idx)]),
-            None => match state.funcs.get(sym) {
-                Some(func_idx) => Ok(vec![Instruction::I32Const(*func_idx as i32)]),
-                None => {
+            None => match (state.globals.get(sym), state.funcs.get(sym)) {
+                (Some(global_idx), _) => Ok(vec![Instruction::GetGlobal(*global_idx)]),
+                (None, Some(func_idx)) => Ok(vec![Instruction::I32Const(*func_idx as i32)]),
+                (None, None) => {
                     return Err(CodeGenerateError::from(
-                        "Symbol not found in locals or function table.",
+                        "Symbol not found in locals, globals, or function table.",
                     ));
                 }
             },
         },
-        ExprKind::Binop(op, arg1, arg2) => Ok(gen_instr_binop(*op, arg1, arg2, state)?),
+        ExprKind::Prim(op, args) => Ok(gen_instr_prim(*op, args, state)?),
         ExprKind::If(pred, cons, alt) => Ok(gen_instr_if(pred, cons, alt, state)?),
         ExprKind::Let(bindings, body) => Ok(gen_instr_let(bindings, body, state)?),
         ExprKind::Lambda(_params, _ret_type, _body) => Err(CodeGenerateError::from(
             "Lambda expressions should have been hoisted to the top level via lambda lifting pass.",
         )),
-        ExprKind::Record(_bindings) => Err(CodeGenerateError::from(
-            "Record expressions should be removed via record conversion pass.",
+        ExprKind::Letrec(_bindings, _body) => Err(CodeGenerateError::from(
+            "Letrec expressions should have been hoisted to the top level via lambda lifting pass.",
         )),
-        ExprKind::RecordGet(_record, _key) => Err(CodeGenerateError::from(
+        ExprKind::Derived(_form, _exp) => Err(CodeGenerateError::from(
+            "Derived forms should have been removed by type checking.",
+        )),
+        ExprKind::Record(_bindings) => Err(CodeGenerateError::from(
             "Record expressions should be removed via record conversion pass.",
         )),
+        ExprKind::RecordGet(_record, _key) | ExprKind::RecordSet(_record, _key, _) => {
+            Err(CodeGenerateError::from(
+                "Record expressions should be removed via record conversion pass.",
+            ))
+        }
         ExprKind::Begin(exps) => Ok(gen_instr_begin(exps, state)?),
         ExprKind::Set(sym, exp) => Ok(gen_instr_set(sym, exp, state)?),
         ExprKind::Cons(first, rest) => Ok(gen_instr_cons(first, rest, state)?),
@@ -550,66 +1841,96 @@ pub fn gen_instr(
         ExprKind::Cdr(cons) => Ok(gen_instr_cdr(cons, state)?),
         ExprKind::IsNull(exp) => Ok(gen_instr_is_null(exp, state)?),
         ExprKind::Null(typ) => Ok(gen_instr_null(typ, state)?),
+        ExprKind::Display(exp) => Ok(gen_instr_display(exp, state)?),
+        ExprKind::Newline => Ok(gen_instr_newline(state)?),
         ExprKind::Tuple(exps) => Ok(gen_instr_tuple(exps, state)?),
         ExprKind::TupleGet(tup, key) => Ok(gen_instr_tuple_get(tup, *key, state)?),
+        ExprKind::TupleSet(tup, key, val) => Ok(gen_instr_tuple_set(tup, *key, val, state)?),
         ExprKind::Pack(val, sub, exist) => Ok(gen_instr_pack(val, sub, exist, state)?),
         ExprKind::Unpack(var, package, type_sub, body) => {
             Ok(gen_instr_unpack(var, package, *type_sub, body, state)?)
         }
+        ExprKind::Raise(exn, _typ) => Ok(gen_instr_raise(exn, state)?),
+        ExprKind::Handle(var, typ, handler, body) => {
+            Ok(gen_instr_handle(var, typ, handler, body, state)?)
+        }
+        ExprKind::CallCC(_) | ExprKind::Throw(_, _, _) => Err(CodeGenerateError::from(
+            "Continuations should be removed via CPS conversion pass.",
+        )),
+        ExprKind::MakeGenerator(_, _) | ExprKind::Yield(_) | ExprKind::Next(_) => Err(
+            CodeGenerateError::from("Generators should be removed via CPS conversion pass."),
+        ),
         ExprKind::FnApp(func, args) => Ok(gen_instr_fn_app(func, args, state)?),
     };
+    state.live = live;
     instructions
 }
 
-/// Construct a WebAssembly module.
-///
-/// We assume that the `Instructions` argument passed in does not contain
-/// a closing `Instruction::End` instruction.
-pub fn construct_module(
-    name: &str,
-    state: CodeGenerateState,
-    param_types: Vec<Type>,
-    mut instructions: Instructions,
-) -> builder::ModuleBuilder {
-    // Construct the list of WebAssembly parameter types
-    let wasm_param_types = std::iter::repeat_n(ValueType::I32, param_types.len())
-        .collect::<Vec<ValueType>>();
-
-    // Construct the list of WebAssembly local types
-    let wasm_locals = construct_locals(&state.locals);
+/// Returns the WebAssembly signature of functions of a type, or None if the
+/// type is not a function type.
+fn func_signature(typ: &Type) -> Option<FuncSig> {
+    match typ {
+        Type::Func(param_types, ret_type) => Some((
+            param_types.iter().map(wasm_type).collect(),
+            wasm_type(ret_type),
+        )),
+        _ => None,
+    }
+}
 
-    // Add the required end instruction
-    instructions.elements_mut().push(Instruction::End);
+/// Returns the index of a signature within the module's type signature
+/// table, adding the signature to the table if it is not there yet.
+fn signature_index(
+    sig: FuncSig,
+    module_builder: &mut builder::ModuleBuilder,
+    state: &mut CodeGenerateState,
+) -> u32 {
+    if let Some(sig_index) = state.sigs.get(&sig) {
+        return *sig_index;
+    }
+    let func_sig = builder::signature()
+        .with_params(sig.0.clone())
+        .with_result(sig.1)
+        .build_sig();
+    let sig_index = module_builder.push_signature(func_sig);
+    state.sigs.insert(sig, sig_index);
+    sig_index
+}
 
-    builder::module()
-        .memory()
-        .with_min(32)
-        .with_max(None)
-        .build()
-        .function()
-        .signature()
-        .with_params(wasm_param_types)
-        .with_result(ValueType::I32)
-        .build()
-        .body()
-        .with_locals(wasm_locals)
-        .with_instructions(instructions)
-        .build()
-        .build()
-        .export()
-        .field(name)
-        .internal()
-        .func(0)
-        .build()
+/// Collect the signatures of all functions which are applied within an
+/// expression.
+fn collect_signatures(exp: &TypedExpr, sigs: &mut Vec<FuncSig>) {
+    if let ExprKind::FnApp(func, _args) = &*exp.kind
+        && let Some(sig) = func_signature(&func.typ)
+        && !sigs.contains(&sig)
+    {
+        sigs.push(sig);
+    }
+    for child in children(exp) {
+        collect_signatures(child, sigs);
+    }
 }
 
+/// Construct a WebAssembly module for a program, using the default options
+/// (see `CodeGenerateOptions`).
 pub fn construct_module_from_prog(prog: &Prog<TypedExpr>) -> Result<Module, CodeGenerateError> {
+    construct_module_from_prog_with_options(prog, &CodeGenerateOptions::default())
+}
+
+/// Same as `construct_module_from_prog`, but with custom options.
+pub fn construct_module_from_prog_with_options(
+    prog: &Prog<TypedExpr>,
+    options: &CodeGenerateOptions,
+) -> Result<Module, CodeGenerateError> {
+    let target = options.target;
     let mut module_builder = builder::module()
         .memory()
-        .with_min(32)
+        .with_min(options.memory_pages)
         .with_max(None)
         .build();
     let mut state = CodeGenerateState::new();
+    state.tail_calls = options.tail_calls;
+    state.native_exceptions = options.exceptions == ExceptionHandling::Native;
 
     // We need to know the index of type signatures in WebAssembly's type
     // signature table at any time when compiling a function in case we need
@@ -619,105 +1940,2339 @@ pub fn construct_module_from_prog(prog: &Prog<TypedExpr>) -> Result<Module, Code
     // the correct number of arguments are on the stack at the time
     // the instruction is run.
     //
-    // We could try scanning the program for all type signatures, and then
-    // constructing a table for them that way, but for simplicity sake we will
-    // just assume functions take anywhere from 0 to 8 arguments, and simply
-    // precompute all of the signatures.
-    for i in 0..=8 {
-        let func_sig = builder::signature()
-            .with_params(
-                std::iter::repeat_n(ValueType::I32, i)
-                    .collect::<Vec<ValueType>>(),
-            )
-            .with_result(ValueType::I32)
-            .build_sig();
-        let sig_index = module_builder.push_signature(func_sig);
-        state.sigs.insert(i as u32, sig_index);
-    }
-
-    // First, the lambda-lifted functions within `prog` will get compiled.
+    // So we scan the program for all function applications, and add their
+    // signatures to the table before compiling anything. Since the types of
+    // values differ in size (see `wasm_type`), a signature depends on the
+    // types of the function's parameters and result, not just their number.
+    let mut sigs = vec![];
+    for (_name, lambda) in prog.fns.iter() {
+        collect_signatures(lambda, &mut sigs);
+    }
+    collect_signatures(&prog.exp, &mut sigs);
+    for sig in sigs {
+        signature_index(sig, &mut module_builder, &mut state);
+    }
+
+    // Extern functions are imported from the host environment. Imported
+    // functions take up the first indices of WebAssembly's function index
+    // space, before any functions defined within the module.
+    for (name, typ) in prog.externs.iter() {
+        let sig = func_signature(typ).ok_or("Extern does not have a function type.")?;
+        let sig_index = signature_index(sig, &mut module_builder, &mut state);
+        module_builder.push_import(ImportEntry::new(
+            String::from("env"),
+            name.clone(),
+            External::Function(sig_index),
+        ));
+    }
+    state.num_imports = prog.externs.len() as u32;
+    if target == Target::Wasi {
+        // fd_write(fd, iovs, iovs_len, nwritten) -> errno
+        let sig_index = signature_index(
+            (vec![ValueType::I32; 4], ValueType::I32),
+            &mut module_builder,
+            &mut state,
+        );
+        module_builder.push_import(ImportEntry::new(
+            String::from("wasi_snapshot_preview1"),
+            String::from("fd_write"),
+            External::Function(sig_index),
+        ));
+        state.num_imports += 1;
+    }
+
+    // Each global definition is stored in a mutable WebAssembly global, which
+    // is initialized by the main expression.
+    for (name, typ) in prog.globals.iter() {
+        let global_index = module_builder.push_global(GlobalEntry::new(
+            GlobalType::new(wasm_type(typ), true),
+            InitExpr::new(vec![zero_instr(wasm_type(typ)), Instruction::End]),
+        ));
+        state.globals.insert(name.clone(), global_index);
+    }
+
+    // The fuel is set by the host before calling into the module, and every
+    // function and loop is charged as it runs (see `meter_instructions`).
+    if options.metering {
+        let fuel_index = module_builder.push_global(GlobalEntry::new(
+            GlobalType::new(ValueType::I32, true),
+            InitExpr::new(vec![Instruction::I32Const(0), Instruction::End]),
+        ));
+        module_builder.push_export(ExportEntry::new(
+            String::from("$$FUEL$$"),
+            Internal::Global(fuel_index),
+        ));
+        state.fuel_global = Some(fuel_index);
+    }
+
+    // Without native exceptions, a raised exception is passed to its handler
+    // through globals (see `gen_instr_raise`), which are only needed if the
+    // program has handlers (otherwise every exception traps where it is
+    // raised).
+    if !state.native_exceptions
+        && (uses_handlers(&prog.exp) || prog.fns.iter().any(|(_name, func)| uses_handlers(func)))
+    {
+        let mut push_global = |value_type: ValueType| {
+            module_builder.push_global(GlobalEntry::new(
+                GlobalType::new(value_type, true),
+                InitExpr::new(vec![zero_instr(value_type), Instruction::End]),
+            ))
+        };
+        state.exn_globals = Some(ExceptionGlobals {
+            tag: push_global(ValueType::I32),
+            value: push_global(ValueType::I64),
+            handlers: push_global(ValueType::I32),
+        });
+    }
+
+    // Tuples, lists and bignums are allocated downwards from the end of linear
+    // memory (below the scratch memory of the int helpers), and must stay
+    // above the program's own data, which ends at `$$HEAP_BASE$$` (see
+    // `construct_alloc_helper`). The end of the program's data isn't known
+    // until everything has been compiled, so `$$HEAP_BASE$$` is set last.
+    // The host may place values of its own above the program's data, and
+    // then move `$$HEAP_BASE$$` past them (see `marshal::encode_value`), so
+    // it is mutable.
+    let scratch_idx = (options.memory_pages * 65536).saturating_sub(BIGNUM_SCRATCH_SIZE);
+    state.heap_global = module_builder.push_global(GlobalEntry::new(
+        GlobalType::new(ValueType::I32, true),
+        InitExpr::new(vec![
+            Instruction::I32Const(scratch_idx as i32),
+            Instruction::End,
+        ]),
+    ));
+    state.heap_base_global = module_builder.push_global(GlobalEntry::new(
+        GlobalType::new(ValueType::I32, true),
+        InitExpr::new(vec![Instruction::I32Const(0), Instruction::End]),
+    ));
+    module_builder.push_export(ExportEntry::new(
+        String::from("$$HEAP_BASE$$"),
+        Internal::Global(state.heap_base_global),
+    ));
+
+    // The memory reserved for printing must come first (see `WASI_TEXT`)
+    if target == Target::Wasi {
+        state
+            .data
+            .push((WASI_DIGITS_END as u32, WASI_TEXT.to_vec()));
+        state.mem_index = WASI_RESERVED_SIZE;
+    }
+    construct_alloc_helper(&mut module_builder, &mut state);
+    construct_int_helpers(scratch_idx, &mut module_builder, &mut state)?;
+    if target == Target::Wasi {
+        construct_wasi_helpers(&mut module_builder, &mut state)?;
+    }
+
+    // Closure conversion makes every function take an environment as its
+    // first parameter, which the host environment has no use for. So each
+    // extern gets a wrapper function (under the same name) which drops the
+    // environment and calls the import, and which can then be called through
+    // closures like any other function. Ints are passed to and from the host
+    // as plain i32s (see `gen_instr_to_host`), so the wrappers come after the
+    // int helpers.
+    for (import_index, (name, typ)) in prog.externs.iter().enumerate() {
+        let (mut param_types, ret_type) = match typ {
+            Type::Func(params, ret_typ) => (
+                params.iter().cloned().collect::<Vec<Type>>(),
+                (**ret_typ).clone(),
+            ),
+            _ => {
+                return Err(CodeGenerateError::from(
+                    "Extern does not have a function type.",
+                ));
+            }
+        };
+        let mut wrapper_instructions = vec![];
+        for (index, param_type) in param_types.iter().enumerate() {
+            wrapper_instructions.push(Instruction::GetLocal(index as u32 + 1));
+            wrapper_instructions.append(&mut gen_instr_to_host(param_type, &state)?);
+        }
+        wrapper_instructions.push(Instruction::Call(import_index as u32));
+        wrapper_instructions.append(&mut gen_instr_from_host(&ret_type, &state)?);
+        param_types.insert(0, Type::Record(Vector::new()));
+        start_function(&[], &mut state);
+        let wrapper_function = construct_function(
+            param_types,
+            ret_type,
+            Instructions::new(wrapper_instructions),
+            &mut state,
+        );
+        let func_index = state.funcs.len() as u32;
+        state.funcs.insert(name.to_string(), func_index);
+        module_builder.push_function(wrapper_function);
+    }
+
+    // Next, the lambda-lifted functions within `prog` will get compiled.
     // This is necessary for populating state.funcs, which maps the names of
     // functions to indices within the WebAssembly store. For reference, see:
     // https://webassembly.github.io/spec/core/exec/instructions.html#function-calls
     // https://webassembly.github.io/spec/core/exec/runtime.html#syntax-store
-    prog.fns
-        .iter()
-        .for_each(|(name, lambda)| match &*lambda.kind {
-            ExprKind::Lambda(params, _ret_type, body) => {
+
+    // Update the `FuncsMap` table within `CodeGenerateState` so that any time
+    // one of the functions gets referred to by name later, we know which index
+    // within WebAssembly's store we need to use to call it.
+
+    // ex. the program has several functions. One of them is named "foo", and
+    // is the third to get compiled so then (key: "foo", value: 2) gets
+    // inserted to the table. Then when compiling a body, when (foo 5) is
+    // seen, the code generate can look at state.funcs to see that foo maps to
+    // 2, so we just need to put 5 on the stack and add Instruction::Call(2)
+    // to perform the function application. This happens before compiling any
+    // of the bodies, since functions bound by a letrec may refer to
+    // themselves and to each other.
+    for (name, _lambda) in &prog.fns {
+        let func_index = state.funcs.len() as u32;
+        state.funcs.insert(name.to_string(), func_index);
+    }
+    for (_name, lambda) in &prog.fns {
+        match &*lambda.kind {
+            ExprKind::Lambda(params, ret_type, body) => {
                 let param_types = params
                     .iter()
                     .map(|(_name, typ)| typ.clone())
                     .collect::<Vec<Type>>();
                 // Add the lambda's n parameters as the first n local variables
-                params.iter().for_each(|(name, _typ)| {
-                    let local_index = state.locals.len() as u32;
-                    state.locals.insert(name.clone(), local_index);
-                });
+                let param_names = params
+                    .iter()
+                    .map(|(name, _typ)| name.clone())
+                    .collect::<Vec<String>>();
+                start_function(&param_names, &mut state);
+                state.ret_type = Some(wasm_type(ret_type));
 
-                let func_instructions = gen_instr(body, &mut state).unwrap();
+                let func_instructions = gen_instr(body, &mut state)?;
                 let wasm_function = construct_function(
                     param_types,
+                    ret_type.clone(),
                     Instructions::new(func_instructions),
                     &mut state,
                 );
 
-                // Update the `FuncsMap` table within `CodeGenerateState` so
-                // that any time this function gets referred to by name later,
-                // we know which index within WebAssembly's store we need to
-                // use to call the function.
-
-                // ex. the program has several functions. One of them is named
-                // "foo", and is the third to get compiled so then
-                // (key: "foo", value: 2) gets inserted to the table. Then
-                // when compiling the body, when it (foo 5) is seen, the
-                // code generate can look at state.funcs to see that foo
-                // maps to 2, so we just need to put 5 on the stack and add
-                // Instruction::Call(2) to perform the function application.
-                let func_index = state.funcs.len() as u32;
-                state.funcs.insert(name.to_string(), func_index);
-
                 // Add the function to the module
                 module_builder.push_function(wasm_function);
-
-                // Reset state.locals so that the locals don't carry on
-                // when compiling the next function...
-                // Having to remember this kind of thing is a bit of a flaw
-                // in the mutating-state-passing pattern we are using.
-                state.locals.clear();
             }
-            _ => panic!("Function inside prog.fns is not a lambda."),
-        });
+            _ => {
+                return Err(CodeGenerateError::from(
+                    "Function inside prog.fns is not a lambda.",
+                ));
+            }
+        }
+    }
 
-    // Construct a dummy table to make Instruction::CallIndirect work.
-    let mut module_builder = module_builder.table().with_min(32).with_max(None);
-    for i in 0..prog.fns.len() {
-        module_builder = module_builder.with_element(i as u32, vec![i as u32]);
+    // Construct a table holding every function, to make
+    // Instruction::CallIndirect work.
+    let mut module_builder = module_builder
+        .table()
+        .with_min(state.funcs.len() as u32)
+        .with_max(None);
+    for i in 0..state.funcs.len() as u32 {
+        module_builder = module_builder.with_element(i, vec![state.num_imports + i]);
     }
     let module_builder = module_builder.build();
 
-    // Finally, the body of the program is compiled. We will just give it a
+    // Next, the body of the program is compiled. We will just give it a
     // fancy name like $$MAIN$$ and hope that nobody else uses it. :-)
-    let mut main_instructions = gen_instr(&prog.exp, &mut state).unwrap();
-    main_instructions.push(Instruction::End);
-    let wasm_locals = construct_locals(&state.locals);
+    //
+    // Since the main expression initializes the global definitions, it
+    // records that it has run in a global, so that the exported functions
+    // can run it first if the host hasn't.
+    let mut module_builder = module_builder;
+    let init_global = if prog.exports.is_empty() {
+        None
+    } else {
+        Some(module_builder.push_global(GlobalEntry::new(
+            GlobalType::new(ValueType::I32, true),
+            InitExpr::new(vec![Instruction::I32Const(0), Instruction::End]),
+        )))
+    };
+    start_function(&[], &mut state);
+    state.ret_type = Some(wasm_type(&prog.exp.typ));
+    let mut main_instructions = vec![];
+    if let Some(init_global) = init_global {
+        main_instructions.push(Instruction::I32Const(1));
+        main_instructions.push(Instruction::SetGlobal(init_global));
+    }
+    main_instructions.append(&mut gen_instr(&prog.exp, &mut state)?);
+    let main_function = construct_function(
+        vec![],
+        prog.exp.typ.clone(),
+        Instructions::new(main_instructions),
+        &mut state,
+    );
+    let main_index = state.num_imports + state.funcs.len() as u32;
+    module_builder.push_function(main_function);
+    module_builder.push_export(ExportEntry::new(
+        String::from("$$MAIN$$"),
+        Internal::Function(main_index),
+    ));
+
+    // Finally, each exported definition gets a wrapper function which takes
+    // the function's parameters, loads the closure stored in the global, and
+    // calls it with the closure's environment, so that the host environment
+    // does not need to know anything about closures (or about how ints are
+    // represented, see `gen_instr_to_host`). If `$$MAIN$$` hasn't been called
+    // yet, the global hasn't been initialized, so the wrapper calls it first
+    // (discarding its result).
+    for (export_offset, (name, export_name, typ)) in prog.exports.iter().enumerate() {
+        let (param_types, ret_type) = match typ {
+            Type::Func(params, ret_typ) => (
+                params.iter().cloned().collect::<Vec<Type>>(),
+                (**ret_typ).clone(),
+            ),
+            _ => {
+                return Err(CodeGenerateError::from(
+                    "Export does not have a function type.",
+                ));
+            }
+        };
+        let global_index = *state
+            .globals
+            .get(name)
+            .ok_or("Exported name not found in globals.")?;
+        // the closure's function takes the environment as its first parameter
+        let (mut wasm_param_types, wasm_ret_type) =
+            func_signature(typ).ok_or("Export does not have a function type.")?;
+        wasm_param_types.insert(0, ValueType::I32);
+        let sig_index = signature_index(
+            (wasm_param_types, wasm_ret_type),
+            &mut module_builder,
+            &mut state,
+        );
+        let mut wrapper_instructions = vec![];
+        if let Some(init_global) = init_global {
+            wrapper_instructions.append(&mut vec![
+                Instruction::GetGlobal(init_global),
+                Instruction::I32Eqz,
+                Instruction::If(BlockType::NoResult),
+                Instruction::Call(main_index),
+                Instruction::Drop,
+                Instruction::End,
+            ]);
+        }
+        // A closure is a tuple of a function table index and an environment
+        wrapper_instructions.push(Instruction::GetGlobal(global_index));
+        wrapper_instructions.push(Instruction::I32Load(0, 4));
+        for (index, param_type) in param_types.iter().enumerate() {
+            wrapper_instructions.push(Instruction::GetLocal(index as u32));
+            wrapper_instructions.append(&mut gen_instr_from_host(param_type, &state)?);
+        }
+        wrapper_instructions.push(Instruction::GetGlobal(global_index));
+        wrapper_instructions.push(Instruction::I32Load(0, 0));
+        wrapper_instructions.push(Instruction::CallIndirect(sig_index, 0));
+        wrapper_instructions.append(&mut gen_instr_to_host(&ret_type, &state)?);
+        start_function(&[], &mut state);
+        let wrapper_function = construct_function(
+            param_types,
+            ret_type,
+            Instructions::new(wrapper_instructions),
+            &mut state,
+        );
+        module_builder.push_function(wrapper_function);
+        module_builder.push_export(ExportEntry::new(
+            export_name.clone(),
+            Internal::Function(main_index + 1 + export_offset as u32),
+        ));
+    }
+
+    // A WASI command is run by calling its `_start` function, which takes no
+    // arguments and returns nothing, so it prints the result of the main
+    // expression instead (if it can be displayed).
+    if target == Target::Wasi {
+        start_function(&[], &mut state);
+        let mut start_instructions = vec![Instruction::Call(main_index)];
+        if is_displayable(&prog.exp.typ) {
+            start_instructions.append(&mut gen_instr_print(&prog.exp.typ, &mut state)?);
+            start_instructions.push(Instruction::Drop);
+            start_instructions.append(&mut gen_instr_write_text(WASI_NEWLINE, &state)?);
+        }
+        start_instructions.push(Instruction::Drop);
+        start_instructions.push(Instruction::End);
+        let start_function = builder::function()
+            .signature()
+            .with_params(vec![])
+            .build()
+            .body()
+            .with_locals(construct_locals(&state.local_types))
+            .with_instructions(Instructions::new(start_instructions))
+            .build()
+            .build();
+        let start_index = main_index + 1 + prog.exports.len() as u32;
+        module_builder.push_function(start_function);
+        module_builder.push_export(ExportEntry::new(
+            String::from("_start"),
+            Internal::Function(start_index),
+        ));
+    }
+
+    // The host environment can read values out of (and write values into)
+    // the module's memory, starting from the first byte which is not used by
+    // the program's own data (see `marshal`).
+    module_builder.push_export(ExportEntry::new(
+        String::from("memory"),
+        Internal::Memory(0),
+    ));
+    if state.mem_index > scratch_idx {
+        return Err(CodeGenerateError::from(
+            "Program needs more memory than the module's memory size.",
+        ));
+    }
+
+    // The module builder can't represent the tag section which declares the
+    // tags of native exceptions, so it is passed on to
+    // `encode::serialize_module` in a custom section.
+    if !state.exn_tags.is_empty() && state.native_exceptions {
+        let sig_indices: Vec<u32> = state
+            .exn_tags
+            .iter()
+            .map(|typ| {
+                module_builder
+                    .push_signature(builder::signature().with_param(wasm_type(typ)).build_sig())
+            })
+            .collect();
+        let tags =
+            tag_section(&sig_indices).map_err(|error| CodeGenerateError(error.to_string()))?;
+        module_builder = module_builder.with_section(Section::Custom(tags));
+    }
+
+    let mut module = with_data_segments(module_builder, state.data).build();
+    let heap_base_entry = &mut module
+        .global_section_mut()
+        .ok_or("Module has no global section.")?
+        .entries_mut()[state.heap_base_global as usize];
+    *heap_base_entry.init_expr_mut() = InitExpr::new(vec![
+        Instruction::I32Const(state.mem_index as i32),
+        Instruction::End,
+    ]);
+    Ok(module)
+}
+
+/// Instructions computing the address of limb `index` of a bignum, minus the
+/// offset of the limbs within the bignum (8), given locals holding the
+/// bignum and the index.
+fn limb_address(bignum: u32, index: u32) -> Vec<Instruction> {
+    vec![
+        Instruction::GetLocal(bignum),
+        Instruction::GetLocal(index),
+        Instruction::I32Const(2),
+        Instruction::I32Shl,
+        Instruction::I32Add,
+    ]
+}
+
+/// Instructions loading limb `index` of a bignum with `len` limbs as an i64,
+/// or 0 if the bignum has no such limb, given locals holding all three.
+fn load_limb_or_zero(bignum: u32, len: u32, index: u32) -> Vec<Instruction> {
+    [
+        vec![
+            Instruction::GetLocal(index),
+            Instruction::GetLocal(len),
+            Instruction::I32LtU,
+            Instruction::If(BlockType::Value(ValueType::I64)),
+        ],
+        limb_address(bignum, index),
+        vec![
+            Instruction::I64Load32U(2, 8),
+            Instruction::Else,
+            Instruction::I64Const(0),
+            Instruction::End,
+        ],
+    ]
+    .concat()
+}
+
+/// Instructions which return the fixnum representing the i64 held in the
+/// local `val`, if it is in the range of fixnums.
+fn return_if_fixnum(val: u32) -> Vec<Instruction> {
+    vec![
+        Instruction::GetLocal(val),
+        Instruction::I64Const(-FIXNUM_MIN),
+        Instruction::I64Add,
+        Instruction::I64Const(FIXNUM_MAX - FIXNUM_MIN + 1),
+        Instruction::I64LtU,
+        Instruction::If(BlockType::NoResult),
+        Instruction::GetLocal(val),
+        Instruction::I32WrapI64,
+        Instruction::I32Const(1),
+        Instruction::I32Shl,
+        Instruction::I32Const(1),
+        Instruction::I32Or,
+        Instruction::Return,
+        Instruction::End,
+    ]
+}
+
+/// Instructions pushing the value of the fixnum held in the local `fixnum`,
+/// as an i64.
+fn fixnum_value(fixnum: u32) -> Vec<Instruction> {
+    vec![
+        Instruction::GetLocal(fixnum),
+        Instruction::I32Const(1),
+        Instruction::I32ShrS,
+        Instruction::I64ExtendSI32,
+    ]
+}
+
+/// Instructions which run `fast_instr` (which must return) if the locals `x`
+/// and `y` both hold fixnums.
+fn if_both_fixnums(x: u32, y: u32, fast_instr: Vec<Instruction>) -> Vec<Instruction> {
+    [
+        vec![
+            Instruction::GetLocal(x),
+            Instruction::GetLocal(y),
+            Instruction::I32And,
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::If(BlockType::NoResult),
+        ],
+        fast_instr,
+        vec![Instruction::End],
+    ]
+    .concat()
+}
+
+/// Instructions which loop over the indices `0..len` in the local `index`,
+/// running `body_instr` for each, given a local holding `len`.
+fn for_each_index(index: u32, len: u32, body_instr: Vec<Instruction>) -> Vec<Instruction> {
+    [
+        vec![
+            Instruction::I32Const(0),
+            Instruction::SetLocal(index),
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(index),
+            Instruction::GetLocal(len),
+            Instruction::I32GeU,
+            Instruction::BrIf(1),
+        ],
+        body_instr,
+        vec![
+            Instruction::GetLocal(index),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::SetLocal(index),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+        ],
+    ]
+    .concat()
+}
+
+/// Add the function `$$alloc(size)`, which allocates `size` bytes of linear
+/// memory at runtime and returns their address. Memory is allocated downwards
+/// from the end of linear memory (below the scratch memory of the int
+/// helpers), and must stay above `$$HEAP_BASE$$`, which is the end of the
+/// program's own data (or of any values the host has placed after it), so it
+/// traps once memory runs out. Memory is never freed.
+///
+/// Sizes are always multiples of 4, so every allocation is aligned to 4
+/// bytes.
+fn construct_alloc_helper(
+    module_builder: &mut builder::ModuleBuilder,
+    state: &mut CodeGenerateState,
+) {
+    start_function(&[String::from("size")], state);
+    let ptr = alloc_local(ValueType::I32, state);
+    let alloc_instructions = vec![
+        // trap if the allocation doesn't fit above the program's data
+        Instruction::GetLocal(0),
+        Instruction::GetGlobal(state.heap_global),
+        Instruction::GetGlobal(state.heap_base_global),
+        Instruction::I32Sub,
+        Instruction::I32GtU,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Unreachable,
+        Instruction::End,
+        Instruction::GetGlobal(state.heap_global),
+        Instruction::GetLocal(0),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(ptr),
+        Instruction::SetGlobal(state.heap_global),
+        Instruction::GetLocal(ptr),
+    ];
+    add_helper(
+        "$$alloc",
+        vec![Type::Int],
+        Type::Int,
+        alloc_instructions,
+        module_builder,
+        state,
+    );
+}
+
+/// Add the functions implementing arithmetic on ints, which are called by
+/// name through `call_runtime`.
+///
+/// Ints are exact, so they are represented in one of two ways:
+/// - a fixnum is an int in the range `FIXNUM_MIN..=FIXNUM_MAX`, represented
+///   by the i32 `(n << 1) | 1`, so that its lowest bit is always set
+/// - a bignum is any other int, represented by the address of a structure in
+///   linear memory. The structure holds the number of limbs (4 bytes),
+///   whether the int is negative (4 bytes), and then the limbs of its
+///   magnitude, which are 32-bit digits stored from least to most
+///   significant. Since these are aligned to 4 bytes, the lowest bit of their
+///   address is never set.
+///
+/// Every int which fits in a fixnum is represented by one, and bignums never
+/// have leading zero limbs, so each int has only one representation.
+///
+/// Arithmetic on two fixnums is done with 64-bit arithmetic, which can't
+/// overflow, and only promotes the result to a bignum if it doesn't fit in a
+/// fixnum. Otherwise, the fixnums are converted into bignums (in the scratch
+/// memory at `scratch_idx`), and the arithmetic is done limb by limb. Bignums are immutable,
+/// and are allocated by `$$alloc` (see `construct_alloc_helper`), below the
+/// scratch memory.
+///
+/// The helpers on ints are:
+/// - `$$int-add(x, y)`, `$$int-sub(x, y)`, `$$int-mul(x, y)` and
+///   `$$int-modulo(x, y)`
+/// - `$$int-divide(x, y, rem)`, which truncates the quotient towards zero,
+///   returning the remainder instead if `rem` is true
+/// - `$$int-compare(x, y)`, which returns -1, 0 or 1
+/// - `$$int-negative(x)` and `$$int-negate(x)`
+/// - `$$int-from-i64(n)`, `$$int-to-i64(x)`, `$$int-to-i32(x)` and
+///   `$$int-to-f64(x)`, which convert between ints and other numbers
+///   (trapping if the int does not fit)
+///
+/// and they are implemented using these helpers on bignums:
+/// - `$$bignum-alloc(len)`, which allocates a (positive) bignum of `len`
+///   limbs which are all zero
+/// - `$$bignum-normalize(b)`, which removes the leading zero limbs of a newly
+///   computed bignum, and converts it into a fixnum if it fits
+/// - `$$bignum-of(x, slot)`, which converts an int into a bignum, using
+///   scratch slot 0 or 1 if it is a fixnum
+/// - `$$bignum-compare(a, b)`, `$$bignum-add(a, b)`, `$$bignum-mul(a, b)`,
+///   `$$bignum-sub-into(a, b, dest)` and `$$bignum-divide(a, b, rem)`, which
+///   act on the magnitudes of bignums (ignoring their signs)
+fn construct_int_helpers(
+    scratch_idx: u32,
+    module_builder: &mut builder::ModuleBuilder,
+    state: &mut CodeGenerateState,
+) -> Result<(), CodeGenerateError> {
+    start_function(&[String::from("len")], state);
+    let ptr = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let alloc_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Const(8),
+            Instruction::I32Add,
+            call_runtime("$$alloc", state)?,
+            Instruction::SetLocal(ptr),
+            Instruction::GetLocal(ptr),
+            Instruction::GetLocal(0),
+            Instruction::I32Store(2, 0),
+            Instruction::GetLocal(ptr),
+            Instruction::I32Const(0),
+            Instruction::I32Store(2, 4),
+        ],
+        // memory may have been used before (see `$$display-int`)
+        for_each_index(
+            index,
+            0,
+            [
+                limb_address(ptr, index),
+                vec![Instruction::I32Const(0), Instruction::I32Store(2, 8)],
+            ]
+            .concat(),
+        ),
+        vec![Instruction::GetLocal(ptr)],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-alloc",
+        vec![Type::Int],
+        Type::Int,
+        alloc_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("n")], state);
+    let magnitude = alloc_local(ValueType::I64, state);
+    let ptr = alloc_local(ValueType::I32, state);
+    let from_i64_instructions = [
+        return_if_fixnum(0),
+        vec![
+            // the magnitude of the smallest i64 is 2^63 when treated as
+            // unsigned
+            Instruction::I64Const(0),
+            Instruction::GetLocal(0),
+            Instruction::I64Sub,
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(0),
+            Instruction::I64Const(0),
+            Instruction::I64LtS,
+            Instruction::Select,
+            Instruction::SetLocal(magnitude),
+            Instruction::I32Const(1),
+            Instruction::I32Const(2),
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Const(32),
+            Instruction::I64ShrU,
+            Instruction::I64Eqz,
+            Instruction::Select,
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::TeeLocal(ptr),
+            Instruction::GetLocal(0),
+            Instruction::I64Const(0),
+            Instruction::I64LtS,
+            Instruction::I32Store(2, 4),
+            Instruction::GetLocal(ptr),
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Store32(2, 8),
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Const(32),
+            Instruction::I64ShrU,
+            Instruction::I64Eqz,
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(ptr),
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Const(32),
+            Instruction::I64ShrU,
+            Instruction::I64Store32(2, 12),
+            Instruction::End,
+            Instruction::GetLocal(ptr),
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-from-i64",
+        vec![Type::Int64],
+        Type::Int,
+        from_i64_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("b")], state);
+    let len = alloc_local(ValueType::I32, state);
+    let val = alloc_local(ValueType::I64, state);
+    let normalize_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::SetLocal(len),
+            // drop leading zero limbs
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(len),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(len),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Load(2, 4),
+            Instruction::BrIf(1),
+            Instruction::GetLocal(len),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(len),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(len),
+            Instruction::I32Store(2, 0),
+            // zero is the fixnum 0
+            Instruction::GetLocal(len),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I32Const(1),
+            Instruction::Return,
+            Instruction::End,
+            // a bignum with one limb may fit in a fixnum
+            Instruction::GetLocal(len),
+            Instruction::I32Const(1),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I64Const(0),
+            Instruction::GetLocal(0),
+            Instruction::I64Load32U(2, 8),
+            Instruction::I64Sub,
+            Instruction::GetLocal(0),
+            Instruction::I64Load32U(2, 8),
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 4),
+            Instruction::Select,
+            Instruction::SetLocal(val),
+        ],
+        return_if_fixnum(val),
+        vec![Instruction::End, Instruction::GetLocal(0)],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-normalize",
+        vec![Type::Int],
+        Type::Int,
+        normalize_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x"), String::from("slot")], state);
+    let val = alloc_local(ValueType::I32, state);
+    let ptr = alloc_local(ValueType::I32, state);
+    let bignum_of_instructions = vec![
+        Instruction::GetLocal(0),
+        Instruction::I32Const(1),
+        Instruction::I32And,
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::NoResult),
+        Instruction::GetLocal(0),
+        Instruction::Return,
+        Instruction::End,
+        Instruction::I32Const(scratch_idx as i32),
+        Instruction::GetLocal(1),
+        Instruction::I32Const(12),
+        Instruction::I32Mul,
+        Instruction::I32Add,
+        Instruction::TeeLocal(ptr),
+        Instruction::I32Const(1),
+        Instruction::I32Store(2, 0),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(1),
+        Instruction::I32ShrS,
+        Instruction::SetLocal(val),
+        Instruction::GetLocal(ptr),
+        Instruction::GetLocal(val),
+        Instruction::I32Const(0),
+        Instruction::I32LtS,
+        Instruction::I32Store(2, 4),
+        Instruction::GetLocal(ptr),
+        Instruction::I32Const(0),
+        Instruction::GetLocal(val),
+        Instruction::I32Sub,
+        Instruction::GetLocal(val),
+        Instruction::GetLocal(val),
+        Instruction::I32Const(0),
+        Instruction::I32LtS,
+        Instruction::Select,
+        Instruction::I32Store(2, 8),
+        Instruction::GetLocal(ptr),
+    ];
+    add_helper(
+        "$$bignum-of",
+        vec![Type::Int; 2],
+        Type::Int,
+        bignum_of_instructions,
+        module_builder,
+        state,
+    );
+
+    // Compare the limbs from the most significant downwards, until they
+    // differ
+    start_function(&[String::from("a"), String::from("b")], state);
+    let len_a = alloc_local(ValueType::I32, state);
+    let len_b = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let limb_a = alloc_local(ValueType::I64, state);
+    let limb_b = alloc_local(ValueType::I64, state);
+    let compare_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_a),
+            Instruction::GetLocal(1),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_b),
+            Instruction::GetLocal(len_a),
+            Instruction::GetLocal(len_b),
+            Instruction::I32GtU,
+            Instruction::Select,
+            Instruction::SetLocal(index),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(index),
+            Instruction::I32Eqz,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I32Const(0),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::GetLocal(index),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(index),
+        ],
+        load_limb_or_zero(0, len_a, index),
+        vec![Instruction::SetLocal(limb_a)],
+        load_limb_or_zero(1, len_b, index),
+        vec![
+            Instruction::SetLocal(limb_b),
+            Instruction::GetLocal(limb_a),
+            Instruction::GetLocal(limb_b),
+            Instruction::I64Ne,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I32Const(-1),
+            Instruction::I32Const(1),
+            Instruction::GetLocal(limb_a),
+            Instruction::GetLocal(limb_b),
+            Instruction::I64LtU,
+            Instruction::Select,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::Unreachable,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-compare",
+        vec![Type::Int; 2],
+        Type::Int,
+        compare_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("a"), String::from("b")], state);
+    let len_a = alloc_local(ValueType::I32, state);
+    let len_b = alloc_local(ValueType::I32, state);
+    let len = alloc_local(ValueType::I32, state);
+    let sum = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let carry = alloc_local(ValueType::I64, state);
+    let add_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_a),
+            Instruction::GetLocal(1),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_b),
+            Instruction::GetLocal(len_a),
+            Instruction::GetLocal(len_b),
+            Instruction::I32GtU,
+            Instruction::Select,
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::TeeLocal(len),
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(sum),
+        ],
+        for_each_index(
+            index,
+            len,
+            [
+                load_limb_or_zero(0, len_a, index),
+                load_limb_or_zero(1, len_b, index),
+                vec![
+                    Instruction::I64Add,
+                    Instruction::GetLocal(carry),
+                    Instruction::I64Add,
+                    Instruction::SetLocal(carry),
+                ],
+                limb_address(sum, index),
+                vec![
+                    Instruction::GetLocal(carry),
+                    Instruction::I64Store32(2, 8),
+                    Instruction::GetLocal(carry),
+                    Instruction::I64Const(32),
+                    Instruction::I64ShrU,
+                    Instruction::SetLocal(carry),
+                ],
+            ]
+            .concat(),
+        ),
+        vec![Instruction::GetLocal(sum)],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-add",
+        vec![Type::Int; 2],
+        Type::Int,
+        add_instructions,
+        module_builder,
+        state,
+    );
+
+    // The magnitude of `a` must be at least that of `b`, and `dest` must
+    // have enough limbs to hold the difference. `dest` may be `a` itself.
+    start_function(
+        &[String::from("a"), String::from("b"), String::from("dest")],
+        state,
+    );
+    let len_a = alloc_local(ValueType::I32, state);
+    let len_b = alloc_local(ValueType::I32, state);
+    let len = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let diff = alloc_local(ValueType::I64, state);
+    let sub_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::SetLocal(len_a),
+            Instruction::GetLocal(1),
+            Instruction::I32Load(2, 0),
+            Instruction::SetLocal(len_b),
+            Instruction::GetLocal(2),
+            Instruction::I32Load(2, 0),
+            Instruction::SetLocal(len),
+        ],
+        for_each_index(
+            index,
+            len,
+            [
+                load_limb_or_zero(0, len_a, index),
+                load_limb_or_zero(1, len_b, index),
+                vec![
+                    Instruction::I64Sub,
+                    // the borrow from the previous limb
+                    Instruction::GetLocal(diff),
+                    Instruction::I64Sub,
+                    Instruction::SetLocal(diff),
+                ],
+                limb_address(2, index),
+                vec![
+                    Instruction::GetLocal(diff),
+                    Instruction::I64Store32(2, 8),
+                    Instruction::GetLocal(diff),
+                    Instruction::I64Const(63),
+                    Instruction::I64ShrU,
+                    Instruction::SetLocal(diff),
+                ],
+            ]
+            .concat(),
+        ),
+        vec![Instruction::GetLocal(2)],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-sub-into",
+        vec![Type::Int; 3],
+        Type::Int,
+        sub_instructions,
+        module_builder,
+        state,
+    );
+
+    // Long multiplication, adding the product of each pair of limbs into the
+    // result. Each step fits in an i64, since (2^32 - 1)^2 + 2 * (2^32 - 1)
+    // is 2^64 - 1.
+    start_function(&[String::from("a"), String::from("b")], state);
+    let len_a = alloc_local(ValueType::I32, state);
+    let len_b = alloc_local(ValueType::I32, state);
+    let product = alloc_local(ValueType::I32, state);
+    let index_a = alloc_local(ValueType::I32, state);
+    let index_b = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let limb_a = alloc_local(ValueType::I64, state);
+    let carry = alloc_local(ValueType::I64, state);
+    let mul_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_a),
+            Instruction::GetLocal(1),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len_b),
+            Instruction::I32Add,
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(product),
+        ],
+        for_each_index(
+            index_a,
+            len_a,
+            [
+                limb_address(0, index_a),
+                vec![
+                    Instruction::I64Load32U(2, 8),
+                    Instruction::SetLocal(limb_a),
+                    Instruction::I64Const(0),
+                    Instruction::SetLocal(carry),
+                ],
+                for_each_index(
+                    index_b,
+                    len_b,
+                    [
+                        vec![
+                            Instruction::GetLocal(index_a),
+                            Instruction::GetLocal(index_b),
+                            Instruction::I32Add,
+                            Instruction::SetLocal(index),
+                            Instruction::GetLocal(limb_a),
+                        ],
+                        limb_address(1, index_b),
+                        vec![Instruction::I64Load32U(2, 8), Instruction::I64Mul],
+                        limb_address(product, index),
+                        vec![
+                            Instruction::I64Load32U(2, 8),
+                            Instruction::I64Add,
+                            Instruction::GetLocal(carry),
+                            Instruction::I64Add,
+                            Instruction::SetLocal(carry),
+                        ],
+                        limb_address(product, index),
+                        vec![
+                            Instruction::GetLocal(carry),
+                            Instruction::I64Store32(2, 8),
+                            Instruction::GetLocal(carry),
+                            Instruction::I64Const(32),
+                            Instruction::I64ShrU,
+                            Instruction::SetLocal(carry),
+                        ],
+                    ]
+                    .concat(),
+                ),
+                vec![
+                    Instruction::GetLocal(index_a),
+                    Instruction::GetLocal(len_b),
+                    Instruction::I32Add,
+                    Instruction::SetLocal(index),
+                ],
+                limb_address(product, index),
+                vec![Instruction::GetLocal(carry), Instruction::I64Store32(2, 8)],
+            ]
+            .concat(),
+        ),
+        vec![Instruction::GetLocal(product)],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-mul",
+        vec![Type::Int; 2],
+        Type::Int,
+        mul_instructions,
+        module_builder,
+        state,
+    );
+
+    // Binary long division: the bits of `a` are shifted into the remainder
+    // one at a time (from the most significant), and whenever the remainder
+    // is at least `b`, `b` is subtracted from it and the quotient's bit is
+    // set. The remainder has an extra limb, since it can reach 2b - 1.
+    start_function(
+        &[String::from("a"), String::from("b"), String::from("rem")],
+        state,
+    );
+    let quotient = alloc_local(ValueType::I32, state);
+    let remainder = alloc_local(ValueType::I32, state);
+    let len = alloc_local(ValueType::I32, state);
+    let bit = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let word = alloc_local(ValueType::I32, state);
+    let carry = alloc_local(ValueType::I64, state);
+    let divide_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(bit),
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(quotient),
+            Instruction::GetLocal(bit),
+            Instruction::I32Const(5),
+            Instruction::I32Shl,
+            Instruction::SetLocal(bit),
+            Instruction::GetLocal(1),
+            Instruction::I32Load(2, 0),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::TeeLocal(len),
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(remainder),
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(bit),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(bit),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::TeeLocal(bit),
+            Instruction::I32Const(5),
+            Instruction::I32ShrU,
+            Instruction::SetLocal(word),
+        ],
+        // shift the next bit of `a` into the remainder (shifts are modulo 32)
+        limb_address(0, word),
+        vec![
+            Instruction::I32Load(2, 8),
+            Instruction::GetLocal(bit),
+            Instruction::I32ShrU,
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::I64ExtendUI32,
+            Instruction::SetLocal(carry),
+        ],
+        for_each_index(
+            index,
+            len,
+            [
+                limb_address(remainder, index),
+                limb_address(remainder, index),
+                vec![
+                    Instruction::I64Load32U(2, 8),
+                    Instruction::I64Const(1),
+                    Instruction::I64Shl,
+                    Instruction::GetLocal(carry),
+                    Instruction::I64Or,
+                    Instruction::TeeLocal(carry),
+                    Instruction::I64Store32(2, 8),
+                    Instruction::GetLocal(carry),
+                    Instruction::I64Const(32),
+                    Instruction::I64ShrU,
+                    Instruction::SetLocal(carry),
+                ],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(remainder),
+            Instruction::GetLocal(1),
+            call_runtime("$$bignum-compare", state)?,
+            Instruction::I32Const(0),
+            Instruction::I32GeS,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(remainder),
+            Instruction::GetLocal(1),
+            Instruction::GetLocal(remainder),
+            call_runtime("$$bignum-sub-into", state)?,
+            Instruction::Drop,
+        ],
+        limb_address(quotient, word),
+        limb_address(quotient, word),
+        vec![
+            Instruction::I32Load(2, 8),
+            Instruction::I32Const(1),
+            Instruction::GetLocal(bit),
+            Instruction::I32Shl,
+            Instruction::I32Or,
+            Instruction::I32Store(2, 8),
+            Instruction::End,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::GetLocal(remainder),
+            Instruction::GetLocal(quotient),
+            Instruction::GetLocal(2),
+            Instruction::Select,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$bignum-divide",
+        vec![Type::Int; 3],
+        Type::Int,
+        divide_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x")], state);
+    let negative_instructions = vec![
+        Instruction::GetLocal(0),
+        Instruction::I32Const(1),
+        Instruction::I32And,
+        Instruction::If(BlockType::Value(ValueType::I32)),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(0),
+        Instruction::I32LtS,
+        Instruction::Else,
+        Instruction::GetLocal(0),
+        Instruction::I32Load(2, 4),
+        Instruction::End,
+    ];
+    add_helper(
+        "$$int-negative",
+        vec![Type::Int],
+        Type::Bool,
+        negative_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x")], state);
+    let len = alloc_local(ValueType::I32, state);
+    let negated = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let negate_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I64Const(0),
+        ],
+        fixnum_value(0),
+        vec![
+            Instruction::I64Sub,
+            call_runtime("$$int-from-i64", state)?,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len),
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(negated),
+        ],
+        for_each_index(
+            index,
+            len,
+            [
+                limb_address(negated, index),
+                limb_address(0, index),
+                vec![Instruction::I32Load(2, 8), Instruction::I32Store(2, 8)],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(negated),
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Eqz,
+            Instruction::I32Store(2, 4),
+            // e.g. negating 2^30 gives a fixnum
+            Instruction::GetLocal(negated),
+            call_runtime("$$bignum-normalize", state)?,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-negate",
+        vec![Type::Int],
+        Type::Int,
+        negate_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x"), String::from("y")], state);
+    let a = alloc_local(ValueType::I32, state);
+    let b = alloc_local(ValueType::I32, state);
+    let sum = alloc_local(ValueType::I32, state);
+    let add_instructions = [
+        if_both_fixnums(
+            0,
+            1,
+            [
+                fixnum_value(0),
+                fixnum_value(1),
+                vec![
+                    Instruction::I64Add,
+                    call_runtime("$$int-from-i64", state)?,
+                    Instruction::Return,
+                ],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::SetLocal(a),
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::SetLocal(b),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(b),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::NoResult),
+            // if the signs are the same, add the magnitudes
+            Instruction::GetLocal(a),
+            Instruction::GetLocal(b),
+            call_runtime("$$bignum-add", state)?,
+            Instruction::SetLocal(sum),
+            Instruction::Else,
+            // otherwise, subtract the smaller magnitude from the larger one,
+            // which determines the sign of the result
+            Instruction::GetLocal(a),
+            Instruction::GetLocal(b),
+            call_runtime("$$bignum-compare", state)?,
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(a),
+            Instruction::GetLocal(b),
+            Instruction::SetLocal(a),
+            Instruction::SetLocal(b),
+            Instruction::End,
+            Instruction::GetLocal(a),
+            Instruction::GetLocal(b),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 0),
+            call_runtime("$$bignum-alloc", state)?,
+            call_runtime("$$bignum-sub-into", state)?,
+            Instruction::SetLocal(sum),
+            Instruction::End,
+            Instruction::GetLocal(sum),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Store(2, 4),
+            Instruction::GetLocal(sum),
+            call_runtime("$$bignum-normalize", state)?,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-add",
+        vec![Type::Int; 2],
+        Type::Int,
+        add_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x"), String::from("y")], state);
+    let sub_instructions = [
+        if_both_fixnums(
+            0,
+            1,
+            [
+                fixnum_value(0),
+                fixnum_value(1),
+                vec![
+                    Instruction::I64Sub,
+                    call_runtime("$$int-from-i64", state)?,
+                    Instruction::Return,
+                ],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(1),
+            call_runtime("$$int-negate", state)?,
+            call_runtime("$$int-add", state)?,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-sub",
+        vec![Type::Int; 2],
+        Type::Int,
+        sub_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x"), String::from("y")], state);
+    let a = alloc_local(ValueType::I32, state);
+    let b = alloc_local(ValueType::I32, state);
+    let product = alloc_local(ValueType::I32, state);
+    let mul_instructions = [
+        if_both_fixnums(
+            0,
+            1,
+            [
+                fixnum_value(0),
+                fixnum_value(1),
+                vec![
+                    Instruction::I64Mul,
+                    call_runtime("$$int-from-i64", state)?,
+                    Instruction::Return,
+                ],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::TeeLocal(a),
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::TeeLocal(b),
+            call_runtime("$$bignum-mul", state)?,
+            Instruction::TeeLocal(product),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(b),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Xor,
+            Instruction::I32Store(2, 4),
+            Instruction::GetLocal(product),
+            call_runtime("$$bignum-normalize", state)?,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-mul",
+        vec![Type::Int; 2],
+        Type::Int,
+        mul_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(
+        &[String::from("x"), String::from("y"), String::from("rem")],
+        state,
+    );
+    let a = alloc_local(ValueType::I32, state);
+    let b = alloc_local(ValueType::I32, state);
+    let result = alloc_local(ValueType::I32, state);
+    let divide_instructions = [
+        vec![
+            // trap when dividing by zero (the fixnum 0)
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::NoResult),
+            Instruction::Unreachable,
+            Instruction::End,
+        ],
+        if_both_fixnums(
+            0,
+            1,
+            [
+                fixnum_value(0),
+                fixnum_value(1),
+                vec![Instruction::I64RemS],
+                fixnum_value(0),
+                fixnum_value(1),
+                vec![
+                    Instruction::I64DivS,
+                    Instruction::GetLocal(2),
+                    Instruction::Select,
+                    call_runtime("$$int-from-i64", state)?,
+                    Instruction::Return,
+                ],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::TeeLocal(a),
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::TeeLocal(b),
+            Instruction::GetLocal(2),
+            call_runtime("$$bignum-divide", state)?,
+            Instruction::TeeLocal(result),
+            // the remainder has the sign of the dividend, while the quotient
+            // is negative if the signs differ
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(b),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Xor,
+            Instruction::GetLocal(2),
+            Instruction::Select,
+            Instruction::I32Store(2, 4),
+            Instruction::GetLocal(result),
+            call_runtime("$$bignum-normalize", state)?,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-divide",
+        vec![Type::Int, Type::Int, Type::Bool],
+        Type::Int,
+        divide_instructions,
+        module_builder,
+        state,
+    );
+
+    // Like `gen_instr_modulo`, the remainder is adjusted by the divisor
+    // whenever their signs differ
+    start_function(&[String::from("x"), String::from("y")], state);
+    let rem = alloc_local(ValueType::I32, state);
+    let modulo_instructions = vec![
+        Instruction::GetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::I32Const(1),
+        call_runtime("$$int-divide", state)?,
+        Instruction::TeeLocal(rem),
+        Instruction::I32Const(1),
+        Instruction::I32Ne,
+        Instruction::GetLocal(rem),
+        call_runtime("$$int-negative", state)?,
+        Instruction::GetLocal(1),
+        call_runtime("$$int-negative", state)?,
+        Instruction::I32Ne,
+        Instruction::I32And,
+        Instruction::If(BlockType::Value(ValueType::I32)),
+        Instruction::GetLocal(rem),
+        Instruction::GetLocal(1),
+        call_runtime("$$int-add", state)?,
+        Instruction::Else,
+        Instruction::GetLocal(rem),
+        Instruction::End,
+    ];
+    add_helper(
+        "$$int-modulo",
+        vec![Type::Int; 2],
+        Type::Int,
+        modulo_instructions,
+        module_builder,
+        state,
+    );
+
+    // Fixnums are ordered in the same way as their representations
+    start_function(&[String::from("x"), String::from("y")], state);
+    let a = alloc_local(ValueType::I32, state);
+    let b = alloc_local(ValueType::I32, state);
+    let order = alloc_local(ValueType::I32, state);
+    let compare_instructions = [
+        if_both_fixnums(
+            0,
+            1,
+            vec![
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32GtS,
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32LtS,
+                Instruction::I32Sub,
+                Instruction::Return,
+            ],
+        ),
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::SetLocal(a),
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            call_runtime("$$bignum-of", state)?,
+            Instruction::SetLocal(b),
+            // a negative int is smaller than any other int
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(b),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Ne,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(b),
+            Instruction::I32Load(2, 4),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::I32Sub,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::GetLocal(a),
+            Instruction::GetLocal(b),
+            call_runtime("$$bignum-compare", state)?,
+            Instruction::SetLocal(order),
+            Instruction::I32Const(0),
+            Instruction::GetLocal(order),
+            Instruction::I32Sub,
+            Instruction::GetLocal(order),
+            Instruction::GetLocal(a),
+            Instruction::I32Load(2, 4),
+            Instruction::Select,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-compare",
+        vec![Type::Int; 2],
+        Type::Int,
+        compare_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x")], state);
+    let len = alloc_local(ValueType::I32, state);
+    let magnitude = alloc_local(ValueType::I64, state);
+    let to_i64_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::If(BlockType::NoResult),
+        ],
+        fixnum_value(0),
+        vec![
+            Instruction::Return,
+            Instruction::End,
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len),
+            Instruction::I32Const(2),
+            Instruction::I32GtU,
+            Instruction::If(BlockType::NoResult),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::GetLocal(0),
+            Instruction::I64Load32U(2, 8),
+            Instruction::GetLocal(len),
+            Instruction::I32Const(2),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Value(ValueType::I64)),
+            Instruction::GetLocal(0),
+            Instruction::I64Load32U(2, 12),
+            Instruction::I64Const(32),
+            Instruction::I64Shl,
+            Instruction::Else,
+            Instruction::I64Const(0),
+            Instruction::End,
+            Instruction::I64Or,
+            Instruction::SetLocal(magnitude),
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 4),
+            Instruction::If(BlockType::Value(ValueType::I64)),
+            // the magnitude of the smallest i64 is 2^63
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Const(i64::MIN),
+            Instruction::I64GtU,
+            Instruction::If(BlockType::NoResult),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::I64Const(0),
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Sub,
+            Instruction::Else,
+            Instruction::GetLocal(magnitude),
+            Instruction::I64Const(0),
+            Instruction::I64LtS,
+            Instruction::If(BlockType::NoResult),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::GetLocal(magnitude),
+            Instruction::End,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-to-i64",
+        vec![Type::Int],
+        Type::Int64,
+        to_i64_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x")], state);
+    let val = alloc_local(ValueType::I64, state);
+    let to_i32_instructions = vec![
+        Instruction::GetLocal(0),
+        call_runtime("$$int-to-i64", state)?,
+        Instruction::TeeLocal(val),
+        Instruction::I64Const(1 << 31),
+        Instruction::I64Add,
+        Instruction::I64Const(1 << 32),
+        Instruction::I64GeU,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Unreachable,
+        Instruction::End,
+        Instruction::GetLocal(val),
+        Instruction::I32WrapI64,
+    ];
+    add_helper(
+        "$$int-to-i32",
+        vec![Type::Int],
+        Type::Int,
+        to_i32_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("x")], state);
+    let index = alloc_local(ValueType::I32, state);
+    let val = alloc_local(ValueType::F64, state);
+    let to_f64_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32ShrS,
+            Instruction::F64ConvertSI32,
+            Instruction::Return,
+            Instruction::End,
+            // add up the limbs from the most significant downwards
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::SetLocal(index),
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(index),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(index),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(index),
+            Instruction::GetLocal(val),
+            Instruction::F64Const(4294967296f64.to_bits()),
+            Instruction::F64Mul,
+        ],
+        limb_address(0, index),
+        vec![
+            Instruction::I64Load32U(2, 8),
+            Instruction::F64ConvertUI64,
+            Instruction::F64Add,
+            Instruction::SetLocal(val),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::GetLocal(val),
+            Instruction::F64Neg,
+            Instruction::GetLocal(val),
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 4),
+            Instruction::Select,
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$int-to-f64",
+        vec![Type::Int],
+        Type::Float,
+        to_f64_instructions,
+        module_builder,
+        state,
+    );
+    Ok(())
+}
+
+/// Add the functions used to print values when targeting WASI, which are
+/// called by name through `call_helper`. These are:
+/// - `$$write(ptr, len)`, which writes `len` bytes starting at `ptr` to
+///   stdout, returning the result of `fd_write`
+/// - `$$write-digits(n, width)`, which prints the unsigned i32 `n` padded
+///   with zeros to `width` digits
+/// - `$$display-int(n)`, `$$display-int64(n)`, `$$display-bool(b)`,
+///   `$$display-str(s)` and `$$display-char(c)`, which print a value using
+///   `$$write`
+///
+/// All of these return the (i32) result of `fd_write`. The memory used for
+/// printing (see `WASI_TEXT`) must already be reserved, and the int helpers
+/// must already be added.
+fn construct_wasi_helpers(
+    module_builder: &mut builder::ModuleBuilder,
+    state: &mut CodeGenerateState,
+) -> Result<(), CodeGenerateError> {
+    // fd_write is imported right after the externs
+    let fd_write_index = state.num_imports - 1;
+
+    let write_instructions = vec![
+        Instruction::I32Const(0),
+        Instruction::GetLocal(0),
+        Instruction::I32Store(0, 0),
+        Instruction::I32Const(0),
+        Instruction::GetLocal(1),
+        Instruction::I32Store(0, 4),
+        Instruction::I32Const(1), // stdout
+        Instruction::I32Const(0),
+        Instruction::I32Const(1),
+        Instruction::I32Const(8),
+        Instruction::Call(fd_write_index),
+    ];
+    start_function(&[String::from("ptr"), String::from("len")], state);
+    add_helper(
+        "$$write",
+        vec![Type::Int; 2],
+        Type::Int,
+        write_instructions,
+        module_builder,
+        state,
+    );
+
+    // Digits are written backwards from the end of the buffer, padded with
+    // zeros to at least `width` digits. `n` is treated as unsigned.
+    start_function(&[String::from("n"), String::from("width")], state);
+    let pos = alloc_local(ValueType::I32, state);
+    let digits_instructions = vec![
+        Instruction::I32Const(WASI_DIGITS_END),
+        Instruction::SetLocal(pos),
+        Instruction::Loop(BlockType::NoResult),
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(pos),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(10),
+        Instruction::I32RemU,
+        Instruction::I32Const(b'0' as i32),
+        Instruction::I32Add,
+        Instruction::I32Store8(0, 0),
+        Instruction::GetLocal(1),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::SetLocal(1),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(10),
+        Instruction::I32DivU,
+        Instruction::TeeLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::I32Const(0),
+        Instruction::I32GtS,
+        Instruction::I32Or,
+        Instruction::BrIf(0),
+        Instruction::End,
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(WASI_DIGITS_END),
+        Instruction::GetLocal(pos),
+        Instruction::I32Sub,
+        Instruction::Call(state.num_imports + state.funcs["$$write"]),
+    ];
+    add_helper(
+        "$$write-digits",
+        vec![Type::Int; 2],
+        Type::Int,
+        digits_instructions,
+        module_builder,
+        state,
+    );
+
+    // A bignum is printed by repeatedly dividing (a copy of) its magnitude by
+    // 10^9, which gives its digits in chunks of 9 from the least significant.
+    // The memory allocated for this is freed afterwards.
+    start_function(&[String::from("n")], state);
+    let heap = alloc_local(ValueType::I32, state);
+    let len = alloc_local(ValueType::I32, state);
+    let magnitude = alloc_local(ValueType::I32, state);
+    let chunks = alloc_local(ValueType::I32, state);
+    let count = alloc_local(ValueType::I32, state);
+    let index = alloc_local(ValueType::I32, state);
+    let rem = alloc_local(ValueType::I64, state);
+    let result = alloc_local(ValueType::I32, state);
+    let int_instructions = [
+        vec![
+            Instruction::GetLocal(0),
+            call_runtime("$$int-negative", state)?,
+            Instruction::If(BlockType::NoResult),
+            Instruction::I32Const(WASI_DIGITS_END - 1),
+            Instruction::I32Const(b'-' as i32),
+            Instruction::I32Store8(0, 0),
+            Instruction::I32Const(WASI_DIGITS_END - 1),
+            Instruction::I32Const(1),
+            Instruction::Call(state.num_imports + state.funcs["$$write"]),
+            Instruction::Drop,
+            Instruction::End,
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32And,
+            Instruction::If(BlockType::NoResult),
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32ShrS,
+            Instruction::SetLocal(0),
+            Instruction::I32Const(0),
+            Instruction::GetLocal(0),
+            Instruction::I32Sub,
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            Instruction::I32LtS,
+            Instruction::Select,
+            Instruction::I32Const(1),
+            Instruction::Call(state.num_imports + state.funcs["$$write-digits"]),
+            Instruction::Return,
+            Instruction::End,
+            Instruction::GetGlobal(state.heap_global),
+            Instruction::SetLocal(heap),
+            Instruction::GetLocal(0),
+            Instruction::I32Load(2, 0),
+            Instruction::TeeLocal(len),
+            Instruction::I32Const(1),
+            Instruction::I32Shl,
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(chunks),
+            Instruction::GetLocal(len),
+            call_runtime("$$bignum-alloc", state)?,
+            Instruction::SetLocal(magnitude),
+        ],
+        for_each_index(
+            index,
+            len,
+            [
+                limb_address(magnitude, index),
+                limb_address(0, index),
+                vec![Instruction::I32Load(2, 8), Instruction::I32Store(2, 8)],
+            ]
+            .concat(),
+        ),
+        vec![
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(len),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            // divide the magnitude by 10^9 in place, from the most
+            // significant limb downwards
+            Instruction::I64Const(0),
+            Instruction::SetLocal(rem),
+            Instruction::GetLocal(len),
+            Instruction::SetLocal(index),
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(index),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(index),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(index),
+            Instruction::GetLocal(rem),
+            Instruction::I64Const(32),
+            Instruction::I64Shl,
+        ],
+        limb_address(magnitude, index),
+        vec![
+            Instruction::I64Load32U(2, 8),
+            Instruction::I64Or,
+            Instruction::SetLocal(rem),
+        ],
+        limb_address(magnitude, index),
+        vec![
+            Instruction::GetLocal(rem),
+            Instruction::I64Const(1_000_000_000),
+            Instruction::I64DivU,
+            Instruction::I64Store32(2, 8),
+            Instruction::GetLocal(rem),
+            Instruction::I64Const(1_000_000_000),
+            Instruction::I64RemU,
+            Instruction::SetLocal(rem),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+        ],
+        limb_address(chunks, count),
+        vec![
+            Instruction::GetLocal(rem),
+            Instruction::I64Store32(2, 8),
+            Instruction::GetLocal(count),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::SetLocal(count),
+            // drop the limbs which have become zero
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(len),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(magnitude),
+            Instruction::GetLocal(len),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::I32Add,
+            Instruction::I32Load(2, 4),
+            Instruction::BrIf(1),
+            Instruction::GetLocal(len),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(len),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            // the most significant chunk isn't padded
+            Instruction::GetLocal(count),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(count),
+        ],
+        limb_address(chunks, count),
+        vec![
+            Instruction::I32Load(2, 8),
+            Instruction::I32Const(1),
+            Instruction::Call(state.num_imports + state.funcs["$$write-digits"]),
+            Instruction::SetLocal(result),
+            Instruction::Block(BlockType::NoResult),
+            Instruction::Loop(BlockType::NoResult),
+            Instruction::GetLocal(count),
+            Instruction::I32Eqz,
+            Instruction::BrIf(1),
+            Instruction::GetLocal(count),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::SetLocal(count),
+        ],
+        limb_address(chunks, count),
+        vec![
+            Instruction::I32Load(2, 8),
+            Instruction::I32Const(9),
+            Instruction::Call(state.num_imports + state.funcs["$$write-digits"]),
+            Instruction::SetLocal(result),
+            Instruction::Br(0),
+            Instruction::End,
+            Instruction::End,
+            Instruction::GetLocal(heap),
+            Instruction::SetGlobal(state.heap_global),
+            Instruction::GetLocal(result),
+        ],
+    ]
+    .concat();
+    add_helper(
+        "$$display-int",
+        vec![Type::Int],
+        Type::Int,
+        int_instructions,
+        module_builder,
+        state,
+    );
+
+    // Like `$$write-digits`, but for signed 64-bit ints
+    start_function(&[String::from("n")], state);
+    let pos = alloc_local(ValueType::I32, state);
+    let negative = alloc_local(ValueType::I32, state);
+    let int64_instructions = vec![
+        Instruction::I32Const(WASI_DIGITS_END),
+        Instruction::SetLocal(pos),
+        Instruction::GetLocal(0),
+        Instruction::I64Const(0),
+        Instruction::I64LtS,
+        Instruction::TeeLocal(negative),
+        Instruction::If(BlockType::NoResult),
+        Instruction::I64Const(0),
+        Instruction::GetLocal(0),
+        Instruction::I64Sub,
+        Instruction::SetLocal(0),
+        Instruction::End,
+        Instruction::Loop(BlockType::NoResult),
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(pos),
+        Instruction::GetLocal(0),
+        Instruction::I64Const(10),
+        Instruction::I64RemU,
+        Instruction::I32WrapI64,
+        Instruction::I32Const(b'0' as i32),
+        Instruction::I32Add,
+        Instruction::I32Store8(0, 0),
+        Instruction::GetLocal(0),
+        Instruction::I64Const(10),
+        Instruction::I64DivU,
+        Instruction::TeeLocal(0),
+        Instruction::I64Const(0),
+        Instruction::I64Ne,
+        Instruction::BrIf(0),
+        Instruction::End,
+        Instruction::GetLocal(negative),
+        Instruction::If(BlockType::NoResult),
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(pos),
+        Instruction::I32Const(b'-' as i32),
+        Instruction::I32Store8(0, 0),
+        Instruction::End,
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(WASI_DIGITS_END),
+        Instruction::GetLocal(pos),
+        Instruction::I32Sub,
+        Instruction::Call(state.num_imports + state.funcs["$$write"]),
+    ];
+    add_helper(
+        "$$display-int64",
+        vec![Type::Int64],
+        Type::Int,
+        int64_instructions,
+        module_builder,
+        state,
+    );
+
+    start_function(&[String::from("b")], state);
+    let bool_instructions = vec![
+        Instruction::I32Const(WASI_TRUE.0),
+        Instruction::I32Const(WASI_FALSE.0),
+        Instruction::GetLocal(0),
+        Instruction::Select,
+        Instruction::I32Const(WASI_TRUE.1),
+        Instruction::I32Const(WASI_FALSE.1),
+        Instruction::GetLocal(0),
+        Instruction::Select,
+        Instruction::Call(state.num_imports + state.funcs["$$write"]),
+    ];
+    add_helper(
+        "$$display-bool",
+        vec![Type::Bool],
+        Type::Int,
+        bool_instructions,
+        module_builder,
+        state,
+    );
+
+    // See `gen_instr_str` for how strings are stored
+    start_function(&[String::from("s")], state);
+    let str_instructions = vec![
+        Instruction::GetLocal(0),
+        Instruction::I32Const(4),
+        Instruction::I32Add,
+        Instruction::GetLocal(0),
+        Instruction::I32Load(0, 0),
+        Instruction::Call(state.num_imports + state.funcs["$$write"]),
+    ];
+    add_helper(
+        "$$display-str",
+        vec![Type::Str],
+        Type::Int,
+        str_instructions,
+        module_builder,
+        state,
+    );
+
+    // Chars are encoded as UTF-8 in the digits buffer. The continuation bytes
+    // are written backwards from the end of the buffer (six bits at a time),
+    // then the first byte, whose prefix depends on the number of bytes.
+    start_function(&[String::from("c")], state);
+    let len = alloc_local(ValueType::I32, state);
+    let rest = alloc_local(ValueType::I32, state);
+    let pos = alloc_local(ValueType::I32, state);
+    let char_instructions = vec![
+        Instruction::GetLocal(0),
+        Instruction::I32Const(0x80),
+        Instruction::I32GeU,
+        Instruction::GetLocal(0),
+        Instruction::I32Const(0x800),
+        Instruction::I32GeU,
+        Instruction::I32Add,
+        Instruction::GetLocal(0),
+        Instruction::I32Const(0x10000),
+        Instruction::I32GeU,
+        Instruction::I32Add,
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::TeeLocal(len),
+        Instruction::SetLocal(rest),
+        Instruction::I32Const(WASI_DIGITS_END),
+        Instruction::SetLocal(pos),
+        Instruction::Block(BlockType::NoResult),
+        Instruction::Loop(BlockType::NoResult),
+        Instruction::GetLocal(rest),
+        Instruction::I32Const(1),
+        Instruction::I32LeU,
+        Instruction::BrIf(1),
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(pos),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(0x3F),
+        Instruction::I32And,
+        Instruction::I32Const(0x80),
+        Instruction::I32Or,
+        Instruction::I32Store8(0, 0),
+        Instruction::GetLocal(0),
+        Instruction::I32Const(6),
+        Instruction::I32ShrU,
+        Instruction::SetLocal(0),
+        Instruction::GetLocal(rest),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::SetLocal(rest),
+        Instruction::Br(0),
+        Instruction::End,
+        Instruction::End,
+        Instruction::GetLocal(pos),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::TeeLocal(pos),
+        Instruction::GetLocal(0),
+        // the prefix has `len` leading ones, unless the char is ASCII
+        Instruction::I32Const(0xFF00),
+        Instruction::GetLocal(len),
+        Instruction::I32ShrU,
+        Instruction::I32Const(0xFF),
+        Instruction::I32And,
+        Instruction::I32Const(0),
+        Instruction::GetLocal(len),
+        Instruction::I32Const(1),
+        Instruction::I32Ne,
+        Instruction::Select,
+        Instruction::I32Or,
+        Instruction::I32Store8(0, 0),
+        Instruction::GetLocal(pos),
+        Instruction::GetLocal(len),
+        Instruction::Call(state.num_imports + state.funcs["$$write"]),
+    ];
+    add_helper(
+        "$$display-char",
+        vec![Type::Char],
+        Type::Int,
+        char_instructions,
+        module_builder,
+        state,
+    );
+    Ok(())
+}
+
+/// Add a helper function to the module, which can then be called by name.
+fn add_helper(
+    name: &str,
+    param_types: Vec<Type>,
+    ret_type: Type,
+    instructions: Vec<Instruction>,
+    module_builder: &mut builder::ModuleBuilder,
+    state: &mut CodeGenerateState,
+) {
+    let helper_function = construct_function(
+        param_types,
+        ret_type,
+        Instructions::new(instructions),
+        state,
+    );
     let func_index = state.funcs.len() as u32;
-    Ok(module_builder
-        .function()
-        .signature()
-        .with_params(vec![])
-        .with_result(ValueType::I32)
-        .build()
-        .body()
-        .with_locals(wasm_locals)
-        .with_instructions(Instructions::new(main_instructions))
-        .build()
-        .build()
-        .export()
-        .field("$$MAIN$$")
-        .internal()
-        .func(func_index)
-        .build()
-        .build())
+    state.funcs.insert(name.to_string(), func_index);
+    module_builder.push_function(helper_function);
+}
+
+/// Add data segments to a module which place constant data (see
+/// `CodeGenerateState`) at the given indices of linear memory.
+fn with_data_segments(
+    mut module_builder: builder::ModuleBuilder,
+    data: Vec<(u32, Vec<u8>)>,
+) -> builder::ModuleBuilder {
+    for (data_idx, bytes) in data {
+        module_builder = module_builder.with_data_segment(DataSegment::new(
+            0,
+            Some(InitExpr::new(vec![
+                Instruction::I32Const(data_idx as i32),
+                Instruction::End,
+            ])),
+            bytes,
+        ));
+    }
+    module_builder
+}
+
+/// Generate instructions which convert the value on top of the stack, of
+/// type `typ`, into the form it is passed to the host environment in.
+///
+/// Ints are passed to the host as plain i32s rather than tagged fixnums, and
+/// trap if they don't fit in an i32. All other values are passed as they
+/// are.
+fn gen_instr_to_host(
+    typ: &Type,
+    state: &CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    match typ {
+        Type::Int => Ok(vec![call_runtime("$$int-to-i32", state)?]),
+        _ => Ok(vec![]),
+    }
+}
+
+/// Generate instructions which convert a value of type `typ` passed by the
+/// host environment (see `gen_instr_to_host`) into the form the program uses.
+///
+/// Any i32 passed for an int is valid, and becomes a bignum if it doesn't fit
+/// in a fixnum.
+fn gen_instr_from_host(
+    typ: &Type,
+    state: &CodeGenerateState,
+) -> Result<Vec<Instruction>, CodeGenerateError> {
+    match typ {
+        Type::Int => Ok(vec![
+            Instruction::I64ExtendSI32,
+            call_runtime("$$int-from-i64", state)?,
+        ]),
+        _ => Ok(vec![]),
+    }
 }
 
 /// Construct a WebAssembly `FunctionDefinition`, a format for a function which
@@ -727,14 +4282,28 @@ pub fn construct_module_from_prog(prog: &Prog<TypedExpr>) -> Result<Module, Code
 /// a closing `Instruction::End` instruction.
 fn construct_function(
     param_types: Vec<Type>,
+    ret_type: Type,
     mut instructions: Instructions,
     state: &mut CodeGenerateState,
 ) -> builder::FunctionDefinition {
     // Construct the list of WebAssembly parameter types
-    let wasm_param_types = std::iter::repeat_n(ValueType::I32, param_types.len())
+    let wasm_param_types = param_types
+        .iter()
+        .map(wasm_type)
         .collect::<Vec<ValueType>>();
 
-    let wasm_locals = construct_locals(&state.locals);
+    let wasm_locals = construct_locals(&state.local_types);
+
+    if state.tail_calls {
+        mark_tail_calls(instructions.elements_mut());
+    }
+
+    // Charge the function's fuel before running its body (and each loop's
+    // fuel before each iteration)
+    if let Some(fuel_index) = state.fuel_global {
+        let metered = meter_instructions(instructions.elements(), fuel_index);
+        *instructions.elements_mut() = metered;
+    }
 
     // Add the required end instruction
     instructions.elements_mut().push(Instruction::End);
@@ -743,7 +4312,7 @@ fn construct_function(
     builder::function()
         .signature()
         .with_params(wasm_param_types)
-        .with_result(ValueType::I32)
+        .with_result(wasm_type(&ret_type))
         .build()
         .body()
         .with_locals(wasm_locals)
@@ -752,12 +4321,119 @@ fn construct_function(
         .build()
 }
 
+/// Mark the calls in tail position within the body of a function (without
+/// its closing `Instruction::End`), whose results are returned by the
+/// function as soon as they finish, by putting a `Nop` in front of them.
+/// These are serialized as `return_call` instructions, which replace the
+/// function's stack frame with the callee's (see `encode::serialize_module`).
+fn mark_tail_calls(instructions: &mut Vec<Instruction>) {
+    let tail_calls: Vec<usize> = (0..instructions.len())
+        .filter(|&index| {
+            matches!(
+                instructions[index],
+                Instruction::Call(_) | Instruction::CallIndirect(_, _)
+            ) && is_tail_position(instructions, index + 1)
+        })
+        .collect();
+    for index in tail_calls.into_iter().rev() {
+        instructions.insert(index, Instruction::Nop);
+    }
+}
+
+/// Check whether the function returns as soon as control reaches the given
+/// index, i.e. the rest of the instructions run are only the ends of
+/// blocks.
+fn is_tail_position(instructions: &[Instruction], mut index: usize) -> bool {
+    while index < instructions.len() {
+        match instructions[index] {
+            Instruction::End => index += 1,
+            // the end of a consequent skips the alternate
+            Instruction::Else => {
+                let mut depth = 0;
+                loop {
+                    index += 1;
+                    match instructions.get(index) {
+                        Some(Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_)) => {
+                            depth += 1
+                        }
+                        Some(Instruction::End) if depth == 0 => break,
+                        Some(Instruction::End) => depth -= 1,
+                        Some(_) => {}
+                        None => return false,
+                    }
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Instructions which subtract `cost` from the fuel in the global
+/// `fuel_index`, trapping if it has run out.
+fn charge_fuel(fuel_index: u32, cost: i32) -> Vec<Instruction> {
+    vec![
+        Instruction::GetGlobal(fuel_index),
+        Instruction::I32Const(cost),
+        Instruction::I32Sub,
+        Instruction::SetGlobal(fuel_index),
+        Instruction::GetGlobal(fuel_index),
+        Instruction::I32Const(0),
+        Instruction::I32LtS,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Unreachable,
+        Instruction::End,
+    ]
+}
+
+/// Add fuel metering to the body of a function (without its closing
+/// `Instruction::End`).
+///
+/// The function is charged for the instructions outside of any loop when it
+/// is called, and each loop is charged for the instructions in its body
+/// (outside of any nested loop) at the start of each iteration, so that the
+/// fuel used is roughly the number of instructions run.
+fn meter_instructions(instructions: &[Instruction], fuel_index: u32) -> Vec<Instruction> {
+    // costs[0] is the cost of the function itself, followed by the cost of
+    // each loop in the order they start
+    let mut costs = vec![0];
+    // the index (into costs) of the cost which each enclosing block adds to
+    let mut block_stack: Vec<usize> = vec![];
+    for instruction in instructions {
+        let current = *block_stack.last().unwrap_or(&0);
+        costs[current] += 1;
+        match instruction {
+            Instruction::Loop(_) => {
+                block_stack.push(costs.len());
+                costs.push(0);
+            }
+            Instruction::Block(_) | Instruction::If(_) => block_stack.push(current),
+            Instruction::End => {
+                block_stack.pop();
+            }
+            _ => {}
+        }
+    }
+    let mut metered = charge_fuel(fuel_index, costs[0]);
+    let mut loop_costs = costs[1..].iter();
+    for instruction in instructions {
+        metered.push(instruction.clone());
+        if let Instruction::Loop(_) = instruction {
+            metered.append(&mut charge_fuel(fuel_index, *loop_costs.next().unwrap()));
+        }
+    }
+    metered
+}
+
 /// The `state` argument passed around by the code generation functions will
-/// track any local variables that were needed during compilation (e.g. let
-/// expressions will generate local variables). These need to be converted
-/// into a format accepted by the `parity_wasm` library's `FunctionBuilder`
-/// API.
-fn construct_locals(locals: &LocalsMap) -> Vec<Local> {
-    std::iter::repeat_n(Local::new(1, ValueType::I32), locals.len())
+/// track the types of the local variables which were needed during
+/// compilation (e.g. let expressions will generate local variables), not
+/// counting the function's parameters. These need to be converted into a
+/// format accepted by the `parity_wasm` library's `FunctionBuilder` API,
+/// where each run of locals with the same type is declared together.
+fn construct_locals(local_types: &[ValueType]) -> Vec<Local> {
+    local_types
+        .chunk_by(|a, b| a == b)
+        .map(|run| Local::new(run.len() as u32, run[0]))
         .collect::<Vec<Local>>()
 }
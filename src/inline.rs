@@ -0,0 +1,362 @@
+use crate::analysis::{assigned_vars, bound_vars, children, expr_size, free_vars};
+use crate::closure_convert::substitute;
+use crate::common::{Expr, ExprKind};
+
+use im_rc::{HashMap, Vector};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug)]
+pub struct InlineError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for InlineError {}
+
+impl From<&str> for InlineError {
+    fn from(message: &str) -> Self {
+        InlineError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for InlineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "InlineError: {}", self.0)
+    }
+}
+
+/// The lambdas which may be inlined at call sites, keyed by the variable they
+/// are bound to.
+type Candidates = HashMap<String, Expr>;
+
+/// Information about the whole expression being inlined.
+struct Context {
+    /// The largest size of a let-bound lambda's body for it to be inlined.
+    threshold: usize,
+    /// The names of variables which are assigned to anywhere, including
+    /// global definitions (which are initialized with set!).
+    assigned: BTreeSet<String>,
+    /// The names of local variables bound anywhere.
+    locals: BTreeSet<String>,
+}
+
+/// Inline functions into the places they are called, before closure
+/// conversion.
+///
+/// Immediately applied lambdas such as `((lambda ((x : int)) : int (+ x 3))
+/// 5)` are always beta-reduced, and lambdas bound by a let whose size (in
+/// number of AST nodes, see `analysis::expr_size`) is at most `threshold` are
+/// copied into each place they are called. Let-bound lambdas which are no
+/// longer referenced afterwards are removed.
+///
+/// Arguments which are constants, or local variables which are never
+/// reassigned, are substituted directly into the function body, while all
+/// other arguments are bound with a let so that they are still evaluated
+/// exactly once, in order (a global or a reassigned variable may be changed
+/// by a call in the body). Functions which are reassigned with set! or which
+/// refer to reassigned variables are never inlined.
+///
+/// The expression should already have been type checked.
+pub fn inline_exp(exp: &Expr, threshold: usize) -> Result<Expr, InlineError> {
+    let ctx = Context {
+        threshold,
+        assigned: assigned_vars(exp),
+        locals: bound_vars(exp),
+    };
+    inline(exp, &Candidates::new(), &ctx)
+}
+
+fn inline_array(
+    exps: &Vector<Expr>,
+    candidates: &Candidates,
+    ctx: &Context,
+) -> Result<Vector<Expr>, InlineError> {
+    exps.iter()
+        .map(|exp| inline(exp, candidates, ctx))
+        .collect()
+}
+
+fn inline(exp: &Expr, candidates: &Candidates, ctx: &Context) -> Result<Expr, InlineError> {
+    match &*exp.kind {
+        ExprKind::Num(_) | ExprKind::Int64(_) | ExprKind::Float(_) => Ok(exp.clone()),
+        ExprKind::Bool(_) | ExprKind::Str(_) | ExprKind::Id(_) => Ok(exp.clone()),
+        ExprKind::Char(_) | ExprKind::Symbol(_) | ExprKind::Quote(_) => Ok(exp.clone()),
+        ExprKind::Null(_) | ExprKind::Newline => Ok(exp.clone()),
+        ExprKind::Prim(op, args) => Ok(Expr::new(ExprKind::Prim(
+            *op,
+            inline_array(args, candidates, ctx)?,
+        ))),
+        ExprKind::If(pred, cons, alt) => Ok(Expr::new(ExprKind::If(
+            inline(pred, candidates, ctx)?,
+            inline(cons, candidates, ctx)?,
+            inline(alt, candidates, ctx)?,
+        ))),
+        ExprKind::Let(bindings, body) => inline_let(bindings, body, candidates, ctx),
+        // recursive functions are never inlined, since inlining a call to one
+        // would leave another call to it behind
+        ExprKind::Letrec(bindings, body) => {
+            let names: Vector<String> = bindings.iter().map(|pair| pair.0.clone()).collect();
+            let rec_candidates = shadow(candidates, &names);
+            let ibindings = bindings
+                .iter()
+                .map(|(name, exp)| Ok((name.clone(), inline(exp, &rec_candidates, ctx)?)))
+                .collect::<Result<Vector<(String, Expr)>, InlineError>>()?;
+            Ok(Expr::new(ExprKind::Letrec(
+                ibindings,
+                inline(body, &rec_candidates, ctx)?,
+            )))
+        }
+        ExprKind::Lambda(params, ret_type, body) => {
+            let names: Vector<String> = params.iter().map(|pair| pair.0.clone()).collect();
+            let body_candidates = shadow(candidates, &names);
+            Ok(Expr::new(ExprKind::Lambda(
+                params.clone(),
+                ret_type.clone(),
+                inline(body, &body_candidates, ctx)?,
+            )))
+        }
+        ExprKind::Begin(exps) => Ok(Expr::new(ExprKind::Begin(inline_array(
+            exps, candidates, ctx,
+        )?))),
+        ExprKind::Set(var, val) => Ok(Expr::new(ExprKind::Set(
+            var.clone(),
+            inline(val, candidates, ctx)?,
+        ))),
+        ExprKind::Cons(first, rest) => Ok(Expr::new(ExprKind::Cons(
+            inline(first, candidates, ctx)?,
+            inline(rest, candidates, ctx)?,
+        ))),
+        ExprKind::Car(pair) => Ok(Expr::new(ExprKind::Car(inline(pair, candidates, ctx)?))),
+        ExprKind::Cdr(pair) => Ok(Expr::new(ExprKind::Cdr(inline(pair, candidates, ctx)?))),
+        ExprKind::IsNull(list) => Ok(Expr::new(ExprKind::IsNull(inline(list, candidates, ctx)?))),
+        ExprKind::Display(val) => Ok(Expr::new(ExprKind::Display(inline(val, candidates, ctx)?))),
+        ExprKind::FnApp(func, args) => inline_fn_app(func, args, candidates, ctx),
+        ExprKind::Tuple(exps) => Ok(Expr::new(ExprKind::Tuple(inline_array(
+            exps, candidates, ctx,
+        )?))),
+        ExprKind::TupleGet(tuple, key) => Ok(Expr::new(ExprKind::TupleGet(
+            inline(tuple, candidates, ctx)?,
+            *key,
+        ))),
+        ExprKind::TupleSet(tuple, key, val) => Ok(Expr::new(ExprKind::TupleSet(
+            inline(tuple, candidates, ctx)?,
+            *key,
+            inline(val, candidates, ctx)?,
+        ))),
+        ExprKind::Pack(val, sub, exist) => Ok(Expr::new(ExprKind::Pack(
+            inline(val, candidates, ctx)?,
+            sub.clone(),
+            exist.clone(),
+        ))),
+        ExprKind::Unpack(var, package, type_var, body) => {
+            let body_candidates = shadow(candidates, &Vector::unit(var.clone()));
+            Ok(Expr::new(ExprKind::Unpack(
+                var.clone(),
+                inline(package, candidates, ctx)?,
+                *type_var,
+                inline(body, &body_candidates, ctx)?,
+            )))
+        }
+        ExprKind::Raise(exn, typ) => Ok(Expr::new(ExprKind::Raise(
+            inline(exn, candidates, ctx)?,
+            typ.clone(),
+        ))),
+        ExprKind::Handle(var, typ, handler, body) => {
+            let handler_candidates = shadow(candidates, &Vector::unit(var.clone()));
+            Ok(Expr::new(ExprKind::Handle(
+                var.clone(),
+                typ.clone(),
+                inline(handler, &handler_candidates, ctx)?,
+                inline(body, candidates, ctx)?,
+            )))
+        }
+        ExprKind::CallCC(func) => Ok(Expr::new(ExprKind::CallCC(inline(func, candidates, ctx)?))),
+        ExprKind::Throw(cont, val, typ) => Ok(Expr::new(ExprKind::Throw(
+            inline(cont, candidates, ctx)?,
+            inline(val, candidates, ctx)?,
+            typ.clone(),
+        ))),
+        ExprKind::MakeGenerator(typ, body) => {
+            // a function which yields would yield from this generator instead
+            // of the one it was defined in if it were inlined here
+            let body_candidates: Candidates = candidates
+                .clone()
+                .into_iter()
+                .filter(|(_name, func)| !contains_yield(func))
+                .collect();
+            Ok(Expr::new(ExprKind::MakeGenerator(
+                typ.clone(),
+                inline(body, &body_candidates, ctx)?,
+            )))
+        }
+        ExprKind::Yield(val) => Ok(Expr::new(ExprKind::Yield(inline(val, candidates, ctx)?))),
+        ExprKind::Next(generator) => Ok(Expr::new(ExprKind::Next(inline(
+            generator, candidates, ctx,
+        )?))),
+        ExprKind::Record(bindings) => {
+            let ibindings = bindings
+                .iter()
+                .map(|(name, exp, mutable)| {
+                    Ok((name.clone(), inline(exp, candidates, ctx)?, *mutable))
+                })
+                .collect::<Result<Vector<(String, Expr, bool)>, InlineError>>()?;
+            Ok(Expr::new(ExprKind::Record(ibindings)))
+        }
+        ExprKind::RecordGet(record, key) => Ok(Expr::new(ExprKind::RecordGet(
+            inline(record, candidates, ctx)?,
+            key.clone(),
+        ))),
+        ExprKind::RecordSet(record, key, val) => Ok(Expr::new(ExprKind::RecordSet(
+            inline(record, candidates, ctx)?,
+            key.clone(),
+            inline(val, candidates, ctx)?,
+        ))),
+        ExprKind::Derived(_, _) => Err(InlineError::from(
+            "Derived forms should have been removed by type checking.",
+        )),
+    }
+}
+
+/// Returns the candidates which are still valid inside the scope of new
+/// bindings for `names`. This removes the candidates bound to those names,
+/// as well as those which refer to them (since copying such a lambda into
+/// the scope would change what its free variables refer to).
+fn shadow(candidates: &Candidates, names: &Vector<String>) -> Candidates {
+    candidates
+        .iter()
+        .filter(|(name, lambda)| {
+            let lambda_vars = free_vars(*lambda);
+            !names
+                .iter()
+                .any(|bound| bound == *name || lambda_vars.contains(bound))
+        })
+        .map(|(name, lambda)| (name.clone(), lambda.clone()))
+        .collect()
+}
+
+fn inline_let(
+    bindings: &Vector<(String, Expr)>,
+    body: &Expr,
+    candidates: &Candidates,
+    ctx: &Context,
+) -> Result<Expr, InlineError> {
+    let ibindings = bindings
+        .iter()
+        .map(|(name, exp)| Ok((name.clone(), inline(exp, candidates, ctx)?)))
+        .collect::<Result<Vector<(String, Expr)>, InlineError>>()?;
+
+    let names: Vector<String> = bindings.iter().map(|pair| pair.0.clone()).collect();
+    let mut body_candidates = shadow(candidates, &names);
+    let body_assigned = assigned_vars(body);
+    let mut inlined = BTreeSet::new();
+    for (name, exp) in ibindings.iter() {
+        // a lambda which assigns to a variable it captures can't be copied
+        // either, since each copy would assign to its own variable
+        let mut assigned = body_assigned.clone();
+        assigned.extend(assigned_vars(exp));
+        if let ExprKind::Lambda(_params, _ret_type, lambda_body) = &*exp.kind
+            && expr_size(lambda_body) <= ctx.threshold
+            && !assigned.contains(name)
+        {
+            let lambda_vars = free_vars(exp);
+            let captures_binding = names.iter().any(|bound| lambda_vars.contains(bound));
+            let refers_to_assigned = lambda_vars.iter().any(|var| assigned.contains(var));
+            if !captures_binding && !refers_to_assigned {
+                body_candidates.insert(name.clone(), exp.clone());
+                inlined.insert(name.clone());
+            }
+        }
+    }
+
+    let ibody = inline(body, &body_candidates, ctx)?;
+
+    // lambdas are pure, so bindings of inlined lambdas can be dropped once
+    // nothing refers to them anymore
+    let body_vars = free_vars(&ibody);
+    let remaining: Vector<(String, Expr)> = ibindings
+        .into_iter()
+        .filter(|(name, _exp)| !inlined.contains(name) || body_vars.contains(name))
+        .collect();
+    if remaining.is_empty() {
+        Ok(ibody)
+    } else {
+        Ok(Expr::new(ExprKind::Let(remaining, ibody)))
+    }
+}
+
+fn inline_fn_app(
+    func: &Expr,
+    args: &Vector<Expr>,
+    candidates: &Candidates,
+    ctx: &Context,
+) -> Result<Expr, InlineError> {
+    let ifunc = inline(func, candidates, ctx)?;
+    let iargs = inline_array(args, candidates, ctx)?;
+    let lambda = match &*ifunc.kind {
+        ExprKind::Id(name) => candidates.get(name).cloned(),
+        ExprKind::Lambda(..) => Some(ifunc.clone()),
+        _ => None,
+    };
+    match lambda {
+        Some(lambda) => {
+            let reduced = beta_reduce(&lambda, &iargs, ctx)?;
+            // the arguments may have been let-bound lambdas which can now be
+            // inlined themselves
+            inline(&reduced, candidates, ctx)
+        }
+        None => Ok(Expr::new(ExprKind::FnApp(ifunc, iargs))),
+    }
+}
+
+/// Apply a lambda to a list of arguments at compile time.
+///
+/// An argument is substituted into the body if it is a constant, or a local
+/// variable which is never reassigned (see `Context`). All other arguments
+/// are bound to their parameters with a let.
+fn beta_reduce(lambda: &Expr, args: &Vector<Expr>, ctx: &Context) -> Result<Expr, InlineError> {
+    let (params, body) = match &*lambda.kind {
+        ExprKind::Lambda(params, _ret_type, body) => (params, body),
+        _ => return Err(InlineError::from("Expected a lambda to beta-reduce.")),
+    };
+    if params.len() != args.len() {
+        return Err(InlineError::from(
+            "Number of arguments does not match number of parameters.",
+        ));
+    }
+
+    let mut assigned = assigned_vars(body);
+    for arg in args {
+        assigned.extend(assigned_vars(arg));
+    }
+    assigned.extend(ctx.assigned.iter().cloned());
+    let param_names: Vector<String> = params.iter().map(|pair| pair.0.clone()).collect();
+
+    let mut new_body = body.clone();
+    let mut bindings = Vector::new();
+    for ((param, _typ), arg) in params.iter().zip(args.iter()) {
+        let substitutable = match &*arg.kind {
+            ExprKind::Num(_) | ExprKind::Int64(_) | ExprKind::Float(_) => true,
+            ExprKind::Bool(_) | ExprKind::Str(_) => true,
+            ExprKind::Char(_) | ExprKind::Symbol(_) | ExprKind::Quote(_) => true,
+            ExprKind::Id(var) => {
+                ctx.locals.contains(var)
+                    && !assigned.contains(var)
+                    && (var == param || !param_names.contains(var))
+            }
+            _ => false,
+        };
+        if substitutable && let Ok(sbody) = substitute(&new_body, param, arg) {
+            new_body = sbody;
+        } else {
+            bindings.push_back((param.clone(), arg.clone()));
+        }
+    }
+
+    if bindings.is_empty() {
+        Ok(new_body)
+    } else {
+        Ok(Expr::new(ExprKind::Let(bindings, new_body)))
+    }
+}
+
+fn contains_yield(exp: &Expr) -> bool {
+    matches!(&*exp.kind, ExprKind::Yield(_)) || children(exp).into_iter().any(contains_yield)
+}
@@ -31,13 +31,17 @@ fn ll_array(
 fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftError> {
     match &*exp.kind {
         ExprKind::Num(_) => Ok(exp.clone()),
+        ExprKind::Int64(_) => Ok(exp.clone()),
+        ExprKind::Float(_) => Ok(exp.clone()),
         ExprKind::Bool(_) => Ok(exp.clone()),
         ExprKind::Str(_) => Ok(exp.clone()),
+        ExprKind::Char(_) => Ok(exp.clone()),
+        ExprKind::Symbol(_) => Ok(exp.clone()),
+        ExprKind::Quote(_) => Ok(exp.clone()),
         ExprKind::Id(_) => Ok(exp.clone()),
-        ExprKind::Binop(op, exp1, exp2) => {
-            let lexp1 = ll(exp1, fns)?;
-            let lexp2 = ll(exp2, fns)?;
-            Ok(Expr::new(ExprKind::Binop(*op, lexp1, lexp2)))
+        ExprKind::Prim(op, args) => {
+            let largs = ll_array(args, fns)?;
+            Ok(Expr::new(ExprKind::Prim(*op, largs)))
         }
         ExprKind::If(pred, cons, alt) => {
             let lpred = ll(pred, fns)?;
@@ -56,10 +60,31 @@ fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftEr
             let lbody = ll(body, fns)?;
             Ok(Expr::new(ExprKind::Let(lbindings, lbody)))
         }
+        // the lambdas of a letrec are already closed (see
+        // `closure_convert::cc_letrec`), so they are lifted under the names
+        // they are bound to
+        ExprKind::Letrec(bindings, body) => {
+            for (name, binding) in bindings {
+                match &*binding.kind {
+                    ExprKind::Lambda(params, ret_typ, lambda_body) => {
+                        let lbody = ll(lambda_body, fns)?;
+                        let new_lambda =
+                            Expr::new(ExprKind::Lambda(params.clone(), ret_typ.clone(), lbody));
+                        fns.push_back((name.clone(), new_lambda));
+                    }
+                    _ => {
+                        return Err(LambdaLiftError(format!(
+                            "Letrec binding {name} is not a lambda."
+                        )));
+                    }
+                }
+            }
+            ll(body, fns)
+        }
         ExprKind::Lambda(params, ret_typ, body) => {
             let lbody = ll(body, fns)?;
-            let new_lambda = Expr::new(ExprKind::Lambda(params.clone(), ret_typ.clone(), lbody));
             let func_name = generate_func_name();
+            let new_lambda = Expr::new(ExprKind::Lambda(params.clone(), ret_typ.clone(), lbody));
             fns.push_back((func_name.clone(), new_lambda));
             Ok(Expr::new(ExprKind::Id(func_name)))
         }
@@ -73,15 +98,20 @@ fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftEr
                 .iter()
                 .map(|binding| {
                     let lexp = ll(&binding.1, fns)?;
-                    Ok((binding.0.clone(), lexp))
+                    Ok((binding.0.clone(), lexp, binding.2))
                 })
-                .collect::<Result<Vector<(String, Expr)>, LambdaLiftError>>()?;
+                .collect::<Result<Vector<(String, Expr, bool)>, LambdaLiftError>>()?;
             Ok(Expr::new(ExprKind::Record(lbindings)))
         }
         ExprKind::RecordGet(record, key) => {
             let lrecord = ll(record, fns)?;
             Ok(Expr::new(ExprKind::RecordGet(lrecord, key.clone())))
         }
+        ExprKind::RecordSet(record, key, val) => {
+            let lrecord = ll(record, fns)?;
+            let lval = ll(val, fns)?;
+            Ok(Expr::new(ExprKind::RecordSet(lrecord, key.clone(), lval)))
+        }
         ExprKind::Begin(exps) => {
             let lexps = ll_array(exps, fns)?;
             Ok(Expr::new(ExprKind::Begin(lexps)))
@@ -108,6 +138,11 @@ fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftEr
             Ok(Expr::new(ExprKind::IsNull(lexp)))
         }
         ExprKind::Null(_typ) => Ok(exp.clone()),
+        ExprKind::Display(exp) => {
+            let lexp = ll(exp, fns)?;
+            Ok(Expr::new(ExprKind::Display(lexp)))
+        }
+        ExprKind::Newline => Ok(exp.clone()),
         ExprKind::Tuple(exps) => {
             let lexps = ll_array(exps, fns)?;
             Ok(Expr::new(ExprKind::Tuple(lexps)))
@@ -116,6 +151,11 @@ fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftEr
             let ltup = ll(tup, fns)?;
             Ok(Expr::new(ExprKind::TupleGet(ltup, *key)))
         }
+        ExprKind::TupleSet(tup, key, val) => {
+            let ltup = ll(tup, fns)?;
+            let lval = ll(val, fns)?;
+            Ok(Expr::new(ExprKind::TupleSet(ltup, *key, lval)))
+        }
         ExprKind::Pack(val, sub, exist) => {
             let lval = ll(val, fns)?;
             Ok(Expr::new(ExprKind::Pack(lval, sub.clone(), exist.clone())))
@@ -130,6 +170,29 @@ fn ll(exp: &Expr, fns: &mut Vector<(String, Expr)>) -> Result<Expr, LambdaLiftEr
                 lbody,
             )))
         }
+        ExprKind::Raise(exn, typ) => {
+            let lexn = ll(exn, fns)?;
+            Ok(Expr::new(ExprKind::Raise(lexn, typ.clone())))
+        }
+        ExprKind::Handle(var, typ, handler, body) => {
+            let lhandler = ll(handler, fns)?;
+            let lbody = ll(body, fns)?;
+            Ok(Expr::new(ExprKind::Handle(
+                var.clone(),
+                typ.clone(),
+                lhandler,
+                lbody,
+            )))
+        }
+        ExprKind::CallCC(_) | ExprKind::Throw(_, _, _) => Err(LambdaLiftError::from(
+            "Continuations should have been removed by CPS conversion.",
+        )),
+        ExprKind::MakeGenerator(_, _) | ExprKind::Yield(_) | ExprKind::Next(_) => Err(
+            LambdaLiftError::from("Generators should have been removed by CPS conversion."),
+        ),
+        ExprKind::Derived(_, _) => Err(LambdaLiftError::from(
+            "Derived forms should have been removed by type checking.",
+        )),
     }
 }
 
@@ -137,6 +200,9 @@ pub fn lambda_lift(exp: &Expr) -> Result<Prog<Expr>, LambdaLiftError> {
     let mut fns: Vector<(String, Expr)> = vector![];
     let lifted_exp = ll(exp, &mut fns)?;
     Ok(Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
         fns,
         exp: lifted_exp,
     })
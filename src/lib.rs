@@ -1,11 +1,24 @@
+pub mod analysis;
 pub mod ast_transform;
 pub mod closure_convert;
 pub mod common;
 pub mod compile;
+pub mod cps;
+pub mod const_fold;
+pub mod dead_code;
+pub mod encode;
 pub mod generate_code;
+pub mod inline;
 pub mod lambda_lift;
+pub mod macro_expand;
+pub mod marshal;
+pub mod module;
 pub mod parse;
 pub mod record_elim;
+#[cfg(feature = "runtime")]
+pub mod repl;
+#[cfg(feature = "runtime")]
+pub mod runtime;
 pub mod type_check;
 pub mod types;
 pub mod util;
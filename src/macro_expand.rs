@@ -0,0 +1,457 @@
+use crate::common::generate_macro_name;
+
+/// The deepest chain of macro uses that may be expanded, so that macros
+/// which expand to themselves forever are reported as errors.
+const MAX_EXPANSION_DEPTH: usize = 1000;
+
+const ELLIPSIS: &str = "...";
+
+#[derive(Clone, Debug)]
+pub struct MacroError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for MacroError {}
+
+impl From<&str> for MacroError {
+    fn from(message: &str) -> Self {
+        MacroError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for MacroError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MacroError: {}", self.0)
+    }
+}
+
+/// A macro defined with `syntax-rules`: a list of literals, and a list of
+/// rules (each a pattern along with the template it expands to).
+#[derive(Clone, Debug)]
+struct SyntaxRules {
+    literals: Vec<String>,
+    rules: Vec<(lexpr::Value, lexpr::Value)>,
+}
+
+/// The values matched by a pattern variable. Variables followed by an
+/// ellipsis (or nested within a pattern followed by one) match a sequence of
+/// values.
+#[derive(Clone, Debug)]
+enum MatchTree {
+    Leaf(lexpr::Value),
+    Seq(Vec<MatchTree>),
+}
+
+type Bindings = Vec<(String, MatchTree)>;
+
+/// The macros which are in scope while expanding a program.
+#[derive(Clone, Debug, Default)]
+pub struct MacroEnv {
+    macros: Vec<(String, SyntaxRules)>,
+}
+
+impl MacroEnv {
+    pub fn new() -> Self {
+        MacroEnv { macros: vec![] }
+    }
+
+    /// If the value is a macro definition, e.g.
+    ///
+    /// ```text
+    /// (define-syntax swap!
+    ///   (syntax-rules ()
+    ///     ((swap! a b) (let ((tmp a)) (begin (set! a b) (set! b tmp))))))
+    /// ```
+    ///
+    /// add the macro to the environment and return its name. Otherwise,
+    /// returns None. A later definition of a macro replaces an earlier one.
+    pub fn define_syntax(&mut self, value: &lexpr::Value) -> Result<Option<String>, MacroError> {
+        let lst = match value.to_vec() {
+            Some(lst) if lst.first().and_then(|head| head.as_symbol()) == Some("define-syntax") => {
+                lst
+            }
+            _ => return Ok(None),
+        };
+        if lst.len() != 3 {
+            return Err(MacroError::from(
+                "Define-syntax does not have the correct number of arguments.",
+            ));
+        }
+        let name = lst[1]
+            .as_symbol()
+            .ok_or("Define-syntax does not have a valid name.")?;
+        let rules = parse_syntax_rules(&lst[2])?;
+        self.macros.retain(|pair| pair.0 != name);
+        self.macros.push((name.to_string(), rules));
+        Ok(Some(name.to_string()))
+    }
+
+    /// Expand every use of a macro within a value, including uses produced
+    /// by expanding other macros.
+    pub fn expand(&self, value: &lexpr::Value) -> Result<lexpr::Value, MacroError> {
+        self.expand_with_depth(value, 0)
+    }
+
+    fn expand_with_depth(
+        &self,
+        value: &lexpr::Value,
+        depth: usize,
+    ) -> Result<lexpr::Value, MacroError> {
+        let lst = match value.to_vec() {
+            Some(lst) if !lst.is_empty() => lst,
+            _ => return Ok(value.clone()),
+        };
+        // quoted data is left as it is, even if it looks like a macro use
+        if lst[0].as_symbol() == Some("quote") {
+            return Ok(value.clone());
+        }
+        let found = lst[0]
+            .as_symbol()
+            .and_then(|name| self.macros.iter().find(|pair| pair.0 == name));
+        match found {
+            Some((name, rules)) => {
+                if depth >= MAX_EXPANSION_DEPTH {
+                    return Err(MacroError(format!(
+                        "Expansion of macro {name} does not terminate."
+                    )));
+                }
+                let expanded = expand_use(name, rules, &lst)?;
+                self.expand_with_depth(&expanded, depth + 1)
+            }
+            None => {
+                let expanded = lst
+                    .iter()
+                    .map(|value| self.expand_with_depth(value, depth))
+                    .collect::<Result<Vec<lexpr::Value>, MacroError>>()?;
+                Ok(lexpr::Value::list(expanded))
+            }
+        }
+    }
+}
+
+/// Expand the macros within the values of a program (or module), returning
+/// the values with all macro definitions removed.
+///
+/// Macros may be defined anywhere at the top level of a program, and can be
+/// used by any value within the same program.
+pub fn expand_prog(values: &[lexpr::Value]) -> Result<Vec<lexpr::Value>, MacroError> {
+    let mut env = MacroEnv::new();
+    let mut rest = vec![];
+    for value in values {
+        if env.define_syntax(value)?.is_none() {
+            rest.push(value);
+        }
+    }
+    rest.into_iter().map(|value| env.expand(value)).collect()
+}
+
+fn parse_syntax_rules(value: &lexpr::Value) -> Result<SyntaxRules, MacroError> {
+    let lst = value
+        .to_vec()
+        .ok_or("Macro must be defined using syntax-rules.")?;
+    if lst.len() < 2 || lst[0].as_symbol() != Some("syntax-rules") {
+        return Err(MacroError::from(
+            "Macro must be defined using syntax-rules.",
+        ));
+    }
+    let literals = lst[1]
+        .to_vec()
+        .ok_or("Syntax-rules literals must be a list.")?
+        .iter()
+        .map(|literal| {
+            let name = literal
+                .as_symbol()
+                .ok_or("Syntax-rules literal is not a valid name.")?;
+            Ok(name.to_string())
+        })
+        .collect::<Result<Vec<String>, MacroError>>()?;
+    let rules = lst[2..]
+        .iter()
+        .map(|rule| {
+            let rule_vec = rule.to_vec().ok_or("Syntax rule is not a valid list.")?;
+            match rule_vec.as_slice() {
+                [pattern, template] if pattern.is_list() && !pattern.is_null() => {
+                    Ok((pattern.clone(), template.clone()))
+                }
+                _ => Err(MacroError::from(
+                    "Syntax rule must consist of a list pattern and a template.",
+                )),
+            }
+        })
+        .collect::<Result<Vec<(lexpr::Value, lexpr::Value)>, MacroError>>()?;
+    Ok(SyntaxRules { literals, rules })
+}
+
+/// Expand a single use of a macro, using the first rule whose pattern
+/// matches it.
+fn expand_use(
+    name: &str,
+    rules: &SyntaxRules,
+    lst: &[lexpr::Value],
+) -> Result<lexpr::Value, MacroError> {
+    for (pattern, template) in rules.rules.iter() {
+        // the keyword position of the pattern is ignored
+        let pattern_vec = pattern.to_vec().unwrap();
+        let mut bindings = vec![];
+        if match_list(&pattern_vec[1..], &lst[1..], &rules.literals, &mut bindings)? {
+            let renames: Vec<(String, String)> = template_binders(template, &bindings)
+                .into_iter()
+                .map(|binder| {
+                    let fresh = generate_macro_name(&binder);
+                    (binder, fresh)
+                })
+                .collect();
+            return instantiate(template, &bindings, &renames);
+        }
+    }
+    Err(MacroError(format!(
+        "No syntax rule of macro {name} matches {}.",
+        lexpr::Value::list(lst.to_vec())
+    )))
+}
+
+/// Match a value against a pattern, adding the values of any pattern
+/// variables to `bindings`. Returns whether the value matches.
+fn match_pattern(
+    pattern: &lexpr::Value,
+    value: &lexpr::Value,
+    literals: &[String],
+    bindings: &mut Bindings,
+) -> Result<bool, MacroError> {
+    if let Some(name) = pattern.as_symbol() {
+        if name == "_" {
+            return Ok(true);
+        }
+        if literals.iter().any(|literal| literal == name) {
+            return Ok(value.as_symbol() == Some(name));
+        }
+        bindings.push((name.to_string(), MatchTree::Leaf(value.clone())));
+        return Ok(true);
+    }
+    match (pattern.to_vec(), value.to_vec()) {
+        (Some(patterns), Some(values)) => match_list(&patterns, &values, literals, bindings),
+        (Some(_), None) => Ok(false),
+        _ => Ok(pattern == value),
+    }
+}
+
+/// Match the elements of a list against a list of patterns, one of which may
+/// be followed by an ellipsis (matching any number of elements).
+fn match_list(
+    patterns: &[lexpr::Value],
+    values: &[lexpr::Value],
+    literals: &[String],
+    bindings: &mut Bindings,
+) -> Result<bool, MacroError> {
+    let ellipsis = patterns
+        .iter()
+        .position(|pattern| pattern.as_symbol() == Some(ELLIPSIS));
+    let Some(ellipsis) = ellipsis else {
+        if patterns.len() != values.len() {
+            return Ok(false);
+        }
+        for (pattern, value) in patterns.iter().zip(values.iter()) {
+            if !match_pattern(pattern, value, literals, bindings)? {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    };
+
+    if ellipsis == 0 {
+        return Err(MacroError::from(
+            "Ellipsis in syntax rule must follow a pattern.",
+        ));
+    }
+    let before = &patterns[..ellipsis - 1];
+    let repeated = &patterns[ellipsis - 1];
+    let after = &patterns[ellipsis + 1..];
+    if after
+        .iter()
+        .any(|pattern| pattern.as_symbol() == Some(ELLIPSIS))
+    {
+        return Err(MacroError::from(
+            "Syntax rule pattern contains more than one ellipsis in a list.",
+        ));
+    }
+    if values.len() < before.len() + after.len() {
+        return Ok(false);
+    }
+    let repeated_end = values.len() - after.len();
+    if !match_list(before, &values[..before.len()], literals, bindings)?
+        || !match_list(after, &values[repeated_end..], literals, bindings)?
+    {
+        return Ok(false);
+    }
+
+    let mut matches = vec![];
+    for value in values[before.len()..repeated_end].iter() {
+        let mut inner_bindings = vec![];
+        if !match_pattern(repeated, value, literals, &mut inner_bindings)? {
+            return Ok(false);
+        }
+        matches.push(inner_bindings);
+    }
+    for var in pattern_vars(repeated, literals) {
+        let seq = matches
+            .iter()
+            .map(|inner_bindings| lookup(inner_bindings, &var).unwrap().clone())
+            .collect();
+        bindings.push((var, MatchTree::Seq(seq)));
+    }
+    Ok(true)
+}
+
+/// Returns the names of the pattern variables within a pattern.
+fn pattern_vars(pattern: &lexpr::Value, literals: &[String]) -> Vec<String> {
+    if let Some(name) = pattern.as_symbol() {
+        if name == "_" || name == ELLIPSIS || literals.iter().any(|literal| literal == name) {
+            return vec![];
+        }
+        return vec![name.to_string()];
+    }
+    match pattern.to_vec() {
+        Some(patterns) => patterns
+            .iter()
+            .flat_map(|pattern| pattern_vars(pattern, literals))
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn lookup<'a>(bindings: &'a Bindings, name: &str) -> Option<&'a MatchTree> {
+    bindings
+        .iter()
+        .rev()
+        .find(|pair| pair.0 == name)
+        .map(|pair| &pair.1)
+}
+
+/// Returns the variables which are bound by a template itself (rather than
+/// being substituted for a pattern variable), e.g. `tmp` in `(let ((tmp a))
+/// ...)`.
+///
+/// These are renamed each time the macro is expanded, so that they can't
+/// capture variables with the same name in the code passed to the macro.
+fn template_binders(template: &lexpr::Value, bindings: &Bindings) -> Vec<String> {
+    let mut binders = vec![];
+    collect_binders(template, &mut binders);
+    binders.retain(|binder| lookup(bindings, binder).is_none() && binder != ELLIPSIS);
+    binders.sort();
+    binders.dedup();
+    binders
+}
+
+fn collect_binders(template: &lexpr::Value, binders: &mut Vec<String>) {
+    let Some(lst) = template.to_vec() else {
+        return;
+    };
+    // let binds (name exp), lambda and with-handler bind (name : type), and
+    // unpack binds (name package type-var), so the name always comes first
+    let binding_lists = match lst.first().and_then(|head| head.as_symbol()) {
+        Some("let") | Some("lambda") => lst.get(1).and_then(|value| value.to_vec()),
+        Some("unpack") => lst.get(1).map(|value| vec![value.clone()]),
+        Some("with-handler") => lst
+            .get(1)
+            .and_then(|value| value.to_vec())
+            .and_then(|clause| clause.first().cloned())
+            .map(|param| vec![param]),
+        _ => None,
+    };
+    for binding in binding_lists.unwrap_or_default() {
+        if let Some(name) = binding
+            .to_vec()
+            .and_then(|binding_vec| binding_vec.first().cloned())
+            .and_then(|name| name.as_symbol().map(|name| name.to_string()))
+        {
+            binders.push(name);
+        }
+    }
+    for value in lst.iter() {
+        collect_binders(value, binders);
+    }
+}
+
+/// Construct the expansion of a template, substituting the values of
+/// pattern variables and renaming the variables bound by the template.
+fn instantiate(
+    template: &lexpr::Value,
+    bindings: &Bindings,
+    renames: &[(String, String)],
+) -> Result<lexpr::Value, MacroError> {
+    if let Some(name) = template.as_symbol() {
+        return match lookup(bindings, name) {
+            Some(MatchTree::Leaf(value)) => Ok(value.clone()),
+            Some(MatchTree::Seq(_)) => Err(MacroError(format!(
+                "Pattern variable {name} must be followed by an ellipsis in the template."
+            ))),
+            None => match renames.iter().find(|pair| pair.0 == name) {
+                Some((_name, fresh)) => Ok(lexpr::Value::symbol(fresh.as_str())),
+                None => Ok(template.clone()),
+            },
+        };
+    }
+    let Some(templates) = template.to_vec() else {
+        return Ok(template.clone());
+    };
+    // symbols within quoted data are not variables, so they aren't renamed
+    let renames = match templates.first().and_then(|first| first.as_symbol()) {
+        Some("quote") => &[],
+        _ => renames,
+    };
+
+    let mut result = vec![];
+    let mut index = 0;
+    while index < templates.len() {
+        let element = &templates[index];
+        let repeated = templates
+            .get(index + 1)
+            .is_some_and(|next| next.as_symbol() == Some(ELLIPSIS));
+        if !repeated {
+            result.push(instantiate(element, bindings, renames)?);
+            index += 1;
+            continue;
+        }
+
+        // repeat the element once for each value matched by the sequence
+        // variables within it
+        let seq_vars: Vec<(String, &Vec<MatchTree>)> = template_symbols(element)
+            .into_iter()
+            .filter_map(|name| match lookup(bindings, &name) {
+                Some(MatchTree::Seq(seq)) => Some((name, seq)),
+                _ => None,
+            })
+            .collect();
+        let len = match seq_vars.first() {
+            Some((_name, seq)) => seq.len(),
+            None => {
+                return Err(MacroError::from(
+                    "Ellipsis in template does not follow a pattern variable matched with an ellipsis.",
+                ));
+            }
+        };
+        if seq_vars.iter().any(|(_name, seq)| seq.len() != len) {
+            return Err(MacroError::from(
+                "Pattern variables used with the same ellipsis matched different numbers of values.",
+            ));
+        }
+        for i in 0..len {
+            let mut inner_bindings = bindings.clone();
+            for (name, seq) in seq_vars.iter() {
+                inner_bindings.push((name.clone(), seq[i].clone()));
+            }
+            result.push(instantiate(element, &inner_bindings, renames)?);
+        }
+        index += 2;
+    }
+    Ok(lexpr::Value::list(result))
+}
+
+fn template_symbols(template: &lexpr::Value) -> Vec<String> {
+    if let Some(name) = template.as_symbol() {
+        return vec![name.to_string()];
+    }
+    match template.to_vec() {
+        Some(templates) => templates.iter().flat_map(template_symbols).collect(),
+        None => vec![],
+    }
+}
@@ -0,0 +1,103 @@
+use scheme_to_wasm::compile::{CompileOptions, compile_prog_with_options};
+use scheme_to_wasm::encode::serialize_module;
+use scheme_to_wasm::generate_code::{
+    CodeGenerateOptions, ExceptionHandling, Target, construct_module_from_prog_with_options,
+};
+use scheme_to_wasm::module::load_prog;
+use std::path::Path;
+
+const USAGE: &str = "Usage: scheme-to-wasm [--target=host|wasi] [--exceptions=native|fallback] [--cps] <input.scm> <output.wasm>
+       scheme-to-wasm repl";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Compile the program in the input file, and write the WebAssembly module
+/// to the output file.
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        return repl();
+    }
+    let mut options = CodeGenerateOptions::default();
+    let mut compile_options = CompileOptions::default();
+    let mut paths = vec![];
+    for arg in std::env::args().skip(1) {
+        if arg == "--cps" {
+            compile_options.cps = true;
+            options.tail_calls = true;
+            continue;
+        }
+        if let Some(exceptions) = arg.strip_prefix("--exceptions=") {
+            options.exceptions = match exceptions {
+                "native" => ExceptionHandling::Native,
+                "fallback" => ExceptionHandling::Fallback,
+                other => {
+                    return Err(format!("Unknown exception handling {other}.\n{USAGE}").into());
+                }
+            };
+            continue;
+        }
+        match arg.strip_prefix("--target=") {
+            Some("host") => options.target = Target::Host,
+            Some("wasi") => options.target = Target::Wasi,
+            Some(other) => return Err(format!("Unknown target {other}.\n{USAGE}").into()),
+            None => paths.push(arg),
+        }
+    }
+    let [input, output] = paths.as_slice() else {
+        return Err(USAGE.into());
+    };
+
+    let prog = compile_prog_with_options(&load_prog(Path::new(input))?, &compile_options)?;
+    let module = construct_module_from_prog_with_options(&prog, &options)?;
+    std::fs::write(output, serialize_module(module)?)?;
+    Ok(())
+}
+
+/// Read definitions and expressions from stdin, printing the result of each
+/// one. Inputs may span multiple lines.
+#[cfg(feature = "runtime")]
+fn repl() -> Result<(), Box<dyn std::error::Error>> {
+    use scheme_to_wasm::repl::Repl;
+    use std::io::{BufRead, Write};
+
+    let mut repl = Repl::default();
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "  " });
+        std::io::stdout().flush()?;
+        if std::io::stdin().lock().read_line(&mut buffer)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let values = match lexpr::Parser::from_str(&buffer)
+            .value_iter()
+            .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        {
+            Ok(values) => values,
+            // keep reading until the input is complete
+            Err(e) if e.is_eof() => continue,
+            Err(e) => {
+                eprintln!("{e}");
+                buffer.clear();
+                continue;
+            }
+        };
+        buffer.clear();
+        for value in values {
+            match repl.input(&value) {
+                Ok(output) => println!("{output}"),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "runtime"))]
+fn repl() -> Result<(), Box<dyn std::error::Error>> {
+    Err("The REPL is only available when built with the runtime feature, e.g. `cargo run --features runtime -- repl`.".into())
+}
@@ -0,0 +1,426 @@
+use crate::common::{Expr, ExprKind};
+use crate::generate_code::{FIXNUM_MAX, FIXNUM_MIN, tuple_offset, wasm_size};
+use crate::types::Type;
+
+#[derive(Clone, Debug)]
+pub struct MarshalError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for MarshalError {}
+
+impl From<&str> for MarshalError {
+    fn from(message: &str) -> Self {
+        MarshalError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MarshalError: {}", self.0)
+    }
+}
+
+/// An int which does not fit in an i32, given by its sign and its magnitude
+/// (as 32-bit limbs, from least to most significant).
+///
+/// The magnitude has no leading zero limbs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BigInt {
+    pub negative: bool,
+    pub magnitude: Vec<u32>,
+}
+
+/// Prints the int in decimal, by repeatedly dividing its magnitude by 10^9.
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut magnitude = self.magnitude.clone();
+        let mut chunks = vec![];
+        while !magnitude.is_empty() {
+            let mut rem = 0u64;
+            for limb in magnitude.iter_mut().rev() {
+                let cur = (rem << 32) | *limb as u64;
+                *limb = (cur / 1_000_000_000) as u32;
+                rem = cur % 1_000_000_000;
+            }
+            chunks.push(rem);
+            while magnitude.last() == Some(&0) {
+                magnitude.pop();
+            }
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap_or(&0))?;
+        for chunk in chunks {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A value of the source language, which can be decoded from (or encoded
+/// into) the linear memory of a compiled WebAssembly module.
+///
+/// Ints are decoded as `Int` whenever they fit in an i32, and as `BigInt`
+/// otherwise. Record fields are kept in the order they are listed in the
+/// record's type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SchemeValue {
+    Int(i32),
+    BigInt(BigInt),
+    Int64(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Symbol(String),
+    List(Vec<SchemeValue>),
+    Tuple(Vec<SchemeValue>),
+    Record(Vec<(String, SchemeValue)>),
+}
+
+/// Values are printed the way they would be written in the source language,
+/// except for lists, which are printed like lists in Scheme, e.g. `(1 2 3)`,
+/// and symbols, which are printed as just their name.
+impl std::fmt::Display for SchemeValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemeValue::Int(x) => write!(f, "{x}"),
+            SchemeValue::BigInt(x) => write!(f, "{x}"),
+            SchemeValue::Int64(x) => write!(f, "{x}"),
+            SchemeValue::Float(x) => write!(f, "{x:?}"),
+            SchemeValue::Bool(true) => write!(f, "#t"),
+            SchemeValue::Bool(false) => write!(f, "#f"),
+            SchemeValue::Str(x) => write!(f, "{x:?}"),
+            SchemeValue::Char(x) => write!(f, "{}", ExprKind::<Expr>::Char(*x)),
+            SchemeValue::Symbol(x) => write!(f, "{x}"),
+            SchemeValue::List(elements) => {
+                let strs: Vec<String> = elements.iter().map(|val| val.to_string()).collect();
+                write!(f, "({})", strs.join(" "))
+            }
+            SchemeValue::Tuple(elements) => {
+                write!(f, "(make-tuple")?;
+                for element in elements {
+                    write!(f, " {element}")?;
+                }
+                write!(f, ")")
+            }
+            SchemeValue::Record(fields) => {
+                write!(f, "(make-record")?;
+                for (name, val) in fields {
+                    write!(f, " ({name} {val})")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Returns the fields of a record type in the order they are laid out in
+/// memory (record elimination turns records into tuples with their fields
+/// sorted by name).
+fn record_layout(fields: &im_rc::Vector<(String, Type, bool)>) -> Vec<(String, Type)> {
+    let mut layout: Vec<(String, Type)> = fields
+        .iter()
+        .map(|(name, typ, _mutable)| (name.clone(), typ.clone()))
+        .collect();
+    layout.sort_by(|a, b| a.0.cmp(&b.0));
+    layout
+}
+
+fn read_i32(memory: &[u8], index: i32) -> Result<i32, MarshalError> {
+    let start = usize::try_from(index).map_err(|_| MarshalError::from("Invalid pointer."))?;
+    let bytes = memory
+        .get(start..start + 4)
+        .ok_or("Pointer is outside of memory.")?;
+    Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decode an int, which is either a fixnum or a pointer to a bignum (see
+/// `construct_int_helpers` in `generate_code`).
+fn decode_int(raw: i32, memory: &[u8]) -> Result<SchemeValue, MarshalError> {
+    if raw & 1 == 1 {
+        return Ok(SchemeValue::Int(raw >> 1));
+    }
+    let len = read_i32(memory, raw)?;
+    let negative = read_i32(memory, raw + 4)? != 0;
+    let magnitude = (0..len)
+        .map(|i| Ok(read_i32(memory, raw + 8 + 4 * i)? as u32))
+        .collect::<Result<Vec<u32>, MarshalError>>()?;
+    if let [limb] = magnitude[..] {
+        let val = if negative {
+            -(limb as i64)
+        } else {
+            limb as i64
+        };
+        if let Ok(val) = i32::try_from(val) {
+            return Ok(SchemeValue::Int(val));
+        }
+    }
+    Ok(SchemeValue::BigInt(BigInt {
+        negative,
+        magnitude,
+    }))
+}
+
+/// Read the string which is stored in memory at `index`.
+fn read_str(memory: &[u8], index: i32) -> Result<String, MarshalError> {
+    let len = read_i32(memory, index)? as usize;
+    let start = usize::try_from(index).map_err(|_| MarshalError::from("Invalid pointer."))? + 4;
+    let bytes = memory
+        .get(start..start + len)
+        .ok_or("String is outside of memory.")?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| MarshalError::from("String is not valid UTF-8."))
+}
+
+/// Read the raw value (see `decode_value`) of a value of type `typ` which is
+/// stored in memory at `index`.
+fn read_raw(memory: &[u8], index: i32, typ: &Type) -> Result<i64, MarshalError> {
+    if wasm_size(typ) == 4 {
+        return Ok(read_i32(memory, index)? as i64);
+    }
+    let start = usize::try_from(index).map_err(|_| MarshalError::from("Invalid pointer."))?;
+    let bytes = memory
+        .get(start..start + 8)
+        .ok_or("Pointer is outside of memory.")?;
+    Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decode a value produced by a compiled program, given its (source
+/// language) type and the contents of the instance's linear memory.
+///
+/// `raw` holds the WebAssembly value representing the value, e.g. the result
+/// of calling `$$MAIN$$`. This is an i64 for values of type int64, the bits
+/// of an f64 for floats, and an i32 (extended to 64 bits) for all other
+/// values. Values which are not stored in memory (booleans, chars, and
+/// numbers other than bignums) are decoded from `raw` directly, while others
+/// are read from memory by following pointers (see `generate_code` for how each kind of value is
+/// laid out). Functions cannot be decoded, since they are only meaningful
+/// within the instance, and neither can cyclic lists.
+pub fn decode_value(typ: &Type, raw: i64, memory: &[u8]) -> Result<SchemeValue, MarshalError> {
+    match typ {
+        Type::Int => decode_int(raw as i32, memory),
+        Type::Int64 => Ok(SchemeValue::Int64(raw)),
+        Type::Float => Ok(SchemeValue::Float(f64::from_bits(raw as u64))),
+        Type::Bool => Ok(SchemeValue::Bool(raw != 0)),
+        Type::Str => Ok(SchemeValue::Str(read_str(memory, raw as i32)?)),
+        Type::Char => char::from_u32(raw as u32)
+            .map(SchemeValue::Char)
+            .ok_or(MarshalError::from("Char is not a valid code point.")),
+        // symbols are the address of their interned name
+        Type::Symbol => Ok(SchemeValue::Symbol(read_str(memory, raw as i32)?)),
+        Type::List(base_type) => {
+            let mut elements = vec![];
+            let mut cons_idx = raw as i32;
+            // A list can be made cyclic with set-cdr!, and a list which has
+            // more cells than fit in memory must be one
+            let cell_size = wasm_size(base_type) as usize + 4;
+            // (null 'typ) is represented as -1
+            while cons_idx != -1 {
+                if elements.len() >= memory.len() / cell_size {
+                    return Err(MarshalError::from("List is cyclic."));
+                }
+                let car = read_raw(memory, cons_idx, base_type)?;
+                elements.push(decode_value(base_type, car, memory)?);
+                cons_idx = read_i32(memory, cons_idx + wasm_size(base_type) as i32)?;
+            }
+            Ok(SchemeValue::List(elements))
+        }
+        Type::Tuple(types) => {
+            let elements = types
+                .iter()
+                .enumerate()
+                .map(|(key, inner_type)| {
+                    let offset = tuple_offset(types, key as u32) as i32;
+                    let val = read_raw(memory, raw as i32 + offset, inner_type)?;
+                    decode_value(inner_type, val, memory)
+                })
+                .collect::<Result<Vec<SchemeValue>, MarshalError>>()?;
+            Ok(SchemeValue::Tuple(elements))
+        }
+        Type::Record(fields) => {
+            let layout = record_layout(fields);
+            let layout_types = layout.iter().map(|pair| pair.1.clone()).collect();
+            let decoded = fields
+                .iter()
+                .map(|(name, inner_type, _mutable)| {
+                    let key = layout.iter().position(|pair| pair.0 == *name).unwrap();
+                    let offset = tuple_offset(&layout_types, key as u32) as i32;
+                    let val = read_raw(memory, raw as i32 + offset, inner_type)?;
+                    Ok((name.clone(), decode_value(inner_type, val, memory)?))
+                })
+                .collect::<Result<Vec<(String, SchemeValue)>, MarshalError>>()?;
+            Ok(SchemeValue::Record(decoded))
+        }
+        _ => Err(MarshalError(format!(
+            "Values of type {typ} cannot be decoded."
+        ))),
+    }
+}
+
+/// Reserve `size` bytes of memory starting at `heap_index`, returning the
+/// index of the first byte.
+fn alloc(size: u32, memory: &[u8], heap_index: &mut u32) -> Result<i32, MarshalError> {
+    let start = *heap_index;
+    if (start + size) as usize > memory.len() {
+        return Err(MarshalError::from("Not enough memory to encode value."));
+    }
+    *heap_index += size;
+    Ok(start as i32)
+}
+
+fn write_i32(memory: &mut [u8], index: i32, val: i32) {
+    let start = index as usize;
+    memory[start..start + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Write the raw value (see `decode_value`) of a value of type `typ` into
+/// memory at `index`.
+fn write_raw(memory: &mut [u8], index: i32, typ: &Type, raw: i64) {
+    if wasm_size(typ) == 4 {
+        write_i32(memory, index, raw as i32);
+    } else {
+        let start = index as usize;
+        memory[start..start + 8].copy_from_slice(&raw.to_le_bytes());
+    }
+}
+
+/// Encode an int with the given sign and magnitude (as 32-bit limbs, from
+/// least to most significant), as a fixnum if it fits in one, and otherwise
+/// as a bignum (see `decode_int`).
+fn encode_int(
+    negative: bool,
+    magnitude: &[u32],
+    memory: &mut [u8],
+    heap_index: &mut u32,
+) -> Result<i64, MarshalError> {
+    let len = magnitude
+        .iter()
+        .rposition(|limb| *limb != 0)
+        .map_or(0, |i| i + 1);
+    let magnitude = &magnitude[..len];
+    let small = match magnitude {
+        [] => Some(0),
+        [limb] if negative => Some(-(*limb as i64)),
+        [limb] => Some(*limb as i64),
+        _ => None,
+    };
+    if let Some(val) = small.filter(|val| (FIXNUM_MIN..=FIXNUM_MAX).contains(val)) {
+        return Ok(((val << 1) | 1) as i32 as i64);
+    }
+    let bignum_idx = alloc(8 + 4 * len as u32, memory, heap_index)?;
+    write_i32(memory, bignum_idx, len as i32);
+    write_i32(memory, bignum_idx + 4, negative as i32);
+    for (i, limb) in magnitude.iter().enumerate() {
+        write_i32(memory, bignum_idx + 8 + 4 * i as i32, *limb as i32);
+    }
+    Ok(bignum_idx as i64)
+}
+
+/// Encode a value into the linear memory of an instance so that it can be
+/// passed as an argument to a compiled function, returning the raw value
+/// representing it (see `decode_value`).
+///
+/// Exported functions (and externs) take and return ints as plain i32s
+/// instead of raw values, so an int only needs to be encoded when it is part
+/// of another value, such as a list.
+///
+/// The value must have the given (source language) type. Any memory needed
+/// is allocated starting from `heap_index`, which is advanced past the
+/// encoded value. This should start at (or after) the `$$HEAP_BASE$$` global
+/// exported by the module, so that the program's own data is not
+/// overwritten. The program allocates memory downwards from the end of
+/// memory until it reaches `$$HEAP_BASE$$`, so before calling into the
+/// program, `$$HEAP_BASE$$` must be set to the final `heap_index`, or the
+/// program's allocations may overwrite the encoded values.
+pub fn encode_value(
+    val: &SchemeValue,
+    typ: &Type,
+    memory: &mut [u8],
+    heap_index: &mut u32,
+) -> Result<i64, MarshalError> {
+    match (val, typ) {
+        (SchemeValue::Int(x), Type::Int) => {
+            encode_int(*x < 0, &[x.unsigned_abs()], memory, heap_index)
+        }
+        (SchemeValue::BigInt(x), Type::Int) => {
+            encode_int(x.negative, &x.magnitude, memory, heap_index)
+        }
+        (SchemeValue::Int64(x), Type::Int64) => Ok(*x),
+        (SchemeValue::Float(x), Type::Float) => Ok(x.to_bits() as i64),
+        (SchemeValue::Bool(x), Type::Bool) => Ok(*x as i64),
+        (SchemeValue::Char(x), Type::Char) => Ok(*x as i64),
+        (SchemeValue::Symbol(_), Type::Symbol) => Err(MarshalError::from(
+            "Symbols cannot be encoded, since they are interned when the program is compiled.",
+        )),
+        (SchemeValue::Str(x), Type::Str) => {
+            // keep later allocations aligned to 4 bytes
+            let size = (x.len() as u32 + 4).div_ceil(4) * 4;
+            let str_idx = alloc(size, memory, heap_index)?;
+            write_i32(memory, str_idx, x.len() as i32);
+            let start = str_idx as usize + 4;
+            memory[start..start + x.len()].copy_from_slice(x.as_bytes());
+            Ok(str_idx as i64)
+        }
+        (SchemeValue::List(elements), Type::List(base_type)) => {
+            let mut list_idx = -1;
+            let car_size = wasm_size(base_type);
+            for element in elements.iter().rev() {
+                let car = encode_value(element, base_type, memory, heap_index)?;
+                let cons_idx = alloc(car_size + 4, memory, heap_index)?;
+                write_raw(memory, cons_idx, base_type, car);
+                write_i32(memory, cons_idx + car_size as i32, list_idx);
+                list_idx = cons_idx;
+            }
+            Ok(list_idx as i64)
+        }
+        (SchemeValue::Tuple(elements), Type::Tuple(types)) if elements.len() == types.len() => {
+            let vals = elements
+                .iter()
+                .zip(types.iter())
+                .map(|(element, inner_type)| encode_value(element, inner_type, memory, heap_index))
+                .collect::<Result<Vec<i64>, MarshalError>>()?;
+            encode_fields(&vals, types, memory, heap_index)
+        }
+        (SchemeValue::Record(fields), Type::Record(field_types))
+            if fields.len() == field_types.len() =>
+        {
+            let layout = record_layout(field_types);
+            let vals = layout
+                .iter()
+                .map(|(name, inner_type)| {
+                    let (_name, field) = fields
+                        .iter()
+                        .find(|pair| pair.0 == *name)
+                        .ok_or("Record is missing a field of its type.")?;
+                    encode_value(field, inner_type, memory, heap_index)
+                })
+                .collect::<Result<Vec<i64>, MarshalError>>()?;
+            let layout_types = layout.into_iter().map(|pair| pair.1).collect();
+            encode_fields(&vals, &layout_types, memory, heap_index)
+        }
+        _ => Err(MarshalError(format!(
+            "Value {val:?} does not have type {typ}."
+        ))),
+    }
+}
+
+/// Store the (already encoded) fields of a tuple contiguously in memory,
+/// given the types of the fields.
+///
+/// Like tuples created by the program, empty tuples still take up 4 bytes.
+fn encode_fields(
+    vals: &[i64],
+    types: &im_rc::Vector<Type>,
+    memory: &mut [u8],
+    heap_index: &mut u32,
+) -> Result<i64, MarshalError> {
+    let size = tuple_offset(types, types.len() as u32).max(4);
+    let tuple_idx = alloc(size, memory, heap_index)?;
+    for (key, (val, typ)) in vals.iter().zip(types.iter()).enumerate() {
+        let offset = tuple_offset(types, key as u32) as i32;
+        write_raw(memory, tuple_idx + offset, typ, *val);
+    }
+    Ok(tuple_idx as i64)
+}
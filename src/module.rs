@@ -0,0 +1,200 @@
+use crate::common::{Decl, SourceProg};
+use crate::parse::{parse_module, parse_prog};
+use crate::type_check::{extern_env, tc_with_env, type_check_decls};
+use crate::types::Type;
+
+use im_rc::Vector;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct ModuleError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for ModuleError {}
+
+impl From<&str> for ModuleError {
+    fn from(message: &str) -> Self {
+        ModuleError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ModuleError: {}", self.0)
+    }
+}
+
+/// Load the program whose main module is in the file at `path`, along with
+/// every module it (transitively) imports, and link them into a single
+/// program.
+///
+/// Each module is type checked on its own, in a module-level environment
+/// which only contains its own declarations and the definitions provided by
+/// the modules it imports. Linking then concatenates the declarations of all
+/// modules (each imported module appearing once, before the modules which
+/// import it), so the linked program compiles to a single WebAssembly module.
+/// Since all definitions end up in the same program, two modules may not
+/// define the same name, even if neither of them provides it.
+pub fn load_prog(path: &Path) -> Result<SourceProg, ModuleError> {
+    let values = read_values(path)?;
+    let prog = parse_prog(&values).map_err(|e| in_file(path, e))?;
+
+    let mut linker = Linker::default();
+    // the main module can't be imported by the modules it imports
+    if let Ok(path) = path.canonicalize() {
+        linker.loading.push(path);
+    }
+    let imports = linker.resolve_imports(&prog.decls, path)?;
+    let decls = own_decls(&prog.decls);
+    let globals = type_check_decls(&decls, &imports).map_err(|e| in_file(path, e))?;
+    let externs: Vector<(String, Type)> = decls
+        .iter()
+        .filter_map(|decl| match decl {
+            Decl::Extern(name, typ) => Some((name.clone(), typ.clone())),
+            _ => None,
+        })
+        .collect();
+    let env = extern_env(&externs)
+        .map_err(|e| in_file(path, e))?
+        .add_bindings(imports)
+        .add_bindings(globals);
+    tc_with_env(&prog.exp, &env).map_err(|e| in_file(path, e))?;
+    linker.link(&decls, path)?;
+
+    Ok(SourceProg {
+        decls: linker.decls,
+        exp: prog.exp,
+    })
+}
+
+/// Keeps track of the modules which have been loaded while linking a program.
+#[derive(Default)]
+struct Linker {
+    /// Paths of loaded modules, with the definitions each one provides
+    loaded: Vec<(PathBuf, Vector<(String, Type)>)>,
+    /// Paths of modules which are being loaded, used to detect cyclic imports
+    loading: Vec<PathBuf>,
+    /// Declarations of every loaded module, in the order they are linked
+    decls: Vector<Decl>,
+    /// Names defined by loaded modules, with the path of the defining module
+    names: Vec<(String, PathBuf)>,
+}
+
+impl Linker {
+    /// Load every module imported by a module (in the file at `path`),
+    /// returning the definitions they provide.
+    fn resolve_imports(
+        &mut self,
+        decls: &Vector<Decl>,
+        path: &Path,
+    ) -> Result<Vector<(String, Type)>, ModuleError> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut imports: Vector<(String, Type)> = Vector::new();
+        for decl in decls {
+            if let Decl::Import(import_path) = decl {
+                for provided in self.load_module(&dir.join(import_path))? {
+                    if !imports.iter().any(|pair| pair.0 == provided.0) {
+                        imports.push_back(provided);
+                    }
+                }
+            }
+        }
+        Ok(imports)
+    }
+
+    /// Load (and type check) an imported module, returning the definitions it
+    /// provides. Modules which have already been loaded are not loaded again.
+    fn load_module(&mut self, path: &Path) -> Result<Vector<(String, Type)>, ModuleError> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| ModuleError(format!("Could not find {}: {e}", path.display())))?;
+        if let Some((_path, provided)) = self.loaded.iter().find(|pair| pair.0 == path) {
+            return Ok(provided.clone());
+        }
+        if self.loading.contains(&path) {
+            return Err(ModuleError(format!(
+                "Module {} imports itself.",
+                path.display()
+            )));
+        }
+
+        self.loading.push(path.clone());
+        let all_decls = parse_module(&read_values(&path)?).map_err(|e| in_file(&path, e))?;
+        let imports = self.resolve_imports(&all_decls, &path)?;
+        let decls = own_decls(&all_decls);
+        let globals = type_check_decls(&decls, &imports).map_err(|e| in_file(&path, e))?;
+        let provided: Vector<(String, Type)> = globals
+            .into_iter()
+            .filter(|(name, _typ)| {
+                decls.iter().any(|decl| match decl {
+                    Decl::Provide(names) => names.contains(name),
+                    _ => false,
+                })
+            })
+            .collect();
+        self.link(&decls, &path)?;
+        self.loading.pop();
+
+        self.loaded.push((path, provided.clone()));
+        Ok(provided)
+    }
+
+    /// Add the declarations of a module to the linked program.
+    ///
+    /// Externs may be declared by several modules, as long as they are
+    /// declared with the same type.
+    fn link(&mut self, decls: &Vector<Decl>, path: &Path) -> Result<(), ModuleError> {
+        for decl in decls {
+            let name = match decl {
+                Decl::Extern(name, typ) => {
+                    let same_extern = self.decls.iter().any(|linked| {
+                        matches!(linked, Decl::Extern(other, other_typ) if other == name && other_typ == typ)
+                    });
+                    if same_extern {
+                        continue;
+                    }
+                    name
+                }
+                Decl::Define(name, _exp) => name,
+                _ => {
+                    self.decls.push_back(decl.clone());
+                    continue;
+                }
+            };
+            if let Some((_name, other_path)) = self.names.iter().find(|pair| pair.0 == *name) {
+                return Err(ModuleError(format!(
+                    "{name} is defined by both {} and {}.",
+                    other_path.display(),
+                    path.display()
+                )));
+            }
+            self.names.push((name.clone(), path.to_path_buf()));
+            self.decls.push_back(decl.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the declarations of a module other than its imports, which are
+/// resolved when the module is loaded.
+fn own_decls(decls: &Vector<Decl>) -> Vector<Decl> {
+    decls
+        .iter()
+        .filter(|decl| !matches!(decl, Decl::Import(_)))
+        .cloned()
+        .collect()
+}
+
+fn read_values(path: &Path) -> Result<Vec<lexpr::Value>, ModuleError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| ModuleError(format!("Could not read {}: {e}", path.display())))?;
+    lexpr::Parser::from_str(&source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .map_err(|e| in_file(path, e))
+}
+
+/// Wrap an error with the path of the module it occurred in.
+fn in_file(path: &Path, e: impl std::error::Error) -> ModuleError {
+    ModuleError(format!("In {}: {e}", path.display()))
+}
@@ -1,6 +1,7 @@
-use crate::common::{BinOp, Expr, ExprKind};
-use crate::types::Type;
-use im_rc::Vector;
+use crate::common::{Datum, Decl, Expr, ExprKind, PrimOp, SourceProg, generate_macro_name};
+use crate::macro_expand::expand_prog;
+use crate::types::{Type, sort_fields};
+use im_rc::{Vector, vector};
 use std::num::ParseIntError;
 
 #[derive(Clone, Debug)]
@@ -41,8 +42,12 @@ pub fn parse_type(annotation: &lexpr::Value) -> Result<Type, ParseError> {
     match annotation {
         lexpr::Value::Symbol(val) => match val.as_ref() {
             "int" => Ok(Type::Int),
+            "int64" => Ok(Type::Int64),
+            "float" => Ok(Type::Float),
             "bool" => Ok(Type::Bool),
             "string" => Ok(Type::Str),
+            "char" => Ok(Type::Char),
+            "symbol" => Ok(Type::Symbol),
             "unknown" => Ok(Type::Unknown),
             val => match val.chars().next() {
                 Some('T') => Ok(Type::TypeVar(
@@ -67,6 +72,8 @@ pub fn parse_type(annotation: &lexpr::Value) -> Result<Type, ParseError> {
             match lst_vec[0].as_symbol() {
                 Some("->") => parse_func_annotation(lst_vec),
                 Some("list") => parse_list_annotation(lst_vec),
+                Some("cont") => parse_cont_annotation(lst_vec),
+                Some("generator") => parse_generator_annotation(lst_vec),
                 Some("tuple") => parse_tuple_annotation(lst_vec),
                 Some("record") => parse_record_annotation(lst_vec),
                 Some("exists") => parse_exists_annotation(lst_vec),
@@ -105,6 +112,26 @@ fn parse_list_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError>
     Ok(Type::List(Box::new(lst_type)))
 }
 
+fn parse_cont_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError> {
+    if lst_vec.len() != 2 {
+        return Err(ParseError::from(
+            "Type annotation for continuation has incorrect number of values.",
+        ));
+    }
+    let val_type = parse_type(&lst_vec[1])?;
+    Ok(Type::Cont(Box::new(val_type)))
+}
+
+fn parse_generator_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError> {
+    if lst_vec.len() != 2 {
+        return Err(ParseError::from(
+            "Type annotation for generator has incorrect number of values.",
+        ));
+    }
+    let val_type = parse_type(&lst_vec[1])?;
+    Ok(Type::Generator(Box::new(val_type)))
+}
+
 fn parse_tuple_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError> {
     let tuple_types: Vec<Type> = lst_vec[1..(lst_vec.len())]
         .iter()
@@ -114,10 +141,13 @@ fn parse_tuple_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError
 }
 
 fn parse_record_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError> {
-    let record_types: Vec<(String, Type)> = lst_vec[1..(lst_vec.len())]
+    let record_types: Vector<(String, Type, bool)> = lst_vec[1..(lst_vec.len())]
         .iter()
         .map(|exp| match exp.to_vec() {
             Some(binding) => {
+                // mutable fields are written (mutable label : type)
+                let mutable = binding.len() == 4 && binding[0].as_symbol() == Some("mutable");
+                let binding = if mutable { &binding[1..] } else { &binding[..] };
                 if binding.len() != 3 {
                     return Err(ParseError::from(
                         "Record type binding has incorrect number of values.",
@@ -135,14 +165,16 @@ fn parse_record_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseErro
                 }
                 let typ = parse_type(&binding[2])?;
 
-                Ok((label, typ))
+                Ok((label, typ, mutable))
             }
             None => Err(ParseError::from(
                 "Record type binding is not a proper list of values.",
             )),
         })
-        .collect::<Result<Vec<(String, Type)>, ParseError>>()?;
-    Ok(Type::Record(Vector::from(record_types)))
+        .collect::<Result<Vector<(String, Type, bool)>, ParseError>>()?;
+    let record_types = sort_fields(&record_types, |field| &field.0)
+        .map_err(|name| ParseError(format!("Record type has more than one field named {name}.")))?;
+    Ok(Type::Record(record_types))
 }
 
 fn parse_exists_annotation(lst_vec: Vec<lexpr::Value>) -> Result<Type, ParseError> {
@@ -171,30 +203,16 @@ fn parse_array(exps: &[lexpr::Value]) -> Result<Vector<Expr>, ParseError> {
     exps.iter().map(parse).collect()
 }
 
-fn parse_binop(op: &str, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
-    if rest.len() != 2 {
-        return Err(ParseError::from(
-            "Binary operator has incorrect number of sub-expressions.",
-        ));
-    }
-    let exp1 = parse(&rest[0])?;
-    let exp2 = parse(&rest[1])?;
-    let operator = match op {
-        "and" => BinOp::And,
-        "or" => BinOp::Or,
-        "+" => BinOp::Add,
-        "-" => BinOp::Subtract,
-        "*" => BinOp::Multiply,
-        "/" => BinOp::Divide,
-        "<" => BinOp::LessThan,
-        ">" => BinOp::GreaterThan,
-        "<=" => BinOp::LessOrEqual,
-        ">=" => BinOp::GreaterOrEqual,
-        "=" => BinOp::EqualTo,
-        "concat" => BinOp::Concat,
-        _ => return Err(ParseError::from("Unrecognized binary operator.")),
-    };
-    Ok(Expr::new(ExprKind::Binop(operator, exp1, exp2)))
+fn parse_prim(op: PrimOp, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != op.arity() {
+        return Err(ParseError(format!(
+            "Primitive operation {op} expects {} argument(s), but was given {}.",
+            op.arity(),
+            rest.len()
+        )));
+    }
+    let args = parse_array(rest)?;
+    Ok(Expr::new(ExprKind::Prim(op, args)))
 }
 
 fn parse_if(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
@@ -215,10 +233,27 @@ fn parse_let(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
             "Let expression has incorrect number of arguments.",
         ));
     }
-    let bindings = rest[0]
+    let bindings_vec = parse_let_bindings(&rest[0])?;
+    let body = parse(&rest[1])?;
+    Ok(Expr::new(ExprKind::Let(bindings_vec, body)))
+}
+
+fn parse_letrec(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "Letrec expression has incorrect number of arguments.",
+        ));
+    }
+    let bindings_vec = parse_let_bindings(&rest[0])?;
+    let body = parse(&rest[1])?;
+    Ok(Expr::new(ExprKind::Letrec(bindings_vec, body)))
+}
+
+fn parse_let_bindings(bindings: &lexpr::Value) -> Result<Vector<(String, Expr)>, ParseError> {
+    let bindings = bindings
         .to_vec()
         .ok_or("Let expression bindings are not in a proper list.")?;
-    let bindings_vec: Vector<(String, Expr)> = bindings
+    bindings
         .iter()
         .map(|binding| {
             let binding_vec = binding.to_vec().ok_or("Let binding is not a valid list.")?;
@@ -233,9 +268,7 @@ fn parse_let(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
             let binding_val = parse(&binding_vec[1])?;
             Ok((String::from(binding_name), binding_val))
         })
-        .collect::<Result<Vector<(String, Expr)>, ParseError>>()?;
-    let body = parse(&rest[1])?;
-    Ok(Expr::new(ExprKind::Let(bindings_vec, body)))
+        .collect()
 }
 
 fn parse_lambda(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
@@ -284,12 +317,19 @@ fn unwrap_lambda_args(args: &lexpr::Value) -> Result<Vector<(String, Type)>, Par
 }
 
 fn parse_make_record(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
-    let bindings_vec: Vector<(String, Expr)> = rest
+    let bindings_vec: Vector<(String, Expr, bool)> = rest
         .iter()
         .map(|binding| {
             let binding_vec = binding
                 .to_vec()
                 .ok_or("Value in make-record expression is not a valid list.")?;
+            // mutable fields are written (mutable name value)
+            let mutable = binding_vec.len() == 3 && binding_vec[0].as_symbol() == Some("mutable");
+            let binding_vec = if mutable {
+                &binding_vec[1..]
+            } else {
+                &binding_vec[..]
+            };
             if binding_vec.len() != 2 {
                 return Err(ParseError::from(
                     "Value in make-record expression is incomplete or contains extra values.",
@@ -299,9 +339,9 @@ fn parse_make_record(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
                 .as_symbol()
                 .ok_or("Make-record binding does not have a valid name.")?;
             let binding_val = parse(&binding_vec[1])?;
-            Ok((String::from(binding_name), binding_val))
+            Ok((String::from(binding_name), binding_val, mutable))
         })
-        .collect::<Result<Vector<(String, Expr)>, ParseError>>()?;
+        .collect::<Result<Vector<(String, Expr, bool)>, ParseError>>()?;
     Ok(Expr::new(ExprKind::Record(bindings_vec)))
 }
 
@@ -318,6 +358,24 @@ fn parse_get_record(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
     Ok(Expr::new(ExprKind::RecordGet(bindings, String::from(key))))
 }
 
+fn parse_set_record(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 3 {
+        return Err(ParseError::from(
+            "Record-set! expression has incorrect number of arguments.",
+        ));
+    }
+    let record = parse(&rest[0])?;
+    let key = rest[1]
+        .as_symbol()
+        .ok_or("Record-set! key is not a valid identifier.")?;
+    let new_val = parse(&rest[2])?;
+    Ok(Expr::new(ExprKind::RecordSet(
+        record,
+        String::from(key),
+        new_val,
+    )))
+}
+
 fn parse_begin(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
     if rest.is_empty() {
         return Err(ParseError::from("Begin expression has no arguments."));
@@ -390,6 +448,25 @@ fn parse_null(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
     Ok(Expr::new(ExprKind::Null(val)))
 }
 
+fn parse_display(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Display expression has incorrect number of arguments.",
+        ));
+    }
+    let val = parse(&rest[0])?;
+    Ok(Expr::new(ExprKind::Display(val)))
+}
+
+fn parse_newline(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if !rest.is_empty() {
+        return Err(ParseError::from(
+            "Newline expression has incorrect number of arguments.",
+        ));
+    }
+    Ok(Expr::new(ExprKind::Newline))
+}
+
 fn parse_func(first: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
     let func = parse(first)?;
     let args = parse_array(rest)?;
@@ -464,24 +541,494 @@ fn parse_unpack(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
     )))
 }
 
+fn parse_raise(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 3 {
+        return Err(ParseError::from(
+            "Raise expression has incorrect number of arguments. Perhaps you are missing the type?",
+        ));
+    }
+    let exn = parse(&rest[0])?;
+    if !check_separator(&rest[1], ':') {
+        return Err(ParseError::from(
+            "Raise expression does not have the correct separator : between the exception and type.",
+        ));
+    }
+    let typ = parse_type(&rest[2])?;
+    Ok(Expr::new(ExprKind::Raise(exn, typ)))
+}
+
+fn parse_with_handler(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "With-handler expression has incorrect number of arguments.",
+        ));
+    }
+    // ((e : type) handler)
+    let clause = rest[0]
+        .to_vec()
+        .ok_or("Handler in with-handler expression is malformed.")?;
+    if clause.len() != 2 {
+        return Err(ParseError::from(
+            "Handler in with-handler expression has incorrect number of values.",
+        ));
+    }
+    let param = clause[0]
+        .to_vec()
+        .ok_or("Handler parameter is not a valid list.")?;
+    if param.len() != 3 {
+        return Err(ParseError::from(
+            "Handler parameter is missing values or contains extra values.",
+        ));
+    }
+    let var = param[0]
+        .as_symbol()
+        .ok_or("Handler parameter does not have a valid name.")?;
+    if !check_separator(&param[1], ':') {
+        return Err(ParseError::from(
+            "Handler parameter does not contain the correct : separator.",
+        ));
+    }
+    let typ = parse_type(&param[2])?;
+    let handler = parse(&clause[1])?;
+    let body = parse(&rest[1])?;
+    Ok(Expr::new(ExprKind::Handle(
+        String::from(var),
+        typ,
+        handler,
+        body,
+    )))
+}
+
+fn parse_call_cc(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Call/cc expression has incorrect number of arguments.",
+        ));
+    }
+    let func = parse(&rest[0])?;
+    Ok(Expr::new(ExprKind::CallCC(func)))
+}
+
+fn parse_throw(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 4 {
+        return Err(ParseError::from(
+            "Throw expression has incorrect number of arguments. Perhaps you are missing the type?",
+        ));
+    }
+    let cont = parse(&rest[0])?;
+    let val = parse(&rest[1])?;
+    if !check_separator(&rest[2], ':') {
+        return Err(ParseError::from(
+            "Throw expression does not have the correct separator : between the value and type.",
+        ));
+    }
+    let typ = parse_type(&rest[3])?;
+    Ok(Expr::new(ExprKind::Throw(cont, val, typ)))
+}
+
+fn parse_make_generator(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "Make-generator expression has incorrect number of arguments.",
+        ));
+    }
+    let typ = parse_type(&rest[0])?;
+    let body = parse(&rest[1])?;
+    Ok(Expr::new(ExprKind::MakeGenerator(typ, body)))
+}
+
+fn parse_yield(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Yield expression has incorrect number of arguments.",
+        ));
+    }
+    let val = parse(&rest[0])?;
+    Ok(Expr::new(ExprKind::Yield(val)))
+}
+
+fn parse_next(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Next expression has incorrect number of arguments.",
+        ));
+    }
+    let generator = parse(&rest[0])?;
+    Ok(Expr::new(ExprKind::Next(generator)))
+}
+
+// Derived forms
+//
+// The following forms don't have their own kind of expression. Instead, they
+// are desugared into other expressions, which are wrapped in a derived
+// expression recording the form they came from, so that type errors can refer
+// to it.
+
+/// Returns the expression that a derived form desugars to, recording the form.
+fn derived(value: &lexpr::Value, exp: Expr) -> Expr {
+    Expr::new(ExprKind::Derived(value.to_string(), exp))
+}
+
+/// Parse a sequence of expressions, which are evaluated in order (like a
+/// begin expression) if there is more than one.
+fn parse_body(exps: &[lexpr::Value], form: &str) -> Result<Expr, ParseError> {
+    match exps {
+        [] => Err(ParseError(format!("{form} has no expressions."))),
+        [exp] => parse(exp),
+        _ => Ok(Expr::new(ExprKind::Begin(parse_array(exps)?))),
+    }
+}
+
+/// Split the clauses of a cond or case expression into the clauses before
+/// the else clause, and the expressions of the else clause.
+fn split_else(
+    clauses: &[lexpr::Value],
+    form: &str,
+) -> Result<(Vec<Vec<lexpr::Value>>, Vec<lexpr::Value>), ParseError> {
+    let mut clause_vecs = clauses
+        .iter()
+        .map(|clause| {
+            clause
+                .to_vec()
+                .ok_or(ParseError(format!("{form} clause is not a valid list.")))
+        })
+        .collect::<Result<Vec<Vec<lexpr::Value>>, ParseError>>()?;
+    let is_else =
+        |clause: &Vec<lexpr::Value>| clause.first().and_then(|v| v.as_symbol()) == Some("else");
+    match clause_vecs.pop() {
+        Some(mut else_clause) if is_else(&else_clause) => {
+            if clause_vecs.iter().any(is_else) {
+                return Err(ParseError(format!(
+                    "{form} expression has more than one else clause."
+                )));
+            }
+            else_clause.remove(0);
+            Ok((clause_vecs, else_clause))
+        }
+        _ => Err(ParseError(format!(
+            "{form} expression does not end with an else clause."
+        ))),
+    }
+}
+
+/// Parse `(cond (test exp ...) ... (else exp ...))` into nested if
+/// expressions. An else clause is required, since every if expression needs
+/// an alternate.
+fn parse_cond(value: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    let (clauses, else_exps) = split_else(rest, "Cond")?;
+    let mut result = parse_body(&else_exps, "Cond clause")?;
+    for clause in clauses.iter().rev() {
+        let (test, exps) = clause
+            .split_first()
+            .ok_or("Cond clause does not have a test.")?;
+        let body = parse_body(exps, "Cond clause")?;
+        result = Expr::new(ExprKind::If(parse(test)?, body, result));
+    }
+    Ok(derived(value, result))
+}
+
+/// Parse `(case key ((datum ...) exp ...) ... (else exp ...))`, where each
+/// datum is an integer, into nested if expressions which compare the key
+/// (evaluated once) against each datum.
+fn parse_case(value: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    let (key, clauses) = rest.split_first().ok_or("Case expression has no key.")?;
+    let (clauses, else_exps) = split_else(clauses, "Case")?;
+    let key_name = generate_macro_name("key");
+    let mut result = parse_body(&else_exps, "Case clause")?;
+    for clause in clauses.iter().rev() {
+        let (data, exps) = clause
+            .split_first()
+            .ok_or("Case clause does not have a list of data.")?;
+        let data = data
+            .to_vec()
+            .ok_or("Case clause data are not in a valid list.")?;
+        let test = data
+            .iter()
+            .map(|datum| {
+                let num = datum
+                    .as_i64()
+                    .and_then(|num| i32::try_from(num).ok())
+                    .ok_or("Case datum is not a 32-bit integer.")?;
+                Ok(Expr::new(ExprKind::Prim(
+                    PrimOp::EqualTo,
+                    vector![
+                        Expr::new(ExprKind::Id(key_name.clone())),
+                        Expr::new(ExprKind::Num(num)),
+                    ],
+                )))
+            })
+            .reduce(|test1: Result<Expr, ParseError>, test2| {
+                Ok(Expr::new(ExprKind::Prim(
+                    PrimOp::Or,
+                    vector![test1?, test2?],
+                )))
+            })
+            .ok_or("Case clause has no data.")??;
+        let body = parse_body(exps, "Case clause")?;
+        result = Expr::new(ExprKind::If(test, body, result));
+    }
+    let exp = Expr::new(ExprKind::Let(Vector::unit((key_name, parse(key)?)), result));
+    Ok(derived(value, exp))
+}
+
+/// Parse `(when test exp ...)` (or `(unless test exp ...)`), which evaluates
+/// the expressions only if the test is true (or false). Either way, its value
+/// is 0.
+fn parse_when(value: &lexpr::Value, rest: &[lexpr::Value], when: bool) -> Result<Expr, ParseError> {
+    let form = if when { "When" } else { "Unless" };
+    let (test, exps) = rest.split_first().ok_or(ParseError(format!(
+        "{form} expression does not have a test."
+    )))?;
+    if exps.is_empty() {
+        return Err(ParseError(format!("{form} expression has no expressions.")));
+    }
+    let mut body = parse_array(exps)?;
+    body.push_back(Expr::new(ExprKind::Num(0)));
+    let body = Expr::new(ExprKind::Begin(body));
+    let zero = Expr::new(ExprKind::Num(0));
+    let (cons, alt) = if when { (body, zero) } else { (zero, body) };
+    Ok(derived(
+        value,
+        Expr::new(ExprKind::If(parse(test)?, cons, alt)),
+    ))
+}
+
+/// Parse `(let* ((name exp) ...) body)` into nested let expressions, so that
+/// each binding can refer to the ones before it.
+fn parse_let_star(value: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "Let* expression has incorrect number of arguments.",
+        ));
+    }
+    let bindings = parse_let_bindings(&rest[0])?;
+    let body = parse(&rest[1])?;
+    let result = bindings.into_iter().rev().fold(body, |body, binding| {
+        Expr::new(ExprKind::Let(Vector::unit(binding), body))
+    });
+    Ok(derived(value, result))
+}
+
+/// Parse a loop variable of a named let (`(name : type init)`) or of a do
+/// expression (`(name : type init [step])`).
+fn parse_loop_var(
+    binding: &lexpr::Value,
+    form: &str,
+    allow_step: bool,
+) -> Result<(String, Type, Expr, Option<Expr>), ParseError> {
+    let binding_vec = binding
+        .to_vec()
+        .ok_or(ParseError(format!("{form} variable is not a valid list.")))?;
+    if binding_vec.len() != 4 && !(allow_step && binding_vec.len() == 5) {
+        return Err(ParseError(format!(
+            "{form} variable is missing values or contains extra values."
+        )));
+    }
+    let name = binding_vec[0].as_symbol().ok_or(ParseError(format!(
+        "{form} variable does not have a valid name."
+    )))?;
+    if !check_separator(&binding_vec[1], ':') {
+        return Err(ParseError(format!(
+            "{form} variable does not contain the correct : separator."
+        )));
+    }
+    let typ = parse_type(&binding_vec[2])?;
+    let init = parse(&binding_vec[3])?;
+    let step = binding_vec.get(4).map(parse).transpose()?;
+    Ok((String::from(name), typ, init, step))
+}
+
+/// Returns an expression which calls a function named `loop_name` with the
+/// initial values of the given parameters, i.e.
+/// `((letrec ((loop_name (lambda params ret_typ body))) loop_name) inits ...)`.
+/// The body of the function may call it recursively, but the initial values
+/// are outside the scope of its name.
+fn loop_exp(
+    loop_name: &str,
+    params: Vector<(String, Type)>,
+    ret_typ: Type,
+    body: Expr,
+    inits: Vector<Expr>,
+) -> Expr {
+    let lambda = Expr::new(ExprKind::Lambda(params, ret_typ, body));
+    let func = Expr::new(ExprKind::Letrec(
+        Vector::unit((loop_name.to_string(), lambda)),
+        Expr::new(ExprKind::Id(loop_name.to_string())),
+    ));
+    Expr::new(ExprKind::FnApp(func, inits))
+}
+
+/// Parse a named let, `(let name ((var : type init) ...) : type body)`, where
+/// the body can call `name` to loop with new values of the variables.
+fn parse_named_let(value: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 5 {
+        return Err(ParseError::from(
+            "Named let expression has incorrect number of arguments. Perhaps you are missing the return type?",
+        ));
+    }
+    let name = rest[0]
+        .as_symbol()
+        .ok_or("Named let expression does not have a valid name.")?;
+    let vars = rest[1]
+        .to_vec()
+        .ok_or("Named let variables are not in a proper list.")?
+        .iter()
+        .map(|binding| parse_loop_var(binding, "Named let", false))
+        .collect::<Result<Vec<_>, ParseError>>()?;
+    if !check_separator(&rest[2], ':') {
+        return Err(ParseError::from(
+            "Named let expression does not have the correct separator : between the variables and return type.",
+        ));
+    }
+    let ret_typ = parse_type(&rest[3])?;
+    let params: Vector<(String, Type)> = vars
+        .iter()
+        .map(|(name, typ, _init, _step)| (name.clone(), typ.clone()))
+        .collect();
+    let body = parse(&rest[4])?;
+    let inits = vars
+        .into_iter()
+        .map(|(_name, _typ, init, _step)| init)
+        .collect();
+    Ok(derived(value, loop_exp(name, params, ret_typ, body, inits)))
+}
+
+/// Parse `(do ((var : type init [step]) ...) (test exp ...) [: type] command
+/// ...)`. Until the test is true, the commands are evaluated and the variables
+/// are updated to their steps (variables without a step keep their value).
+/// Then the expressions after the test are evaluated, and the value of the
+/// last one (whose type must be given, unless it is int) is the value of the
+/// loop. If there are no expressions, the value is 0.
+fn parse_do(value: &lexpr::Value, rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() < 2 {
+        return Err(ParseError::from(
+            "Do expression has incorrect number of arguments.",
+        ));
+    }
+    let vars = rest[0]
+        .to_vec()
+        .ok_or("Do variables are not in a proper list.")?
+        .iter()
+        .map(|binding| parse_loop_var(binding, "Do", true))
+        .collect::<Result<Vec<_>, ParseError>>()?;
+    let exit = rest[1]
+        .to_vec()
+        .ok_or("Do expression test is not in a valid list.")?;
+    let (test, result_exps) = exit
+        .split_first()
+        .ok_or("Do expression does not have a test.")?;
+    let (ret_typ, commands) = match rest.get(2) {
+        Some(sep) if check_separator(sep, ':') => {
+            let typ = rest
+                .get(3)
+                .ok_or("Do expression is missing its return type.")?;
+            (parse_type(typ)?, &rest[4..])
+        }
+        _ => (Type::Int, &rest[2..]),
+    };
+    let result = if result_exps.is_empty() {
+        Expr::new(ExprKind::Num(0))
+    } else {
+        parse_body(result_exps, "Do result")?
+    };
+
+    let loop_name = generate_macro_name("do");
+    let steps = vars
+        .iter()
+        .map(|(name, _typ, _init, step)| match step {
+            Some(step) => step.clone(),
+            None => Expr::new(ExprKind::Id(name.clone())),
+        })
+        .collect();
+    let mut step_exps = parse_array(commands)?;
+    step_exps.push_back(Expr::new(ExprKind::FnApp(
+        Expr::new(ExprKind::Id(loop_name.clone())),
+        steps,
+    )));
+    let step = if step_exps.len() == 1 {
+        step_exps.pop_back().unwrap()
+    } else {
+        Expr::new(ExprKind::Begin(step_exps))
+    };
+    let body = Expr::new(ExprKind::If(parse(test)?, result, step));
+
+    let params = vars
+        .iter()
+        .map(|(name, typ, _init, _step)| (name.clone(), typ.clone()))
+        .collect();
+    let inits = vars
+        .into_iter()
+        .map(|(_name, _typ, init, _step)| init)
+        .collect();
+    Ok(derived(
+        value,
+        loop_exp(&loop_name, params, ret_typ, body, inits),
+    ))
+}
+
+/// Parse a quoted datum, e.g. `'foo` (which is read as `(quote foo)`).
+///
+/// Quoting a symbol produces a symbol, while self-evaluating literals (such
+/// as numbers and strings) evaluate to themselves when quoted. Quoting a list
+/// produces a constant list, e.g. `'(1 2 3)`.
+fn parse_quote(rest: &[lexpr::Value]) -> Result<Expr, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Quote does not have the correct number of arguments.",
+        ));
+    }
+    match parse_datum(&rest[0])? {
+        Datum::Symbol(x) => Ok(Expr::new(ExprKind::Symbol(x))),
+        Datum::List(data) => Ok(Expr::new(ExprKind::Quote(data))),
+        _ => parse(&rest[0]),
+    }
+}
+
+/// Parse a value within a quoted list. Symbols aren't evaluated, so names
+/// like `true` are symbols rather than booleans.
+fn parse_datum(value: &lexpr::Value) -> Result<Datum, ParseError> {
+    match value {
+        lexpr::Value::Number(_) => match *parse(value)?.kind {
+            ExprKind::Num(x) => Ok(Datum::Num(x)),
+            ExprKind::Int64(x) => Ok(Datum::Int64(x)),
+            ExprKind::Float(x) => Ok(Datum::Float(x)),
+            _ => Err(ParseError::from("Invalid number found in quoted list.")),
+        },
+        lexpr::Value::Bool(x) => Ok(Datum::Bool(*x)),
+        lexpr::Value::String(x) => Ok(Datum::Str(x.to_string())),
+        lexpr::Value::Char(x) => Ok(Datum::Char(*x)),
+        lexpr::Value::Symbol(x) => Ok(Datum::Symbol(x.to_string())),
+        lexpr::Value::Null | lexpr::Value::Cons(_) => {
+            let lst = value.to_vec().ok_or("Quoted list is not a valid list.")?;
+            let data = lst
+                .iter()
+                .map(parse_datum)
+                .collect::<Result<Vector<Datum>, ParseError>>()?;
+            Ok(Datum::List(data))
+        }
+        _ => Err(ParseError::from(
+            "Only symbols, literals and lists can be quoted.",
+        )),
+    }
+}
+
 pub fn parse(value: &lexpr::Value) -> Result<Expr, ParseError> {
     match value {
-        lexpr::Value::Number(x) => match x.as_i64() {
-            Some(val) => {
-                if val >= i32::MIN as i64 && val <= i32::MAX as i64 {
-                    Ok(Expr::new(ExprKind::Num(val as i32)))
-                } else {
-                    Err(ParseError::from(
-                        "Invalid number found (must be a 32-bit integer).",
-                    ))
-                }
-            }
-            None => Err(ParseError::from(
-                "Invalid number found (must be a 32-bit integer).",
+        // integers which don't fit in 32 bits are int64s, and numbers with a
+        // decimal point or exponent are floats
+        lexpr::Value::Number(x) => match (x.as_i64(), x.is_f64()) {
+            (Some(val), _) => match i32::try_from(val) {
+                Ok(val) => Ok(Expr::new(ExprKind::Num(val))),
+                Err(_) => Ok(Expr::new(ExprKind::Int64(val))),
+            },
+            (None, true) => Ok(Expr::new(ExprKind::Float(x.as_f64().unwrap()))),
+            (None, false) => Err(ParseError::from(
+                "Invalid number found (must be a 64-bit integer or a float).",
             )),
         },
         lexpr::Value::Bool(x) => Ok(Expr::new(ExprKind::Bool(*x))),
         lexpr::Value::String(x) => Ok(Expr::new(ExprKind::Str((*x).to_string()))),
+        lexpr::Value::Char(x) => Ok(Expr::new(ExprKind::Char(*x))),
         lexpr::Value::Cons(_) => {
             let lst = value
                 .to_vec()
@@ -498,15 +1045,28 @@ pub fn parse(value: &lexpr::Value) -> Result<Expr, ParseError> {
             let first = &(lst_parts.0)[0];
             let rest = lst_parts.1;
 
+            if let Some(op) = first.as_symbol().and_then(PrimOp::from_name) {
+                return parse_prim(op, rest);
+            }
             match first.as_symbol() {
                 Some(val) => match val {
-                    "and" | "or" | "+" | "*" | "-" | "/" | ">" | "<" | ">=" | "<=" | "="
-                    | "concat" => parse_binop(val, rest),
                     "if" => parse_if(rest),
+                    "quote" => parse_quote(rest),
+                    "let" if rest.first().is_some_and(|first| first.is_symbol()) => {
+                        parse_named_let(value, rest)
+                    }
                     "let" => parse_let(rest),
+                    "letrec" => parse_letrec(rest),
+                    "let*" => parse_let_star(value, rest),
+                    "cond" => parse_cond(value, rest),
+                    "case" => parse_case(value, rest),
+                    "when" => parse_when(value, rest, true),
+                    "unless" => parse_when(value, rest, false),
+                    "do" => parse_do(value, rest),
                     "lambda" => parse_lambda(rest),
                     "make-record" => parse_make_record(rest),
                     "record-ref" => parse_get_record(rest),
+                    "record-set!" => parse_set_record(rest),
                     "begin" => parse_begin(rest),
                     "set!" => parse_set_bang(rest),
                     "cons" => parse_cons(rest),
@@ -514,10 +1074,19 @@ pub fn parse(value: &lexpr::Value) -> Result<Expr, ParseError> {
                     "cdr" => parse_cdr(rest),
                     "null?" => parse_is_null(rest),
                     "null" => parse_null(rest),
+                    "display" => parse_display(rest),
+                    "newline" => parse_newline(rest),
                     "make-tuple" => parse_make_tuple(rest),
                     "tuple-ref" => parse_get_tuple(rest),
                     "pack" => parse_pack(rest),
                     "unpack" => parse_unpack(rest),
+                    "raise" => parse_raise(rest),
+                    "with-handler" => parse_with_handler(rest),
+                    "call/cc" => parse_call_cc(rest),
+                    "throw" => parse_throw(rest),
+                    "make-generator" => parse_make_generator(rest),
+                    "yield" => parse_yield(rest),
+                    "next" => parse_next(rest),
                     _ => parse_func(first, rest),
                 },
                 None => parse_func(first, rest),
@@ -531,3 +1100,137 @@ pub fn parse(value: &lexpr::Value) -> Result<Expr, ParseError> {
         _ => Err(ParseError::from("Unrecognized form of expression found.")),
     }
 }
+
+fn parse_extern(rest: &[lexpr::Value]) -> Result<Decl, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "Extern declaration does not have the correct number of arguments.",
+        ));
+    }
+    let name = rest[0]
+        .as_symbol()
+        .ok_or("Extern declaration does not have a valid name.")?;
+    let typ = parse_type(&rest[1])?;
+    match typ {
+        Type::Func(_, _) => Ok(Decl::Extern(name.to_string(), typ)),
+        _ => Err(ParseError::from(
+            "Extern declaration must have a function type.",
+        )),
+    }
+}
+
+fn parse_define(rest: &[lexpr::Value]) -> Result<Decl, ParseError> {
+    if rest.len() != 2 {
+        return Err(ParseError::from(
+            "Define does not have the correct number of arguments.",
+        ));
+    }
+    let name = rest[0]
+        .as_symbol()
+        .ok_or("Define does not have a valid name.")?;
+    Ok(Decl::Define(name.to_string(), parse(&rest[1])?))
+}
+
+/// Parse an export declaration, e.g. `(export add "add")`. If the export
+/// name is omitted, the definition is exported under its own name.
+fn parse_export(rest: &[lexpr::Value]) -> Result<Decl, ParseError> {
+    if rest.is_empty() || rest.len() > 2 {
+        return Err(ParseError::from(
+            "Export declaration does not have the correct number of arguments.",
+        ));
+    }
+    let name = rest[0]
+        .as_symbol()
+        .ok_or("Export declaration does not have a valid name.")?;
+    let export_name = match rest.get(1) {
+        Some(value) => value.as_str().ok_or("Export name must be a string.")?,
+        None => name,
+    };
+    Ok(Decl::Export(name.to_string(), export_name.to_string()))
+}
+
+/// Parse a provide declaration, e.g. `(provide add sub)`.
+fn parse_provide(rest: &[lexpr::Value]) -> Result<Decl, ParseError> {
+    let names = rest
+        .iter()
+        .map(|value| {
+            let name = value
+                .as_symbol()
+                .ok_or("Provide declaration does not have a valid name.")?;
+            Ok(name.to_string())
+        })
+        .collect::<Result<Vector<String>, ParseError>>()?;
+    Ok(Decl::Provide(names))
+}
+
+/// Parse an import declaration, e.g. `(import "math.scm")`.
+fn parse_import(rest: &[lexpr::Value]) -> Result<Decl, ParseError> {
+    if rest.len() != 1 {
+        return Err(ParseError::from(
+            "Import declaration does not have the correct number of arguments.",
+        ));
+    }
+    let path = rest[0].as_str().ok_or("Import path must be a string.")?;
+    Ok(Decl::Import(path.to_string()))
+}
+
+/// Parse a top-level declaration, returning None if the value is not a
+/// declaration (and so should be parsed as an expression instead).
+pub fn parse_decl(value: &lexpr::Value) -> Result<Option<Decl>, ParseError> {
+    let lst = match value.to_vec() {
+        Some(lst) if !lst.is_empty() => lst,
+        _ => return Ok(None),
+    };
+    match lst[0].as_symbol() {
+        Some("extern") => Ok(Some(parse_extern(&lst[1..])?)),
+        Some("define") => Ok(Some(parse_define(&lst[1..])?)),
+        Some("export") => Ok(Some(parse_export(&lst[1..])?)),
+        Some("provide") => Ok(Some(parse_provide(&lst[1..])?)),
+        Some("import") => Ok(Some(parse_import(&lst[1..])?)),
+        _ => Ok(None),
+    }
+}
+
+/// Parse a whole program, consisting of any number of top-level declarations
+/// followed by a single expression.
+///
+/// Macros defined within the program (see `macro_expand`) are expanded
+/// before the program is parsed.
+pub fn parse_prog(values: &[lexpr::Value]) -> Result<SourceProg, ParseError> {
+    let values = expand_prog(values).map_err(|e| ParseError(e.to_string()))?;
+    let (last, decl_values) = values
+        .split_last()
+        .ok_or("Program does not contain an expression.")?;
+    let decls = decl_values
+        .iter()
+        .map(|value| {
+            parse_decl(value)?.ok_or(ParseError::from(
+                "Only declarations may precede the main expression of a program.",
+            ))
+        })
+        .collect::<Result<Vector<Decl>, ParseError>>()?;
+    if parse_decl(last)?.is_some() {
+        return Err(ParseError::from(
+            "Program must end with an expression, not a declaration.",
+        ));
+    }
+    Ok(SourceProg {
+        decls,
+        exp: parse(last)?,
+    })
+}
+
+/// Parse a module which is imported by other modules, consisting only of
+/// top-level declarations (and no main expression). Like in programs, macros
+/// are expanded first.
+pub fn parse_module(values: &[lexpr::Value]) -> Result<Vector<Decl>, ParseError> {
+    expand_prog(values)
+        .map_err(|e| ParseError(e.to_string()))?
+        .iter()
+        .map(|value| {
+            parse_decl(value)?.ok_or(ParseError::from(
+                "Imported modules may only contain declarations.",
+            ))
+        })
+        .collect()
+}
@@ -3,11 +3,12 @@
 // does transformations on the cases I am interested in, and does some form
 // of default recursion in other cases
 
+use crate::analysis::is_pure;
 use crate::ast_transform::{
     transform_type_recursive, transform_typed_exp_recursive, transform_typed_prog_recursive,
 };
-use crate::common::{ExprKind, Prog, TypedExpr};
-use crate::types::Type;
+use crate::common::{ExprKind, Prog, TypedExpr, generate_var_name};
+use crate::types::{Type, sort_fields};
 use im_rc::Vector;
 
 #[derive(Clone, Debug)]
@@ -28,10 +29,15 @@ impl std::fmt::Display for RecordElimError {
     }
 }
 
-/// Convert an expression into one without record or record-ref expressions.
+/// Convert an expression into one without record, record-ref or record-set!
+/// expressions.
 ///
-/// Conversion is performed by replacing records with corresponding tuples,
-/// and replacing record-ref with tuple-ref expressions.
+/// Conversion is performed by replacing records with corresponding tuples
+/// (whose components are the fields sorted by name), and replacing record-ref
+/// and record-set! with tuple-ref and tuple-set! expressions. The type
+/// checker copies records into the type they are used as (see
+/// `type_check::coerce`), so every record of a given type has the same
+/// layout, and each field can be read or assigned at a fixed tuple index.
 ///
 /// Expression must be type checked (annotated with types) before being passed
 /// in. After conversion, the output expression of this function will have all
@@ -40,7 +46,8 @@ pub fn record_elim_exp(exp: &TypedExpr) -> Result<TypedExpr, RecordElimError> {
     transform_typed_exp_recursive(exp, re_helper, re_type_helper)
 }
 
-/// Converts a program into one without record or record-ref expressions.
+/// Converts a program into one without record, record-ref or record-set!
+/// expressions.
 ///
 /// See `record_elim_exp` for more specific details.
 pub fn record_elim_prog(prog: &Prog<TypedExpr>) -> Result<Prog<TypedExpr>, RecordElimError> {
@@ -56,21 +63,22 @@ fn re_type_helper(typ: &Type) -> Option<Result<Type, RecordElimError>> {
         Type::Record(bindings) => {
             let re_bindings = bindings
                 .iter()
-                .map(|(name, inner_type)| Ok((name.clone(), re_type(inner_type)?)))
-                .collect::<Result<Vec<(String, Type)>, RecordElimError>>();
+                .map(|(name, inner_type, mutable)| {
+                    Ok((name.clone(), re_type(inner_type)?, *mutable))
+                })
+                .collect::<Result<Vec<(String, Type, bool)>, RecordElimError>>();
             // We can't immediately unwrap the results in the line above since
             // this function returns an Option of result. We could resolve this
             // by using _another_ helper function that just returns a
             // Result<Type, ..> just for the convenience of using the ?
             // operator, but that seems unnecessary.
-            let mut re_bindings = match re_bindings {
+            let re_bindings = match re_bindings.and_then(|vec| layout(&Vector::from(vec))) {
                 Ok(vec) => vec,
                 Err(e) => return Some(Err(e)),
             };
-            re_bindings.sort_unstable_by(|a, b| a.0.cmp(&b.0));
             let type_vec: Vec<Type> = re_bindings
                 .iter()
-                .map(|(_field, typ)| typ.clone())
+                .map(|(_field, typ, _mutable)| typ.clone())
                 .collect();
             Some(Ok(Type::Tuple(Vector::from(type_vec))))
         }
@@ -83,28 +91,58 @@ fn re_helper(exp: &TypedExpr) -> Option<Result<TypedExpr, RecordElimError>> {
         ExprKind::Record(bindings) => {
             let rbindings = bindings
                 .iter()
-                .map(|(name, subexp)| {
+                .map(|(name, subexp, mutable)| {
                     let tsubexp = record_elim_exp(subexp)?;
-                    Ok((name.clone(), tsubexp))
+                    Ok((name.clone(), tsubexp, *mutable))
                 })
-                .collect::<Result<Vector<(String, TypedExpr)>, RecordElimError>>();
+                .collect::<Result<Vector<(String, TypedExpr, bool)>, RecordElimError>>();
             let rbindings = match rbindings {
                 Ok(vec) => vec,
                 Err(e) => return Some(Err(e)),
             };
-            let mut bindings_vec: Vec<(String, TypedExpr)> = rbindings.iter().cloned().collect();
-            bindings_vec.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-            let exp_vec: Vector<TypedExpr> = bindings_vec
+            let sorted_bindings = match layout(&rbindings) {
+                Ok(vec) => vec,
+                Err(e) => return Some(Err(e)),
+            };
+            let inner_types = sorted_bindings
                 .iter()
-                .map(|(_field, exp)| exp.clone())
+                .map(|(_field, typed_exp, _mutable)| typed_exp.typ.clone())
+                .collect::<Vector<Type>>();
+            let tuple_type = Type::Tuple(inner_types);
+            let in_order = sorted_bindings
+                .iter()
+                .map(|pair| &pair.0)
+                .eq(rbindings.iter().map(|pair| &pair.0));
+            if in_order || rbindings.iter().all(|pair| is_pure(&pair.1)) {
+                let exp_vec = sorted_bindings.into_iter().map(|pair| pair.1).collect();
+                return Some(Ok(TypedExpr::new(tuple_type, ExprKind::Tuple(exp_vec))));
+            }
+            // Otherwise the fields are evaluated in the order they were
+            // written, and then stored in the order they are laid out
+            let var_bindings: Vector<(String, String, TypedExpr)> = rbindings
+                .into_iter()
+                .map(|(name, typed_exp, _mutable)| (name, generate_var_name(), typed_exp))
                 .collect();
-            let inner_types = exp_vec
+            let exp_vec = sorted_bindings
                 .iter()
-                .map(|typed_exp| typed_exp.typ.clone())
-                .collect::<Vector<Type>>();
+                .map(|(name, typed_exp, _mutable)| {
+                    let (_name, var, _exp) = var_bindings
+                        .iter()
+                        .find(|triple| triple.0 == *name)
+                        .unwrap();
+                    TypedExpr::new(typed_exp.typ.clone(), ExprKind::Id(var.clone()))
+                })
+                .collect();
+            let let_bindings = var_bindings
+                .into_iter()
+                .map(|(_name, var, typed_exp)| (var, typed_exp))
+                .collect();
             Some(Ok(TypedExpr::new(
-                Type::Tuple(inner_types),
-                ExprKind::Tuple(exp_vec),
+                tuple_type.clone(),
+                ExprKind::Let(
+                    let_bindings,
+                    TypedExpr::new(tuple_type, ExprKind::Tuple(exp_vec)),
+                ),
             )))
         }
         ExprKind::RecordGet(record, key) => {
@@ -135,21 +173,47 @@ fn re_helper(exp: &TypedExpr) -> Option<Result<TypedExpr, RecordElimError>> {
                 ))),
             }
         }
+        ExprKind::RecordSet(record, key, val) => {
+            let tuple = match record_elim_exp(record) {
+                Ok(val) => val,
+                Err(e) => return Some(Err(e)),
+            };
+            let tuple_index = match get_field_index(record, key) {
+                Ok(val) => val,
+                Err(e) => return Some(Err(e)),
+            };
+            let new_val = match record_elim_exp(val) {
+                Ok(val) => val,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(Ok(TypedExpr::new(
+                new_val.typ.clone(),
+                ExprKind::TupleSet(tuple, tuple_index, new_val),
+            )))
+        }
         _ => None,
     }
 }
 
 fn get_field_index(record: &TypedExpr, field: &str) -> Result<u32, RecordElimError> {
-    let mut fields_vec: Vec<(String, Type)> = match &record.typ {
-        Type::Record(fields) => Ok(fields.iter().cloned().collect()),
+    let fields_vec = match &record.typ {
+        Type::Record(fields) => layout(fields),
         _ => Err(RecordElimError::from(
             "Type annotation for record has incorrect type.",
         )),
     }?;
-    fields_vec.sort_unstable_by(|a, b| a.0.cmp(&b.0));
     fields_vec
         .iter()
         .position(|pair| pair.0 == field)
         .map(|val| val as u32)
         .ok_or_else(|| RecordElimError::from("Field not found in record."))
 }
+
+/// Order the fields of a record the way they are laid out in the tuple that
+/// replaces it (see `types::sort_fields`).
+fn layout<T: Clone>(
+    fields: &Vector<(String, T, bool)>,
+) -> Result<Vector<(String, T, bool)>, RecordElimError> {
+    sort_fields(fields, |field| &field.0)
+        .map_err(|_| RecordElimError::from("Record has more than one field with the same name."))
+}
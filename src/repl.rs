@@ -0,0 +1,136 @@
+//! This module implements the state behind the interactive REPL (see
+//! `scheme-to-wasm repl`). It is only available when the `runtime` feature is
+//! enabled.
+
+use crate::common::{Decl, Expr, ExprKind, SourceProg, TypeEnv};
+use crate::macro_expand::MacroEnv;
+use crate::marshal::SchemeValue;
+use crate::parse::{parse, parse_decl};
+use crate::runtime::{Session, SessionOptions};
+use crate::type_check::{tc_with_env, type_check_define};
+use crate::types::Type;
+
+use im_rc::Vector;
+
+#[derive(Clone, Debug)]
+pub struct ReplError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for ReplError {}
+
+impl From<&str> for ReplError {
+    fn from(message: &str) -> Self {
+        ReplError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for ReplError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ReplError: {}", self.0)
+    }
+}
+
+/// The result of a single input to the REPL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplOutput {
+    Defined(String, Type),    // name, type
+    DefinedSyntax(String),    // macro name
+    Value(SchemeValue, Type), // result, type
+}
+
+impl std::fmt::Display for ReplOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplOutput::Defined(name, typ) => write!(f, "{name} : {typ}"),
+            ReplOutput::DefinedSyntax(name) => write!(f, "{name} : syntax"),
+            ReplOutput::Value(val, typ) => write!(f, "{val} : {typ}"),
+        }
+    }
+}
+
+/// An interactive session, in which definitions entered earlier remain
+/// available to later inputs.
+///
+/// Each input is type checked against the types of the definitions entered
+/// so far. Expressions are then evaluated by compiling them as the main
+/// expression of a program containing all of the definitions, so the state of
+/// global variables (e.g. after a set!) does not carry over between inputs.
+pub struct Repl {
+    session: Session,
+    macros: MacroEnv,
+    decls: Vector<Decl>,
+    env: TypeEnv,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new(SessionOptions::default())
+    }
+}
+
+impl Repl {
+    pub fn new(options: SessionOptions) -> Self {
+        Repl {
+            session: Session::new(options),
+            macros: MacroEnv::new(),
+            decls: Vector::new(),
+            env: TypeEnv::new(),
+        }
+    }
+
+    /// Handle a single input, which is either a definition, a macro
+    /// definition or an expression. Macros stay available to later inputs,
+    /// like definitions.
+    ///
+    /// Definitions are also run when they are entered, so that any errors in
+    /// them (such as traps) are reported immediately. An input which fails
+    /// leaves the REPL unchanged.
+    pub fn input(&mut self, value: &lexpr::Value) -> Result<ReplOutput, ReplError> {
+        if let Some(name) = self
+            .macros
+            .define_syntax(value)
+            .map_err(|e| ReplError(e.to_string()))?
+        {
+            return Ok(ReplOutput::DefinedSyntax(name));
+        }
+        let value = self
+            .macros
+            .expand(value)
+            .map_err(|e| ReplError(e.to_string()))?;
+        match parse_decl(&value).map_err(|e| ReplError(e.to_string()))? {
+            Some(Decl::Define(name, exp)) => {
+                if self.env.find(&name).is_some() {
+                    return Err(ReplError(format!("{name} is already defined.")));
+                }
+                let typ = type_check_define(&name, &exp, &self.env)
+                    .map_err(|e| ReplError(e.to_string()))?
+                    .typ;
+                let mut decls = self.decls.clone();
+                decls.push_back(Decl::Define(name.clone(), exp));
+                self.run(decls.clone(), Expr::new(ExprKind::Num(0)))?;
+
+                self.decls = decls;
+                self.env = self.env.add_binding((name.clone(), typ.clone()));
+                Ok(ReplOutput::Defined(name, typ))
+            }
+            Some(_) => Err(ReplError::from(
+                "Only definitions and expressions may be entered in the REPL.",
+            )),
+            None => {
+                let exp = parse(&value).map_err(|e| ReplError(e.to_string()))?;
+                let typ = tc_with_env(&exp, &self.env)
+                    .map_err(|e| ReplError(e.to_string()))?
+                    .typ;
+                let val = self.run(self.decls.clone(), exp)?;
+                Ok(ReplOutput::Value(val, typ))
+            }
+        }
+    }
+
+    fn run(&mut self, decls: Vector<Decl>, exp: Expr) -> Result<SchemeValue, ReplError> {
+        let prog = SourceProg { decls, exp };
+        self.session
+            .eval_prog(&prog)
+            .map_err(|e| ReplError(e.to_string()))
+    }
+}
@@ -0,0 +1,170 @@
+//! This module provides an API for compiling and running programs in a single
+//! call, using wasmer as the WebAssembly runtime. It is only available when
+//! the `runtime` feature is enabled.
+
+use crate::common::SourceProg;
+use crate::compile::{CompileOptions, compile_prog_with_options};
+use crate::generate_code::{CodeGenerateOptions, construct_module_from_prog_with_options};
+use crate::marshal::{SchemeValue, decode_value};
+use crate::parse::parse_prog;
+use crate::type_check::type_check_source_prog;
+
+use wasmer::{Instance, Module, Store, Value, imports};
+
+#[derive(Clone, Debug)]
+pub struct SessionError(String);
+
+// Allows other errors to wrap this one
+impl std::error::Error for SessionError {}
+
+impl From<&str> for SessionError {
+    fn from(message: &str) -> Self {
+        SessionError(String::from(message))
+    }
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SessionError: {}", self.0)
+    }
+}
+
+/// Options controlling how a `Session` compiles and runs programs.
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    pub compile: CompileOptions,
+    /// The amount of fuel each program may use before it is stopped, which
+    /// is roughly the number of WebAssembly instructions it runs (see
+    /// `CodeGenerateOptions::metering`). If None, programs may run forever.
+    pub fuel: Option<u32>,
+    /// The largest amount of linear memory (in bytes) each program may use.
+    /// Programs whose data does not fit are rejected, while allocations at
+    /// run time trap once they pass the limit rounded up to whole 64 KiB
+    /// pages. If None, programs get the default amount of memory.
+    pub memory_limit: Option<u32>,
+}
+
+/// A runtime for compiling and running programs, e.g.
+///
+/// ```ignore
+/// let mut session = Session::default();
+/// let value = session.eval("(cons 1 (null int))")?;
+/// ```
+///
+/// Programs are evaluated independently of each other, and may not declare
+/// externs (since the session has no functions to provide for them). Each
+/// program gets its own store (and engine, which would otherwise keep the
+/// code of every module compiled with it), so everything it used is freed
+/// once it has been evaluated.
+pub struct Session {
+    options: SessionOptions,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new(SessionOptions::default())
+    }
+}
+
+impl Session {
+    pub fn new(options: SessionOptions) -> Self {
+        Session { options }
+    }
+
+    /// Compile a program (see `parse::parse_prog`), run its main expression,
+    /// and decode its result.
+    pub fn eval(&mut self, source: &str) -> Result<SchemeValue, SessionError> {
+        let values = lexpr::Parser::from_str(source)
+            .value_iter()
+            .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+            .map_err(|e| SessionError(e.to_string()))?;
+        let source_prog = parse_prog(&values).map_err(|e| SessionError(e.to_string()))?;
+        self.eval_prog(&source_prog)
+    }
+
+    /// Compile an already parsed program, run its main expression, and decode
+    /// its result.
+    pub fn eval_prog(&mut self, source_prog: &SourceProg) -> Result<SchemeValue, SessionError> {
+        // the result is decoded according to its type in the source language
+        let typ = type_check_source_prog(source_prog)
+            .map_err(|e| SessionError(e.to_string()))?
+            .typ;
+        let prog = compile_prog_with_options(source_prog, &self.options.compile)
+            .map_err(|e| SessionError(e.to_string()))?;
+
+        let mut codegen_options = CodeGenerateOptions {
+            metering: self.options.fuel.is_some(),
+            ..CodeGenerateOptions::default()
+        };
+        if let Some(memory_limit) = self.options.memory_limit {
+            codegen_options.memory_pages = memory_limit.div_ceil(65536);
+        }
+        let module = construct_module_from_prog_with_options(&prog, &codegen_options)
+            .map_err(|e| SessionError(e.to_string()))?;
+        let binary = parity_wasm::serialize(module).map_err(|e| SessionError(e.to_string()))?;
+
+        let mut store = Store::default();
+        let module = Module::new(&store, &binary).map_err(|e| SessionError(e.to_string()))?;
+        let instance = Instance::new(&mut store, &module, &imports! {})
+            .map_err(|e| SessionError(e.to_string()))?;
+        let exports = &instance.exports;
+
+        let heap_base = exports
+            .get_global("$$HEAP_BASE$$")
+            .map_err(|e| SessionError(e.to_string()))?
+            .get(&mut store)
+            .unwrap_i32();
+        if let Some(memory_limit) = self.options.memory_limit
+            && heap_base as u32 > memory_limit
+        {
+            return Err(SessionError::from(
+                "Program needs more memory than the memory limit.",
+            ));
+        }
+        if let Some(fuel) = self.options.fuel {
+            let fuel = i32::try_from(fuel).unwrap_or(i32::MAX);
+            exports
+                .get_global("$$FUEL$$")
+                .map_err(|e| SessionError(e.to_string()))?
+                .set(&mut store, Value::I32(fuel))
+                .map_err(|e| SessionError(e.to_string()))?;
+        }
+
+        let main = exports
+            .get_function("$$MAIN$$")
+            .map_err(|e| SessionError(e.to_string()))?;
+        let result = match main.call(&mut store, &[]) {
+            Ok(result) => result,
+            Err(e) => {
+                // the fuel only goes negative when the program is stopped
+                let out_of_fuel = exports
+                    .get_global("$$FUEL$$")
+                    .is_ok_and(|fuel| fuel.get(&mut store).unwrap_i32() < 0);
+                if out_of_fuel {
+                    return Err(SessionError::from("Program ran out of fuel."));
+                }
+                return Err(SessionError(e.message()));
+            }
+        };
+        // see `decode_value` for how each kind of WebAssembly value is passed
+        let raw = match result[0] {
+            Value::I32(val) => val as i64,
+            Value::I64(val) => val,
+            Value::F64(val) => val.to_bits() as i64,
+            _ => {
+                return Err(SessionError::from(
+                    "Main expression returned an unknown value.",
+                ));
+            }
+        };
+
+        let memory = exports
+            .get_memory("memory")
+            .map_err(|e| SessionError(e.to_string()))?;
+        let bytes = memory
+            .view(&store)
+            .copy_to_vec()
+            .map_err(|e| SessionError(e.to_string()))?;
+        decode_value(&typ, raw, &bytes).map_err(|e| SessionError(e.to_string()))
+    }
+}
@@ -1,5 +1,8 @@
-use crate::common::{BinOp, Expr, ExprKind, Prog, TypeEnv, TypedExpr};
-use crate::types::{type_contains_var, type_var_substitute, Type};
+use crate::common::{
+    generate_var_name, Datum, Decl, Expr, ExprKind, PrimOp, Prog, SourceProg, TypeEnv, TypedExpr,
+    PRIM_TYPE_VAR, RESERVED_EXPORTS,
+};
+use crate::types::{is_subtype, sort_fields, type_contains_var, type_var_substitute, Type};
 use im_rc::{vector, Vector};
 
 #[derive(Clone, Debug)]
@@ -25,7 +28,8 @@ impl std::fmt::Display for TypeCheckError {
 //
 
 /// Given a function type and a list of parameter types, check that provided
-/// list of parameter types matches the types for the function, and return the
+/// list of parameter types matches the types for the function (each of them
+/// may be a subtype of the function's parameter type), and return the
 /// function's return type.
 pub fn validate_lambda_type(
     fn_type: &Type,
@@ -34,7 +38,12 @@ pub fn validate_lambda_type(
     match fn_type {
         Type::Func(arg_types, ret_type_boxed) => {
             let ret_type = ret_type_boxed.as_ref();
-            if *arg_types == *param_types {
+            if arg_types.len() == param_types.len()
+                && param_types
+                    .iter()
+                    .zip(arg_types.iter())
+                    .all(|(param_type, arg_type)| is_subtype(param_type, arg_type))
+            {
                 Ok((*ret_type).clone())
             } else {
                 Err(TypeCheckError::from(
@@ -48,54 +57,137 @@ pub fn validate_lambda_type(
     }
 }
 
+/// Convert an expression into one of a supertype of its type (see
+/// `types::is_subtype`), which must already have been checked.
+///
+/// Records and tuples are copied into new ones containing just the fields
+/// of the supertype (each of which is converted itself), so that every value
+/// has exactly the type it is used as. Later passes can then rely on the
+/// layout of a record being determined by its type (see `record_elim`).
+pub fn coerce(exp: TypedExpr, typ: &Type) -> TypedExpr {
+    if exp.typ == *typ {
+        return exp;
+    }
+    let var = generate_var_name();
+    let var_exp = TypedExpr::new(exp.typ.clone(), ExprKind::Id(var.clone()));
+    let copy = match (&exp.typ, typ) {
+        (Type::Record(fields), Type::Record(sup_fields)) => {
+            let bindings = sup_fields
+                .iter()
+                .filter_map(|(name, sup_typ, mutable)| {
+                    let field = fields.iter().find(|field| field.0 == *name)?;
+                    let get = TypedExpr::new(
+                        field.1.clone(),
+                        ExprKind::RecordGet(var_exp.clone(), name.clone()),
+                    );
+                    Some((name.clone(), coerce(get, sup_typ), *mutable))
+                })
+                .collect();
+            ExprKind::Record(bindings)
+        }
+        (Type::Tuple(typs), Type::Tuple(sup_typs)) => {
+            let exps = typs
+                .iter()
+                .zip(sup_typs.iter())
+                .enumerate()
+                .map(|(key, (typ, sup_typ))| {
+                    let get = TypedExpr::new(
+                        typ.clone(),
+                        ExprKind::TupleGet(var_exp.clone(), key as u32),
+                    );
+                    coerce(get, sup_typ)
+                })
+                .collect();
+            ExprKind::Tuple(exps)
+        }
+        _ => return exp,
+    };
+    TypedExpr::new(
+        typ.clone(),
+        ExprKind::Let(vector![(var, exp)], TypedExpr::new(typ.clone(), copy)),
+    )
+}
+
+/// Returns the type of a value which may be either of two expressions (such
+/// as the branches of an if expression), converting one of them to the type
+/// of the other if it is a subtype of it.
+fn join(a: TypedExpr, b: TypedExpr) -> Option<(TypedExpr, TypedExpr, Type)> {
+    if is_subtype(&b.typ, &a.typ) {
+        let typ = a.typ.clone();
+        Some((a, coerce(b, &typ), typ))
+    } else if is_subtype(&a.typ, &b.typ) {
+        let typ = b.typ.clone();
+        Some((coerce(a, &typ), b, typ))
+    } else {
+        None
+    }
+}
+
 //
 // Type checking functions
 //
 
-fn tc_binop_with_env(
-    op: BinOp,
-    arg1: &Expr,
-    arg2: &Expr,
+/// Check that the type of an argument of a primitive operation matches the
+/// type in its signature, where `PRIM_TYPE_VAR` matches any type (see
+/// `PrimOp::signature`). The type it matches is recorded in `var_typ`.
+fn match_prim_type(expected: &Type, actual: &Type, var_typ: &mut Option<Type>) -> bool {
+    match (expected, actual) {
+        (Type::TypeVar(PRIM_TYPE_VAR), _) => match var_typ {
+            Some(typ) => typ == actual,
+            None => {
+                *var_typ = Some(actual.clone());
+                true
+            }
+        },
+        (Type::List(expected), Type::List(actual)) => match_prim_type(expected, actual, var_typ),
+        _ => expected == actual,
+    }
+}
+
+/// Replace `PRIM_TYPE_VAR` within the result type of a primitive operation.
+fn instantiate_prim_type(typ: &Type, var_typ: &Option<Type>) -> Result<Type, TypeCheckError> {
+    match typ {
+        Type::TypeVar(PRIM_TYPE_VAR) => var_typ.clone().ok_or(TypeCheckError::from(
+            "Could not infer the result type of a primitive operation.",
+        )),
+        Type::List(base_typ) => Ok(Type::List(Box::new(instantiate_prim_type(
+            base_typ, var_typ,
+        )?))),
+        _ => Ok(typ.clone()),
+    }
+}
+
+fn tc_prim_with_env(
+    op: PrimOp,
+    args: &Vector<Expr>,
     env: &TypeEnv,
 ) -> Result<TypedExpr, TypeCheckError> {
-    let arg1_expect_typ: Type;
-    let arg2_expect_typ: Type;
-    let ret_typ: Type;
-    match op {
-        BinOp::Add | BinOp::Subtract | BinOp::Multiply | BinOp::Divide => {
-            arg1_expect_typ = Type::Int;
-            arg2_expect_typ = Type::Int;
-            ret_typ = Type::Int;
-        }
-        BinOp::LessThan
-        | BinOp::GreaterThan
-        | BinOp::LessOrEqual
-        | BinOp::GreaterOrEqual
-        | BinOp::EqualTo => {
-            arg1_expect_typ = Type::Int;
-            arg2_expect_typ = Type::Int;
-            ret_typ = Type::Bool;
-        }
-        BinOp::And | BinOp::Or => {
-            arg1_expect_typ = Type::Bool;
-            arg2_expect_typ = Type::Bool;
-            ret_typ = Type::Bool;
-        }
-        BinOp::Concat => {
-            arg1_expect_typ = Type::Str;
-            arg2_expect_typ = Type::Str;
-            ret_typ = Type::Str;
+    let (param_typs, ret_typ) = op.signature();
+    if args.len() != param_typs.len() {
+        return Err(TypeCheckError(format!(
+            "Primitive operation {op} expects {} argument(s), but was given {}.",
+            param_typs.len(),
+            args.len()
+        )));
+    }
+    let args = tc_array_with_env(args, env)?;
+    let mut var_typ = None;
+    for (expected, arg) in param_typs.iter().zip(args.iter()) {
+        if !match_prim_type(expected, &arg.typ, &mut var_typ) {
+            return Err(TypeCheckError(format!(
+                "Arguments of primitive operation {op} do not match expected types."
+            )));
         }
     }
-    let arg1 = tc_with_env(arg1, env)?;
-    let arg2 = tc_with_env(arg2, env)?;
-    if arg1_expect_typ != arg1.typ || arg2_expect_typ != arg2.typ {
-        Err(TypeCheckError::from(
-            "Binary operation parameters do not match expected types.",
-        ))
-    } else {
-        Ok(TypedExpr::new(ret_typ, ExprKind::Binop(op, arg1, arg2)))
+    if let (Some(instances), Some(typ)) = (op.instances(), &var_typ)
+        && !instances.contains(typ)
+    {
+        return Err(TypeCheckError(format!(
+            "Primitive operation {op} cannot be applied to values of type {typ}."
+        )));
     }
+    let ret_typ = instantiate_prim_type(&ret_typ, &var_typ)?;
+    Ok(TypedExpr::new(ret_typ, ExprKind::Prim(op, args)))
 }
 
 fn tc_if_with_env(
@@ -108,18 +200,15 @@ fn tc_if_with_env(
     let cons = tc_with_env(consequent, env)?;
     let alt = tc_with_env(alternate, env)?;
     if pred.typ != Type::Bool {
-        Err(TypeCheckError::from(
+        return Err(TypeCheckError::from(
             "Predicate in if expression does not evaluate to a boolean value.",
-        ))
-    } else if cons.typ != alt.typ {
-        Err(TypeCheckError::from(
+        ));
+    }
+    match join(cons, alt) {
+        Some((cons, alt, typ)) => Ok(TypedExpr::new(typ, ExprKind::If(pred, cons, alt))),
+        None => Err(TypeCheckError::from(
             "Consequent and alternate values in if expression do not match types.",
-        ))
-    } else {
-        Ok(TypedExpr::new(
-            cons.typ.clone(),
-            ExprKind::If(pred, cons, alt),
-        ))
+        )),
     }
 }
 
@@ -144,6 +233,41 @@ fn tc_let_with_env(
     ))
 }
 
+/// Type check a letrec expression, whose bindings must all be lambdas. Each
+/// name is bound to the annotated type of its lambda, both within the lambdas
+/// (so that they can call themselves and each other) and within the body.
+fn tc_letrec_with_env(
+    bindings: &Vector<(String, Expr)>,
+    body: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let binding_types = bindings
+        .iter()
+        .map(|(name, exp)| match &*exp.kind {
+            ExprKind::Lambda(params, ret_typ, _body) => {
+                let param_typs = params.iter().map(|pair| pair.1.clone()).collect();
+                Ok((
+                    name.clone(),
+                    Type::Func(param_typs, Box::new(ret_typ.clone())),
+                ))
+            }
+            _ => Err(TypeCheckError(format!(
+                "Letrec binding {name} is not a lambda."
+            ))),
+        })
+        .collect::<Result<Vector<(String, Type)>, TypeCheckError>>()?;
+    let new_env = env.add_bindings(binding_types);
+    let typed_bindings = bindings
+        .iter()
+        .map(|pair| Ok((pair.0.clone(), tc_with_env(&pair.1, &new_env)?)))
+        .collect::<Result<Vector<(String, TypedExpr)>, TypeCheckError>>()?;
+    let typed_body = tc_with_env(body, &new_env)?;
+    Ok(TypedExpr::new(
+        typed_body.typ.clone(),
+        ExprKind::Letrec(typed_bindings, typed_body),
+    ))
+}
+
 fn tc_lambda_with_env(
     params: &Vector<(String, Type)>,
     ret_type: &Type,
@@ -151,16 +275,16 @@ fn tc_lambda_with_env(
     env: &TypeEnv,
 ) -> Result<TypedExpr, TypeCheckError> {
     // Add arg types to the type environment for use in the body
+    let param_types: Vector<Type> = params.iter().map(|pair| pair.1.clone()).collect();
+    let lambda_typ = Type::Func(param_types, Box::new(ret_type.clone()));
     let new_env = env.add_bindings(params.clone());
 
     // Type check lambda body
     let body = tc_with_env(body, &new_env)?;
-    if *ret_type == body.typ {
-        let param_types: Vector<Type> = params.iter().map(|pair| pair.1.clone()).collect();
-        let lambda_typ = Type::Func(param_types, Box::new(ret_type.clone()));
+    if is_subtype(&body.typ, ret_type) {
         Ok(TypedExpr::new(
             lambda_typ,
-            ExprKind::Lambda(params.clone(), ret_type.clone(), body),
+            ExprKind::Lambda(params.clone(), ret_type.clone(), coerce(body, ret_type)),
         ))
     } else {
         Err(TypeCheckError::from(
@@ -200,10 +324,10 @@ fn tc_set_bang_with_env(
         .ok_or("Variable in set! cannot be found within the local scope - the variable must already be defined by a function parameter or a let expression.")?
         .clone();
     let new_val = tc_with_env(new_val, env)?;
-    if new_val.typ == expected_typ {
+    if is_subtype(&new_val.typ, &expected_typ) {
         Ok(TypedExpr::new(
-            new_val.typ.clone(),
-            ExprKind::Set(String::from(var), new_val),
+            expected_typ.clone(),
+            ExprKind::Set(String::from(var), coerce(new_val, &expected_typ)),
         ))
     } else {
         Err(TypeCheckError::from(
@@ -217,7 +341,8 @@ fn tc_cons_with_env(first: &Expr, rest: &Expr, env: &TypeEnv) -> Result<TypedExp
     let cdr = tc_with_env(rest, env)?;
     match cdr.typ.clone() {
         Type::List(boxed_type) => {
-            if *boxed_type == car.typ {
+            if is_subtype(&car.typ, &boxed_type) {
+                let car = coerce(car, &boxed_type);
                 Ok(TypedExpr::new(
                     Type::List(boxed_type),
                     ExprKind::Cons(car, cdr),
@@ -288,18 +413,51 @@ fn tc_tuple_get_with_env(tup: &Expr, key: u32, env: &TypeEnv) -> Result<TypedExp
     }
 }
 
+// tuple-set! is only produced by record elimination (from record-set!), so
+// its value must already have the type of the tuple's component
+fn tc_tuple_set_with_env(
+    tup: &Expr,
+    key: u32,
+    new_val: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let tup = tc_with_env(tup, env)?;
+    let new_val = tc_with_env(new_val, env)?;
+    match tup.typ.clone() {
+        Type::Tuple(vec) => match vec.get(key as usize) {
+            Some(elem_type) if *elem_type == new_val.typ => Ok(TypedExpr::new(
+                elem_type.clone(),
+                ExprKind::TupleSet(tup, key, new_val),
+            )),
+            Some(_elem_type) => Err(TypeCheckError::from(
+                "Type of value in tuple-set! does not match the type of the tuple's element.",
+            )),
+            None => Err(TypeCheckError::from(
+                "Key in tuple-set! is too large for the provided tuple.",
+            )),
+        },
+        _ => Err(TypeCheckError::from(
+            "First expression in tuple-set! is not a tuple.",
+        )),
+    }
+}
+
 fn tc_record_with_env(
-    bindings: &Vector<(String, Expr)>,
+    bindings: &Vector<(String, Expr, bool)>,
     env: &TypeEnv,
 ) -> Result<TypedExpr, TypeCheckError> {
     let typed_bindings = bindings
         .iter()
-        .map(|pair| Ok((pair.0.clone(), tc_with_env(&pair.1, env)?)))
-        .collect::<Result<Vector<(String, TypedExpr)>, TypeCheckError>>()?;
+        .map(|(name, exp, mutable)| Ok((name.clone(), tc_with_env(exp, env)?, *mutable)))
+        .collect::<Result<Vector<(String, TypedExpr, bool)>, TypeCheckError>>()?;
     let bindings_type = typed_bindings
         .iter()
-        .map(|pair| (pair.0.clone(), pair.1.typ.clone()))
-        .collect::<Vector<(String, Type)>>();
+        .map(|(name, typed_exp, mutable)| (name.clone(), typed_exp.typ.clone(), *mutable))
+        .collect::<Vector<(String, Type, bool)>>();
+    // The bindings are evaluated in the order they are written, but the type
+    // lists the fields in canonical order
+    let bindings_type = sort_fields(&bindings_type, |field| &field.0)
+        .map_err(|name| TypeCheckError(format!("Record has more than one field named {name}.")))?;
     Ok(TypedExpr::new(
         Type::Record(bindings_type),
         ExprKind::Record(typed_bindings),
@@ -314,8 +472,8 @@ fn tc_record_get_with_env(
     let typed_record = tc_with_env(record, env)?;
     match typed_record.typ.clone() {
         Type::Record(fields) => {
-            let matches: Vector<(String, Type)> = fields
-                .iter().filter(|&pair| pair.0 == *key).cloned()
+            let matches: Vector<(String, Type, bool)> = fields
+                .iter().filter(|&field| field.0 == *key).cloned()
                 .collect();
             if matches.is_empty() {
                 return Err(TypeCheckError::from(
@@ -334,6 +492,47 @@ fn tc_record_get_with_env(
     }
 }
 
+// Like set!, record-set! returns the value that is being assigned
+fn tc_record_set_with_env(
+    record: &Expr,
+    key: &str,
+    new_val: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let typed_record = tc_with_env(record, env)?;
+    let (_name, field_type, mutable) = match &typed_record.typ {
+        Type::Record(fields) => fields
+            .iter()
+            .find(|field| field.0 == *key)
+            .cloned()
+            .ok_or("Key in record-set! not found in record.")?,
+        _ => {
+            return Err(TypeCheckError::from(
+                "First expression in record-set! is not a record.",
+            ));
+        }
+    };
+    if !mutable {
+        return Err(TypeCheckError(format!(
+            "Field {key} in record-set! is not mutable."
+        )));
+    }
+    let new_val = tc_with_env(new_val, env)?;
+    if !is_subtype(&new_val.typ, &field_type) {
+        return Err(TypeCheckError::from(
+            "Type of value in record-set! does not match the type of the field.",
+        ));
+    }
+    Ok(TypedExpr::new(
+        field_type.clone(),
+        ExprKind::RecordSet(
+            typed_record,
+            String::from(key),
+            coerce(new_val, &field_type),
+        ),
+    ))
+}
+
 fn tc_apply_with_env(
     func: &Expr,
     args: &Vector<Expr>,
@@ -348,6 +547,14 @@ fn tc_apply_with_env(
 
     // TODO: is this variable (and the function call) appropriately named?
     let lambda_type = validate_lambda_type(&func.typ, &arg_types)?;
+    let typed_args = match &func.typ {
+        Type::Func(param_types, _ret_type) => typed_args
+            .into_iter()
+            .zip(param_types.iter())
+            .map(|(arg, param_type)| coerce(arg, param_type))
+            .collect(),
+        _ => typed_args,
+    };
     Ok(TypedExpr::new(
         lambda_type,
         ExprKind::FnApp(func, typed_args),
@@ -359,6 +566,87 @@ fn tc_is_null_with_env(exp: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheck
     Ok(TypedExpr::new(Type::Bool, ExprKind::IsNull(typed_exp)))
 }
 
+/// Infer the type of a quoted list from its elements, which must all have
+/// the same type.
+///
+/// The element type of an empty list is inferred from the lists next to it
+/// (e.g. `'((1) ())` is a list of lists of ints), so a quoted list with no
+/// elements other than empty lists has no type.
+fn tc_quote(data: &Vector<Datum>) -> Result<TypedExpr, TypeCheckError> {
+    let typ = datum_list_type(data)?;
+    if contains_unknown(&typ) {
+        return Err(TypeCheckError::from(
+            "The element type of an empty quoted list cannot be inferred (use null instead).",
+        ));
+    }
+    Ok(TypedExpr::new(typ, ExprKind::Quote(data.clone())))
+}
+
+/// Returns the type of a list of data, using `Type::Unknown` for the element
+/// types of empty lists.
+fn datum_list_type(data: &Vector<Datum>) -> Result<Type, TypeCheckError> {
+    let mut elem_typ = Type::Unknown;
+    for datum in data.iter() {
+        let typ = match datum {
+            Datum::Num(_) => Type::Int,
+            Datum::Int64(_) => Type::Int64,
+            Datum::Float(_) => Type::Float,
+            Datum::Bool(_) => Type::Bool,
+            Datum::Str(_) => Type::Str,
+            Datum::Char(_) => Type::Char,
+            Datum::Symbol(_) => Type::Symbol,
+            Datum::List(data) => datum_list_type(data)?,
+        };
+        elem_typ = join_datum_types(&elem_typ, &typ).ok_or_else(|| {
+            TypeCheckError(format!(
+                "Elements of a quoted list must have the same type, but found {elem_typ} and {typ}."
+            ))
+        })?;
+    }
+    Ok(Type::List(Box::new(elem_typ)))
+}
+
+/// Returns the type of values which have both types, filling in the unknown
+/// parts of one type with the other.
+fn join_datum_types(typ1: &Type, typ2: &Type) -> Option<Type> {
+    match (typ1, typ2) {
+        (Type::Unknown, typ) | (typ, Type::Unknown) => Some(typ.clone()),
+        (Type::List(base1), Type::List(base2)) => {
+            Some(Type::List(Box::new(join_datum_types(base1, base2)?)))
+        }
+        (typ1, typ2) if typ1 == typ2 => Some(typ1.clone()),
+        _ => None,
+    }
+}
+
+fn contains_unknown(typ: &Type) -> bool {
+    match typ {
+        Type::Unknown => true,
+        Type::List(base_typ) => contains_unknown(base_typ),
+        _ => false,
+    }
+}
+
+/// Check whether values of a type can be printed with display.
+pub fn is_displayable(typ: &Type) -> bool {
+    match typ {
+        Type::Int | Type::Int64 | Type::Bool | Type::Str | Type::Char | Type::Symbol => true,
+        Type::List(base_typ) => is_displayable(base_typ),
+        _ => false,
+    }
+}
+
+fn tc_display_with_env(exp: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    let typed_exp = tc_with_env(exp, env)?;
+    if !is_displayable(&typed_exp.typ) {
+        return Err(TypeCheckError(format!(
+            "Values of type {} cannot be displayed.",
+            typed_exp.typ
+        )));
+    }
+    Ok(TypedExpr::new(Type::Int, ExprKind::Display(typed_exp)))
+}
+
 fn tc_pack_with_env(
     packed_exp: &Expr,
     sub: &Type,
@@ -370,7 +658,8 @@ fn tc_pack_with_env(
         let substituted_typ = type_var_substitute(base_typ, *type_var, sub);
         // now check if the type of "substituted" matches the type of the packed expression
         let packed_exp = tc_with_env(packed_exp, env)?;
-        if packed_exp.typ == substituted_typ {
+        if is_subtype(&packed_exp.typ, &substituted_typ) {
+            let packed_exp = coerce(packed_exp, &substituted_typ);
             Ok(TypedExpr::new(
                 exist.clone(),
                 ExprKind::Pack(packed_exp, sub.clone(), exist.clone()),
@@ -430,6 +719,168 @@ fn tc_unpack_with_env(
     ))
 }
 
+fn tc_raise_with_env(exn: &Expr, typ: &Type, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    let exn = tc_with_env(exn, env)?;
+    check_exception_type(&exn.typ)?;
+    Ok(TypedExpr::new(
+        typ.clone(),
+        ExprKind::Raise(exn, typ.clone()),
+    ))
+}
+
+fn tc_handle_with_env(
+    var: &str,
+    exn_typ: &Type,
+    handler: &Expr,
+    body: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    check_exception_type(exn_typ)?;
+    let body = tc_with_env(body, env)?;
+    let handler = tc_with_env(
+        handler,
+        &env.add_binding((String::from(var), exn_typ.clone())),
+    )?;
+    let handler_typ = handler.typ.clone();
+    let body_typ = body.typ.clone();
+    match join(body, handler) {
+        Some((body, handler, typ)) => Ok(TypedExpr::new(
+            typ,
+            ExprKind::Handle(String::from(var), exn_typ.clone(), handler, body),
+        )),
+        None => Err(TypeCheckError(format!(
+            "Handler has type {handler_typ} but the expression it handles has type {body_typ}."
+        ))),
+    }
+}
+
+/// Exceptions are caught by comparing their types, so their types must be
+/// known exactly where they are raised. Since functions become existentially
+/// typed closures, exceptions also cannot contain functions.
+fn check_exception_type(typ: &Type) -> Result<(), TypeCheckError> {
+    if contains_func_type(typ) || contains_type_var(typ) {
+        return Err(TypeCheckError(format!(
+            "Values of type {typ} cannot be raised as exceptions."
+        )));
+    }
+    Ok(())
+}
+
+fn contains_type_var(typ: &Type) -> bool {
+    match typ {
+        Type::TypeVar(_) | Type::Exists(_, _) => true,
+        Type::List(base_typ) | Type::Cont(base_typ) | Type::Generator(base_typ) => {
+            contains_type_var(base_typ)
+        }
+        Type::Tuple(typs) => typs.iter().any(contains_type_var),
+        Type::Record(bindings) => bindings.iter().any(|pair| contains_type_var(&pair.1)),
+        Type::Func(in_typs, ret_typ) => {
+            in_typs.iter().any(contains_type_var) || contains_type_var(ret_typ)
+        }
+        Type::Int
+        | Type::Int64
+        | Type::Float
+        | Type::Bool
+        | Type::Str
+        | Type::Char
+        | Type::Symbol
+        | Type::Unknown => false,
+    }
+}
+
+fn tc_call_cc_with_env(func: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    let func = tc_with_env(func, env)?;
+    let ret_typ = match &func.typ {
+        Type::Func(in_typs, ret_typ)
+            if in_typs.len() == 1 && in_typs[0] == Type::Cont(ret_typ.clone()) =>
+        {
+            (**ret_typ).clone()
+        }
+        _ => {
+            return Err(TypeCheckError(format!(
+                "Argument of call/cc must have type (-> (cont T) T), but found {}.",
+                func.typ
+            )));
+        }
+    };
+    Ok(TypedExpr::new(ret_typ, ExprKind::CallCC(func)))
+}
+
+fn tc_throw_with_env(
+    cont: &Expr,
+    val: &Expr,
+    typ: &Type,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let cont = tc_with_env(cont, env)?;
+    let val = tc_with_env(val, env)?;
+    match &cont.typ {
+        Type::Cont(val_typ) if is_subtype(&val.typ, val_typ) => {
+            let val = coerce(val, val_typ);
+            Ok(TypedExpr::new(
+                typ.clone(),
+                ExprKind::Throw(cont, val, typ.clone()),
+            ))
+        }
+        Type::Cont(val_typ) => Err(TypeCheckError(format!(
+            "Continuation expects a value of type {val_typ}, but was thrown a value of type {}.",
+            val.typ
+        ))),
+        _ => Err(TypeCheckError(format!(
+            "First argument of throw is not a continuation, but has type {}.",
+            cont.typ
+        ))),
+    }
+}
+
+/// The body of a generator can have any type, since its value is discarded
+/// once the generator finishes.
+fn tc_make_generator_with_env(
+    typ: &Type,
+    body: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let body = tc_with_env(body, &env.with_yield_type(typ.clone()))?;
+    Ok(TypedExpr::new(
+        Type::Generator(Box::new(typ.clone())),
+        ExprKind::MakeGenerator(typ.clone(), body),
+    ))
+}
+
+/// A yield must be within the body of a generator (including within functions
+/// defined there, such as loops), and yields from the innermost one. It
+/// evaluates to the value it yields.
+fn tc_yield_with_env(val: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    let yield_typ = env
+        .yield_type()
+        .ok_or("Yield expression is not within the body of a generator.")?;
+    let val = tc_with_env(val, env)?;
+    if !is_subtype(&val.typ, yield_typ) {
+        return Err(TypeCheckError(format!(
+            "Generator yields values of type {yield_typ}, but a value of type {} was yielded.",
+            val.typ
+        )));
+    }
+    let val = coerce(val, yield_typ);
+    Ok(TypedExpr::new(val.typ.clone(), ExprKind::Yield(val)))
+}
+
+/// Resuming a generator gives a list containing the next value it yields, or
+/// an empty list if it has finished.
+fn tc_next_with_env(generator: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    let generator = tc_with_env(generator, env)?;
+    match &generator.typ {
+        Type::Generator(val_typ) => Ok(TypedExpr::new(
+            Type::List(val_typ.clone()),
+            ExprKind::Next(generator),
+        )),
+        _ => Err(TypeCheckError(format!(
+            "Argument of next is not a generator, but has type {}.",
+            generator.typ
+        ))),
+    }
+}
+
 fn tc_array_with_env(
     values: &Vector<Expr>,
     env: &TypeEnv,
@@ -437,11 +888,34 @@ fn tc_array_with_env(
     values.iter().map(|val| tc_with_env(val, env)).collect()
 }
 
+const IN_FORM: &str = " (in the form ";
+
+/// Type check an expression desugared from a derived form (see
+/// `parse::parse`), so that errors within it mention the form the user
+/// wrote. When derived forms are nested, the innermost one is mentioned.
+///
+/// The typed expression is just the desugared one, since the form is no
+/// longer needed once type checking has succeeded.
+fn tc_derived_with_env(form: &str, exp: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
+    tc_with_env(exp, env).map_err(|e| {
+        if e.0.contains(IN_FORM) {
+            e
+        } else {
+            TypeCheckError(format!("{}{IN_FORM}{form})", e.0))
+        }
+    })
+}
+
 pub fn tc_with_env(value: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckError> {
     match &*value.kind {
         ExprKind::Num(x) => Ok(TypedExpr::new(Type::Int, ExprKind::Num(*x))),
+        ExprKind::Int64(x) => Ok(TypedExpr::new(Type::Int64, ExprKind::Int64(*x))),
+        ExprKind::Float(x) => Ok(TypedExpr::new(Type::Float, ExprKind::Float(*x))),
         ExprKind::Bool(x) => Ok(TypedExpr::new(Type::Bool, ExprKind::Bool(*x))),
         ExprKind::Str(x) => Ok(TypedExpr::new(Type::Str, ExprKind::Str(x.clone()))),
+        ExprKind::Char(x) => Ok(TypedExpr::new(Type::Char, ExprKind::Char(*x))),
+        ExprKind::Symbol(x) => Ok(TypedExpr::new(Type::Symbol, ExprKind::Symbol(x.clone()))),
+        ExprKind::Quote(data) => tc_quote(data),
         ExprKind::Id(sym) => {
             let typ = match env.find(sym.as_str()) {
                 Some(val) => Ok(val.clone()),
@@ -451,14 +925,16 @@ pub fn tc_with_env(value: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckEr
             }?;
             Ok(TypedExpr::new(typ, ExprKind::Id(sym.clone())))
         }
-        ExprKind::Binop(op, arg1, arg2) => tc_binop_with_env(*op, arg1, arg2, env),
+        ExprKind::Prim(op, args) => tc_prim_with_env(*op, args, env),
         ExprKind::If(pred, cons, alt) => tc_if_with_env(pred, cons, alt, env),
         ExprKind::Let(bindings, body) => tc_let_with_env(bindings, body, env),
+        ExprKind::Letrec(bindings, body) => tc_letrec_with_env(bindings, body, env),
         ExprKind::Lambda(params, ret_typ, body) => {
             tc_lambda_with_env(params, ret_typ, body, env)
         }
         ExprKind::Record(bindings) => tc_record_with_env(bindings, env),
         ExprKind::RecordGet(record, key) => tc_record_get_with_env(record, key, env),
+        ExprKind::RecordSet(record, key, val) => tc_record_set_with_env(record, key, val, env),
         ExprKind::Begin(exps) => tc_begin_with_env(exps, env),
         ExprKind::Set(sym, exp) => tc_set_bang_with_env(sym, exp, env),
         ExprKind::Cons(first, rest) => tc_cons_with_env(first, rest, env),
@@ -469,13 +945,26 @@ pub fn tc_with_env(value: &Expr, env: &TypeEnv) -> Result<TypedExpr, TypeCheckEr
             typ: Type::List(Box::new(typ.clone())),
             kind: Box::new(ExprKind::Null(typ.clone())),
         }),
+        ExprKind::Display(exp) => tc_display_with_env(exp, env),
+        ExprKind::Newline => Ok(TypedExpr::new(Type::Int, ExprKind::Newline)),
         ExprKind::Tuple(exps) => tc_tuple_with_env(exps, env),
         ExprKind::TupleGet(tup, key) => tc_tuple_get_with_env(tup, *key, env),
+        ExprKind::TupleSet(tup, key, val) => tc_tuple_set_with_env(tup, *key, val, env),
         ExprKind::Pack(val, sub, exist) => tc_pack_with_env(val, sub, exist, env),
         ExprKind::Unpack(var, package, type_sub, body) => {
             tc_unpack_with_env(var, package, *type_sub, body, env)
         }
+        ExprKind::Raise(exn, typ) => tc_raise_with_env(exn, typ, env),
+        ExprKind::Handle(var, typ, handler, body) => {
+            tc_handle_with_env(var, typ, handler, body, env)
+        }
+        ExprKind::CallCC(func) => tc_call_cc_with_env(func, env),
+        ExprKind::Throw(cont, val, typ) => tc_throw_with_env(cont, val, typ, env),
+        ExprKind::MakeGenerator(typ, body) => tc_make_generator_with_env(typ, body, env),
+        ExprKind::Yield(val) => tc_yield_with_env(val, env),
+        ExprKind::Next(generator) => tc_next_with_env(generator, env),
         ExprKind::FnApp(func, args) => tc_apply_with_env(func, args, env),
+        ExprKind::Derived(form, exp) => tc_derived_with_env(form, exp, env),
     }
 }
 
@@ -483,16 +972,255 @@ pub fn type_check(value: &Expr) -> Result<TypedExpr, TypeCheckError> {
     tc_with_env(value, &TypeEnv::new())
 }
 
+/// Check whether a type contains a function type anywhere within it.
+pub fn contains_func_type(typ: &Type) -> bool {
+    match typ {
+        Type::Func(_, _) | Type::Cont(_) | Type::Generator(_) => true,
+        Type::List(base_typ) => contains_func_type(base_typ),
+        Type::Tuple(typs) => typs.iter().any(contains_func_type),
+        Type::Record(bindings) => bindings.iter().any(|pair| contains_func_type(&pair.1)),
+        Type::Exists(_, base_typ) => contains_func_type(base_typ),
+        Type::Int
+        | Type::Int64
+        | Type::Float
+        | Type::Bool
+        | Type::Str
+        | Type::Char
+        | Type::Symbol
+        | Type::TypeVar(_)
+        | Type::Unknown => false,
+    }
+}
+
+/// Check that a function passed between the program and the host
+/// environment (`kind` being "Extern" or "Export") has a function type which
+/// does not take or return functions, since the host environment has no way
+/// to call a Scheme closure (or give one to the program).
+fn validate_host_func_type(kind: &str, name: &str, typ: &Type) -> Result<(), TypeCheckError> {
+    match typ {
+        Type::Func(params, ret_typ) => {
+            if params.iter().any(contains_func_type) || contains_func_type(ret_typ) {
+                return Err(TypeCheckError(format!(
+                    "{kind} function {name} cannot take or return functions."
+                )));
+            }
+            Ok(())
+        }
+        _ => Err(TypeCheckError(format!(
+            "{kind} {name} does not have a function type."
+        ))),
+    }
+}
+
+/// Construct the type environment in which extern functions are bound to
+/// their declared types.
+///
+/// Extern functions may not take or return functions.
+pub fn extern_env(externs: &Vector<(String, Type)>) -> Result<TypeEnv, TypeCheckError> {
+    for (name, typ) in externs {
+        validate_host_func_type("Extern", name, typ)?;
+    }
+    Ok(TypeEnv::from(externs.clone()))
+}
+
+/// Type check the global definitions of a source program, returning the
+/// type of each definition.
+///
+/// See `type_check_decls` for more specific details.
+pub fn type_check_defines(prog: &SourceProg) -> Result<Vector<(String, Type)>, TypeCheckError> {
+    type_check_decls(&prog.decls, &Vector::new())
+}
+
+/// Same as `type_check_defines`, but returns the typed expression of each
+/// definition (rather than just its type).
+pub fn tc_defines(prog: &SourceProg) -> Result<Vector<(String, TypedExpr)>, TypeCheckError> {
+    tc_decls(&prog.decls, &Vector::new())
+}
+
+/// Type check the declarations of a module, returning the type of each of
+/// its global definitions.
+///
+/// The module-level environment contains the module's externs, and the
+/// definitions it imports from other modules (along with their types).
+/// Definitions are checked in order, so each definition may refer to these
+/// and to earlier definitions. A definition of a lambda may also refer to
+/// itself (using the lambda's annotated type), allowing recursive functions
+/// to be defined. Exported definitions must be functions which do not take or
+/// return functions, like externs, while provided definitions may have any
+/// type. Imports must already have been resolved (see `module::load_prog`).
+pub fn type_check_decls(
+    decls: &Vector<Decl>,
+    imports: &Vector<(String, Type)>,
+) -> Result<Vector<(String, Type)>, TypeCheckError> {
+    Ok(tc_decls(decls, imports)?
+        .into_iter()
+        .map(|(name, typed_exp)| (name, typed_exp.typ))
+        .collect())
+}
+
+/// Same as `type_check_decls`, but returns the typed expression of each
+/// global definition.
+fn tc_decls(
+    decls: &Vector<Decl>,
+    imports: &Vector<(String, Type)>,
+) -> Result<Vector<(String, TypedExpr)>, TypeCheckError> {
+    let externs: Vector<(String, Type)> = decls
+        .iter()
+        .filter_map(|decl| match decl {
+            Decl::Extern(name, typ) => Some((name.clone(), typ.clone())),
+            _ => None,
+        })
+        .collect();
+    let mut env = extern_env(&externs)?.add_bindings(imports.clone());
+    let mut names: Vector<String> = externs.iter().map(|pair| pair.0.clone()).collect();
+    names.append(imports.iter().map(|pair| pair.0.clone()).collect());
+    let mut globals = Vector::new();
+    for decl in decls {
+        match decl {
+            Decl::Define(name, exp) => {
+                if names.contains(name) {
+                    return Err(TypeCheckError(format!(
+                        "Duplicate top-level definition of {name}."
+                    )));
+                }
+                let typed_exp = type_check_define(name, exp, &env)?;
+                env = env.add_binding((name.clone(), typed_exp.typ.clone()));
+                names.push_back(name.clone());
+                globals.push_back((name.clone(), typed_exp));
+            }
+            Decl::Import(path) => {
+                return Err(TypeCheckError(format!(
+                    "Import of {path} has not been resolved."
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    let mut export_names: Vector<String> = Vector::new();
+    for decl in decls {
+        match decl {
+            Decl::Export(name, export_name) => {
+                if export_names.contains(export_name) {
+                    return Err(TypeCheckError(format!(
+                        "Duplicate export named {export_name}."
+                    )));
+                }
+                if RESERVED_EXPORTS.contains(&export_name.as_str()) {
+                    return Err(TypeCheckError(format!(
+                        "Export name {export_name} is reserved by the compiler."
+                    )));
+                }
+                export_names.push_back(export_name.clone());
+                match globals.iter().find(|pair| pair.0 == *name) {
+                    Some((_name, typed_exp)) => {
+                        validate_host_func_type("Export", name, &typed_exp.typ)?
+                    }
+                    None => {
+                        return Err(TypeCheckError(format!(
+                            "Exported name {name} is not a top-level definition."
+                        )));
+                    }
+                }
+            }
+            Decl::Provide(provided) => {
+                for name in provided {
+                    if !globals.iter().any(|pair| pair.0 == *name) {
+                        return Err(TypeCheckError(format!(
+                            "Provided name {name} is not a top-level definition."
+                        )));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(globals)
+}
+
+/// Type check the expression of a single global definition, given the
+/// environment of everything defined before it.
+///
+/// A definition whose expression is a lambda may refer to itself, so its name
+/// is bound (using the lambda's annotated type) while checking its body.
+pub fn type_check_define(
+    name: &str,
+    exp: &Expr,
+    env: &TypeEnv,
+) -> Result<TypedExpr, TypeCheckError> {
+    let exp_env = match &*exp.kind {
+        ExprKind::Lambda(params, ret_typ, _body) => {
+            let param_typs = params.iter().map(|pair| pair.1.clone()).collect();
+            env.add_binding((
+                name.to_string(),
+                Type::Func(param_typs, Box::new(ret_typ.clone())),
+            ))
+        }
+        _ => env.clone(),
+    };
+    tc_with_env(exp, &exp_env)
+}
+
+/// Type check the main expression of a source program, in an environment
+/// containing the program's extern functions and global definitions.
+pub fn type_check_source_prog(prog: &SourceProg) -> Result<TypedExpr, TypeCheckError> {
+    let env = extern_env(&prog.externs())?.add_bindings(type_check_defines(prog)?);
+    tc_with_env(&prog.exp, &env)
+}
+
+/// Type check a lambda-lifted program.
+///
+/// After closure conversion, each extern function is called through a
+/// wrapper function which takes an (empty) environment as its first
+/// parameter, like every other lifted function. These wrappers are referred
+/// to by the name of the extern. Global definitions are bound to their
+/// (closure converted) types everywhere in the program.
 pub fn type_check_prog(prog: &Prog<Expr>) -> Result<Prog<TypedExpr>, TypeCheckError> {
     let mut env = TypeEnv::new();
+    for (name, typ) in prog.externs.iter() {
+        match typ {
+            Type::Func(params, ret_typ) => {
+                let mut wrapper_params = params.clone();
+                wrapper_params.push_front(Type::Record(vector![]));
+                env = env.add_binding((name.clone(), Type::Func(wrapper_params, ret_typ.clone())));
+            }
+            _ => {
+                return Err(TypeCheckError(format!(
+                    "Extern {name} does not have a function type."
+                )));
+            }
+        }
+    }
+    env = env.add_bindings(prog.globals.clone());
+    // functions lifted from a letrec may refer to themselves and to each
+    // other (see `lambda_lift`), so every function is bound to its annotated
+    // type before any of them are checked
+    for (name, func) in prog.fns.iter() {
+        match &*func.kind {
+            ExprKind::Lambda(params, ret_typ, _body) => {
+                let param_typs = params.iter().map(|pair| pair.1.clone()).collect();
+                env = env.add_binding((
+                    name.clone(),
+                    Type::Func(param_typs, Box::new(ret_typ.clone())),
+                ));
+            }
+            _ => {
+                return Err(TypeCheckError(format!(
+                    "Lifted function {name} is not a lambda."
+                )));
+            }
+        }
+    }
     let mut typed_fns: Vector<(String, TypedExpr)> = vector![];
     for def in prog.fns.iter() {
         let typed_fn = tc_with_env(&def.1, &env)?;
-        env = env.add_binding((def.0.clone(), typed_fn.typ.clone()));
         typed_fns.push_back((def.0.clone(), typed_fn));
     }
     let prog_exp = tc_with_env(&prog.exp, &env)?;
     Ok(Prog {
+        externs: prog.externs.clone(),
+        globals: prog.globals.clone(),
+        exports: prog.exports.clone(),
         fns: typed_fns,
         exp: prog_exp,
     })
@@ -6,27 +6,41 @@ use im_rc::Vector;
 #[derive(Clone, Debug)]
 pub enum Type {
     Int,
+    Int64,
+    Float, // 64-bit floating point
     Bool,
     Str,
-    List(Box<Type>),                // homogenous list
-    Func(Vector<Type>, Box<Type>),  // array of input types, and a return type
-    Tuple(Vector<Type>),            // array of types
-    Record(Vector<(String, Type)>), // array of bindings
-    Exists(u64, Box<Type>),         // abstract type T, and base type in terms of T
-    TypeVar(u64),                   // abstract type T
-    Unknown,                        // placeholder, for debugging etc.
+    Char,                                 // a Unicode scalar value
+    Symbol,                               // an interned name
+    List(Box<Type>),                      // homogenous list
+    Func(Vector<Type>, Box<Type>),        // array of input types, and a return type
+    Cont(Box<Type>),                      // continuation expecting a value of a type
+    Generator(Box<Type>),                 // generator yielding values of a type
+    Tuple(Vector<Type>),                  // array of types
+    Record(Vector<(String, Type, bool)>), // array of bindings, and whether each is mutable
+    Exists(u64, Box<Type>),               // abstract type T, and base type in terms of T
+    TypeVar(u64),                         // abstract type T
+    Unknown,                              // placeholder, for debugging etc.
 }
 
 // PartialEq is implemented manually to handle the specific case where two
 // types are both existential types, and they should be equal with respect to
-// substitution of one type variable for the other
+// substitution of one type variable for the other, and so that record types
+// are equal regardless of the order their fields are listed in
 impl PartialEq for Type {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Type::List(base_a), Type::List(base_b)) => base_a == base_b,
             (Type::Func(in_a, ret_a), Type::Func(in_b, ret_b)) => in_a == in_b && ret_a == ret_b,
+            (Type::Cont(base_a), Type::Cont(base_b)) => base_a == base_b,
+            (Type::Generator(base_a), Type::Generator(base_b)) => base_a == base_b,
             (Type::Tuple(vec_a), Type::Tuple(vec_b)) => vec_a == vec_b,
-            (Type::Record(vec_a), Type::Record(vec_b)) => vec_a == vec_b,
+            (Type::Record(vec_a), Type::Record(vec_b)) => {
+                vec_a.len() == vec_b.len()
+                    && vec_a
+                        .iter()
+                        .all(|field| vec_b.iter().any(|other| field == other))
+            }
             (Type::Exists(typ_var_a, base_typ_a), Type::Exists(typ_var_b, base_typ_b)) => {
                 let other_sub =
                     type_var_substitute(base_typ_b, *typ_var_b, &Type::TypeVar(*typ_var_a));
@@ -34,8 +48,12 @@ impl PartialEq for Type {
             }
             (Type::TypeVar(a), Type::TypeVar(b)) => a == b,
             (Type::Int, Type::Int) => true,
+            (Type::Int64, Type::Int64) => true,
+            (Type::Float, Type::Float) => true,
             (Type::Bool, Type::Bool) => true,
             (Type::Str, Type::Str) => true,
+            (Type::Char, Type::Char) => true,
+            (Type::Symbol, Type::Symbol) => true,
             (Type::Unknown, Type::Unknown) => true,
             (_, _) => false,
         }
@@ -85,8 +103,12 @@ pub fn type_var_substitute_recursive(
 pub fn type_var_substitute(typ: &Type, type_var: u64, replace_with: &Type) -> Type {
     match typ {
         Type::Int => Type::Int,
+        Type::Int64 => Type::Int64,
+        Type::Float => Type::Float,
         Type::Bool => Type::Bool,
         Type::Str => Type::Str,
+        Type::Char => Type::Char,
+        Type::Symbol => Type::Symbol,
         Type::List(base_typ) => {
             let sbase_typ = type_var_substitute(base_typ, type_var, replace_with);
             Type::List(Box::new(sbase_typ))
@@ -99,6 +121,14 @@ pub fn type_var_substitute(typ: &Type, type_var: u64, replace_with: &Type) -> Ty
             let sret_typ = type_var_substitute(ret_typ, type_var, replace_with);
             Type::Func(sin_typs, Box::new(sret_typ))
         }
+        Type::Cont(base_typ) => {
+            let sbase_typ = type_var_substitute(base_typ, type_var, replace_with);
+            Type::Cont(Box::new(sbase_typ))
+        }
+        Type::Generator(base_typ) => {
+            let sbase_typ = type_var_substitute(base_typ, type_var, replace_with);
+            Type::Generator(Box::new(sbase_typ))
+        }
         Type::Tuple(typs) => {
             let styps: Vector<Type> = typs
                 .iter()
@@ -107,11 +137,11 @@ pub fn type_var_substitute(typ: &Type, type_var: u64, replace_with: &Type) -> Ty
             Type::Tuple(styps)
         }
         Type::Record(bindings) => {
-            let sbindings: Vector<(String, Type)> = bindings
+            let sbindings: Vector<(String, Type, bool)> = bindings
                 .iter()
-                .map(|pair| {
-                    let styp = type_var_substitute(&pair.1, type_var, replace_with);
-                    (pair.0.clone(), styp)
+                .map(|(name, typ, mutable)| {
+                    let styp = type_var_substitute(typ, type_var, replace_with);
+                    (name.clone(), styp, *mutable)
                 })
                 .collect();
             Type::Record(sbindings)
@@ -142,9 +172,15 @@ pub fn type_var_substitute(typ: &Type, type_var: u64, replace_with: &Type) -> Ty
 pub fn type_contains_var(typ: &Type, var: u64) -> bool {
     match typ {
         Type::Int => false,
+        Type::Int64 => false,
+        Type::Float => false,
         Type::Bool => false,
         Type::Str => false,
+        Type::Char => false,
+        Type::Symbol => false,
         Type::List(x) => type_contains_var(x, var),
+        Type::Cont(x) => type_contains_var(x, var),
+        Type::Generator(x) => type_contains_var(x, var),
         Type::Func(typs, ret_typ) => {
             typs.iter().any(|typ| type_contains_var(typ, var)) || type_contains_var(ret_typ, var)
         }
@@ -158,13 +194,81 @@ pub fn type_contains_var(typ: &Type, var: u64) -> bool {
     }
 }
 
+/// Sort the fields of a record (or record type) by name, which is the
+/// canonical order of a record type's fields, and the order in which they are
+/// laid out in memory. `name` gives the name of each field.
+///
+/// Returns the name of a field that appears more than once as an error.
+pub fn sort_fields<T: Clone>(
+    fields: &Vector<T>,
+    name: impl Fn(&T) -> &String,
+) -> Result<Vector<T>, String> {
+    let mut sorted: Vec<T> = fields.iter().cloned().collect();
+    sorted.sort_by(|a, b| name(a).cmp(name(b)));
+    match sorted
+        .windows(2)
+        .find(|pair| name(&pair[0]) == name(&pair[1]))
+    {
+        Some(pair) => Err(name(&pair[0]).clone()),
+        None => Ok(Vector::from(sorted)),
+    }
+}
+
+/// Check whether values of type `sub` can be used wherever values of type
+/// `sup` are expected.
+///
+/// A record type is a subtype of another if it has all of the other's fields
+/// (and possibly more), each of which is a subtype of the other's field of the
+/// same name. Records with mutable fields are only subtypes of themselves,
+/// since converting them would copy the fields that are meant to be shared.
+/// Tuples are subtypes of tuples of the same length whose
+/// components are supertypes of their own. All other types (including the
+/// types of functions, and lists, whose elements can be replaced with
+/// set-car!) are only subtypes of themselves.
+///
+/// Values are converted to the type they are used as by copying them (see
+/// `type_check::coerce`), since the layout of a record depends on its fields.
+pub fn is_subtype(sub: &Type, sup: &Type) -> bool {
+    match (sub, sup) {
+        (Type::Tuple(sub_typs), Type::Tuple(sup_typs)) => {
+            sub_typs.len() == sup_typs.len()
+                && sub_typs
+                    .iter()
+                    .zip(sup_typs.iter())
+                    .all(|(sub_typ, sup_typ)| is_subtype(sub_typ, sup_typ))
+        }
+        (Type::Record(sub_fields), Type::Record(sup_fields)) => {
+            if sub_fields
+                .iter()
+                .chain(sup_fields.iter())
+                .any(|field| field.2)
+            {
+                return sub == sup;
+            }
+            sup_fields.iter().all(|(name, sup_typ, _mutable)| {
+                sub_fields
+                    .iter()
+                    .find(|field| field.0 == *name)
+                    .is_some_and(|field| is_subtype(&field.1, sup_typ))
+            })
+        }
+        _ => sub == sup,
+    }
+}
+
 impl std::fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Type::Int => write!(f, "int"),
+            Type::Int64 => write!(f, "int64"),
+            Type::Float => write!(f, "float"),
             Type::Bool => write!(f, "bool"),
             Type::Str => write!(f, "string"),
+            Type::Char => write!(f, "char"),
+            Type::Symbol => write!(f, "symbol"),
             Type::List(typ) => write!(f, "(list {typ})"),
+            Type::Cont(typ) => write!(f, "(cont {typ})"),
+            Type::Generator(typ) => write!(f, "(generator {typ})"),
             Type::Func(in_typs, ret_typ) => {
                 if in_typs.is_empty() {
                     write!(f, "(-> {ret_typ})")
@@ -182,7 +286,10 @@ impl std::fmt::Display for Type {
                 } else {
                     let bindings_str_vec = bindings
                         .iter()
-                        .map(|pair| format!("({} : {})", pair.0, pair.1))
+                        .map(|(name, typ, mutable)| match mutable {
+                            true => format!("(mutable {name} : {typ})"),
+                            false => format!("({name} : {typ})"),
+                        })
                         .collect();
                     write!(f, "(record {})", format_vector(bindings_str_vec))
                 }
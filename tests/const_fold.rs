@@ -0,0 +1,154 @@
+use scheme_to_wasm::const_fold::const_fold_exp;
+use scheme_to_wasm::parse::parse;
+use scheme_to_wasm::type_check::type_check;
+
+/// Folds the source expression, and checks that it matches the expected
+/// (type checked) expression
+fn assert_folds_to(source: &str, expected: &str) {
+    let exp = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap();
+    let expected_exp = type_check(&parse(&lexpr::from_str(expected).unwrap()).unwrap()).unwrap();
+    let folded_exp = const_fold_exp(&exp).unwrap();
+
+    println!("Source: {exp}");
+    println!("Constant folded: {folded_exp}");
+    assert_eq!(folded_exp, expected_exp);
+}
+
+#[test]
+fn test_const_fold_arithmetic() {
+    assert_folds_to("(+ 1 2)", "3");
+    assert_folds_to("(* (+ 3 5) (- 4 2))", "16");
+    assert_folds_to("(/ 7 2)", "3");
+    assert_folds_to("(/ -7 2)", "-3");
+    // ints are exact, so results which don't fit in a literal are left alone
+    assert_folds_to("(+ 2147483647 1)", "(+ 2147483647 1)");
+    assert_folds_to("(- (* 65536 65536) 1)", "(- (* 65536 65536) 1)");
+}
+
+#[test]
+fn test_const_fold_division_traps_preserved() {
+    assert_folds_to("(/ 1 0)", "(/ 1 0)");
+    assert_folds_to("(/ -2147483648 -1)", "(/ -2147483648 -1)");
+}
+
+#[test]
+fn test_const_fold_comparisons() {
+    assert_folds_to("(< 1 2)", "true");
+    assert_folds_to("(>= 1 2)", "false");
+    assert_folds_to("(= (+ 1 1) 2)", "true");
+    assert_folds_to("(and (< 1 2) (> 1 2))", "false");
+    assert_folds_to("(or (< 1 2) (> 1 2))", "true");
+    assert_folds_to(r#"(concat "hello " "world")"#, r#""hello world""#);
+}
+
+#[test]
+fn test_const_fold_primitives() {
+    assert_folds_to("(not (< 1 2))", "false");
+    assert_folds_to("(!= 1 2)", "true");
+    assert_folds_to("(incr (decr 5))", "5");
+    assert_folds_to("(remainder -7 2)", "-1");
+    assert_folds_to("(modulo -7 2)", "1");
+    assert_folds_to("(modulo 7 -2)", "-1");
+    assert_folds_to("(modulo 1 0)", "(modulo 1 0)");
+    assert_folds_to("(bitwise-xor (bitwise-or 12 1) (bitwise-and 12 10))", "5");
+    assert_folds_to("(bitwise-not 0)", "-1");
+    assert_folds_to("(shift-left 1 33)", "2");
+    assert_folds_to("(shift-right -8 1)", "-4");
+    assert_folds_to(r#"(str-len "hello")"#, "5");
+    assert_folds_to(r#"(string-equal? "a" "b")"#, "false");
+    assert_folds_to(r"(char->int #\a)", "97");
+    assert_folds_to("(int->char 955)", r"#\x3bb");
+    assert_folds_to(r"(char=? #\a (int->char 97))", "true");
+    assert_folds_to("(symbol=? 'a 'b)", "false");
+    assert_folds_to("(symbol->string 'foo)", r#""foo""#);
+
+    // Invalid code points trap at runtime
+    assert_folds_to("(int->char 55296)", "(int->char 55296)");
+
+    // Mutations are never folded away
+    assert_folds_to(
+        "(set-car! (cons 1 (null int)) 2)",
+        "(set-car! (cons 1 (null int)) 2)",
+    );
+}
+
+#[test]
+fn test_const_fold_int64_float() {
+    assert_folds_to("(+ 4000000000 5000000000)", "9000000000");
+    assert_folds_to("(* (int->int64 3) 3000000000)", "9000000000");
+    assert_folds_to("(int64->int (int->int64 -5))", "-5");
+    assert_folds_to("(int64->int 4294967298)", "(int64->int 4294967298)");
+    assert_folds_to("(< (int->int64 -1) 4000000000)", "true");
+    assert_folds_to(
+        "(+ 9223372036854775807 (int->int64 1))",
+        "-9223372036854775808",
+    );
+
+    // There is no literal for small 64-bit integers, so compare the printed
+    // expression instead
+    let source = "(/ 4000000000 (int->int64 0))";
+    let exp = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap();
+    let folded_exp = const_fold_exp(&exp).unwrap();
+    assert_eq!(folded_exp.to_string(), "(/ 4000000000 0)");
+
+    assert_folds_to("(* 1.5 (- 3.0 1.0))", "3.0");
+    assert_folds_to("(>= 1.5 2.5)", "false");
+    assert_folds_to("(int->float (float->int -2.75))", "-2.0");
+    assert_folds_to("(int64->float 4000000000)", "4000000000.0");
+    assert_folds_to("(float->int64 5000000000.5)", "5000000000");
+
+    // Floating point division by zero does not trap
+    assert_folds_to("(< (/ -1.0 0.0) -1000000.0)", "true");
+
+    // Conversions which would trap are not folded
+    assert_folds_to("(float->int 3000000000.0)", "(float->int 3000000000.0)");
+    assert_folds_to(
+        "(float->int64 10000000000000000000.0)",
+        "(float->int64 10000000000000000000.0)",
+    );
+}
+
+#[test]
+fn test_const_fold_algebraic_identities() {
+    assert_folds_to("(let ((x 5)) (* (+ x 0) 1))", "(let ((x 5)) x)");
+    assert_folds_to("(let ((x 5)) (* 0 (- x 0)))", "(let ((x 5)) 0)");
+    assert_folds_to("(let ((b true)) (and true b))", "(let ((b true)) b)");
+    assert_folds_to("(let ((b true)) (or b true))", "(let ((b true)) true)");
+
+    // The discarded argument has a side effect, so it must be kept
+    assert_folds_to(
+        "(let ((x 5)) (* (set! x 3) 0))",
+        "(let ((x 5)) (* (set! x 3) 0))",
+    );
+    assert_folds_to(
+        "(and false (null? (cdr (null int))))",
+        "(and false (null? (cdr (null int))))",
+    );
+}
+
+#[test]
+fn test_const_fold_if() {
+    assert_folds_to("(if (< 5 3) 10 20)", "20");
+    assert_folds_to("(if true (+ 1 2) (car (null int)))", "3");
+    assert_folds_to(
+        "(let ((a true)) (if a (+ 1 2) 4))",
+        "(let ((a true)) (if a 3 4))",
+    );
+}
+
+#[test]
+fn test_const_fold_projections() {
+    assert_folds_to("(tuple-ref (make-tuple 1 (+ 2 3) 4) 1)", "5");
+    assert_folds_to("(car (cons 1 (null int)))", "1");
+    assert_folds_to("(null? (cdr (cons 1 (null int))))", "(null? (null int))");
+
+    // The discarded components have side effects, so they must be kept
+    assert_folds_to(
+        "(let ((a 3)) (tuple-ref (make-tuple 1 (set! a 4)) 0))",
+        "(let ((a 3)) (tuple-ref (make-tuple 1 (set! a 4)) 0))",
+    );
+    assert_folds_to(
+        "(let ((a 3)) (car (cons 1 (cons (set! a 4) (null int)))))",
+        "(let ((a 3)) (car (cons 1 (cons (set! a 4) (null int)))))",
+    );
+}
@@ -0,0 +1,97 @@
+use scheme_to_wasm::compile::compile_exp;
+use scheme_to_wasm::dead_code::{dce_exp, dce_prog};
+use scheme_to_wasm::parse::parse;
+use scheme_to_wasm::type_check::type_check;
+
+/// Eliminates dead code from the source expression, and checks that it
+/// matches the expected (type checked) expression
+fn assert_dce_to(source: &str, expected: &str) {
+    let exp = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap();
+    let expected_exp = type_check(&parse(&lexpr::from_str(expected).unwrap()).unwrap()).unwrap();
+    let live_exp = dce_exp(&exp).unwrap();
+
+    println!("Source: {exp}");
+    println!("Dead code eliminated: {live_exp}");
+    assert_eq!(live_exp, expected_exp);
+}
+
+#[test]
+fn test_dce_let() {
+    assert_dce_to("(let ((x 1)) 2)", "2");
+    assert_dce_to("(let ((x 1) (y 2)) y)", "(let ((y 2)) y)");
+    assert_dce_to("(let ((x (cons 1 (null int)))) 2)", "2");
+    assert_dce_to("(let ((f (lambda ((x : int)) : int x))) 2)", "2");
+    // bindings only used by other dead bindings are removed too
+    assert_dce_to("(let ((x 1)) (let ((y x)) 2))", "2");
+}
+
+#[test]
+fn test_dce_let_effects_preserved() {
+    // the division may trap
+    assert_dce_to("(let ((x (/ 1 0))) 2)", "(let ((x (/ 1 0))) 2)");
+    // the call may have side effects
+    let source = r#"
+(let ((f (lambda ((x : int)) : int x)))
+  (let ((y (f 1)))
+    2))"#;
+    assert_dce_to(source, source);
+    // assigning to a variable counts as using it
+    let source = "(let ((x 1)) (begin (set! x 2) 3))";
+    assert_dce_to(source, source);
+}
+
+#[test]
+fn test_dce_begin() {
+    assert_dce_to("(begin 1 (+ 2 3) 4)", "4");
+    assert_dce_to(
+        "(let ((x 1)) (begin 1 (set! x 3) (+ x 2) x))",
+        "(let ((x 1)) (begin (set! x 3) x))",
+    );
+}
+
+#[test]
+fn test_dce_unpack() {
+    assert_dce_to("(unpack (p (pack 1 int (exists T0 T0)) T1) 5)", "5");
+    // the package may trap
+    let source = "(unpack (p (pack (/ 1 0) int (exists T0 T0)) T1) 5)";
+    assert_dce_to(source, source);
+}
+
+#[test]
+fn test_dce_handle() {
+    // the body can't raise an exception, so the handler never runs
+    assert_dce_to("(with-handler ((e : int) e) (+ 1 2))", "(+ 1 2)");
+    let source = "(with-handler ((e : int) e) (raise 1 : int))";
+    assert_dce_to(source, source);
+}
+
+#[test]
+fn test_dce_unreachable_fns() {
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((f (lambda ((x : int)) : int (+ x 1)))
+      (g (lambda ((x : int)) : int (* x 2))))
+  (begin (f 1) (g 2)))"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    assert!(prog.fns.is_empty());
+
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((f (lambda ((x : int)) : int (+ x 1)))
+      (g (lambda ((x : int)) : int (* x 2)))
+      (h (lambda ((x : int)) : int (- x 3))))
+  (make-tuple f g))"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    assert_eq!(prog.fns.len(), 2);
+    assert_eq!(dce_prog(&prog).unwrap().fns.len(), 2);
+}
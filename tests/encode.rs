@@ -0,0 +1,114 @@
+use scheme_to_wasm::compile::compile_exp;
+use scheme_to_wasm::encode::serialize_module;
+use scheme_to_wasm::generate_code::{
+    CodeGenerateOptions, ExceptionHandling, construct_module_from_prog,
+    construct_module_from_prog_with_options,
+};
+use scheme_to_wasm::parse::parse;
+
+use parity_wasm::builder;
+use parity_wasm::elements::{BlockType, Instruction, Instructions, Module, ValueType};
+
+/// Builds a module with a single function of type [i32] -> i32, with the
+/// given body
+fn module_with_body(instrs: Vec<Instruction>) -> Module {
+    builder::module()
+        .function()
+        .signature()
+        .with_param(ValueType::I32)
+        .with_result(ValueType::I32)
+        .build()
+        .body()
+        .with_instructions(Instructions::new(instrs))
+        .build()
+        .build()
+        .build()
+}
+
+#[test]
+fn test_serialize_module() {
+    // modules without marked instructions are serialized as usual
+    let exp = parse(&lexpr::from_str("(let ((x 2)) (+ x 3))").unwrap()).unwrap();
+    let module = construct_module_from_prog(&compile_exp(&exp).unwrap()).unwrap();
+    assert_eq!(
+        serialize_module(module.clone()).unwrap(),
+        parity_wasm::serialize(module).unwrap()
+    );
+
+    // a call following a nop becomes a tail call
+    let module = module_with_body(vec![
+        Instruction::GetLocal(0),
+        Instruction::Nop,
+        Instruction::Call(0),
+        Instruction::End,
+    ]);
+    let binary = serialize_module(module.clone()).unwrap();
+    let plain = parity_wasm::serialize(module).unwrap();
+    // the nop and call (0x01 0x10 0x00) are replaced by return_call (0x12 0x00)
+    assert_eq!(binary.len() + 1, plain.len());
+    assert!(
+        binary
+            .windows(4)
+            .any(|bytes| bytes == [0x20, 0x00, 0x12, 0x00])
+    );
+    assert!(
+        plain
+            .windows(5)
+            .any(|bytes| bytes == [0x20, 0x00, 0x01, 0x10, 0x00])
+    );
+
+    // the instructions of the exception-handling proposal
+    let module = module_with_body(vec![
+        Instruction::Nop,
+        Instruction::Block(BlockType::Value(ValueType::I32)),
+        Instruction::GetLocal(0),
+        Instruction::Nop,
+        Instruction::I32Const(0),
+        Instruction::Unreachable,
+        Instruction::Nop,
+        Instruction::I32Const(0),
+        Instruction::Else,
+        Instruction::End,
+        Instruction::End,
+    ]);
+    let binary = serialize_module(module).unwrap();
+    // try (result i32), local.get 0, throw 0, catch 0, end, end
+    let code = [0x06, 0x7f, 0x20, 0x00, 0x08, 0x00, 0x07, 0x00, 0x0b, 0x0b];
+    assert!(binary.windows(code.len()).any(|bytes| bytes == code));
+}
+
+#[test]
+fn test_serialize_tag_section() {
+    let options = CodeGenerateOptions {
+        exceptions: ExceptionHandling::Native,
+        ..CodeGenerateOptions::default()
+    };
+    let exp =
+        parse(&lexpr::from_str("(with-handler ((e : int) e) (raise 1 : int))").unwrap()).unwrap();
+    let module =
+        construct_module_from_prog_with_options(&compile_exp(&exp).unwrap(), &options).unwrap();
+    let binary = serialize_module(module).unwrap();
+    // the ids of the module's sections, in order
+    let mut ids = vec![];
+    let mut pos = 8;
+    while pos < binary.len() {
+        ids.push(binary[pos]);
+        let mut size = 0;
+        let mut shift = 0;
+        loop {
+            pos += 1;
+            size |= ((binary[pos] & 0x7f) as usize) << shift;
+            shift += 7;
+            if binary[pos] & 0x80 == 0 {
+                break;
+            }
+        }
+        pos += 1 + size;
+    }
+    // the tag section goes between the memory and global sections, and the
+    // custom section standing in for it is removed
+    let tag = ids.iter().position(|id| *id == 13).unwrap();
+    assert_eq!(ids[tag - 1], 5);
+    assert_eq!(ids[tag + 1], 6);
+    assert!(!ids.contains(&0));
+}
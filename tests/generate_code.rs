@@ -1,16 +1,20 @@
 use scheme_to_wasm::common::{Expr, ExprKind, Prog, TypedExpr};
-use scheme_to_wasm::compile::compile_exp;
+use scheme_to_wasm::compile::{
+    CompileOptions, compile_exp, compile_exp_with_options, compile_prog, compile_prog_with_options,
+};
+use scheme_to_wasm::encode::serialize_module;
 use scheme_to_wasm::generate_code::{
-    CodeGenerateState, construct_module, construct_module_from_prog, gen_instr,
+    CodeGenerateOptions, CodeGenerateState, ExceptionHandling, Target, construct_module_from_prog,
+    construct_module_from_prog_with_options, gen_instr, wasm_type,
 };
-use scheme_to_wasm::parse::parse;
+use scheme_to_wasm::parse::{parse, parse_prog};
 use scheme_to_wasm::type_check::type_check;
 use scheme_to_wasm::types::Type;
 
 use im_rc::vector;
 use parity_wasm::builder;
 use parity_wasm::elements::{Instruction, Instructions, Module, ValueType};
-use wasmer::{Instance, Store, Value, imports};
+use wasmer::{Function, FunctionEnv, FunctionEnvMut, Instance, Memory, Store, Value, imports};
 
 fn output_wasm_to_file(module: Module, test_name: &str) {
     let output_dir = std::env::current_dir().unwrap().join("wasm-output");
@@ -21,30 +25,32 @@ fn output_wasm_to_file(module: Module, test_name: &str) {
 /// Compiles the (untyped) expression into wasm and outputs the resulting value
 fn test_runner_exp(exp: Expr, test_name: &str) -> Value {
     let typed_exp = type_check(&exp).unwrap();
-    let mut state = CodeGenerateState::default();
-    let instructions = gen_instr(&typed_exp, &mut state).unwrap();
-    let module = construct_module("$$MAIN$$", state, vec![], Instructions::new(instructions));
-    let module = module.build();
-    let binary = parity_wasm::serialize(module.clone()).unwrap();
-    output_wasm_to_file(module, test_name);
-
-    let import_object = imports! {};
-    let engine = wasmer::Engine::default();
-    let module = wasmer::Module::new(&engine, &binary).unwrap();
-    let mut store = Store::default();
-    let instance = Instance::new(&mut store, &module, &import_object).unwrap();
-    let values = instance
-        .exports
-        .get_function("$$MAIN$$")
-        .unwrap()
-        .call(&mut store, &[])
-        .unwrap();
+    let prog = Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
+        fns: vector![],
+        exp: typed_exp,
+    };
+    test_runner_prog(prog, test_name)
+}
 
-    values[0].clone()
+/// Converts a value returned by a module into a plain number. Ints must be
+/// fixnums, which are tagged (see `construct_int_helpers`), while other
+/// values are returned as they are.
+fn untag(value: Value, typ: &Type) -> Value {
+    match (typ, value) {
+        (Type::Int, Value::I32(raw)) => {
+            assert_eq!(raw & 1, 1, "int result is not a fixnum");
+            Value::I32(raw >> 1)
+        }
+        (_, value) => value,
+    }
 }
 
 /// Compiles the (typed) program into wasm and outputs the resulting value
 fn test_runner_prog(prog: Prog<TypedExpr>, test_name: &str) -> Value {
+    let ret_type = prog.exp.typ.clone();
     let module = construct_module_from_prog(&prog).unwrap();
     let binary = parity_wasm::serialize(module.clone()).unwrap();
     output_wasm_to_file(module, test_name);
@@ -61,7 +67,7 @@ fn test_runner_prog(prog: Prog<TypedExpr>, test_name: &str) -> Value {
         .call(&mut store, &[])
         .unwrap();
 
-    values[0].clone()
+    untag(values[0].clone(), &ret_type)
 }
 
 #[test]
@@ -71,6 +77,296 @@ fn test_compile_math() {
     assert_eq!(output, Value::I32(16));
 }
 
+#[test]
+fn test_compile_primitives() {
+    let cases = [
+        ("(not (< 1 2))", 0),
+        ("(!= 3 4)", 1),
+        ("(remainder -7 2)", -1),
+        ("(modulo -7 2)", 1),
+        ("(modulo 7 -2)", -1),
+        ("(modulo -6 3)", 0),
+        ("(incr (decr (decr 5)))", 4),
+        ("(bitwise-and 12 10)", 8),
+        ("(bitwise-or 12 10)", 14),
+        ("(bitwise-xor 12 10)", 6),
+        ("(bitwise-not 0)", -1),
+        ("(shift-left 3 2)", 12),
+        ("(shift-right -8 1)", -4),
+        (r#"(str-len "hello")"#, 5),
+        (r#"(string-equal? "hello" "hello")"#, 1),
+        (r#"(string-equal? "hello" "help!")"#, 0),
+        (r#"(string-equal? "hell" "hello")"#, 0),
+        (r#"(string-equal? "" "")"#, 1),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let output = test_runner_exp(exp, &format!("prim{i}.wasm"));
+        assert_eq!(output, Value::I32(expected), "{source}");
+    }
+}
+
+#[test]
+fn test_compile_int64_float() {
+    let cases = [
+        ("(+ 4000000000 5000000000)", Value::I64(9000000000)),
+        ("(* (int->int64 -3) 3000000000)", Value::I64(-9000000000)),
+        ("(modulo (int->int64 -7) (int->int64 2))", Value::I64(1)),
+        (
+            "(shift-left (int->int64 1) (int->int64 40))",
+            Value::I64(1 << 40),
+        ),
+        ("(int64->int (- 4294967298 4294967296))", Value::I32(2)),
+        ("(int->int64 (* 65536 65536))", Value::I64(4294967296)),
+        ("(< 4000000000 5000000000)", Value::I32(1)),
+        ("(/ 7.0 2.0)", Value::F64(3.5)),
+        ("(decr (- 0.5 2.0))", Value::F64(-2.5)),
+        ("(>= 1.5 1.25)", Value::I32(1)),
+        ("(int->float 7)", Value::F64(7.0)),
+        ("(float->int -2.75)", Value::I32(-2)),
+        ("(float->int64 1000000000000.5)", Value::I64(1000000000000)),
+        ("(int64->float 4000000000)", Value::F64(4000000000.0)),
+        ("(if (< 1 2) 1.5 2.5)", Value::F64(1.5)),
+        (
+            "(let ((x 2.5) (y 5000000000)) (+ x (int64->float y)))",
+            Value::F64(5000000002.5),
+        ),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let output = test_runner_exp(exp, &format!("wide{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+}
+
+#[test]
+fn test_compile_bignums() {
+    let cases = [
+        // results are promoted to bignums, and demoted back to fixnums
+        ("(int->int64 (+ 1073741823 1))", Value::I64(1073741824)),
+        ("(- (+ 1073741823 1) 1)", Value::I32(1073741823)),
+        ("(int->int64 (* -1073741824 -1))", Value::I64(1073741824)),
+        ("(- 2147483647 2147483646)", Value::I32(1)),
+        (
+            "(int->int64 (* 123456789 987654321))",
+            Value::I64(121932631112635269),
+        ),
+        (
+            "(int->int64 (- 0 (* (* 65536 65536) (* 65536 32768))))",
+            Value::I64(i64::MIN),
+        ),
+        ("(- (* 65536 65536) (* 65536 65536))", Value::I32(0)),
+        (
+            "(+ (* 65536 65536) (- 0 (* 65536 65535)))",
+            Value::I32(65536),
+        ),
+        (
+            "(int->int64 (/ (* (* 100000 100000) 100000) 1000))",
+            Value::I64(1000000000000),
+        ),
+        (
+            "(/ (* (* 100000 100000) 100000) (* 100000 100000))",
+            Value::I32(100000),
+        ),
+        ("(remainder (- 0 (* 65536 65536)) 7)", Value::I32(-4)),
+        ("(modulo (- 0 (* 65536 65536)) 7)", Value::I32(3)),
+        ("(modulo (* 65536 65536) -7)", Value::I32(-3)),
+        ("(< (* 65536 65536) (* 65536 65537))", Value::I32(1)),
+        ("(< (- 0 (* 65536 65536)) 5)", Value::I32(1)),
+        (
+            "(>= (- 0 (* 65536 65536)) (- 0 (* 65536 65537)))",
+            Value::I32(1),
+        ),
+        ("(= (* 65536 65536) (* 65536 65536))", Value::I32(1)),
+        ("(int->float (* 65536 65536))", Value::F64(4294967296.0)),
+        ("(bitwise-and (+ 1073741823 7) 15)", Value::I32(6)),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let output = test_runner_exp(exp, &format!("bignum{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+
+    // 30! / 28! = 870, which needs several limbs along the way
+    let source = r#"
+(define fact (lambda ((n : int)) : int (if (= n 0) 1 (* n (fact (- n 1))))))
+(/ (fact 30) (fact 28))"#;
+    assert_eq!(
+        test_runner_source(source, "bignum_fact.wasm"),
+        Value::I32(870)
+    );
+}
+
+#[test]
+fn test_compile_int_traps() {
+    let sources = [
+        "(/ (* 65536 65536) 0)",
+        "(int->int64 (* (* 65536 65536) (* 65536 65536)))",
+        "(bitwise-and (* 65536 65536) 1)",
+        "(shift-left 1 (* 65536 65536))",
+    ];
+    for (i, source) in sources.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let prog = compile_exp(&exp).unwrap();
+        let module = construct_module_from_prog(&prog).unwrap();
+        let binary = parity_wasm::serialize(module.clone()).unwrap();
+        output_wasm_to_file(module, &format!("int_trap{i}.wasm"));
+
+        let engine = wasmer::Engine::default();
+        let module = wasmer::Module::new(&engine, &binary).unwrap();
+        let mut store = Store::default();
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        let result = instance
+            .exports
+            .get_function("$$MAIN$$")
+            .unwrap()
+            .call(&mut store, &[]);
+        assert!(result.is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_compile_chars_and_symbols() {
+    let cases = [
+        (r"(char->int #\a)", Value::I32(97)),
+        (r"#\x3bb", Value::I32(0x3bb)),
+        (r"(char=? #\a (int->char 97))", Value::I32(1)),
+        (r"(char=? #\a #\b)", Value::I32(0)),
+        ("(symbol=? 'foo 'foo)", Value::I32(1)),
+        ("(symbol=? 'foo 'bar)", Value::I32(0)),
+        (
+            "(let ((x 'foo)) (symbol=? (car (cons x (null symbol))) 'foo))",
+            Value::I32(1),
+        ),
+        ("(str-len (symbol->string 'hello))", Value::I32(5)),
+        (
+            r#"(string-equal? (symbol->string 'hello) "hello")"#,
+            Value::I32(1),
+        ),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let output = test_runner_exp(exp, &format!("char_symbol{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+
+    // every occurrence of a symbol refers to the same interned name
+    let exp = parse(&lexpr::from_str("(make-tuple 'foo 'bar 'foo)").unwrap()).unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    let data = module.data_section().unwrap().entries();
+    assert_eq!(
+        data.iter()
+            .filter(|segment| segment.value().ends_with(b"foo"))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_compile_char_traps() {
+    let sources = [
+        "(int->char -1)",
+        "(int->char 1114112)",
+        "(int->char 55296)",
+        "(int->char 57343)",
+    ];
+    for (i, source) in sources.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let prog = compile_exp(&exp).unwrap();
+        let module = construct_module_from_prog(&prog).unwrap();
+        let binary = parity_wasm::serialize(module.clone()).unwrap();
+        output_wasm_to_file(module, &format!("char_trap{i}.wasm"));
+
+        let engine = wasmer::Engine::default();
+        let module = wasmer::Module::new(&engine, &binary).unwrap();
+        let mut store = Store::default();
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        let result = instance
+            .exports
+            .get_function("$$MAIN$$")
+            .unwrap()
+            .call(&mut store, &[]);
+        assert!(result.is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_compile_wide_values_in_memory() {
+    // values of different sizes can be mixed in lists and tuples
+    let source = r#"
+(let ((lst (cons 1.5 (cons 2.5 (null float))))
+      (tup (make-tuple 1 5000000000 2.5 3)))
+  (begin
+    (set-car! (cdr lst) 4.0)
+    (set-cdr! (cdr lst) (cons 8.0 (null float)))
+    (+ (+ (car lst) (car (cdr lst)))
+       (+ (car (cdr (cdr lst)))
+          (+ (int->float (tuple-ref tup 3)) (tuple-ref tup 2))))))"#;
+    assert_eq!(
+        test_runner_source(source, "wide_memory1.wasm"),
+        Value::F64(19.0)
+    );
+
+    // functions (and closures) can take and return wide values, including
+    // as their environment
+    let source = r#"
+(define scale (lambda ((x : float) (n : int64)) : float (* x (int64->float n))))
+(let ((offset 5000000000))
+  (let ((shift (lambda ((y : int64)) : int64 (+ y offset))))
+    (+ (scale 0.5 (shift (int->int64 1))) 0.5)))"#;
+    assert_eq!(
+        test_runner_source(source, "wide_memory2.wasm"),
+        Value::F64(2500000001.0)
+    );
+
+    let source = r#"
+(define total 0.0)
+(define add (lambda ((a : int) (b : float) (c : int64) (d : int) (e : float)
+                     (f : int64) (g : int) (h : float) (i : int64)) : int64
+  (begin
+    (set! total (+ b (+ e h)))
+    (+ c (+ f (+ i (int->int64 (+ a (+ d g)))))))))
+(if (= (add 1 1.5 3000000000 2 2.5 3000000000 3 3.0 3000000000) 9000000006)
+    total
+    -1.0)"#;
+    assert_eq!(
+        test_runner_source(source, "wide_memory3.wasm"),
+        Value::F64(7.0)
+    );
+}
+
+#[test]
+fn test_compile_pack_wide_value() {
+    // abstract types are always represented by 4-byte values
+    let exp = parse(&lexpr::from_str("(pack 1.5 float (exists T0 T0))").unwrap()).unwrap();
+    let typed_exp = type_check(&exp).unwrap();
+    let mut state = CodeGenerateState::default();
+    assert!(gen_instr(&typed_exp, &mut state).is_err());
+}
+
+#[test]
+fn test_compile_set_car_cdr() {
+    let exp = parse(
+        &lexpr::from_str(
+            "(let ((lst (cons 1 (cons 2 (null int)))))
+               (begin
+                 (set-car! lst 5)
+                 (set-cdr! (cdr lst) (cons 7 (null int)))
+                 (+ (* 100 (car lst)) (+ (* 10 (car (cdr lst))) (car (cdr (cdr lst)))))))",
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let output = test_runner_exp(exp, "set_car_cdr.wasm");
+    assert_eq!(output, Value::I32(527));
+
+    // set-car! evaluates to the assigned value
+    let exp = parse(&lexpr::from_str("(set-car! (cons 1 (null int)) 9)").unwrap()).unwrap();
+    let output = test_runner_exp(exp, "set_car_value.wasm");
+    assert_eq!(output, Value::I32(9));
+}
+
 #[test]
 fn test_compile_control() {
     let exp = parse(&lexpr::from_str("(if (< 5 3) 10 20)").unwrap()).unwrap();
@@ -96,6 +392,78 @@ fn test_compile_let_multiple_bindings() {
     assert_eq!(output, Value::I32(56));
 }
 
+#[test]
+fn test_compile_let_shadowing() {
+    let exp = parse(&lexpr::from_str("(let ((a 1)) (let ((b (let ((a 2)) a))) (+ a b)))").unwrap())
+        .unwrap();
+    let output = test_runner_exp(exp, "let_shadowing1.wasm");
+    assert_eq!(output, Value::I32(3));
+
+    let exp = parse(&lexpr::from_str("(let ((a 1)) (+ (let ((a 2)) a) a))").unwrap()).unwrap();
+    let output = test_runner_exp(exp, "let_shadowing2.wasm");
+    assert_eq!(output, Value::I32(3));
+
+    // bindings within the same let can't see each other
+    let exp =
+        parse(&lexpr::from_str("(let ((x 1)) (let ((x 2) (y x)) (+ x y)))").unwrap()).unwrap();
+    let output = test_runner_exp(exp, "let_shadowing3.wasm");
+    assert_eq!(output, Value::I32(3));
+}
+
+#[test]
+fn test_compile_let_reuses_locals() {
+    let exp = parse(
+        &lexpr::from_str("(+ (let ((a 1) (b 2)) (+ a b)) (let ((c 3)) (let ((d 4)) (* c d))))")
+            .unwrap(),
+    )
+    .unwrap();
+    let typed_exp = type_check(&exp).unwrap();
+    let prog = Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
+        fns: vector![],
+        exp: typed_exp,
+    };
+    let module = construct_module_from_prog(&prog).unwrap();
+    assert_eq!(count_locals(&module), 2);
+    let output = test_runner_prog(prog, "let_reuses_locals.wasm");
+    assert_eq!(output, Value::I32(15));
+}
+
+#[test]
+fn test_compile_let_reuses_dead_locals() {
+    let progs = vec![
+        (
+            "(let ((a 1)) (let ((b (+ a 1))) (let ((c (* b 2))) c)))",
+            1,
+            4,
+        ),
+        ("(let ((a 1)) (begin (+ a 1) (let ((b 2)) (+ b 3))))", 1, 5),
+        (
+            "(let ((a 1)) (if (= a 1) (let ((b 2)) (+ b 1)) (let ((c 3)) c)))",
+            1,
+            3,
+        ),
+        ("(let ((a 1) (b 2)) (let ((c (+ a 2))) (+ c b)))", 2, 5),
+    ];
+    for (index, (source, expected_locals, expected)) in progs.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let typed_exp = type_check(&exp).unwrap();
+        let prog = Prog {
+            externs: vector![],
+            globals: vector![],
+            exports: vector![],
+            fns: vector![],
+            exp: typed_exp,
+        };
+        let module = construct_module_from_prog(&prog).unwrap();
+        assert_eq!(count_locals(&module), expected_locals, "{}", source);
+        let output = test_runner_prog(prog, &format!("let_reuses_dead_locals{}.wasm", index));
+        assert_eq!(output, Value::I32(expected), "{}", source);
+    }
+}
+
 #[test]
 fn test_compile_tuple() {
     let exp = parse(&lexpr::from_str("(tuple-ref (make-tuple 3 4) 1)").unwrap()).unwrap();
@@ -210,6 +578,78 @@ fn test_compile_cons_tuple() {
     assert_eq!(output, Value::I32(1)); // true
 }
 
+// Tests records used as supertypes of their own types
+#[test]
+fn test_compile_record_subtyping() {
+    let source = r#"
+(define norm
+  (lambda ((p : (record (x : int) (y : int)))) : int
+    (+ (* (record-ref p x) (record-ref p x)) (* (record-ref p y) (record-ref p y)))))
+(define origin-x
+  (lambda ((s : (record (origin : (record (x : int)))))) : int
+    (record-ref (record-ref s origin) x)))
+(let ((p (make-record (name "p") (y 4) (x 3)))
+      (s (make-record (size 10) (origin (make-record (y 2) (x 7))))))
+  (let ((q (if (> (norm p) 20) p (make-record (x 0) (y 0)))))
+    (+ (* (norm q) 100) (origin-x s))))"#;
+    assert_eq!(
+        test_runner_source(source, "record_subtyping.wasm"),
+        Value::I32(2507)
+    );
+}
+
+// Tests that mutable record fields are updated in place, so the change is
+// seen through every reference to the record
+#[test]
+fn test_compile_record_set() {
+    let source = r#"
+(define bump!
+  (lambda ((c : (record (mutable big : int64) (mutable count : int) (step : int)))) : int
+    (begin
+      (record-set! c big (+ (record-ref c big) (int->int64 1)))
+      (record-set! c count (+ (record-ref c count) (record-ref c step))))))
+(let ((c (make-record (step 5) (mutable count 1) (mutable big (int->int64 100)))))
+  (let ((f (lambda () : int (bump! c))))
+    (begin
+      (bump! c)
+      (bump! c)
+      (f)
+      (+ (* (record-ref c count) 1000) (int64->int (record-ref c big))))))"#;
+    assert_eq!(
+        test_runner_source(source, "record_set.wasm"),
+        Value::I32(16103)
+    );
+}
+
+// Tests that the fields of a record are evaluated in the order they are
+// written, regardless of how they are laid out
+#[test]
+fn test_compile_record_evaluation_order() {
+    let source = r#"
+(let ((x 1))
+  (let ((r (make-record (b (begin (set! x (* x 10)) x)) (a (begin (set! x (+ x 1)) x)))))
+    (+ (* (record-ref r a) 100) (record-ref r b))))"#;
+    assert_eq!(
+        test_runner_source(source, "record_evaluation_order.wasm"),
+        Value::I32(1110)
+    );
+}
+
+// Tests that each evaluation of a cons or make-tuple expression allocates a
+// new value
+#[test]
+fn test_compile_cons_in_loop() {
+    let source = r#"
+(let ((xs (let build ((i : int 0) (acc : (list (tuple int)) (null (tuple int)))) : (list (tuple int))
+            (if (< i 4) (build (+ i 1) (cons (make-tuple i) acc)) acc))))
+  (let sum ((xs : (list (tuple int)) xs) (total : int 0)) : int
+    (if (null? xs) total (sum (cdr xs) (+ (* total 10) (tuple-ref (car xs) 0))))))"#;
+    assert_eq!(
+        test_runner_source(source, "cons_in_loop.wasm"),
+        Value::I32(3210)
+    );
+}
+
 // Tests tuples containing complex list types
 #[test]
 fn test_compile_tuple_cons_tuple() {
@@ -286,6 +726,9 @@ fn test_compile_func_without_closure_conversion() {
     );
     // let typed_exp = type_check(&exp).unwrap();
     let prog = Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
         fns: vector![(String::from("func0"), typed_func)],
         exp: typed_exp,
     };
@@ -437,6 +880,1107 @@ fn test_compile_higher_order_func() {
     assert_eq!(output, Value::I32(6));
 }
 
+/// The number of helper functions which every module starts with (see
+/// `construct_int_helpers`), found by compiling a program without any
+/// functions of its own
+fn num_helpers() -> usize {
+    let prog = Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
+        fns: vector![],
+        exp: TypedExpr::new(Type::Int, ExprKind::Num(0)),
+    };
+    let module = construct_module_from_prog(&prog).unwrap();
+    module.code_section().unwrap().bodies().len() - 1
+}
+
+/// Whether the instruction calls one of the program's own functions, rather
+/// than a helper function (assuming the module has no imports)
+fn is_direct_call(instr: &Instruction) -> bool {
+    matches!(instr, Instruction::Call(func_idx) if *func_idx as usize >= num_helpers())
+}
+
+/// Counts the instructions within the bodies of the program's own functions
+/// (not including the helper functions) which satisfy the given predicate
+fn count_instructions(module: &Module, pred: fn(&Instruction) -> bool) -> usize {
+    module
+        .code_section()
+        .map(|code| {
+            code.bodies()
+                .iter()
+                .skip(num_helpers())
+                .flat_map(|body| body.code().elements())
+                .filter(|instr| pred(instr))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// Counts the local variables declared across the program's own functions
+/// (not including function parameters, or the helper functions)
+fn count_locals(module: &Module) -> u32 {
+    module
+        .code_section()
+        .map(|code| {
+            code.bodies()
+                .iter()
+                .skip(num_helpers())
+                .flat_map(|body| body.locals())
+                .map(|local| local.count())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Options which keep functions from being inlined away, so that calls
+/// remain in the generated code
+fn no_inlining() -> CompileOptions {
+    CompileOptions {
+        inline: false,
+        ..CompileOptions::default()
+    }
+}
+
+#[test]
+fn test_compile_direct_call() {
+    let exp = parse(&lexpr::from_str("((lambda ((x : int)) : int (+ x 1)) 5)").unwrap()).unwrap();
+    let prog = compile_exp_with_options(&exp, &no_inlining()).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    assert_eq!(
+        count_instructions(&module, |instr| matches!(instr, Instruction::CallIndirect(_, _))),
+        0
+    );
+    assert_eq!(count_instructions(&module, is_direct_call), 1);
+    let output = test_runner_prog(prog, "direct_call.wasm");
+    assert_eq!(output, Value::I32(6));
+}
+
+#[test]
+fn test_compile_direct_call_higher_order() {
+    // `apply` and `double` are both known within the main expression, but
+    // `fn` is an unknown parameter within `apply`, so it must be called
+    // through the function table.
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((apply (lambda ((fn : (-> int int)) (x : int)) : int (fn x))))
+  (let ((double (lambda ((num : int)) : int (* num 2))))
+    (apply double 3)))
+                "#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp_with_options(&exp, &no_inlining()).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    assert_eq!(
+        count_instructions(&module, |instr| matches!(instr, Instruction::CallIndirect(_, _))),
+        1
+    );
+    assert_eq!(count_instructions(&module, is_direct_call), 1);
+    let output = test_runner_prog(prog, "direct_call_higher_order.wasm");
+    assert_eq!(output, Value::I32(6));
+}
+
+#[test]
+fn test_compile_direct_call_after_set() {
+    // Once `f` is reassigned, it is no longer known to hold a closure of the
+    // first lambda.
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((f (lambda ((x : int)) : int (+ x 1)))
+      (g (lambda ((x : int)) : int (* x 10))))
+  (+ (f 1) (begin (set! f g) (f 1))))
+                "#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    let output = test_runner_prog(prog, "direct_call_after_set.wasm");
+    assert_eq!(output, Value::I32(12));
+}
+
+#[test]
+fn test_compile_captured_closure() {
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((f (lambda ((x : int)) : int (+ x 1))))
+  (let ((g (lambda ((y : int)) : int (* (f y) 2))))
+    (g 4)))"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp_with_options(&exp, &no_inlining()).unwrap();
+    let output = test_runner_prog(prog, "captured_closure.wasm");
+    assert_eq!(output, Value::I32(10));
+}
+
+#[test]
+fn test_compile_quoted_lists() {
+    let source = r#"
+(define sum (lambda ((lst : (list int))) : int (if (null? lst) 0 (+ (car lst) (sum (cdr lst))))))
+(= (sum '(1 2 3 2147483647)) (+ 2147483647 6))"#;
+    assert_eq!(test_runner_source(source, "quote_sum.wasm"), Value::I32(1));
+
+    let cases = [
+        ("(car (cdr (car (cdr '((1) (2 3))))))", Value::I32(3)),
+        ("(car (cdr '(1.5 2.5)))", Value::F64(2.5)),
+        (
+            "(car (cdr '(5000000000 6000000000)))",
+            Value::I64(6000000000),
+        ),
+        ("(str-len (car (cdr '(\"a\" \"bcd\"))))", Value::I32(3)),
+        ("(symbol=? (car (cdr '(a b))) 'b)", Value::I32(1)),
+        ("(null? (car (cdr '((1) ()))))", Value::I32(1)),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let output = test_runner_exp(exp, &format!("quote{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+
+    // the cons cells are placed in memory by data segments, so nothing is
+    // stored at runtime
+    let exp = parse(&lexpr::from_str("'((1 2) (3))").unwrap()).unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    assert_eq!(
+        count_instructions(&module, |instr| matches!(
+            instr,
+            Instruction::I32Store(_, _)
+        )),
+        0
+    );
+}
+
+/// Compiles a program declaring the externs `add-ten` and `negate`, and runs
+/// it with host implementations of them
+fn test_runner_externs(source: &str, options: &CompileOptions, test_name: &str) -> Value {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let source_prog = parse_prog(&values).unwrap();
+    let prog = compile_prog_with_options(&source_prog, options).unwrap();
+    let ret_type = prog.exp.typ.clone();
+    let module = construct_module_from_prog(&prog).unwrap();
+    let binary = parity_wasm::serialize(module.clone()).unwrap();
+    output_wasm_to_file(module, test_name);
+
+    let engine = wasmer::Engine::default();
+    let module = wasmer::Module::new(&engine, &binary).unwrap();
+    let mut store = Store::default();
+    let import_object = imports! {
+        "env" => {
+            "add-ten" => Function::new_typed(&mut store, |x: i32| -> i32 { x + 10 }),
+            "negate" => Function::new_typed(&mut store, |x: i32| -> i32 { -x }),
+        }
+    };
+    let instance = Instance::new(&mut store, &module, &import_object).unwrap();
+    let values = instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+
+    untag(values[0].clone(), &ret_type)
+}
+
+#[test]
+fn test_compile_externs() {
+    let source = r#"
+(extern add-ten (-> int int))
+(extern negate (-> int int))
+(let ((double (lambda ((x : int)) : int (add-ten (* x 2)))))
+  (negate (double (add-ten 1))))"#;
+    let output = test_runner_externs(source, &CompileOptions::default(), "externs1.wasm");
+    assert_eq!(output, Value::I32(-32));
+    let output = test_runner_externs(source, &no_inlining(), "externs2.wasm");
+    assert_eq!(output, Value::I32(-32));
+
+    // externs can be passed around like any other function
+    let source = r#"
+(extern add-ten (-> int int))
+(extern negate (-> int int))
+(let ((apply (lambda ((fn : (-> int int)) (x : int)) : int (fn x))))
+  (+ (apply add-ten 1) (apply negate 5)))"#;
+    let output = test_runner_externs(source, &no_inlining(), "externs3.wasm");
+    assert_eq!(output, Value::I32(6));
+
+    // ints which don't fit in a fixnum are passed as plain i32s too
+    let source = r#"
+(extern negate (-> int int))
+(- (negate (negate 1073741824)) 1073741823)"#;
+    let output = test_runner_externs(source, &no_inlining(), "externs4.wasm");
+    assert_eq!(output, Value::I32(1));
+}
+
+/// Compiles a program, runs its main expression to initialize its global
+/// definitions, and then calls the exported function `export_name`.
+fn test_runner_export(
+    source: &str,
+    options: &CompileOptions,
+    export_name: &str,
+    args: &[i32],
+    test_name: &str,
+) -> Result<Value, wasmer::RuntimeError> {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let source_prog = parse_prog(&values).unwrap();
+    let prog = compile_prog_with_options(&source_prog, options).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    let binary = parity_wasm::serialize(module.clone()).unwrap();
+    output_wasm_to_file(module, test_name);
+
+    let engine = wasmer::Engine::default();
+    let module = wasmer::Module::new(&engine, &binary).unwrap();
+    let mut store = Store::default();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+    let args = args
+        .iter()
+        .map(|arg| Value::I32(*arg))
+        .collect::<Vec<Value>>();
+    let values = instance
+        .exports
+        .get_function(export_name)
+        .unwrap()
+        .call(&mut store, &args)?;
+
+    Ok(values[0].clone())
+}
+
+#[test]
+fn test_compile_exports() {
+    let options = CompileOptions::default();
+    let source = r#"
+(define add (lambda ((a : int) (b : int)) : int (+ a b)))
+(export add "plus")
+0"#;
+    let output = test_runner_export(source, &options, "plus", &[3, 4], "exports1.wasm");
+    assert_eq!(output.unwrap(), Value::I32(7));
+    // ints are passed as plain i32s, even if they don't fit in a fixnum
+    let output = test_runner_export(source, &options, "plus", &[i32::MAX, -5], "exports1.wasm");
+    assert_eq!(output.unwrap(), Value::I32(i32::MAX - 5));
+    // results which don't fit in an i32 trap
+    assert!(test_runner_export(source, &options, "plus", &[i32::MAX, 1], "exports1.wasm").is_err());
+
+    // exported functions can refer to other definitions, and to themselves
+    let source = r#"
+(define base 1)
+(define fact (lambda ((n : int)) : int (if (= n 0) base (* n (fact (- n 1))))))
+(export fact)
+(fact 3)"#;
+    let output = test_runner_export(source, &options, "fact", &[5], "exports2.wasm");
+    assert_eq!(output.unwrap(), Value::I32(120));
+
+    // exported definitions can be closures
+    let source = r#"
+(define make-adder (lambda ((x : int)) : (-> int int) (lambda ((y : int)) : int (+ x y))))
+(define add-five (make-adder 5))
+(export add-five)
+0"#;
+    let output = test_runner_export(source, &options, "add-five", &[10], "exports3.wasm");
+    assert_eq!(output.unwrap(), Value::I32(15));
+}
+
+#[test]
+fn test_compile_exports_before_main() {
+    let source = r#"
+(define counter 0)
+(define bump (lambda ((n : int)) : int (begin (set! counter (+ counter n)) counter)))
+(export bump)
+(bump 100)"#;
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    let binary = parity_wasm::serialize(module.clone()).unwrap();
+    output_wasm_to_file(module, "exports_before_main.wasm");
+
+    let mut store = Store::default();
+    let module = wasmer::Module::new(&store, &binary).unwrap();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    let bump = instance.exports.get_function("bump").unwrap();
+    // the first call runs the main expression (once) to initialize `counter`
+    let values = bump.call(&mut store, &[Value::I32(1)]).unwrap();
+    assert_eq!(values[0], Value::I32(101));
+    let values = bump.call(&mut store, &[Value::I32(1)]).unwrap();
+    assert_eq!(values[0], Value::I32(102));
+}
+
+#[test]
+fn test_compile_defines() {
+    let source = r#"
+(define counter 0)
+(define bump (lambda () : int (begin (set! counter (+ counter 1)) counter)))
+(let ((counter 10))
+  (begin (bump) (bump) (+ counter (bump))))"#;
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    let output = test_runner_prog(prog, "defines.wasm");
+    assert_eq!(output, Value::I32(13));
+}
+
+/// Compiles and runs a program which may use derived forms
+fn test_runner_source(source: &str, test_name: &str) -> Value {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    test_runner_prog(prog, test_name)
+}
+
+#[test]
+fn test_compile_inline_assigned_args() {
+    // a call in the body of an inlined function may change a global (or a
+    // variable assigned by a closure) which is passed as an argument, so the
+    // argument is evaluated before the body runs
+    let cases = [
+        (
+            r#"
+(define g 0)
+(define inc (lambda () : int (set! g (+ g 1))))
+((lambda ((y : int)) : int (begin (inc) y)) g)"#,
+            [CompileOptions::default(), no_inlining()],
+        ),
+        (
+            r#"
+(let ((n 0))
+  (let ((inc (lambda () : int (set! n (+ n 1)))))
+    ((lambda ((y : int)) : int (begin (inc) y)) n)))"#,
+            [
+                cps(),
+                CompileOptions {
+                    inline: false,
+                    ..cps()
+                },
+            ],
+        ),
+    ];
+    for (i, (source, options)) in cases.into_iter().enumerate() {
+        for (j, options) in options.iter().enumerate() {
+            let output = test_runner_externs(source, options, &format!("inline_args{i}_{j}.wasm"));
+            assert_eq!(output, Value::I32(0), "{source}");
+        }
+    }
+}
+
+#[test]
+fn test_compile_exceptions() {
+    let cases = [
+        (
+            "(with-handler ((e : int) (+ e 1)) (+ 1 (raise 41 : int)))",
+            Value::I32(42),
+        ),
+        ("(with-handler ((e : int) 0) 5)", Value::I32(5)),
+        (
+            "(with-handler ((x : float) (* x 2.0)) (raise 1.25 : float))",
+            Value::F64(2.5),
+        ),
+        (
+            "(with-handler ((x : int64) x) (if (raise 5000000000 : bool) (int->int64 1) (int->int64 2)))",
+            Value::I64(5000000000),
+        ),
+        // a handler only catches exceptions of its own type
+        (
+            "(with-handler ((e : int) e) (with-handler ((s : string) 0) (+ 1 (raise 7 : int))))",
+            Value::I32(7),
+        ),
+        // an exception raised by a handler goes to the enclosing handler
+        (
+            "(with-handler ((e : int) (* e 2)) (with-handler ((e : int) (raise (+ e 1) : int)) (raise 1 : int)))",
+            Value::I32(4),
+        ),
+        // a handler no longer catches exceptions once its body has finished
+        (
+            "(with-handler ((e : int) (+ e 100)) (+ (with-handler ((e : int) e) 1) (raise 2 : int)))",
+            Value::I32(102),
+        ),
+    ];
+    for (i, (source, expected)) in cases.iter().enumerate() {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let prog = compile_exp(&exp).unwrap();
+        let output = test_runner_prog(prog, &format!("exceptions{i}.wasm"));
+        assert_eq!(output, *expected, "{source}");
+    }
+    // CPS converted programs give the same results
+    for (i, (source, expected)) in cases.iter().enumerate() {
+        let output = test_runner_externs(source, &cps(), &format!("exceptions_cps{i}.wasm"));
+        assert_eq!(output, *expected, "{source}");
+    }
+    // and so do native exceptions, which wasmer doesn't support yet
+    let native = CodeGenerateOptions {
+        exceptions: ExceptionHandling::Native,
+        ..CodeGenerateOptions::default()
+    };
+    for (i, (source, expected)) in cases.iter().enumerate() {
+        let test_name = format!("exceptions_native{i}.wasm");
+        let Some(output) = test_runner_node(source, &no_inlining(), &native, &test_name) else {
+            eprintln!("node not found, skipping native exceptions test");
+            break;
+        };
+        assert_eq!(output.unwrap(), *expected, "{source}");
+    }
+
+    // exceptions propagate out of functions and closures
+    let source = r#"
+(define safe-div
+  (lambda ((a : int) (b : int)) : int
+    (if (= b 0) (raise "division by zero" : int) (/ a b))))
+(with-handler ((msg : string) (str-len msg))
+  (+ (safe-div 10 2) (safe-div 1 0)))"#;
+    assert_eq!(
+        test_runner_source(source, "exceptions_fn.wasm"),
+        Value::I32(16)
+    );
+    assert_eq!(
+        test_runner_externs(source, &cps(), "exceptions_fn_cps.wasm"),
+        Value::I32(16)
+    );
+
+    let source = r#"
+(define fail (lambda ((x : int)) : int (raise x : int)))
+(+ (with-handler ((e : int) e) (fail 1))
+   (with-handler ((e : int) (* e 10)) (fail 2)))"#;
+    assert_eq!(
+        test_runner_source(source, "exceptions_twice.wasm"),
+        Value::I32(21)
+    );
+
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((f (lambda ((x : int)) : int (if (< x 0) (raise x : int) x))))
+  (with-handler ((e : int) (- 0 e))
+    (+ (f 3) (f -4))))"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp_with_options(&exp, &no_inlining()).unwrap();
+    let output = test_runner_prog(prog, "exceptions_closure.wasm");
+    assert_eq!(output, Value::I32(4));
+}
+
+#[test]
+fn test_compile_uncaught_exceptions() {
+    for (source, options) in [
+        "(+ 1 (raise 5 : int))",
+        "(with-handler ((s : string) 0) (raise 1 : int))",
+        "(let ((f (lambda ((x : int)) : int (raise x : int)))) (+ (with-handler ((e : int) e) (f 1)) (f 2)))",
+    ]
+    .into_iter()
+    .flat_map(|source| [(source, no_inlining()), (source, cps())])
+    {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        let prog = compile_exp_with_options(&exp, &options).unwrap();
+        let module = construct_module_from_prog(&prog).unwrap();
+        let binary = parity_wasm::serialize(module).unwrap();
+
+        let engine = wasmer::Engine::default();
+        let module = wasmer::Module::new(&engine, &binary).unwrap();
+        let mut store = Store::default();
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        let result = instance
+            .exports
+            .get_function("$$MAIN$$")
+            .unwrap()
+            .call(&mut store, &[]);
+        assert!(result.is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_compile_native_exceptions() {
+    let options = CodeGenerateOptions {
+        exceptions: ExceptionHandling::Native,
+        ..CodeGenerateOptions::default()
+    };
+    // native exceptions don't need any globals, or checks after calls
+    let source = r#"
+(define fail (lambda ((x : int)) : int (raise x : int)))
+(with-handler ((e : int) e) (+ (fail 1) (fail 2)))"#;
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog_with_options(&parse_prog(&values).unwrap(), &no_inlining()).unwrap();
+    let fallback = construct_module_from_prog(&prog).unwrap();
+    let native = construct_module_from_prog_with_options(&prog, &options).unwrap();
+    let num_globals = |module: &Module| module.global_section().unwrap().entries().len();
+    assert_eq!(num_globals(&fallback), num_globals(&native) + 3);
+
+    let Some(output) = test_runner_node(source, &no_inlining(), &options, "native_exceptions.wasm")
+    else {
+        eprintln!("node not found, skipping native exceptions test");
+        return;
+    };
+    assert_eq!(output.unwrap(), Value::I32(1));
+    // exceptions which aren't caught are thrown out of the module
+    for (i, source) in [
+        "(+ 1 (raise 5 : int))",
+        "(with-handler ((s : string) 0) (raise 1 : int))",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let test_name = format!("native_uncaught{i}.wasm");
+        let output = test_runner_node(source, &no_inlining(), &options, &test_name).unwrap();
+        assert!(output.is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_compile_derived_forms() {
+    let source = r#"
+(define classify
+  (lambda ((n : int)) : int
+    (cond ((< n 0) -1)
+          ((= n 0) (+ n 1) 0)
+          (else 1))))
+(let* ((x (classify -5)) (y (+ x (classify 7))))
+  (+ (* 10 y) (classify 0)))"#;
+    assert_eq!(test_runner_source(source, "cond.wasm"), Value::I32(0));
+
+    let source = r#"
+(define count 0)
+(let ((digit (lambda ((n : int)) : int
+               (case (* n 2) ((0 2 4) 1) ((6) 2) (else 3)))))
+  (begin
+    (when (= (digit 1) 1) (set! count (+ count 10)))
+    (unless (= (digit 3) 2) (set! count (+ count 100)))
+    (+ count (digit 5))))"#;
+    assert_eq!(test_runner_source(source, "case.wasm"), Value::I32(13));
+}
+
+#[test]
+fn test_compile_named_let() {
+    // the loop can refer to variables outside of it, and closures within
+    // the loop can call it
+    let source = r#"
+(let ((step 2))
+  (let loop ((i : int 0) (total : int 0)) : int
+    (if (>= i 10)
+        total
+        (let ((recur (lambda ((t : int)) : int (loop (+ i step) t))))
+          (recur (+ total i))))))"#;
+    assert_eq!(test_runner_source(source, "named_let.wasm"), Value::I32(20));
+
+    // the loop's name shadows an existing variable
+    let source = r#"
+(let ((fact 5))
+  (let fact ((n : int fact) (acc : int 1)) : int
+    (if (= n 0) acc (fact (- n 1) (* acc n)))))"#;
+    assert_eq!(
+        test_runner_source(source, "named_let_shadow.wasm"),
+        Value::I32(120)
+    );
+
+    let source = r#"
+(let outer ((i : int 0) (total : int 0)) : int
+  (if (= i 3)
+      total
+      (outer (+ i 1)
+             (let inner ((j : int 0) (sum : int total)) : int
+               (if (= j 3) sum (inner (+ j 1) (+ sum (* i j))))))))"#;
+    assert_eq!(
+        test_runner_source(source, "named_let_nested.wasm"),
+        Value::I32(9)
+    );
+}
+
+#[test]
+fn test_compile_do() {
+    let source = r#"
+(do ((i : int 0 (+ i 1))
+     (acc : (list int) (null int) (cons i acc)))
+    ((= i 4) (car acc)))"#;
+    assert_eq!(test_runner_source(source, "do.wasm"), Value::I32(3));
+
+    // the loop is a function, so (like in a lambda) the commands can only
+    // assign to global definitions
+    let source = r#"
+(define total 0)
+(begin
+  (do ((i : int 1 (+ i 1))) ((> i 5)) (set! total (+ total i)))
+  total)"#;
+    assert_eq!(
+        test_runner_source(source, "do_commands.wasm"),
+        Value::I32(15)
+    );
+
+    let source = r#"
+(do ((i : int 0 (+ i 1))) ((= i 3) (> i 2)) : bool)"#;
+    assert_eq!(test_runner_source(source, "do_typed.wasm"), Value::I32(1));
+}
+
+#[test]
+fn test_compile_letrec() {
+    // mutually recursive functions share the variables they capture
+    let source = r#"
+(let ((zero 0) (one 1))
+  (letrec ((even? (lambda ((n : int)) : int (if (= n zero) one (odd? (- n one)))))
+           (odd? (lambda ((n : int)) : int (if (= n zero) zero (even? (- n one))))))
+    (+ (* (even? 10) 10) (odd? 7))))"#;
+    assert_eq!(test_runner_source(source, "letrec.wasm"), Value::I32(11));
+
+    // a recursive closure can escape the letrec which defined it
+    let source = r#"
+(let ((make-sum (lambda ((step : int)) : (-> int int)
+                  (letrec ((sum (lambda ((n : int)) : int
+                                  (if (<= n 0) 0 (+ n (sum (- n step)))))))
+                    sum))))
+  ((make-sum 2) 10))"#;
+    assert_eq!(
+        test_runner_source(source, "letrec_escape.wasm"),
+        Value::I32(30)
+    );
+
+    // a lambda bound by a plain let refers to the outer binding of its name
+    let source = r#"
+(let ((f (lambda ((x : int)) : int (* x 10))))
+  (let ((f (lambda ((x : int)) : int (+ (f x) 1))))
+    (f 4)))"#;
+    assert_eq!(
+        test_runner_source(source, "let_not_recursive.wasm"),
+        Value::I32(41)
+    );
+}
+
+fn cps() -> CompileOptions {
+    CompileOptions {
+        cps: true,
+        ..CompileOptions::default()
+    }
+}
+
+#[test]
+fn test_compile_call_cc() {
+    let cases = [
+        // throwing to a continuation skips the rest of the function
+        (
+            "(+ 1 (call/cc (lambda ((k : (cont int))) : int (+ 10 (throw k 5 : int)))))",
+            Value::I32(6),
+        ),
+        // a continuation which isn't used
+        (
+            "(+ 1 (call/cc (lambda ((k : (cont int))) : int 2)))",
+            Value::I32(3),
+        ),
+        (
+            "(if (call/cc (lambda ((k : (cont bool))) : bool (throw k #f : bool))) 1 2)",
+            Value::I32(2),
+        ),
+        // an early exit from a loop
+        (
+            r#"
+(call/cc (lambda ((return : (cont int))) : int
+  (let loop ((i : int 0)) : int
+    (if (= (* i i) 49) (throw return i : int) (loop (+ i 1))))))"#,
+            Value::I32(7),
+        ),
+        // a continuation can be resumed after call/cc has returned, so the
+        // code following it runs again
+        (
+            r#"
+(let ((saved (null (cont int))) (count 0))
+  (let ((x (call/cc (lambda ((k : (cont int))) : int (begin (set! saved (cons k saved)) 0)))))
+    (begin
+      (set! count (+ count 1))
+      (if (< count 3) (throw (car saved) (+ x 10) : int) (+ x count)))))"#,
+            Value::I32(23),
+        ),
+        // mutually recursive functions
+        (
+            r#"
+(letrec ((even? (lambda ((n : int)) : bool (if (= n 0) #t (odd? (- n 1)))))
+         (odd? (lambda ((n : int)) : bool (if (= n 0) #f (even? (- n 1))))))
+  (call/cc (lambda ((k : (cont int))) : int (if (odd? 7) (throw k 1 : int) 2))))"#,
+            Value::I32(1),
+        ),
+        // closures can assign to the variables they capture
+        (
+            r#"
+(let ((total 0))
+  (let ((add (lambda ((x : int)) : int (set! total (+ total x)))))
+    (begin (add 3) (add 4) total)))"#,
+            Value::I32(7),
+        ),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let output = test_runner_externs(source, &cps(), &format!("call_cc{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+
+    // programs without continuations give the same results
+    let source = r#"
+(extern add-ten (-> int int))
+(extern negate (-> int int))
+(define twice (lambda ((f : (-> int int)) (x : int)) : int (f (f x))))
+(let ((step 2))
+  (let loop ((i : int 0) (total : int 0)) : int
+    (if (>= i 10)
+        (negate (twice add-ten total))
+        (loop (+ i step) (+ total i)))))"#;
+    for (options, test_name) in [
+        (cps(), "cps.wasm"),
+        (CompileOptions::default(), "no_cps.wasm"),
+    ] {
+        assert_eq!(
+            test_runner_externs(source, &options, test_name),
+            Value::I32(-40)
+        );
+    }
+}
+
+#[test]
+fn test_compile_call_cc_errors() {
+    let compile = |source: &str, options: &CompileOptions| {
+        let values = lexpr::Parser::from_str(source)
+            .value_iter()
+            .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+            .unwrap();
+        compile_prog_with_options(&parse_prog(&values).unwrap(), options)
+    };
+    // call/cc requires CPS conversion
+    let source = "(call/cc (lambda ((k : (cont int))) : int (throw k 1 : int)))";
+    assert!(compile(source, &CompileOptions::default()).is_err());
+    assert!(compile(source, &cps()).is_ok());
+    // the result of the program can't be a continuation
+    let source = "(call/cc (lambda ((k : (cont int))) : (cont int) k))";
+    assert!(compile(source, &cps()).is_err());
+}
+
+#[test]
+fn test_compile_cps_exports() {
+    // exported functions are called in direct style by the host
+    let source = r#"
+(define scale 3)
+(define scale-sum
+  (lambda ((a : int) (b : int)) : int
+    (call/cc (lambda ((k : (cont int))) : int
+      (if (< a 0) (throw k 0 : int) (* scale (+ a b)))))))
+(export scale-sum)
+0"#;
+    let output = test_runner_export(source, &cps(), "scale-sum", &[3, 4], "cps_exports.wasm");
+    assert_eq!(output.unwrap(), Value::I32(21));
+    let output = test_runner_export(source, &cps(), "scale-sum", &[-3, 4], "cps_exports.wasm");
+    assert_eq!(output.unwrap(), Value::I32(0));
+}
+
+/// Compiles a program and runs it with node, which (unlike wasmer) supports
+/// the tail-call and exception-handling proposals, returning the result or
+/// the error node reported. Returns `None` if node isn't installed.
+fn test_runner_node(
+    source: &str,
+    compile_options: &CompileOptions,
+    options: &CodeGenerateOptions,
+    test_name: &str,
+) -> Option<Result<Value, String>> {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog_with_options(&parse_prog(&values).unwrap(), compile_options).unwrap();
+    let module = construct_module_from_prog_with_options(&prog, options).unwrap();
+    let output_dir = std::env::current_dir().unwrap().join("wasm-output");
+    std::fs::create_dir_all(output_dir.clone()).unwrap();
+    let path = output_dir.join(test_name);
+    std::fs::write(&path, serialize_module(module).unwrap()).unwrap();
+
+    let script = r#"
+const bytes = require("fs").readFileSync(process.argv[1]);
+WebAssembly.instantiate(bytes, {}).then(({ instance }) =>
+  console.log(instance.exports["$$MAIN$$"]()));"#;
+    let output = std::process::Command::new("node")
+        .args(["-e", script])
+        .arg(&path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return Some(Err(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    let result = String::from_utf8(output.stdout).unwrap();
+    let value = match wasm_type(&prog.exp.typ) {
+        ValueType::I64 => Value::I64(result.trim().trim_end_matches('n').parse().unwrap()),
+        ValueType::F64 => Value::F64(result.trim().parse().unwrap()),
+        _ => Value::I32(result.trim().parse().unwrap()),
+    };
+    Some(Ok(untag(value, &prog.exp.typ)))
+}
+
+#[test]
+fn test_compile_cps_tail_calls() {
+    let cases = [
+        // a loop which would run out of stack without tail calls
+        (
+            "(let loop ((i : int 0) (acc : int 0)) : int (if (= i 200000) acc (loop (+ i 1) (+ acc 1))))",
+            Value::I32(200000),
+        ),
+        // a generator resumed many times (fewer, since every resume
+        // allocates memory which is never freed)
+        (
+            r#"
+(let ((g (make-generator int (let loop ((i : int 0)) : int (begin (yield i) (loop (+ i 1)))))))
+  (let loop ((n : int 0) (last : int 0)) : int
+    (if (= n 20000) last (loop (+ n 1) (car (next g))))))"#,
+            Value::I32(19999),
+        ),
+    ];
+    let options = CodeGenerateOptions {
+        tail_calls: true,
+        ..CodeGenerateOptions::default()
+    };
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let test_name = format!("tail_calls{i}.wasm");
+        let Some(output) = test_runner_node(source, &cps(), &options, &test_name) else {
+            eprintln!("node not found, skipping tail call test");
+            return;
+        };
+        assert_eq!(output.unwrap(), expected, "{source}");
+    }
+}
+
+#[test]
+fn test_compile_generators() {
+    let cases = [
+        // next gives an empty list once the generator has finished
+        (
+            r#"
+(let ((g (make-generator int (begin (yield 1) (yield 2) #t))))
+  (let ((a (next g)) (b (next g)) (c (next g)) (d (next g)))
+    (+ (+ (car a) (car b)) (if (and (null? c) (null? d)) 100 0))))"#,
+            Value::I32(103),
+        ),
+        // generators are resumed independently
+        (
+            r#"
+(let ((make (lambda ((start : int)) : (generator int)
+              (make-generator int (begin (yield start) (yield (+ start 1)) 0)))))
+  (let ((a (make 10)) (b (make 20)))
+    (let ((a1 (next a)) (b1 (next b)) (a2 (next a)))
+      (- (* (car a2) 100) (+ (car a1) (car b1))))))"#,
+            Value::I32(1070),
+        ),
+    ];
+    for (i, (source, expected)) in cases.into_iter().enumerate() {
+        let output = test_runner_externs(source, &cps(), &format!("generators{i}.wasm"));
+        assert_eq!(output, expected, "{source}");
+    }
+
+    // a pipeline of generators, which yield from within loops
+    let source = r#"
+(define range
+  (lambda ((n : int)) : (generator int)
+    (make-generator int
+      (let loop ((i : int 0)) : int
+        (if (< i n) (begin (yield i) (loop (+ i 1))) 0)))))
+(define squares
+  (lambda ((g : (generator int))) : (generator int)
+    (make-generator int
+      (let loop ((x : (list int) (next g))) : int
+        (if (null? x) 0 (begin (yield (* (car x) (car x))) (loop (next g))))))))
+(let sum ((g : (generator int) (squares (range 5))) (total : int 0)) : int
+  (let ((x (next g)))
+    (if (null? x) total (sum g (+ total (car x))))))"#;
+    assert_eq!(
+        test_runner_externs(source, &cps(), "generators_pipeline.wasm"),
+        Value::I32(30)
+    );
+}
+
+#[test]
+fn test_compile_generators_require_cps() {
+    let source = "(let ((g (make-generator int (yield 1)))) (car (next g)))";
+    let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+    assert!(compile_exp(&exp).is_err());
+    assert!(compile_exp_with_options(&exp, &cps()).is_ok());
+}
+
+/// The state used by the fake WASI `fd_write` function in
+/// `test_runner_wasi`, which collects everything written to stdout.
+#[derive(Default)]
+struct WasiOutput {
+    memory: Option<Memory>,
+    stdout: Vec<u8>,
+}
+
+fn fake_fd_write(
+    mut env: FunctionEnvMut<WasiOutput>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nwritten: i32,
+) -> i32 {
+    assert_eq!(fd, 1);
+    let (output, store) = env.data_and_store_mut();
+    let view = output.memory.as_ref().unwrap().view(&store);
+    let mut written = 0;
+    for i in 0..iovs_len {
+        let mut iov = [0u8; 8];
+        view.read((iovs + 8 * i) as u64, &mut iov).unwrap();
+        let ptr = u32::from_le_bytes(iov[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(iov[4..8].try_into().unwrap());
+        let mut bytes = vec![0u8; len as usize];
+        view.read(ptr as u64, &mut bytes).unwrap();
+        output.stdout.extend(bytes);
+        written += len;
+    }
+    view.write(nwritten as u64, &written.to_le_bytes()).unwrap();
+    0
+}
+
+/// Compiles a program for WASI, runs its `_start` function, and returns what
+/// it printed to stdout.
+fn test_runner_wasi(source: &str, test_name: &str) -> String {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    let options = CodeGenerateOptions {
+        target: Target::Wasi,
+        ..CodeGenerateOptions::default()
+    };
+    let module = construct_module_from_prog_with_options(&prog, &options).unwrap();
+    let binary = parity_wasm::serialize(module.clone()).unwrap();
+    output_wasm_to_file(module, test_name);
+
+    let engine = wasmer::Engine::default();
+    let module = wasmer::Module::new(&engine, &binary).unwrap();
+    let mut store = Store::default();
+    let env = FunctionEnv::new(&mut store, WasiOutput::default());
+    let import_object = imports! {
+        "wasi_snapshot_preview1" => {
+            "fd_write" => Function::new_typed_with_env(&mut store, &env, fake_fd_write),
+        }
+    };
+    let instance = Instance::new(&mut store, &module, &import_object).unwrap();
+    env.as_mut(&mut store).memory = Some(instance.exports.get_memory("memory").unwrap().clone());
+    instance
+        .exports
+        .get_function("_start")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+
+    String::from_utf8(env.as_ref(&store).stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_compile_wasi_display() {
+    let source = r#"
+(begin
+  (display 42)
+  (newline)
+  (display -2147483648)
+  (newline)
+  (display true)
+  (display false)
+  (display "hello")
+  (newline)
+  0)"#;
+    let output = test_runner_wasi(source, "wasi_display1.wasm");
+    assert_eq!(output, "42\n-2147483648\ntruefalsehello\n0\n");
+
+    let source = r#"
+(let ((lst (cons (cons 1 (cons 2 (null int))) (cons (null int) (null (list int))))))
+  (begin (display lst) (newline) (cons "a" (cons "b" (null string)))))"#;
+    let output = test_runner_wasi(source, "wasi_display2.wasm");
+    assert_eq!(output, "((1 2) ())\n(a b)\n");
+
+    let source = r#"
+(begin
+  (display 9223372036854775807)
+  (newline)
+  (display (cons -9223372036854775808 (null int64)))
+  (newline)
+  (int->int64 7))"#;
+    let output = test_runner_wasi(source, "wasi_display_int64.wasm");
+    assert_eq!(output, "9223372036854775807\n(-9223372036854775808)\n7\n");
+
+    let source = r#"
+(begin
+  (display (* 123456789 987654321))
+  (newline)
+  (display (- 0 (* (* 1000000000 1000000000) 1000000000)))
+  (newline)
+  (display (cons (+ 1073741823 1) (cons -1073741824 (null int))))
+  (newline)
+  (* (* 1000000007 1000000007) 1000000007))"#;
+    let output = test_runner_wasi(source, "wasi_display_bignum.wasm");
+    assert_eq!(
+        output,
+        "121932631112635269\n-1000000000000000000000000000\n(1073741824 -1073741824)\n1000000021000000147000000343\n"
+    );
+
+    let source = r#"
+(begin
+  (display #\a)
+  (display #\x3bb)
+  (display #\x1f600)
+  (display #\xe9)
+  (display #\space)
+  (display 'foo)
+  (newline)
+  (cons 'a (cons 'b (null symbol))))"#;
+    let output = test_runner_wasi(source, "wasi_display_char_symbol.wasm");
+    assert_eq!(output, "a\u{3bb}\u{1f600}\u{e9} foo\n(a b)\n");
+
+    let source = r#"
+(begin
+  (display '((1 2) () (-3)))
+  (newline)
+  '(a b c))"#;
+    let output = test_runner_wasi(source, "wasi_display_quote.wasm");
+    assert_eq!(output, "((1 2) () (-3))\n(a b c)\n");
+
+    // results which cannot be displayed are not printed
+    let output = test_runner_wasi("(begin (display 5) (make-tuple 1 2))", "wasi_display3.wasm");
+    assert_eq!(output, "5");
+}
+
+#[test]
+fn test_compile_display_requires_wasi() {
+    let exp = parse(&lexpr::from_str("(display 3)").unwrap()).unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    assert!(construct_module_from_prog(&prog).is_err());
+
+    // the same applies within lambda-lifted functions
+    let source = "(define f (lambda ((x : int)) : int (display x))) (f 1)";
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    let options = CodeGenerateOptions {
+        target: Target::Host,
+        ..CodeGenerateOptions::default()
+    };
+    let error = construct_module_from_prog_with_options(&prog, &options).unwrap_err();
+    assert!(error.to_string().contains("Printing is only supported"));
+}
+
 #[test]
 fn test_handwritten_lambda() {
     let module = builder::module()
@@ -0,0 +1,148 @@
+use scheme_to_wasm::compile::{CompileOptions, compile_exp, compile_exp_with_options};
+use scheme_to_wasm::inline::inline_exp;
+use scheme_to_wasm::parse::parse;
+use scheme_to_wasm::type_check::type_check;
+
+/// Inlines the source expression with the given size threshold, and checks
+/// that it matches the expected expression (and that it still type checks)
+fn assert_inlines_to(source: &str, threshold: usize, expected: &str) {
+    let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+    let expected_exp = parse(&lexpr::from_str(expected).unwrap()).unwrap();
+    let inlined_exp = inline_exp(&exp, threshold).unwrap();
+
+    println!("Source: {exp}");
+    println!("Inlined: {inlined_exp}");
+    assert_eq!(inlined_exp, expected_exp);
+    assert_eq!(
+        type_check(&inlined_exp).unwrap().typ,
+        type_check(&exp).unwrap().typ
+    );
+}
+
+#[test]
+fn test_inline_beta_reduce() {
+    assert_inlines_to("((lambda ((x : int)) : int (+ x 3)) 5)", 0, "(+ 5 3)");
+    assert_inlines_to(
+        "(let ((a 1) (b 2)) ((lambda ((x : int) (y : int)) : int (- x y)) a b))",
+        0,
+        "(let ((a 1) (b 2)) (- a b))",
+    );
+    // arguments which are not trivial are evaluated once, in order
+    assert_inlines_to(
+        "(let ((a (cons 1 (null int)))) ((lambda ((x : int) (y : int)) : int (+ x x)) (car a) 2))",
+        0,
+        "(let ((a (cons 1 (null int)))) (let ((x (car a))) (+ x x)))",
+    );
+    // substituting would capture the argument
+    assert_inlines_to(
+        "(let ((y 2)) ((lambda ((x : int)) : int (let ((y 1)) (+ x y))) y))",
+        0,
+        "(let ((y 2)) (let ((x y)) (let ((y 1)) (+ x y))))",
+    );
+    // parameters which are assigned to cannot be substituted
+    assert_inlines_to(
+        "((lambda ((x : int)) : int (begin (set! x 2) x)) 1)",
+        0,
+        "(let ((x 1)) (begin (set! x 2) x))",
+    );
+    // variables which are assigned to are not substituted
+    assert_inlines_to(
+        "(let ((a 1)) ((lambda ((x : int)) : int (begin (set! a 2) x)) a))",
+        0,
+        "(let ((a 1)) (let ((x a)) (begin (set! a 2) x)))",
+    );
+}
+
+#[test]
+fn test_inline_let_bound() {
+    assert_inlines_to(
+        "(let ((f (lambda ((x : int)) : int (+ x 1)))) (+ (f 1) (f 2)))",
+        16,
+        "(+ (+ 1 1) (+ 2 1))",
+    );
+    // functions passed as arguments get inlined after beta-reduction
+    assert_inlines_to(
+        r#"
+(let ((apply (lambda ((fn : (-> int int)) (x : int)) : int (fn x))))
+  (let ((double (lambda ((num : int)) : int (* num 2))))
+    (apply double 3)))"#,
+        16,
+        "(* 3 2)",
+    );
+    // functions used as values are kept around
+    assert_inlines_to(
+        "(let ((f (lambda ((x : int)) : int (+ x 1)))) (make-tuple (f 1) f))",
+        16,
+        "(let ((f (lambda ((x : int)) : int (+ x 1)))) (make-tuple (+ 1 1) f))",
+    );
+}
+
+#[test]
+fn test_inline_threshold() {
+    let source = "(let ((f (lambda ((x : int)) : int (+ x 1)))) (f 1))";
+    // the body (+ x 1) has size 3
+    assert_inlines_to(source, 3, "(+ 1 1)");
+    assert_inlines_to(source, 2, source);
+}
+
+#[test]
+fn test_inline_scoping() {
+    // the free variable y refers to a different binding at the call site
+    let source = r#"
+(let ((y 1))
+  (let ((f (lambda ((x : int)) : int (+ x y))))
+    (let ((y 2))
+      (f 3))))"#;
+    assert_inlines_to(source, 16, source);
+
+    // functions which are reassigned are not inlined
+    let source = r#"
+(let ((f (lambda ((x : int)) : int (+ x 1))))
+  (begin
+    (set! f (lambda ((x : int)) : int x))
+    (f 3)))"#;
+    assert_inlines_to(source, 16, source);
+
+    // neither are functions whose free variables are reassigned
+    let source = r#"
+(let ((y 1))
+  (let ((f (lambda ((x : int)) : int (+ x y))))
+    (begin
+      (set! y 2)
+      (f 3))))"#;
+    assert_inlines_to(source, 16, source);
+
+    // nor are functions which assign to the variables they capture, so the
+    // program is rejected by closure conversion with or without inlining
+    let source = r#"
+(let ((x 0))
+  (let ((f (lambda () : int (set! x (+ x 1)))))
+    (begin (f) (f) x)))"#;
+    assert_inlines_to(source, 16, source);
+    let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+    for inline in [true, false] {
+        let options = CompileOptions {
+            inline,
+            ..CompileOptions::default()
+        };
+        assert!(compile_exp_with_options(&exp, &options).is_err());
+    }
+}
+
+#[test]
+fn test_inline_removes_closures() {
+    let exp = parse(
+        &lexpr::from_str(
+            r#"
+(let ((add1 (lambda ((x : int)) : int (+ x 1))))
+  (let ((twice (lambda ((x : int)) : int (add1 (add1 x)))))
+    (twice 5)))"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let prog = compile_exp(&exp).unwrap();
+    assert!(prog.fns.is_empty());
+    let expected = parse(&lexpr::from_str("(let ((x 6)) (+ x 1))").unwrap()).unwrap();
+    assert_eq!(prog.exp, type_check(&expected).unwrap());
+}
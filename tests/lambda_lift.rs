@@ -49,6 +49,9 @@ fn test_lambda_lift_simple_happy() {
     )
     .unwrap();
     let expected_prog = Prog {
+        externs: vector![],
+        globals: vector![],
+        exports: vector![],
         fns: vector![(String::from("func0"), expected_fn)],
         exp: expected_exp,
     };
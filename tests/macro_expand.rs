@@ -0,0 +1,177 @@
+use scheme_to_wasm::compile::compile_prog;
+use scheme_to_wasm::generate_code::construct_module_from_prog;
+use scheme_to_wasm::macro_expand::{MacroEnv, expand_prog};
+use scheme_to_wasm::parse::parse_prog;
+
+use wasmer::{Instance, Store, imports};
+
+fn parse_values(source: &str) -> Vec<lexpr::Value> {
+    lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap()
+}
+
+/// Expands the macros in a program, and checks that the result matches the
+/// expected values
+fn assert_expand_to(source: &str, expected: &str) {
+    let expanded = expand_prog(&parse_values(source)).unwrap();
+    println!("Expanded: {expanded:?}");
+    assert_eq!(expanded, parse_values(expected));
+}
+
+/// Compiles and runs a program, returning the result of its main expression
+/// (which must be a fixnum)
+fn run_prog(source: &str) -> i32 {
+    let prog = compile_prog(&parse_prog(&parse_values(source)).unwrap()).unwrap();
+    let binary = parity_wasm::serialize(construct_module_from_prog(&prog).unwrap()).unwrap();
+
+    let mut store = Store::default();
+    let module = wasmer::Module::new(&store, &binary).unwrap();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    let result = instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+    result[0].unwrap_i32() >> 1
+}
+
+const SWAP: &str = r#"
+(define-syntax swap!
+  (syntax-rules ()
+    ((_ a b) (let ((tmp a)) (begin (set! a b) (set! b tmp))))))"#;
+
+#[test]
+fn test_expand_simple() {
+    let source = r#"
+(define-syntax unless
+  (syntax-rules ()
+    ((unless c body) (if c 0 body))))
+(unless (< 1 2) 5)"#;
+    assert_expand_to(source, "(if (< 1 2) 0 5)");
+
+    // macros are expanded anywhere within a value, and in their own output
+    let source = r#"
+(define-syntax double (syntax-rules () ((double x) (+ x x))))
+(define-syntax quadruple (syntax-rules () ((quadruple x) (double (double x)))))
+(define y (quadruple 1))
+(let ((z (double 2))) z)"#;
+    assert_expand_to(
+        source,
+        "(define y (+ (+ 1 1) (+ 1 1))) (let ((z (+ 2 2))) z)",
+    );
+}
+
+#[test]
+fn test_expand_rules() {
+    // rules are tried in order, and literals must match exactly
+    let source = r#"
+(define-syntax my-if
+  (syntax-rules (then else)
+    ((_ c then t else e) (if c t e))
+    ((_ c then t) (if c t 0))))
+(my-if #t then 1 else 2)
+(my-if #t then 1)"#;
+    assert_expand_to(source, "(if #t 1 2) (if #t 1 0)");
+
+    let source = r#"
+(define-syntax my-or
+  (syntax-rules ()
+    ((_) #f)
+    ((_ e) e)
+    ((_ e rest ...) (if e #t (my-or rest ...)))))
+(my-or a b c)"#;
+    assert_expand_to(source, "(if a #t (if b #t c))");
+}
+
+#[test]
+fn test_expand_ellipsis() {
+    let source = r#"
+(define-syntax my-let
+  (syntax-rules ()
+    ((_ ((name val) ...) body1 body2 ...) (let ((name val) ...) (begin body1 body2 ...)))))
+(my-let ((x 1) (y 2)) (set! x y) x)"#;
+    assert_expand_to(source, "(let ((x 1) (y 2)) (begin (set! x y) x))");
+
+    let source = r#"
+(define-syntax sum
+  (syntax-rules ()
+    ((_ (a b) ...) (+ 0 (begin (+ a b) ...)))))
+(sum (1 2) (3 4))
+(sum)"#;
+    assert_expand_to(source, "(+ 0 (begin (+ 1 2) (+ 3 4))) (+ 0 (begin))");
+}
+
+#[test]
+fn test_expand_hygiene() {
+    // the tmp bound by the template is renamed, so it doesn't capture the
+    // user's tmp
+    let source =
+        format!("{SWAP} (let ((tmp 1) (other 2)) (begin (swap! tmp other) (- tmp other)))");
+    assert_eq!(run_prog(&source), 1);
+
+    let expanded = expand_prog(&parse_values(&format!("{SWAP} (swap! x y)"))).unwrap();
+    let binder = expanded[0].to_vec().unwrap()[1].to_vec().unwrap()[0]
+        .to_vec()
+        .unwrap()[0]
+        .clone();
+    let binder = binder.as_symbol().unwrap();
+    assert_ne!(binder, "tmp");
+    assert!(binder.starts_with("tmp"));
+}
+
+#[test]
+fn test_expand_quote() {
+    // quoted data is never expanded
+    let source = format!("{SWAP} (quote (swap! x y))");
+    assert_expand_to(&source, "(quote (swap! x y))");
+
+    // symbols quoted by a template are not renamed, even if the template
+    // binds a variable of the same name
+    let source = r#"
+(define-syntax tagged
+  (syntax-rules ()
+    ((_ e) (let ((tag e)) (if (= tag 0) 'tag 'other)))))
+(if (symbol=? (tagged 0) 'tag) 1 0)"#;
+    let expanded = format!("{:?}", expand_prog(&parse_values(source)).unwrap());
+    assert!(expanded.contains(r#"(Symbol("quote") . Cons((Symbol("tag") . Null)))"#));
+    assert_eq!(run_prog(source), 1);
+}
+
+#[test]
+fn test_macro_env() {
+    let mut env = MacroEnv::new();
+    let value = lexpr::from_str("(incr x)").unwrap();
+    assert_eq!(env.expand(&value).unwrap(), value);
+
+    let def =
+        lexpr::from_str("(define-syntax incr (syntax-rules () ((_ v) (set! v (+ v 1)))))").unwrap();
+    assert_eq!(env.define_syntax(&def).unwrap(), Some(String::from("incr")));
+    assert_eq!(env.define_syntax(&value).unwrap(), None);
+    assert_eq!(
+        env.expand(&value).unwrap(),
+        lexpr::from_str("(set! x (+ x 1))").unwrap()
+    );
+}
+
+#[test]
+fn test_expand_invalid() {
+    let invalid = [
+        // no rule matches
+        "(define-syntax m (syntax-rules () ((_ a) a))) (m 1 2)",
+        // expansion does not terminate
+        "(define-syntax m (syntax-rules () ((_ a) (m a)))) (m 1)",
+        // ellipsis doesn't follow a pattern variable matched with an ellipsis
+        "(define-syntax m (syntax-rules () ((_ a) (+ a ...)))) (m 1)",
+        "(define-syntax m (syntax-rules () ((_ a ...) a))) (m 1)",
+        "(define-syntax m (syntax-rules () ((_ ... a) a))) (m 1)",
+        // not defined with syntax-rules
+        "(define-syntax m (lambda (x) x)) 1",
+        "(define-syntax m) 1",
+    ];
+    for source in invalid {
+        assert!(expand_prog(&parse_values(source)).is_err(), "{source}");
+    }
+}
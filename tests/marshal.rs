@@ -0,0 +1,432 @@
+use scheme_to_wasm::compile::compile_prog;
+use scheme_to_wasm::generate_code::{
+    CodeGenerateOptions, construct_module_from_prog, construct_module_from_prog_with_options,
+};
+use scheme_to_wasm::marshal::{BigInt, SchemeValue, decode_value, encode_value};
+use scheme_to_wasm::parse::{parse_prog, parse_type};
+use scheme_to_wasm::type_check::type_check_source_prog;
+use scheme_to_wasm::types::Type;
+
+use wasmer::{Instance, Store, Value, imports};
+
+/// Compiles a program and instantiates it, returning the instance along with
+/// the (source language) type of its main expression.
+fn instantiate(source: &str) -> (Store, Instance, Type) {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let source_prog = parse_prog(&values).unwrap();
+    let typ = type_check_source_prog(&source_prog).unwrap().typ;
+    let prog = compile_prog(&source_prog).unwrap();
+    let module = construct_module_from_prog(&prog).unwrap();
+    let binary = parity_wasm::serialize(module).unwrap();
+
+    let mut store = Store::default();
+    let module = wasmer::Module::new(&store, &binary).unwrap();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    (store, instance, typ)
+}
+
+/// Runs the main expression of a program, and decodes its result.
+fn run_and_decode(source: &str) -> SchemeValue {
+    let (mut store, instance, typ) = instantiate(source);
+    let result = instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+    let memory = instance.exports.get_memory("memory").unwrap();
+    let bytes = memory.view(&store).copy_to_vec().unwrap();
+    let raw = match result[0] {
+        Value::I32(val) => val as i64,
+        Value::I64(val) => val,
+        Value::F64(val) => val.to_bits() as i64,
+        _ => panic!("unexpected result {:?}", result[0]),
+    };
+    decode_value(&typ, raw, &bytes).unwrap()
+}
+
+/// Copies memory holding encoded values back into the instance, and moves
+/// `$$HEAP_BASE$$` past the encoded values so that the program's allocations
+/// can't overwrite them.
+fn write_encoded(store: &mut Store, instance: &Instance, bytes: &[u8], heap_index: u32) {
+    let memory = instance.exports.get_memory("memory").unwrap();
+    memory.view(store).write(0, bytes).unwrap();
+    instance
+        .exports
+        .get_global("$$HEAP_BASE$$")
+        .unwrap()
+        .set(store, Value::I32(heap_index as i32))
+        .unwrap();
+}
+
+fn parse_type_str(annotation: &str) -> Type {
+    parse_type(&lexpr::from_str(annotation).unwrap()).unwrap()
+}
+
+#[test]
+fn test_decode_values() {
+    assert_eq!(run_and_decode("(+ 3 4)"), SchemeValue::Int(7));
+    assert_eq!(run_and_decode("(< 3 4)"), SchemeValue::Bool(true));
+    assert_eq!(
+        run_and_decode(r#""hello""#),
+        SchemeValue::Str(String::from("hello"))
+    );
+    assert_eq!(
+        run_and_decode("(cons 1 (cons 2 (null int)))"),
+        SchemeValue::List(vec![SchemeValue::Int(1), SchemeValue::Int(2)])
+    );
+    assert_eq!(run_and_decode("(null int)"), SchemeValue::List(vec![]));
+    assert_eq!(
+        run_and_decode(r#"(make-tuple 1 (cons "a" (null string)) (make-tuple))"#),
+        SchemeValue::Tuple(vec![
+            SchemeValue::Int(1),
+            SchemeValue::List(vec![SchemeValue::Str(String::from("a"))]),
+            SchemeValue::Tuple(vec![]),
+        ])
+    );
+    // fields are decoded in the order of the record's type, which lists them
+    // alphabetically
+    assert_eq!(
+        run_and_decode("(make-record (y true) (x 2))"),
+        SchemeValue::Record(vec![
+            (String::from("x"), SchemeValue::Int(2)),
+            (String::from("y"), SchemeValue::Bool(true)),
+        ])
+    );
+    assert_eq!(run_and_decode("5000000000"), SchemeValue::Int64(5000000000));
+    assert_eq!(run_and_decode("(/ 1.0 4.0)"), SchemeValue::Float(0.25));
+    assert_eq!(run_and_decode(r"#\x3bb"), SchemeValue::Char('λ'));
+    assert_eq!(
+        run_and_decode("(cons 'a (cons 'b (null symbol)))"),
+        SchemeValue::List(vec![
+            SchemeValue::Symbol(String::from("a")),
+            SchemeValue::Symbol(String::from("b")),
+        ])
+    );
+    assert_eq!(
+        run_and_decode(r"(make-tuple 'a #\a)").to_string(),
+        r"(make-tuple a #\a)"
+    );
+    assert_eq!(
+        run_and_decode(r#"'(("a" "b") () ("c"))"#).to_string(),
+        r#"(("a" "b") () ("c"))"#
+    );
+    assert_eq!(
+        run_and_decode("(cons 1.5 (cons -2.5 (null float)))"),
+        SchemeValue::List(vec![SchemeValue::Float(1.5), SchemeValue::Float(-2.5)])
+    );
+    assert_eq!(
+        run_and_decode("(make-record (a 1) (b 5000000000) (c 2.5))"),
+        SchemeValue::Record(vec![
+            (String::from("a"), SchemeValue::Int(1)),
+            (String::from("b"), SchemeValue::Int64(5000000000)),
+            (String::from("c"), SchemeValue::Float(2.5)),
+        ])
+    );
+}
+
+#[test]
+fn test_decode_bignums() {
+    assert_eq!(
+        run_and_decode("(* 65536 65536)"),
+        SchemeValue::BigInt(BigInt {
+            negative: false,
+            magnitude: vec![0, 1]
+        })
+    );
+    // ints which fit in an i32 are decoded as such, even if they are bignums
+    assert_eq!(
+        run_and_decode("(+ 1073741823 1)"),
+        SchemeValue::Int(1073741824)
+    );
+    assert_eq!(
+        run_and_decode("(- -2147483647 1)"),
+        SchemeValue::Int(-2147483648)
+    );
+    assert_eq!(
+        run_and_decode("(* (* 1000000000 1000000000) -1000)").to_string(),
+        "-1000000000000000000000"
+    );
+    assert_eq!(
+        run_and_decode("(cons (* 65536 65536) (null int))").to_string(),
+        "(4294967296)"
+    );
+}
+
+#[test]
+fn test_decode_invalid() {
+    let typ = parse_type_str("(-> int int)");
+    assert!(decode_value(&typ, 0, &[0; 16]).is_err());
+    // pointers outside of memory
+    let typ = parse_type_str("(list int)");
+    assert!(decode_value(&typ, 14, &[0; 16]).is_err());
+    // a cons cell at 0 holding 3, whose cdr points back to itself
+    let mut memory = [0; 16];
+    memory[0..4].copy_from_slice(&7i32.to_le_bytes());
+    assert!(decode_value(&typ, 0, &memory).is_err());
+    // surrogates are not chars
+    assert!(decode_value(&Type::Char, 0xD800, &[0; 16]).is_err());
+}
+
+#[test]
+fn test_encode_values() {
+    let source = r#"
+(define sum-points
+  (lambda ((points : (list (record (x : int) (y : int))))) : int
+    (if (null? points)
+        0
+        (+ (+ (record-ref (car points) x) (record-ref (car points) y))
+           (sum-points (cdr points))))))
+(export sum-points)
+0"#;
+    let (mut store, instance, _typ) = instantiate(source);
+    instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+
+    let point = |x: i32, y: i32| {
+        SchemeValue::Record(vec![
+            (String::from("y"), SchemeValue::Int(y)),
+            (String::from("x"), SchemeValue::Int(x)),
+        ])
+    };
+    let points = SchemeValue::List(vec![point(1, 2), point(30, 40)]);
+    let typ = parse_type_str("(list (record (x : int) (y : int)))");
+
+    let memory = instance.exports.get_memory("memory").unwrap();
+    let mut bytes = memory.view(&store).copy_to_vec().unwrap();
+    let mut heap_index = instance
+        .exports
+        .get_global("$$HEAP_BASE$$")
+        .unwrap()
+        .get(&mut store)
+        .unwrap_i32() as u32;
+    let arg = encode_value(&points, &typ, &mut bytes, &mut heap_index).unwrap();
+    write_encoded(&mut store, &instance, &bytes, heap_index);
+    // encoded values decode back to the same value
+    assert_eq!(
+        decode_value(&typ, arg, &bytes).unwrap(),
+        SchemeValue::List(vec![
+            SchemeValue::Record(vec![
+                (String::from("x"), SchemeValue::Int(1)),
+                (String::from("y"), SchemeValue::Int(2)),
+            ]),
+            SchemeValue::Record(vec![
+                (String::from("x"), SchemeValue::Int(30)),
+                (String::from("y"), SchemeValue::Int(40)),
+            ]),
+        ])
+    );
+
+    let result = instance
+        .exports
+        .get_function("sum-points")
+        .unwrap()
+        .call(&mut store, &[Value::I32(arg as i32)])
+        .unwrap();
+    // exported functions return ints as plain i32s
+    assert_eq!(result[0], Value::I32(73));
+}
+
+#[test]
+fn test_encode_bignums() {
+    let source = r#"
+(define square
+  (lambda ((ns : (list int))) : (list int) (cons (* (car ns) (car ns)) (null int))))
+(export square)
+0"#;
+    let (mut store, instance, _typ) = instantiate(source);
+    instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+
+    let memory = instance.exports.get_memory("memory").unwrap();
+    let mut bytes = memory.view(&store).copy_to_vec().unwrap();
+    let mut heap_index = instance
+        .exports
+        .get_global("$$HEAP_BASE$$")
+        .unwrap()
+        .get(&mut store)
+        .unwrap_i32() as u32;
+    let cases = [
+        (SchemeValue::Int(-3), "9"),
+        (SchemeValue::Int(-2147483648), "4611686018427387904"),
+        (
+            SchemeValue::BigInt(BigInt {
+                negative: true,
+                magnitude: vec![0, 1],
+            }),
+            "18446744073709551616",
+        ),
+        // leading zero limbs are ignored
+        (
+            SchemeValue::BigInt(BigInt {
+                negative: false,
+                magnitude: vec![5, 0],
+            }),
+            "25",
+        ),
+    ];
+    // exported functions take ints as plain i32s, so bignums are passed
+    // within lists
+    let typ = parse_type_str("(list int)");
+    for (val, expected) in cases {
+        let arg = encode_value(
+            &SchemeValue::List(vec![val]),
+            &typ,
+            &mut bytes,
+            &mut heap_index,
+        )
+        .unwrap();
+        write_encoded(&mut store, &instance, &bytes, heap_index);
+        let result = instance
+            .exports
+            .get_function("square")
+            .unwrap()
+            .call(&mut store, &[Value::I32(arg as i32)])
+            .unwrap();
+        bytes = memory.view(&store).copy_to_vec().unwrap();
+        let square = decode_value(&typ, result[0].unwrap_i32() as i64, &bytes).unwrap();
+        assert_eq!(square.to_string(), format!("({expected})"));
+    }
+}
+
+#[test]
+fn test_encode_wide_values() {
+    let source = r#"
+(define total
+  (lambda ((items : (list (tuple int float int64)))) : float
+    (if (null? items)
+        0.0
+        (let ((item (car items)))
+          (+ (* (int->float (tuple-ref item 0)) (tuple-ref item 1))
+             (+ (int64->float (tuple-ref item 2)) (total (cdr items))))))))
+(export total)
+0"#;
+    let (mut store, instance, _typ) = instantiate(source);
+    instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+    let memory = instance.exports.get_memory("memory").unwrap();
+    let mut bytes = memory.view(&store).copy_to_vec().unwrap();
+    let mut heap_index = instance
+        .exports
+        .get_global("$$HEAP_BASE$$")
+        .unwrap()
+        .get(&mut store)
+        .unwrap_i32() as u32;
+
+    let typ = parse_type_str("(list (tuple int float int64))");
+    let items = SchemeValue::List(vec![
+        SchemeValue::Tuple(vec![
+            SchemeValue::Int(2),
+            SchemeValue::Float(1.25),
+            SchemeValue::Int64(5000000000),
+        ]),
+        SchemeValue::Tuple(vec![
+            SchemeValue::Int(-1),
+            SchemeValue::Float(0.5),
+            SchemeValue::Int64(-4000000000),
+        ]),
+    ]);
+    let arg = encode_value(&items, &typ, &mut bytes, &mut heap_index).unwrap();
+    write_encoded(&mut store, &instance, &bytes, heap_index);
+    assert_eq!(decode_value(&typ, arg, &bytes).unwrap(), items);
+
+    let result = instance
+        .exports
+        .get_function("total")
+        .unwrap()
+        .call(&mut store, &[Value::I32(arg as i32)])
+        .unwrap();
+    assert_eq!(result[0], Value::F64(1000000002.0));
+
+    // the raw value of a float is its bits
+    let arg = encode_value(
+        &SchemeValue::Float(1.5),
+        &Type::Float,
+        &mut bytes,
+        &mut heap_index,
+    );
+    assert_eq!(arg.unwrap(), 1.5f64.to_bits() as i64);
+}
+
+#[test]
+fn test_encode_protected_from_allocation() {
+    let source = r#"
+(define build
+  (lambda ((n : int)) : (list int) (if (= n 0) (null int) (cons n (build (- n 1))))))
+(define first-after-build
+  (lambda ((n : int) (lst : (list int))) : int (begin (build n) (car lst))))
+(export first-after-build)
+0"#;
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    let prog = compile_prog(&parse_prog(&values).unwrap()).unwrap();
+    let options = CodeGenerateOptions {
+        memory_pages: 1,
+        ..CodeGenerateOptions::default()
+    };
+    let module = construct_module_from_prog_with_options(&prog, &options).unwrap();
+    let binary = parity_wasm::serialize(module).unwrap();
+    let mut store = Store::default();
+    let module = wasmer::Module::new(&store, &binary).unwrap();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    let main = instance.exports.get_function("$$MAIN$$").unwrap();
+    main.call(&mut store, &[]).unwrap();
+    let memory = instance.exports.get_memory("memory").unwrap();
+    let mut bytes = memory.view(&store).copy_to_vec().unwrap();
+    let mut heap_index = instance
+        .exports
+        .get_global("$$HEAP_BASE$$")
+        .unwrap()
+        .get(&mut store)
+        .unwrap_i32() as u32;
+    let typ = parse_type_str("(list int)");
+    let lst = SchemeValue::List(vec![SchemeValue::Int(7)]);
+    let arg = encode_value(&lst, &typ, &mut bytes, &mut heap_index).unwrap();
+    write_encoded(&mut store, &instance, &bytes, heap_index);
+
+    let first_after_build = instance.exports.get_function("first-after-build").unwrap();
+    let result = first_after_build
+        .call(&mut store, &[Value::I32(10), Value::I32(arg as i32)])
+        .unwrap();
+    assert_eq!(result[0], Value::I32(7));
+    // running out of memory traps instead of overwriting the list
+    assert!(
+        first_after_build
+            .call(&mut store, &[Value::I32(10000), Value::I32(arg as i32)])
+            .is_err()
+    );
+    let bytes = memory.view(&store).copy_to_vec().unwrap();
+    assert_eq!(decode_value(&typ, arg, &bytes).unwrap(), lst);
+}
+
+#[test]
+fn test_encode_invalid() {
+    let mut memory = vec![0; 12];
+    let mut heap_index = 0;
+    let typ = parse_type_str("(list int)");
+    let val = SchemeValue::List(vec![SchemeValue::Bool(true)]);
+    assert!(encode_value(&val, &typ, &mut memory, &mut heap_index).is_err());
+    // not enough memory
+    let val = SchemeValue::List(vec![SchemeValue::Int(1), SchemeValue::Int(2)]);
+    assert!(encode_value(&val, &typ, &mut memory, &mut heap_index).is_err());
+    // symbols are only interned when the program is compiled
+    let val = SchemeValue::Symbol(String::from("a"));
+    assert!(encode_value(&val, &Type::Symbol, &mut memory, &mut heap_index).is_err());
+}
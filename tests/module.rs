@@ -0,0 +1,73 @@
+use scheme_to_wasm::common::Decl;
+use scheme_to_wasm::compile::compile_prog;
+use scheme_to_wasm::generate_code::construct_module_from_prog;
+use scheme_to_wasm::module::load_prog;
+
+use std::path::Path;
+use wasmer::{Instance, Store, imports};
+
+/// Loads the program in tests/modules/<name>, and returns the result of
+/// running it (which must be a fixnum)
+fn run_module(name: &str) -> i32 {
+    let prog = load_prog(&Path::new("tests/modules").join(name)).unwrap();
+    let module = construct_module_from_prog(&compile_prog(&prog).unwrap()).unwrap();
+    let binary = parity_wasm::serialize(module).unwrap();
+
+    let mut store = Store::default();
+    let module = wasmer::Module::new(&store, &binary).unwrap();
+    let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+    let result = instance
+        .exports
+        .get_function("$$MAIN$$")
+        .unwrap()
+        .call(&mut store, &[])
+        .unwrap();
+    result[0].unwrap_i32() >> 1
+}
+
+fn load_err(name: &str) -> String {
+    load_prog(&Path::new("tests/modules").join(name))
+        .unwrap_err()
+        .to_string()
+}
+
+#[test]
+fn test_load_prog() {
+    let prog = load_prog(Path::new("tests/modules/main.scm")).unwrap();
+    // math.scm is imported twice, but only linked once
+    let defines: Vec<&str> = prog
+        .decls
+        .iter()
+        .filter_map(|decl| match decl {
+            Decl::Define(name, _exp) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        defines,
+        vec!["helper", "square", "add-helper", "area", "square-area"]
+    );
+    assert!(
+        !prog
+            .decls
+            .iter()
+            .any(|decl| matches!(decl, Decl::Import(_)))
+    );
+}
+
+#[test]
+fn test_run_linked_prog() {
+    assert_eq!(run_module("main.scm"), 22);
+}
+
+#[test]
+fn test_load_prog_invalid() {
+    // only provided definitions can be used by importers
+    assert!(load_err("private.scm").contains("helper"));
+    assert!(load_err("conflict.scm").contains("helper is defined by both"));
+    assert!(load_err("cycle_main.scm").contains("imports itself"));
+    assert!(load_err("bad_provide.scm").contains("Provided name y"));
+    // programs with a main expression cannot be imported
+    assert!(load_err("not_module.scm").contains("may only contain declarations"));
+    assert!(load_err("nonexistent.scm").contains("Could not read"));
+}
@@ -0,0 +1,2 @@
+(import "missing_provide.scm")
+0
@@ -0,0 +1,3 @@
+(import "math.scm")
+(define helper 5)
+helper
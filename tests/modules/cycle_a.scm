@@ -0,0 +1,3 @@
+(import "cycle_b.scm")
+(define a 1)
+(provide a)
@@ -0,0 +1,3 @@
+(import "cycle_a.scm")
+(define b 2)
+(provide b)
@@ -0,0 +1,2 @@
+(import "cycle_a.scm")
+a
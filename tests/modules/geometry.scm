@@ -0,0 +1,4 @@
+(import "math.scm")
+(define area (lambda ((w : int) (h : int)) : int (* w h)))
+(define square-area (lambda ((s : int)) : int (square s)))
+(provide area square-area)
@@ -0,0 +1,3 @@
+(import "math.scm")
+(import "geometry.scm")
+(+ (square-area 3) (add-helper (area 2 5)))
@@ -0,0 +1,4 @@
+(define helper 3)
+(define square (lambda ((x : int)) : int (* x x)))
+(define add-helper (lambda ((x : int)) : int (+ x helper)))
+(provide square add-helper)
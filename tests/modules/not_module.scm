@@ -0,0 +1,2 @@
+(import "main.scm")
+0
@@ -0,0 +1,2 @@
+(import "math.scm")
+(+ helper 1)
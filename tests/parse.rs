@@ -1,5 +1,8 @@
 use im_rc::vector;
+use scheme_to_wasm::common::{Datum, Decl, Expr, ExprKind};
 use scheme_to_wasm::parse::parse;
+use scheme_to_wasm::parse::parse_module;
+use scheme_to_wasm::parse::parse_prog;
 use scheme_to_wasm::parse::parse_type;
 use scheme_to_wasm::types::Type;
 
@@ -13,10 +16,203 @@ use scheme_to_wasm::types::Type;
 
 #[test]
 fn test_parse_int_bounds() {
+    let exp = lexpr::from_str("2147483647").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Num(2147483647));
+
+    // integer literals which don't fit in 32 bits are 64-bit integers
     let exp = lexpr::from_str("2147483648").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Int64(2147483648));
+
+    let exp = lexpr::from_str("-9223372036854775808").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Int64(i64::MIN));
+
+    let exp = lexpr::from_str("9223372036854775808").unwrap();
     assert!(parse(&exp).is_err());
 }
 
+#[test]
+fn test_parse_float() {
+    let exp = lexpr::from_str("1.5").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Float(1.5));
+
+    let exp = lexpr::from_str("-2.0").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Float(-2.0));
+}
+
+#[test]
+fn test_parse_chars_and_symbols() {
+    let exp = lexpr::from_str(r"#\a").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Char('a'));
+
+    let exp = lexpr::from_str(r"#\x3bb").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Char('λ'));
+
+    let exp = lexpr::from_str("'foo").unwrap();
+    assert_eq!(
+        *parse(&exp).unwrap().kind,
+        ExprKind::Symbol(String::from("foo"))
+    );
+
+    let exp = lexpr::from_str("(quote if)").unwrap();
+    assert_eq!(
+        *parse(&exp).unwrap().kind,
+        ExprKind::Symbol(String::from("if"))
+    );
+
+    // literals evaluate to themselves when quoted
+    let exp = lexpr::from_str("'3").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Num(3));
+
+    let exp = lexpr::from_str(r#"'"hi""#).unwrap();
+    assert_eq!(
+        *parse(&exp).unwrap().kind,
+        ExprKind::Str(String::from("hi"))
+    );
+
+    // symbols and chars print the way they are written
+    for source in ["'foo", r"#\a", r"#\space", r"#\x3bb"] {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        assert_eq!(exp.to_string(), source);
+    }
+
+    for source in ["(quote)", "(quote a b)"] {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn test_parse_quoted_lists() {
+    let exp = lexpr::from_str(r#"'(1 "a" (b #t) ())"#).unwrap();
+    assert_eq!(
+        *parse(&exp).unwrap().kind,
+        ExprKind::Quote(vector![
+            Datum::Num(1),
+            Datum::Str(String::from("a")),
+            Datum::List(vector![Datum::Symbol(String::from("b")), Datum::Bool(true)]),
+            Datum::List(vector![]),
+        ])
+    );
+
+    // names are symbols within quoted lists, even if they are keywords
+    let exp = lexpr::from_str("'(if true)").unwrap();
+    assert_eq!(
+        *parse(&exp).unwrap().kind,
+        ExprKind::Quote(vector![
+            Datum::Symbol(String::from("if")),
+            Datum::Symbol(String::from("true")),
+        ])
+    );
+
+    let exp = lexpr::from_str("'()").unwrap();
+    assert_eq!(*parse(&exp).unwrap().kind, ExprKind::Quote(vector![]));
+
+    for source in ["'(1 2.5 5000000000)", r"'(#\a (a b) ())"] {
+        let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+        assert_eq!(exp.to_string(), source);
+    }
+
+    for source in ["'(1 . 2)", "'(1 #(2))"] {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn test_parse_exceptions() {
+    let exp = lexpr::from_str("(with-handler ((e : int) (+ e 1)) (raise 5 : bool))").unwrap();
+    match &*parse(&exp).unwrap().kind {
+        ExprKind::Handle(var, typ, _handler, body) => {
+            assert_eq!(var, "e");
+            assert_eq!(*typ, Type::Int);
+            assert!(matches!(&*body.kind, ExprKind::Raise(_, Type::Bool)));
+        }
+        kind => panic!("Expected a with-handler expression, found {kind:?}"),
+    }
+
+    for source in [
+        "(raise 5)",
+        "(raise 5 int)",
+        "(with-handler (e (+ e 1)) 5)",
+        "(with-handler ((e int) e) 5)",
+        "(with-handler ((e : int) e))",
+    ] {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn test_parse_call_cc() {
+    let exp =
+        lexpr::from_str("(call/cc (lambda ((k : (cont int))) : int (throw k 1 : int)))").unwrap();
+    match &*parse(&exp).unwrap().kind {
+        ExprKind::CallCC(func) => match &*func.kind {
+            ExprKind::Lambda(params, ret_typ, body) => {
+                assert_eq!(params[0].1, Type::Cont(Box::new(Type::Int)));
+                assert_eq!(*ret_typ, Type::Int);
+                assert!(matches!(&*body.kind, ExprKind::Throw(_, _, Type::Int)));
+            }
+            kind => panic!("Expected a lambda expression, found {kind:?}"),
+        },
+        kind => panic!("Expected a call/cc expression, found {kind:?}"),
+    }
+
+    for source in [
+        "(call/cc)",
+        "(call/cc f g)",
+        "(throw k 1)",
+        "(throw k 1 int)",
+        "(lambda ((k : (cont))) : int 0)",
+    ] {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn test_parse_generators() {
+    let exp = lexpr::from_str("(next (make-generator int (yield 1)))").unwrap();
+    match &*parse(&exp).unwrap().kind {
+        ExprKind::Next(generator) => match &*generator.kind {
+            ExprKind::MakeGenerator(typ, body) => {
+                assert_eq!(*typ, Type::Int);
+                assert!(matches!(&*body.kind, ExprKind::Yield(_)));
+            }
+            kind => panic!("Expected a make-generator expression, found {kind:?}"),
+        },
+        kind => panic!("Expected a next expression, found {kind:?}"),
+    }
+
+    let exp = lexpr::from_str("(generator (list int))").unwrap();
+    assert_eq!(
+        parse_type(&exp).unwrap(),
+        Type::Generator(Box::new(Type::List(Box::new(Type::Int))))
+    );
+
+    for source in [
+        "(make-generator int)",
+        "(make-generator (yield 1))",
+        "(yield)",
+        "(yield 1 2)",
+        "(next)",
+        "(lambda ((g : (generator))) : int 0)",
+    ] {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
+
 #[test]
 fn test_parse_type_primitives() {
     let exp = lexpr::from_str("int").unwrap();
@@ -27,6 +223,18 @@ fn test_parse_type_primitives() {
 
     let exp = lexpr::from_str("string").unwrap();
     assert_eq!(parse_type(&exp).unwrap(), Type::Str);
+
+    let exp = lexpr::from_str("int64").unwrap();
+    assert_eq!(parse_type(&exp).unwrap(), Type::Int64);
+
+    let exp = lexpr::from_str("float").unwrap();
+    assert_eq!(parse_type(&exp).unwrap(), Type::Float);
+
+    let exp = lexpr::from_str("char").unwrap();
+    assert_eq!(parse_type(&exp).unwrap(), Type::Char);
+
+    let exp = lexpr::from_str("symbol").unwrap();
+    assert_eq!(parse_type(&exp).unwrap(), Type::Symbol);
 }
 
 #[test]
@@ -85,20 +293,38 @@ fn test_parse_type_records() {
     let exp = lexpr::from_str("(record (x : int))").unwrap();
     assert_eq!(
         parse_type(&exp).unwrap(),
-        Type::Record(vector![(String::from("x"), Type::Int)])
+        Type::Record(vector![(String::from("x"), Type::Int, false)])
     );
 
     let exp = lexpr::from_str("(record (init : string) (update : (-> string string)))").unwrap();
     assert_eq!(
         parse_type(&exp).unwrap(),
         Type::Record(vector![
-            (String::from("init"), Type::Str),
+            (String::from("init"), Type::Str, false),
             (
                 String::from("update"),
-                Type::Func(vector![Type::Str], Box::new(Type::Str))
+                Type::Func(vector![Type::Str], Box::new(Type::Str)),
+                false
             )
         ])
     );
+
+    // fields are listed in alphabetical order
+    let exp = lexpr::from_str("(record (y : int) (x : bool))").unwrap();
+    let typ = parse_type(&exp).unwrap();
+    assert_eq!(typ.to_string(), "(record (x : bool) (y : int))");
+
+    let exp = lexpr::from_str("(record (x : int) (x : bool))").unwrap();
+    assert!(parse_type(&exp).is_err());
+
+    let exp = lexpr::from_str("(record (mutable x : int) (y : int))").unwrap();
+    assert_eq!(
+        parse_type(&exp).unwrap(),
+        Type::Record(vector![
+            (String::from("x"), Type::Int, true),
+            (String::from("y"), Type::Int, false)
+        ])
+    );
 }
 
 #[test]
@@ -134,3 +360,205 @@ fn test_parse_type_functions() {
         )
     );
 }
+
+fn parse_values(source: &str) -> Vec<lexpr::Value> {
+    lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap()
+}
+
+#[test]
+fn test_parse_prog_externs() {
+    let values = parse_values("(extern add-one (-> int int)) (extern flag (-> bool)) (add-one 2)");
+    let prog = parse_prog(&values).unwrap();
+    assert_eq!(
+        prog.decls,
+        vector![
+            Decl::Extern(
+                String::from("add-one"),
+                Type::Func(vector![Type::Int], Box::new(Type::Int))
+            ),
+            Decl::Extern(
+                String::from("flag"),
+                Type::Func(vector![], Box::new(Type::Bool))
+            ),
+        ]
+    );
+    assert_eq!(
+        prog.exp,
+        parse(&lexpr::from_str("(add-one 2)").unwrap()).unwrap()
+    );
+
+    let prog = parse_prog(&parse_values("(+ 1 2)")).unwrap();
+    assert!(prog.decls.is_empty());
+}
+
+#[test]
+fn test_parse_prog_defines() {
+    let values = parse_values(
+        r#"(define x 3) (define add (lambda ((a : int) (b : int)) : int (+ a b))) (export add "plus") (export add) (add x x)"#,
+    );
+    let prog = parse_prog(&values).unwrap();
+    assert_eq!(
+        prog.decls,
+        vector![
+            Decl::Define(
+                String::from("x"),
+                parse(&lexpr::from_str("3").unwrap()).unwrap()
+            ),
+            Decl::Define(
+                String::from("add"),
+                parse(&lexpr::from_str("(lambda ((a : int) (b : int)) : int (+ a b))").unwrap())
+                    .unwrap()
+            ),
+            Decl::Export(String::from("add"), String::from("plus")),
+            Decl::Export(String::from("add"), String::from("add")),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_modules() {
+    let values = parse_values(r#"(import "math.scm") (define x 3) (provide x) (provide) x"#);
+    let prog = parse_prog(&values).unwrap();
+    assert_eq!(prog.decls[0], Decl::Import(String::from("math.scm")));
+    assert_eq!(prog.decls[2], Decl::Provide(vector![String::from("x")]));
+    assert_eq!(prog.decls[3], Decl::Provide(vector![]));
+
+    let decls = parse_module(&parse_values("(define x 3) (provide x)")).unwrap();
+    assert_eq!(decls.len(), 2);
+    assert!(parse_module(&parse_values("(define x 3) x")).is_err());
+    assert!(parse_module(&parse_values("(import math.scm)")).is_err());
+    assert!(parse_module(&parse_values(r#"(import "a.scm" "b.scm")"#)).is_err());
+    assert!(parse_module(&parse_values(r#"(provide "x")"#)).is_err());
+}
+
+#[test]
+fn test_parse_prog_invalid() {
+    // no main expression
+    assert!(parse_prog(&parse_values("")).is_err());
+    assert!(parse_prog(&parse_values("(extern f (-> int int))")).is_err());
+    // expressions may only come last
+    assert!(parse_prog(&parse_values("(+ 1 2) (+ 3 4)")).is_err());
+    // externs must be functions
+    assert!(parse_prog(&parse_values("(extern x int) x")).is_err());
+    assert!(parse_prog(&parse_values("(extern (f) (-> int int)) 3")).is_err());
+    assert!(parse_prog(&parse_values("(define x) 3")).is_err());
+    assert!(parse_prog(&parse_values("(define x 1) (export x plus) 3")).is_err());
+}
+
+/// Checks that a derived form is parsed the same as the expression it is
+/// equivalent to
+fn assert_desugars_to(source: &str, expected: &str) {
+    let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+    assert_eq!(
+        exp,
+        Expr::new(ExprKind::Derived(
+            String::from(source),
+            parse(&lexpr::from_str(expected).unwrap()).unwrap()
+        ))
+    );
+}
+
+#[test]
+fn test_parse_primitives_arity() {
+    for source in ["(not)", "(not #t #f)", "(+ 1)", "(+ 1 2 3)", "(set-car! x)"] {
+        let err = parse(&lexpr::from_str(source).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("expects"), "{source}: {err}");
+    }
+}
+
+#[test]
+fn test_parse_derived_forms() {
+    assert_desugars_to(
+        "(cond ((< x 0) -1) ((= x 0) (set! y 1) 0) (else 1))",
+        "(if (< x 0) -1 (if (= x 0) (begin (set! y 1) 0) 1))",
+    );
+    assert_desugars_to("(cond (else 5))", "5");
+    assert_desugars_to(
+        "(when (< x 0) (set! x 0))",
+        "(if (< x 0) (begin (set! x 0) 0) 0)",
+    );
+    assert_desugars_to("(unless #t 1 2)", "(if #t 0 (begin 1 2 0))");
+    assert_desugars_to(
+        "(let* ((x 1) (y (+ x 1))) y)",
+        "(let ((x 1)) (let ((y (+ x 1))) y))",
+    );
+    assert_desugars_to("(let* () 3)", "3");
+    assert_desugars_to(
+        "(let loop ((i : int 0)) : int (if (< i 3) (loop (+ i 1)) i))",
+        "((letrec ((loop (lambda ((i : int)) : int (if (< i 3) (loop (+ i 1)) i)))) loop) 0)",
+    );
+
+    // forms which bind new variables use fresh names
+    let source = "(case (+ 1 2) ((1 2) #t) (else #f))";
+    let exp = parse(&lexpr::from_str(source).unwrap()).unwrap();
+    let inner = match &*exp.kind {
+        ExprKind::Derived(form, inner) if form == source => inner.clone(),
+        _ => panic!("Case expression was not parsed as a derived form."),
+    };
+    let key = match &*inner.kind {
+        ExprKind::Let(bindings, _body) => bindings[0].0.clone(),
+        _ => panic!("Case expression was not desugared into a let."),
+    };
+    assert!(key.contains('%'));
+    assert_eq!(
+        inner,
+        parse(
+            &lexpr::from_str(&format!(
+                "(let (({key} (+ 1 2))) (if (or (= {key} 1) (= {key} 2)) #t #f))"
+            ))
+            .unwrap()
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn test_parse_letrec() {
+    let exp = parse(
+        &lexpr::from_str(
+            "(letrec ((f (lambda ((x : int)) : int (g x))) (g (lambda ((x : int)) : int x))) (f 1))",
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    match &*exp.kind {
+        ExprKind::Letrec(bindings, _body) => {
+            assert_eq!(bindings[0].0, "f");
+            assert_eq!(bindings[1].0, "g");
+        }
+        _ => panic!("Letrec expression was not parsed as a letrec."),
+    }
+    assert!(parse(&lexpr::from_str("(letrec ((f)) 1)").unwrap()).is_err());
+    assert!(parse(&lexpr::from_str("(letrec ((f 1)))").unwrap()).is_err());
+}
+
+#[test]
+fn test_parse_derived_forms_invalid() {
+    let invalid = [
+        // cond and case need an else clause, which must come last
+        "(cond ((< x 0) 1))",
+        "(cond (else 1) (#t 2))",
+        "(cond (#t))",
+        "(case x ((1) 1))",
+        "(case x ((a) 1) (else 2))",
+        "(case x (() 1) (else 2))",
+        "(when #t)",
+        "(let* ((x)) x)",
+        // loop variables need types, and named lets need a return type
+        "(let loop ((i 0)) (loop i))",
+        "(let loop ((i : int 0)) (loop i))",
+        "(let loop ((i : int 0 1)) : int (loop i))",
+        "(do ((i 0 (+ i 1))) ((= i 3)))",
+        "(do ((i : int 0)))",
+        "(do ((i : int 0)) ((= i 3)) :)",
+    ];
+    for source in invalid {
+        assert!(
+            parse(&lexpr::from_str(source).unwrap()).is_err(),
+            "{source}"
+        );
+    }
+}
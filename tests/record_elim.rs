@@ -1,6 +1,9 @@
+use im_rc::vector;
+use scheme_to_wasm::common::ExprKind;
 use scheme_to_wasm::parse::parse;
 use scheme_to_wasm::record_elim::record_elim_exp;
 use scheme_to_wasm::type_check::type_check;
+use scheme_to_wasm::types::Type;
 
 #[test]
 fn test_record_elim_simple() {
@@ -30,6 +33,36 @@ fn test_record_elim_order_invariant() {
     assert_eq!(rc_exp, expected_exp);
 }
 
+#[test]
+fn test_record_elim_evaluation_order() {
+    // fields with side effects are still evaluated in the order they are
+    // written, before being stored in alphabetical order
+    let exp = parse(
+        &lexpr::from_str("(make-record (foo (begin (display 1) 2)) (bar (begin (display 3) 4)))")
+            .unwrap(),
+    )
+    .unwrap();
+    let typed_exp = type_check(&exp).unwrap();
+    let rc_exp = record_elim_exp(&typed_exp).unwrap();
+
+    println!("Source: {exp}");
+    println!("Record elimination: {rc_exp}");
+    match &*rc_exp.kind {
+        ExprKind::Let(bindings, body) => {
+            // foo is bound first, but bar is the first component of the tuple
+            assert_eq!(bindings.len(), 2);
+            match &*body.kind {
+                ExprKind::Tuple(exps) => {
+                    assert_eq!(*exps[0].kind, ExprKind::Id(bindings[1].0.clone()));
+                    assert_eq!(*exps[1].kind, ExprKind::Id(bindings[0].0.clone()));
+                }
+                kind => panic!("Expected a tuple, found {kind:?}"),
+            }
+        }
+        kind => panic!("Expected a let expression, found {kind:?}"),
+    }
+}
+
 #[test]
 fn test_record_elim_record_get() {
     let exp =
@@ -48,6 +81,30 @@ fn test_record_elim_record_get() {
     assert_eq!(rc_exp, expected_exp);
 }
 
+#[test]
+fn test_record_elim_record_set() {
+    let exp = parse(
+        &lexpr::from_str("(let ((r (make-record (mutable foo 1) (bar 3)))) (record-set! r foo 2))")
+            .unwrap(),
+    )
+    .unwrap();
+    let typed_exp = type_check(&exp).unwrap();
+    let rc_exp = record_elim_exp(&typed_exp).unwrap();
+
+    println!("Source: {exp}");
+    println!("Record elimination: {rc_exp}");
+    match &*rc_exp.kind {
+        ExprKind::Let(_bindings, body) => match &*body.kind {
+            ExprKind::TupleSet(tuple, key, _val) => {
+                assert_eq!(tuple.typ, Type::Tuple(vector![Type::Int, Type::Int]));
+                assert_eq!(*key, 1);
+            }
+            kind => panic!("Expected a tuple-set! expression, found {kind:?}"),
+        },
+        kind => panic!("Expected a let expression, found {kind:?}"),
+    }
+}
+
 #[test]
 fn test_record_elim_complex() {
     let exp = parse(
@@ -89,3 +146,26 @@ fn test_record_elim_complex() {
     println!("Record elimination: {rc_exp}");
     assert_eq!(rc_exp, expected_exp);
 }
+
+#[test]
+fn test_record_elim_subtyping() {
+    // the argument is converted to the parameter's type by the type checker,
+    // so the field is found at the same index of the tuple it becomes
+    let exp = parse(
+        &lexpr::from_str(
+            "((lambda ((r : (record (b : int)))) : int (record-ref r b))
+              (make-record (a \"hello\") (b 3)))",
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let typed_exp = type_check(&exp).unwrap();
+    let rc_exp = record_elim_exp(&typed_exp).unwrap();
+
+    println!("Source: {exp}");
+    println!("Record elimination: {rc_exp}");
+    match &*rc_exp.kind {
+        ExprKind::FnApp(_func, args) => assert_eq!(args[0].typ, Type::Tuple(vector![Type::Int])),
+        kind => panic!("Expected a function application, found {kind:?}"),
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "runtime")]
+
+use scheme_to_wasm::marshal::SchemeValue;
+use scheme_to_wasm::repl::{Repl, ReplOutput};
+use scheme_to_wasm::types::Type;
+
+/// Enters a single input into the REPL, returning the printed output
+fn input(repl: &mut Repl, source: &str) -> Result<String, String> {
+    repl.input(&lexpr::from_str(source).unwrap())
+        .map(|output| output.to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_repl_definitions() {
+    let mut repl = Repl::default();
+    assert_eq!(
+        repl.input(&lexpr::from_str("(define x 5)").unwrap())
+            .unwrap(),
+        ReplOutput::Defined(String::from("x"), Type::Int)
+    );
+    assert_eq!(
+        input(
+            &mut repl,
+            "(define fact (lambda ((n : int)) : int (if (= n 0) 1 (* n (fact (- n 1))))))"
+        ),
+        Ok(String::from("fact : (-> int int)"))
+    );
+    assert_eq!(
+        repl.input(&lexpr::from_str("(fact x)").unwrap()).unwrap(),
+        ReplOutput::Value(SchemeValue::Int(120), Type::Int)
+    );
+    // later definitions can use earlier ones
+    assert_eq!(
+        input(&mut repl, "(define y (+ x 1))"),
+        Ok(String::from("y : int"))
+    );
+    assert_eq!(input(&mut repl, "(fact y)"), Ok(String::from("720 : int")));
+}
+
+#[test]
+fn test_repl_printing() {
+    let mut repl = Repl::default();
+    assert_eq!(input(&mut repl, "(< 1 2)"), Ok(String::from("#t : bool")));
+    assert_eq!(
+        input(&mut repl, "(cons \"a\" (cons \"b\" (null string)))"),
+        Ok(String::from("(\"a\" \"b\") : (list string)"))
+    );
+    assert_eq!(
+        input(&mut repl, "(make-tuple 1 (make-record (a #f)))"),
+        Ok(String::from(
+            "(make-tuple 1 (make-record (a #f))) : (tuple int (record (a : bool)))"
+        ))
+    );
+}
+
+#[test]
+fn test_repl_macros() {
+    let mut repl = Repl::default();
+    assert_eq!(
+        input(
+            &mut repl,
+            "(define-syntax unless (syntax-rules () ((_ c e) (if c 0 e))))"
+        ),
+        Ok(String::from("unless : syntax"))
+    );
+    assert_eq!(
+        input(&mut repl, "(define x (unless #f 3))"),
+        Ok(String::from("x : int"))
+    );
+    assert_eq!(
+        input(&mut repl, "(unless #t x)"),
+        Ok(String::from("0 : int"))
+    );
+}
+
+#[test]
+fn test_repl_errors() {
+    let mut repl = Repl::default();
+    assert!(input(&mut repl, "(define x 5)").is_ok());
+    assert!(input(&mut repl, "(define x 6)").is_err());
+    assert!(input(&mut repl, "(+ x #t)").is_err());
+    assert!(input(&mut repl, "(extern f (-> int int))").is_err());
+    // definitions which fail are not kept
+    assert!(input(&mut repl, "(define y (/ x 0))").is_err());
+    assert!(input(&mut repl, "y").is_err());
+    assert!(
+        input(
+            &mut repl,
+            "(define f (lambda ((n : int)) : int (display n)))"
+        )
+        .is_err()
+    );
+    assert_eq!(input(&mut repl, "x"), Ok(String::from("5 : int")));
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "runtime")]
+
+use scheme_to_wasm::marshal::SchemeValue;
+use scheme_to_wasm::runtime::{Session, SessionOptions};
+
+const LOOP_PROG: &str = r#"
+(define sum-to (lambda ((n : int)) : int (if (= n 0) 0 (+ n (sum-to (- n 1))))))
+(sum-to 100)"#;
+
+#[test]
+fn test_session_eval() {
+    let mut session = Session::default();
+    assert_eq!(session.eval("(+ 1 2)").unwrap(), SchemeValue::Int(3));
+    assert_eq!(session.eval("(< 1 2)").unwrap(), SchemeValue::Bool(true));
+    assert_eq!(
+        session
+            .eval("(cons \"a\" (cons \"bc\" (null string)))")
+            .unwrap(),
+        SchemeValue::List(vec![
+            SchemeValue::Str(String::from("a")),
+            SchemeValue::Str(String::from("bc"))
+        ])
+    );
+    // each program is evaluated independently
+    assert_eq!(session.eval(LOOP_PROG).unwrap(), SchemeValue::Int(5050));
+    assert_eq!(session.eval(LOOP_PROG).unwrap(), SchemeValue::Int(5050));
+}
+
+#[test]
+fn test_session_eval_invalid() {
+    let mut session = Session::default();
+    assert!(session.eval("(+ 1 2").is_err());
+    assert!(session.eval("(+ 1 #t)").is_err());
+    assert!(session.eval("(extern f (-> int int)) (f 1)").is_err());
+    // traps are reported as errors
+    assert!(session.eval("(/ 1 0)").is_err());
+    // cyclic lists can't be decoded
+    assert!(
+        session
+            .eval("(let ((l (cons 1 (null int)))) (begin (set-cdr! l l) l))")
+            .is_err()
+    );
+}
+
+#[test]
+fn test_session_fuel() {
+    let mut session = Session::new(SessionOptions {
+        fuel: Some(100_000),
+        ..SessionOptions::default()
+    });
+    assert_eq!(session.eval(LOOP_PROG).unwrap(), SchemeValue::Int(5050));
+
+    let mut session = Session::new(SessionOptions {
+        fuel: Some(100),
+        ..SessionOptions::default()
+    });
+    let err = session.eval(LOOP_PROG).unwrap_err();
+    assert!(err.to_string().contains("ran out of fuel"));
+    assert_eq!(session.eval("(+ 1 2)").unwrap(), SchemeValue::Int(3));
+
+    // programs which never finish are stopped
+    let source = r#"
+(define spin (lambda ((n : int)) : int (spin (+ n 1))))
+(spin 0)"#;
+    let err = session.eval(source).unwrap_err();
+    assert!(err.to_string().contains("ran out of fuel"));
+
+    // loops within a function are charged for each iteration
+    let mut session = Session::new(SessionOptions {
+        fuel: Some(10_000),
+        ..SessionOptions::default()
+    });
+    let text = "a".repeat(20_000);
+    let source = format!("(let ((s \"{text}\")) (string-equal? s s))");
+    let err = session.eval(&source).unwrap_err();
+    assert!(err.to_string().contains("ran out of fuel"));
+    let source = "(let ((s \"abc\")) (string-equal? s s))";
+    assert_eq!(session.eval(source).unwrap(), SchemeValue::Bool(true));
+}
+
+#[test]
+fn test_session_memory_limit() {
+    let mut session = Session::new(SessionOptions {
+        memory_limit: Some(16),
+        ..SessionOptions::default()
+    });
+    assert_eq!(session.eval("(+ 1 2)").unwrap(), SchemeValue::Int(3));
+    assert!(session.eval("\"a string that is too long\"").is_err());
+}
@@ -1,7 +1,9 @@
 use im_rc::vector;
-use scheme_to_wasm::common::{ExprKind, TypeEnv};
-use scheme_to_wasm::parse::{parse, parse_type};
-use scheme_to_wasm::type_check::{tc_with_env, type_check};
+use scheme_to_wasm::common::{ExprKind, SourceProg, TypeEnv};
+use scheme_to_wasm::parse::{parse, parse_prog, parse_type};
+use scheme_to_wasm::type_check::{
+    tc_with_env, type_check, type_check_decls, type_check_defines, type_check_source_prog,
+};
 use scheme_to_wasm::types::Type;
 
 #[test]
@@ -84,11 +86,11 @@ fn test_typecheck_all_nodes_annotated() {
     let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
     assert_eq!(typed_exp.typ, Type::Bool);
     match *typed_exp.kind {
-        ExprKind::Binop(_op, arg1, arg2) => {
-            assert_eq!(arg1.typ, Type::Int);
-            assert_eq!(arg2.typ, Type::Int);
+        ExprKind::Prim(_op, args) => {
+            assert_eq!(args[0].typ, Type::Int);
+            assert_eq!(args[1].typ, Type::Int);
         }
-        _ => panic!("Non-binop found!"),
+        _ => panic!("Non-primitive found!"),
     }
 }
 
@@ -123,6 +125,69 @@ fn test_typecheck_binops_sad() {
     assert!(typed_exp.is_err());
 }
 
+#[test]
+fn test_typecheck_primitives() {
+    let cases = [
+        ("(not true)", Type::Bool),
+        ("(!= 1 2)", Type::Bool),
+        ("(modulo 7 (incr 2))", Type::Int),
+        ("(bitwise-not (shift-left 1 4))", Type::Int),
+        (r#"(str-len "hello")"#, Type::Int),
+        (r#"(string-equal? "a" "b")"#, Type::Bool),
+        ("(set-car! (cons true (null bool)) false)", Type::Bool),
+        (
+            "(set-cdr! (cons 1 (null int)) (null int))",
+            Type::List(Box::new(Type::Int)),
+        ),
+        ("5000000000", Type::Int64),
+        ("(+ 1.5 (int->float 2))", Type::Float),
+        ("(modulo 5000000000 (int->int64 3))", Type::Int64),
+        ("(< 0.5 1.5)", Type::Bool),
+        ("(float->int64 (int64->float 5000000000))", Type::Int64),
+        (r"#\a", Type::Char),
+        ("'foo", Type::Symbol),
+        (r"(char=? #\a (int->char 98))", Type::Bool),
+        ("(symbol=? 'a 'b)", Type::Bool),
+        (r"(char->int #\a)", Type::Int),
+        ("(symbol->string 'foo)", Type::Str),
+        (
+            "(cons 'a (null symbol))",
+            Type::List(Box::new(Type::Symbol)),
+        ),
+    ];
+    for (source, expected) in cases {
+        let exp = lexpr::from_str(source).unwrap();
+        let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+        assert_eq!(typed_exp.typ, expected, "{source}");
+    }
+
+    let invalid = [
+        "(not 1)",
+        "(str-len 5)",
+        r#"(string-equal? "a" 1)"#,
+        "(set-car! (cons 1 (null int)) true)",
+        "(set-cdr! (cons 1 (null int)) (null bool))",
+        "(bitwise-and true false)",
+        "(bitwise-and 1.5 2.5)",
+        "(modulo 1.5 2.5)",
+        "(+ 1 1.5)",
+        "(+ 1 5000000000)",
+        "(< true false)",
+        "(int->float 5000000000)",
+        r#"(char=? #\a "a")"#,
+        r"(symbol=? 'a #\a)",
+        "(symbol=? 'a \"a\")",
+        "(char->int 97)",
+        "(int->char (int->int64 97))",
+        "(symbol->string \"foo\")",
+        r"(= #\a #\b)",
+    ];
+    for source in invalid {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
 #[test]
 fn test_typecheck_lists_happy() {
     let exp = lexpr::from_str("(null int)").unwrap();
@@ -174,6 +239,38 @@ fn test_typecheck_lists_happy() {
     assert_eq!(typed_exp.typ, Type::Bool);
 }
 
+#[test]
+fn test_typecheck_quoted_lists() {
+    let cases = [
+        ("'(1 2 3)", "(list int)"),
+        ("'(a b)", "(list symbol)"),
+        (r#"'(("a") ())"#, "(list (list string))"),
+        ("'(() ((1.5)) (()))", "(list (list (list float)))"),
+        ("(car '(5000000000))", "int64"),
+        ("(cons 0 '(1 2))", "(list int)"),
+    ];
+    for (source, expected) in cases {
+        let exp = lexpr::from_str(source).unwrap();
+        let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+        let expected = parse_type(&lexpr::from_str(expected).unwrap()).unwrap();
+        assert_eq!(typed_exp.typ, expected, "{source}");
+    }
+
+    // the element type must be the same throughout, and must be known
+    let invalid = [
+        "'(1 a)",
+        "'(1 5000000000)",
+        "'((1) (a))",
+        "'(1 ())",
+        "'()",
+        "'(() ())",
+    ];
+    for source in invalid {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
 #[test]
 fn test_typecheck_lists_sad() {
     // type of car does not match type of cdr
@@ -245,8 +342,8 @@ fn test_typecheck_records_happy() {
     assert_eq!(
         typed_exp.typ,
         Type::Record(vector![
-            (String::from("num"), Type::Int),
-            (String::from("name"), Type::Str)
+            (String::from("num"), Type::Int, false),
+            (String::from("name"), Type::Str, false)
         ])
     );
 
@@ -254,7 +351,7 @@ fn test_typecheck_records_happy() {
     let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
     assert_eq!(
         typed_exp.typ,
-        Type::Record(vector![(String::from("foo"), Type::Int)])
+        Type::Record(vector![(String::from("foo"), Type::Int, false)])
     );
 
     let exp = lexpr::from_str(r#"(record-ref (make-record (num 3) (name "hello")) num)"#).unwrap();
@@ -277,20 +374,15 @@ fn test_typecheck_records_sad() {
     let typed_exp = type_check(&parse(&exp).unwrap());
     assert!(typed_exp.is_err());
 
-    // TODO: In practice, the compiler should have some kind of check to ensure
-    // records are not being constructed with multiple fields of the same name,
-    // (unless perhaps there were macros or something), but this isn't a
-    // high priority feature.
-
-    // // record contains duplicate values
-    // let exp = lexpr::from_str(r#"(make-record (num 3) (num 4))"#).unwrap();
-    // let typed_exp = type_check(&parse(&exp).unwrap());
-    // assert_eq!(typed_exp.is_err(), true);
+    // record contains duplicate values
+    let exp = lexpr::from_str(r#"(make-record (num 3) (num 4))"#).unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap());
+    assert!(typed_exp.is_err());
 
-    // // record contains duplicate values (of different types)
-    // let exp = lexpr::from_str(r#"(make-record (num 3) (num "hello"))"#).unwrap();
-    // let typed_exp = type_check(&parse(&exp).unwrap());
-    // assert_eq!(typed_exp.is_err(), true);
+    // record contains duplicate values (of different types)
+    let exp = lexpr::from_str(r#"(make-record (num 3) (num "hello"))"#).unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap());
+    assert!(typed_exp.is_err());
 
     // invalid key on record-ref
     let exp = lexpr::from_str(r#"(record-ref (make-record (num 3) (name "hello")) foo)"#).unwrap();
@@ -303,6 +395,149 @@ fn test_typecheck_records_sad() {
     assert!(typed_exp.is_err());
 }
 
+#[test]
+fn test_typecheck_record_field_order() {
+    // the order fields are written in doesn't change the type of a record
+    let exp = lexpr::from_str(
+        "((lambda ((r : (record (a : int) (b : bool)))) : int (record-ref r a))
+          (make-record (b #t) (a 1)))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    let exp = lexpr::from_str(r#"(make-record (name "hello") (num 3))"#).unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(
+        typed_exp.typ,
+        Type::Record(vector![
+            (String::from("num"), Type::Int, false),
+            (String::from("name"), Type::Str, false)
+        ])
+    );
+    assert_eq!(
+        typed_exp.typ.to_string(),
+        "(record (name : string) (num : int))"
+    );
+}
+
+#[test]
+fn test_typecheck_record_set() {
+    // record-set! evaluates to the assigned value
+    let exp = lexpr::from_str(
+        r#"(let ((r (make-record (mutable num 3) (name "a")))) (record-set! r num 4))"#,
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    // mutable fields can be assigned through function parameters
+    let exp = lexpr::from_str(
+        "((lambda ((r : (record (mutable num : int)))) : int (record-set! r num 5))
+          (make-record (mutable num 3)))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    // immutable fields can't be assigned
+    let exp =
+        lexpr::from_str(r#"(let ((r (make-record (num 3)))) (record-set! r num 4))"#).unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+
+    // the new value must have the type of the field
+    let exp =
+        lexpr::from_str(r#"(let ((r (make-record (mutable num 3)))) (record-set! r num "a"))"#)
+            .unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+
+    // the field must exist
+    let exp = lexpr::from_str(r#"(let ((r (make-record (mutable num 3)))) (record-set! r foo 4))"#)
+        .unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+
+    // records with mutable fields can't be converted to other record types,
+    // since the copy wouldn't share the fields with the original
+    let exp = lexpr::from_str(
+        "((lambda ((r : (record (mutable num : int)))) : int (record-ref r num))
+          (make-record (mutable num 3) (other 4)))",
+    )
+    .unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+
+    let exp = lexpr::from_str(
+        "((lambda ((r : (record (num : int)))) : int (record-ref r num))
+          (make-record (mutable num 3)))",
+    )
+    .unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+}
+
+#[test]
+fn test_typecheck_record_subtyping() {
+    // a record can be passed to a function expecting a record with fewer
+    // fields, and is converted to the function's parameter type
+    let exp = lexpr::from_str(
+        "((lambda ((r : (record (y : int)))) : int (record-ref r y))
+          (make-record (x 1) (y 2)))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+    match &*typed_exp.kind {
+        ExprKind::FnApp(_func, args) => assert_eq!(
+            args[0].typ,
+            Type::Record(vector![(String::from("y"), Type::Int, false)])
+        ),
+        kind => panic!("Expected a function application, found {kind:?}"),
+    }
+
+    // fields of records and tuples are subtypes of the expected fields
+    let exp = lexpr::from_str(
+        "(let ((get (lambda ((r : (record (p : (tuple (record (a : int))))))) : int
+                      (record-ref (tuple-ref (record-ref r p) 0) a))))
+           (get (make-record (q #t) (p (make-tuple (make-record (b #f) (a 5)))))))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    // the branches of an if expression are converted to the wider type
+    let exp = lexpr::from_str("(if #t (make-record (a 1) (b 2)) (make-record (a 3)))").unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(
+        typed_exp.typ,
+        Type::Record(vector![(String::from("a"), Type::Int, false)])
+    );
+
+    // a lambda can return a subtype of its return type, and a variable can be
+    // assigned a subtype of its type
+    for source in [
+        "(lambda () : (record (a : int)) (make-record (b #t) (a 1)))",
+        "(let ((r (make-record (a 1)))) (set! r (make-record (a 2) (b 3))))",
+        "(cons (make-record (a 1) (b 2)) (null (record (a : int))))",
+    ] {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_ok(), "{source}");
+    }
+
+    for source in [
+        // the record is missing a field, or has a field of the wrong type
+        "((lambda ((r : (record (y : int)))) : int 0) (make-record (x 1)))",
+        "((lambda ((r : (record (y : int)))) : int 0) (make-record (y #t)))",
+        // functions and lists are not subtypes of each other
+        "((lambda ((f : (-> (record (a : int))))) : int 0)
+          (lambda () : (record (a : int) (b : int)) (make-record (a 1) (b 2))))",
+        "((lambda ((l : (list (record (a : int))))) : int 0)
+          (cons (make-record (a 1) (b 2)) (null (record (a : int) (b : int)))))",
+        // tuples must have the same length
+        "((lambda ((t : (tuple int))) : int 0) (make-tuple 1 2))",
+    ] {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
 #[test]
 fn test_typecheck_let_happy() {
     let exp = lexpr::from_str("(let ((x 23)) (+ x 24))").unwrap();
@@ -517,6 +752,73 @@ fn test_typecheck_lambdas_recursive_happy() {
     );
 }
 
+#[test]
+fn test_typecheck_letrec() {
+    // the lambdas bound by a letrec can refer to themselves and each other
+    let exp = lexpr::from_str(
+        r#"(letrec ((even? (lambda ((x : int)) : bool (if (= x 0) #t (odd? (- x 1)))))
+            (odd? (lambda ((x : int)) : bool (if (= x 0) #f (even? (- x 1))))))
+    (even? 10))"#,
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Bool);
+
+    // but a lambda bound by a plain let can't refer to itself
+    let exp = lexpr::from_str(
+        r#"(let ((foo (lambda ((x : int)) : int (if (< x 1) 0 (+ 1 (foo (- x 1)))))))
+    (foo 3))"#,
+    )
+    .unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+
+    // its name refers to the outer variable instead
+    let exp = lexpr::from_str(
+        r#"(let ((foo (lambda ((x : int)) : int (+ x 1))))
+    (let ((foo (lambda ((x : int)) : int (foo x)))) (foo 3)))"#,
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    // every binding of a letrec must be a lambda
+    let exp = lexpr::from_str("(letrec ((x 5)) x)").unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+    let exp = lexpr::from_str("(letrec ((f (lambda ((x : int)) : int (f #t)))) (f 1))").unwrap();
+    assert!(type_check(&parse(&exp).unwrap()).is_err());
+}
+
+#[test]
+fn test_typecheck_derived_forms() {
+    let exp = lexpr::from_str(
+        r#"(let loop ((i : int 0) (acc : (list int) (null int))) : (list int)
+    (cond ((= i 3) acc)
+          (else (loop (+ i 1) (cons i acc)))))"#,
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::List(Box::new(Type::Int)));
+
+    // errors refer to the form the user wrote, rather than its desugaring
+    let source = "(cond ((< 1 2) 3) (else #f))";
+    let err = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap_err();
+    assert!(err.to_string().contains(source), "{err}");
+
+    // when derived forms are nested, the innermost one is mentioned
+    let source = "(when (< 1 2) (let* ((x #t)) (+ x 1)))";
+    let err = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("(in the form (let* ((x #t)) (+ x 1)))"),
+        "{err}"
+    );
+    assert!(!err.to_string().contains("(when"), "{err}");
+
+    let source = "(do ((i : int 0 (+ i 1))) ((= i #t)))";
+    let err = type_check(&parse(&lexpr::from_str(source).unwrap()).unwrap()).unwrap_err();
+    assert!(err.to_string().contains(source), "{err}");
+}
+
 #[test]
 fn test_typecheck_lambda_sad() {
     // mismatched return type
@@ -738,6 +1040,102 @@ fn test_typecheck_unpack_sad() {
     assert!(typed_exp.is_err());
 }
 
+#[test]
+fn test_typecheck_exceptions() {
+    // a raise has whatever type it is annotated with
+    let exp = lexpr::from_str("(+ 1 (raise \"oops\" : int))").unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    let exp = lexpr::from_str("(with-handler ((e : string) (str-len e)) (raise \"oops\" : int))")
+        .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    let exp = lexpr::from_str(
+        "(with-handler ((e : (tuple int float)) (tuple-ref e 1)) (raise (make-tuple 1 2.5) : float))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Float);
+
+    for source in [
+        // the handler must have the same type as its body
+        "(with-handler ((e : int) #t) 5)",
+        // the exception variable is only in scope within the handler
+        "(with-handler ((e : int) 0) e)",
+        "(with-handler ((e : int) (str-len e)) 5)",
+        // exceptions can't contain functions or values of abstract types
+        "(raise (lambda ((x : int)) : int x) : int)",
+        "(with-handler ((f : (-> int int)) (f 1)) 5)",
+        "(with-handler ((e : (exists T0 T0)) 0) 5)",
+    ] {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_typecheck_call_cc() {
+    // call/cc has the type of the function's result, and a throw has
+    // whatever type it is annotated with
+    let exp = lexpr::from_str(
+        "(+ 1 (call/cc (lambda ((k : (cont int))) : int (+ 10 (throw k 5 : int)))))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Int);
+
+    let exp = lexpr::from_str("(call/cc (lambda ((k : (cont bool))) : bool (throw k #t : bool)))")
+        .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Bool);
+
+    for source in [
+        // the function must take a continuation of its own result type
+        "(call/cc (lambda ((k : (cont int))) : bool #t))",
+        "(call/cc (lambda ((k : int)) : int k))",
+        "(call/cc (lambda ((k : (cont int)) (x : int)) : int x))",
+        "(call/cc 5)",
+        // the thrown value must match the continuation
+        "(call/cc (lambda ((k : (cont int))) : int (throw k #t : int)))",
+        "(throw 5 5 : int)",
+    ] {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
+#[test]
+fn test_typecheck_generators() {
+    // next gives a list holding the next yielded value, and a yield has the
+    // type of the value it yields
+    let exp = lexpr::from_str("(next (make-generator int (+ 1 (yield 2))))").unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::List(Box::new(Type::Int)));
+
+    // a yield within a function yields from the enclosing generator
+    let exp = lexpr::from_str(
+        "(make-generator bool
+           (let ((emit (lambda ((b : bool)) : bool (yield b)))) (emit #t)))",
+    )
+    .unwrap();
+    let typed_exp = type_check(&parse(&exp).unwrap()).unwrap();
+    assert_eq!(typed_exp.typ, Type::Generator(Box::new(Type::Bool)));
+
+    for source in [
+        // yields must be within a generator, and match its type
+        "(yield 1)",
+        "(lambda () : int (yield 1))",
+        "(make-generator int (yield #t))",
+        "(make-generator bool (make-generator int (yield #t)))",
+        "(next 5)",
+    ] {
+        let exp = lexpr::from_str(source).unwrap();
+        assert!(type_check(&parse(&exp).unwrap()).is_err(), "{source}");
+    }
+}
+
 #[test]
 fn test_typecheck_adt() {
     let exp = lexpr::from_str(
@@ -762,3 +1160,125 @@ fn test_typecheck_adt() {
     .unwrap();
     assert_eq!(typed_exp.typ, typ);
 }
+
+fn parse_source_prog(source: &str) -> SourceProg {
+    let values = lexpr::Parser::from_str(source)
+        .value_iter()
+        .collect::<Result<Vec<lexpr::Value>, lexpr::parse::Error>>()
+        .unwrap();
+    parse_prog(&values).unwrap()
+}
+
+#[test]
+fn test_typecheck_externs() {
+    let prog = parse_source_prog("(extern add-one (-> int int)) (add-one 2)");
+    assert_eq!(type_check_source_prog(&prog).unwrap().typ, Type::Int);
+
+    let prog = parse_source_prog("(extern add-one (-> int int)) add-one");
+    assert_eq!(
+        type_check_source_prog(&prog).unwrap().typ,
+        Type::Func(vector![Type::Int], Box::new(Type::Int))
+    );
+
+    // externs can be shadowed
+    let prog = parse_source_prog("(extern f (-> int int)) (let ((f true)) f)");
+    assert_eq!(type_check_source_prog(&prog).unwrap().typ, Type::Bool);
+
+    let prog = parse_source_prog("(extern add-one (-> int int)) (add-one true)");
+    assert!(type_check_source_prog(&prog).is_err());
+
+    // the host can't call closures
+    let prog = parse_source_prog("(extern apply (-> (-> int int) int)) 3");
+    assert!(type_check_source_prog(&prog).is_err());
+}
+
+#[test]
+fn test_typecheck_defines() {
+    let prog = parse_source_prog("(define x 3) (define y (+ x 1)) (+ x y)");
+    assert_eq!(type_check_source_prog(&prog).unwrap().typ, Type::Int);
+    assert_eq!(
+        type_check_defines(&prog).unwrap(),
+        vector![
+            (String::from("x"), Type::Int),
+            (String::from("y"), Type::Int)
+        ]
+    );
+
+    // lambdas can refer to themselves
+    let prog = parse_source_prog(
+        r#"
+(define fact (lambda ((n : int)) : int (if (= n 0) 1 (* n (fact (- n 1))))))
+(export fact "fact")
+(fact 5)"#,
+    );
+    assert_eq!(type_check_source_prog(&prog).unwrap().typ, Type::Int);
+
+    // definitions can only refer to earlier definitions
+    let prog = parse_source_prog("(define x y) (define y 3) x");
+    assert!(type_check_source_prog(&prog).is_err());
+
+    let prog = parse_source_prog("(define x 3) (define x 4) x");
+    assert!(type_check_source_prog(&prog).is_err());
+}
+
+#[test]
+fn test_typecheck_exports() {
+    let prog = parse_source_prog("(define x 3) (export x) x");
+    assert!(type_check_source_prog(&prog).is_err());
+
+    let prog = parse_source_prog("(export f) 3");
+    assert!(type_check_source_prog(&prog).is_err());
+
+    // the host can't call closures
+    let prog = parse_source_prog(
+        "(define apply (lambda ((f : (-> int int))) : int (f 1))) (export apply) 3",
+    );
+    assert!(type_check_source_prog(&prog).is_err());
+
+    let prog =
+        parse_source_prog(r#"(define f (lambda () : int 1)) (export f "g") (export f "g") 3"#);
+    assert!(type_check_source_prog(&prog).is_err());
+
+    // the module's own exports can't be replaced
+    let prog = parse_source_prog("(define memory (lambda ((x : int)) : int x)) (export memory) 0");
+    assert!(type_check_source_prog(&prog).is_err());
+    let prog = parse_source_prog(r#"(define f (lambda () : int 1)) (export f "$$MAIN$$") 0"#);
+    assert!(type_check_source_prog(&prog).is_err());
+}
+
+#[test]
+fn test_typecheck_module_decls() {
+    let square_typ = Type::Func(vector![Type::Int], Box::new(Type::Int));
+    let imports = vector![(String::from("square"), square_typ)];
+    let prog = parse_source_prog("(define y (square 3)) (provide y) 0");
+    assert_eq!(
+        type_check_decls(&prog.decls, &imports).unwrap(),
+        vector![(String::from("y"), Type::Int)]
+    );
+
+    // imported names can't be redefined
+    let prog = parse_source_prog("(define square 3) 0");
+    assert!(type_check_decls(&prog.decls, &imports).is_err());
+    let prog = parse_source_prog("(define y 3) (provide z) 0");
+    assert!(type_check_decls(&prog.decls, &imports).is_err());
+    // imports must be resolved first
+    let prog = parse_source_prog(r#"(import "math.scm") 0"#);
+    assert!(type_check_source_prog(&prog).is_err());
+}
+
+#[test]
+fn test_typecheck_display() {
+    let exp = parse(&lexpr::from_str("(display (cons \"a\" (null string)))").unwrap()).unwrap();
+    assert_eq!(type_check(&exp).unwrap().typ, Type::Int);
+    let exp = parse(&lexpr::from_str("(newline)").unwrap()).unwrap();
+    assert_eq!(type_check(&exp).unwrap().typ, Type::Int);
+    let exp = parse(&lexpr::from_str(r"(display (cons #\a (null char)))").unwrap()).unwrap();
+    assert_eq!(type_check(&exp).unwrap().typ, Type::Int);
+    let exp = parse(&lexpr::from_str("(display 'foo)").unwrap()).unwrap();
+    assert_eq!(type_check(&exp).unwrap().typ, Type::Int);
+
+    let exp = parse(&lexpr::from_str("(display (make-tuple 1 2))").unwrap()).unwrap();
+    assert!(type_check(&exp).is_err());
+    let exp = parse(&lexpr::from_str("(display (lambda () : int 1))").unwrap()).unwrap();
+    assert!(type_check(&exp).is_err());
+}